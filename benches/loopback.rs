@@ -0,0 +1,125 @@
+//! Runtime overhead of the bundled nodes over the in-process loopback
+//! transport: messages per second with every request in flight at once, and
+//! the latency of a single request, which is dominated by serialization and
+//! channel hops rather than by the node.
+//!
+//! Run with `cargo bench --bench loopback`.
+
+use std::time::{Duration, Instant};
+
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use vorticity::{loopback::Loopback, Init, Node};
+
+#[allow(dead_code)]
+#[path = "../src/bin/broadcast.rs"]
+mod broadcast;
+#[allow(dead_code)]
+#[path = "../src/bin/echo.rs"]
+mod echo;
+#[allow(dead_code)]
+#[path = "../src/bin/kafka.rs"]
+mod kafka;
+
+const SEED: u64 = 0;
+const WARMUP: usize = 1_000;
+const MESSAGES: usize = 20_000;
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn main() -> anyhow::Result<()> {
+    println!(
+        "{:<12} {:>12} {:>10} {:>10} {:>10}",
+        "node", "msgs/sec", "p50 us", "p99 us", "max us"
+    );
+    bench::<_, echo::Request, (), echo::EchoNode>(
+        "echo",
+        (),
+        |i| json!({ "type": "echo", "echo": format!("echo {i}") }),
+    )?;
+    bench::<_, broadcast::Payload, broadcast::InjectedPayload, broadcast::BroadcastNode>(
+        "broadcast",
+        broadcast::BroadcastConfig::default(),
+        |i| json!({ "type": "broadcast", "message": i }),
+    )?;
+    bench::<_, kafka::Payload, kafka::InjectedPayload, kafka::KafkaNode>(
+        "kafka",
+        kafka::KafkaConfig::default(),
+        |i| json!({ "type": "send", "key": format!("k{}", i % 10), "msg": i }),
+    )?;
+
+    Ok(())
+}
+
+fn bench<S, P, IP, N>(name: &str, state: S, request: impl Fn(usize) -> Value) -> anyhow::Result<()>
+where
+    S: Clone + Send + 'static,
+    P: DeserializeOwned + Send + 'static,
+    IP: Clone + Send + 'static,
+    N: Node<S, P, IP>,
+{
+    let init = Init {
+        node_id: "n0".to_string(),
+        node_ids: vec!["n0".to_string(), "n1".to_string(), "n2".to_string()],
+        extensions: Default::default(),
+    };
+
+    // One request at a time, so each latency covers a single step.
+    let mut node = Loopback::start::<S, P, N>(state.clone(), init.clone(), SEED)?;
+    let mut latencies = Vec::with_capacity(MESSAGES);
+    for i in 0..WARMUP + MESSAGES {
+        let start = Instant::now();
+        node.send(&client_message(i, request(i)))?;
+        wait_for_reply(&mut node, i)?;
+        if i >= WARMUP {
+            latencies.push(start.elapsed());
+        }
+    }
+    node.shutdown()?;
+
+    // Everything in flight at once, for throughput.
+    let mut node = Loopback::start::<S, P, N>(state, init, SEED)?;
+    let start = Instant::now();
+    for i in 0..MESSAGES {
+        node.send(&client_message(i, request(i)))?;
+    }
+    for i in 0..MESSAGES {
+        wait_for_reply(&mut node, i)?;
+    }
+    let rate = MESSAGES as f64 / start.elapsed().as_secs_f64();
+    node.shutdown()?;
+
+    latencies.sort();
+    let percentile = |p: f64| {
+        let i = ((latencies.len() as f64 * p) as usize).min(latencies.len() - 1);
+        latencies[i].as_secs_f64() * 1_000_000.0
+    };
+    println!(
+        "{name:<12} {rate:>12.0} {:>10.1} {:>10.1} {:>10.1}",
+        percentile(0.5),
+        percentile(0.99),
+        percentile(1.0),
+    );
+
+    Ok(())
+}
+
+fn client_message(i: usize, mut body: Value) -> String {
+    body["msg_id"] = (i + 1).into();
+    json!({ "src": "c0", "dest": "n0", "body": body }).to_string()
+}
+
+/// Read output until the reply to request `i`, skipping gossip to peers.
+fn wait_for_reply<IP>(node: &mut Loopback<IP>, i: usize) -> anyhow::Result<()>
+where
+    IP: Clone + Send + 'static,
+{
+    loop {
+        let Some(line) = node.recv(REPLY_TIMEOUT)? else {
+            anyhow::bail!("no reply to request {i}");
+        };
+        let msg: Value = serde_json::from_str(&line)?;
+        if msg["body"]["in_reply_to"].as_u64() == Some(i as u64 + 1) {
+            return Ok(());
+        }
+    }
+}
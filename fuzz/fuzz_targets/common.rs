@@ -0,0 +1,15 @@
+use serde::{Deserialize, Serialize};
+
+/// A payload shaped like the workloads', so that parsing gets past the type
+/// tag some of the time.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Echo { echo: String },
+    EchoOk { echo: String },
+    Broadcast { message: usize },
+    Read,
+    ReadOk { messages: Vec<usize> },
+    Send { key: String, msg: serde_json::Value },
+}
@@ -0,0 +1,22 @@
+//! Raw harness input, line by line, through the same parsing as the stdin
+//! loop and on to `ToEvent::to_event`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use vorticity::message::ToEvent;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(input) = std::str::from_utf8(data) else {
+        return;
+    };
+    for line in input.lines() {
+        let Ok(event) = ToEvent::<()>::from_line(line) else {
+            continue;
+        };
+        let _ = event.to_event::<common::Payload>();
+        let _ = event.to_event::<serde_json::Value>();
+    }
+});
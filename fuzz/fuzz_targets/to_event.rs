@@ -0,0 +1,72 @@
+//! Well-formed messages with arbitrary JSON bodies, which get past envelope
+//! parsing and exercise payload and admin dispatch in `ToEvent::to_event`.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use serde_json::{json, Map, Value};
+use vorticity::message::ToEvent;
+
+mod common;
+
+#[derive(Debug, Arbitrary)]
+enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl From<Json> for Value {
+    fn from(json: Json) -> Value {
+        match json {
+            Json::Null => Value::Null,
+            Json::Bool(b) => Value::Bool(b),
+            Json::Int(i) => Value::from(i),
+            Json::Float(f) => Value::from(f),
+            Json::String(s) => Value::String(s),
+            Json::Array(values) => Value::Array(values.into_iter().map(Value::from).collect()),
+            Json::Object(fields) => Value::Object(
+                fields
+                    .into_iter()
+                    .map(|(k, v)| (k, Value::from(v)))
+                    .collect::<Map<_, _>>(),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    /// Picks a known type tag, so payloads are often close to valid.
+    tag: u8,
+    msg_id: Option<u64>,
+    in_reply_to: Option<u64>,
+    body: Json,
+}
+
+const TAGS: &[&str] = &["echo", "broadcast", "read", "read_ok", "send", "admin"];
+
+fuzz_target!(|input: Input| {
+    let mut body = Value::from(input.body);
+    if let Value::Object(fields) = &mut body {
+        if let Some(tag) = TAGS.get(input.tag as usize) {
+            fields.insert("type".to_string(), json!(tag));
+        }
+        if let Some(id) = input.msg_id {
+            fields.insert("msg_id".to_string(), json!(id));
+        }
+        if let Some(id) = input.in_reply_to {
+            fields.insert("in_reply_to".to_string(), json!(id));
+        }
+    }
+    let line = json!({ "src": "c1", "dest": "n0", "body": body }).to_string();
+    let Ok(event) = ToEvent::<()>::from_line(&line) else {
+        return;
+    };
+    let _ = event.to_event::<common::Payload>();
+});
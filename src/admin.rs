@@ -0,0 +1,215 @@
+use std::collections::BTreeMap;
+
+use anyhow::Context as _;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[cfg(feature = "crdt-yrs")]
+use crate::gossip::GossipPayload;
+use crate::{
+    barrier::BarrierPayload, handshake::HandshakePayload, heartbeat::HeartbeatPayload,
+    metrics::Metric, Context, Event, Init, Message, Node, SnapshotState,
+};
+
+/// The body `type` reserved for inter-node control messages.
+pub const ADMIN_TYPE: &str = "admin";
+
+/// Body of an inter-node control message, `{"type": "admin", "admin": ...}`.
+///
+/// The runtime routes these to [`crate::Event::Admin`] before trying the
+/// node's own payload type, so workloads don't need to declare them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "admin")]
+pub struct Admin {
+    pub admin: AdminPayload,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminPayload {
+    /// Gossip for the [`crate::gossip::GossipDoc`] called `doc`.
+    #[cfg(feature = "crdt-yrs")]
+    Gossip { doc: String, gossip: GossipPayload },
+
+    /// A ping or pong of the [`crate::heartbeat`] protocol.
+    Heartbeat(HeartbeatPayload),
+
+    /// An announcement of the [`crate::barrier`] protocol.
+    Barrier(BarrierPayload),
+
+    /// A greeting of the [`crate::handshake`], which the runtime answers
+    /// itself, so nodes never see these.
+    Handshake(HandshakePayload),
+
+    /// Workload specific control messages.
+    Custom(serde_json::Value),
+}
+
+/// The body `type` of a request for a node's statistics,
+/// `{"type": "admin_stats"}`. The runtime answers these itself with
+/// [`AdminStats`], from any sender, so a cluster can be inspected mid-test.
+pub const ADMIN_STATS_TYPE: &str = "admin_stats";
+
+/// A node's statistics, as of when it got an `admin_stats` request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "admin_stats_ok")]
+pub struct AdminStats {
+    pub node_id: Option<String>,
+
+    /// The id the node will send its next message with, i.e. roughly how
+    /// many it sent.
+    pub next_msg_id: usize,
+
+    /// Messages sent but not yet written out.
+    pub outgoing_queued: usize,
+
+    /// Injected events the node has yet to step through.
+    pub injected_queued: usize,
+
+    /// Every metric of the node, e.g. `runtime.messages_received` or
+    /// `gossip.bytes_sent`.
+    pub metrics: BTreeMap<String, Metric>,
+}
+
+impl AdminStats {
+    pub fn of<IP>(ctx: &Context<IP>) -> Self {
+        Self {
+            node_id: ctx.node_id().map(str::to_string),
+            next_msg_id: ctx.msg_id(),
+            outgoing_queued: ctx.outgoing_queued(),
+            injected_queued: ctx.injected_queued(),
+            metrics: ctx.metrics().snapshot(),
+        }
+    }
+}
+
+pub(crate) fn is_stats_request(payload: &Value) -> bool {
+    payload.get("type").and_then(Value::as_str) == Some(ADMIN_STATS_TYPE)
+}
+
+impl Admin {
+    pub fn message(src: &str, dst: &str, admin: AdminPayload) -> anyhow::Result<Message<Admin>> {
+        Message::builder()
+            .src(src)
+            .dst(dst)
+            .payload(Admin { admin })
+            .build()
+    }
+}
+
+/// The body `type` of a request for a node's [`SnapshotState`], answered by
+/// nodes wrapped in [`StateDump`]:
+/// `{"type": "admin_state", "keys": ["logs"], "max_bytes": 65536}`, both
+/// fields being optional.
+pub const ADMIN_STATE_TYPE: &str = "admin_state";
+
+/// Largest state [`StateDump`] replies with, unless the request sets
+/// `max_bytes`.
+pub const DEFAULT_STATE_MAX_BYTES: usize = 1 << 20;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "admin_state")]
+pub struct StateRequest {
+    /// Only these top-level fields of the state, if set.
+    #[serde(default)]
+    pub keys: Option<Vec<String>>,
+
+    #[serde(default)]
+    pub max_bytes: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "admin_state_ok")]
+pub struct StateDumpReply {
+    /// The node's state, or `None` if it was larger than allowed.
+    pub state: Option<Value>,
+
+    /// Size of the serialized state, even if it was left out.
+    pub bytes: usize,
+    pub truncated: bool,
+}
+
+impl StateDumpReply {
+    fn of(state: Value, request: &StateRequest) -> Self {
+        let state = match (&request.keys, state) {
+            (Some(keys), Value::Object(mut fields)) => {
+                fields.retain(|key, _| keys.contains(key));
+                Value::Object(fields)
+            }
+            (_, state) => state,
+        };
+        let bytes = state.to_string().len();
+        let truncated = bytes > request.max_bytes.unwrap_or(DEFAULT_STATE_MAX_BYTES);
+        Self {
+            state: (!truncated).then_some(state),
+            bytes,
+            truncated,
+        }
+    }
+}
+
+/// The node `N`, answering `admin_state` requests with its
+/// [`SnapshotState`] while it runs, so operators can diff the views of two
+/// nodes during a divergence investigation:
+///
+/// ```ignore
+/// Runtime::node::<StateDump<KafkaNode>>().run()
+/// ```
+pub struct StateDump<N> {
+    inner: N,
+}
+
+impl<N> StateDump<N> {
+    pub fn inner(&self) -> &N {
+        &self.inner
+    }
+
+    /// Answer `msg` if it's an `admin_state` request, returning whether it
+    /// was.
+    fn answer<IP>(&self, msg: &Message<Value>, ctx: &Context<IP>) -> anyhow::Result<bool>
+    where
+        N: SnapshotState,
+    {
+        if msg.body().payload.get("type").and_then(Value::as_str) != Some(ADMIN_STATE_TYPE) {
+            return Ok(false);
+        }
+        let request: StateRequest = serde_json::from_value(msg.body().payload.clone())
+            .context("parse admin_state request")?;
+        let state = serde_json::to_value(self.inner.snapshot()).context("serialize node state")?;
+        let reply = ctx.construct_reply(msg, StateDumpReply::of(state, &request));
+        ctx.send(reply).context("send admin state")?;
+        Ok(true)
+    }
+}
+
+impl<S, P, IP, N> Node<S, P, IP> for StateDump<N>
+where
+    N: Node<S, P, IP> + SnapshotState,
+{
+    fn from_init(state: S, init: &Init, context: Context<IP>) -> anyhow::Result<Self> {
+        Ok(Self {
+            inner: N::from_init(state, init, context)?,
+        })
+    }
+
+    fn step(&mut self, input: Event<P, IP>, context: Context<IP>) -> anyhow::Result<()> {
+        if let Event::Arbitrary(msg) = &input {
+            if self.answer(msg, &context)? {
+                return Ok(());
+            }
+        }
+        self.inner.step(input, context)
+    }
+
+    fn handle_reply(&mut self, input: Event<P, IP>, context: Context<IP>) -> anyhow::Result<()> {
+        self.inner.handle_reply(input, context)
+    }
+}
+
+impl<N: SnapshotState> SnapshotState for StateDump<N> {
+    type State = N::State;
+
+    fn snapshot(&self) -> N::State {
+        self.inner.snapshot()
+    }
+}
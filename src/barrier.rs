@@ -0,0 +1,185 @@
+//! Rendezvous of the whole cluster on named phases. Every node announces on
+//! the admin namespace that it reached a phase, and the phase completes on a
+//! node once it reached it itself and heard that every peer did too, e.g.
+//! before compacting, rebalancing, or moving a simulated scenario on:
+//!
+//! ```ignore
+//! if self.barrier.arrive("compact-3", &ctx)? {
+//!     self.compact()?;
+//! }
+//! // ... and on AdminPayload::Barrier messages:
+//! if let Some(phase) = self.barrier.receive(msg.src(), payload, &ctx)? {
+//!     self.compact()?;
+//! }
+//! ```
+//!
+//! Announcements are acknowledged, and [`Barrier::tick`], called from a
+//! timer, sends them again to the peers that didn't, so phases complete on
+//! a lossy network too. A phase completes only once per name; use a fresh
+//! name, like one with a round number, for every rendezvous.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::Context as _;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admin::{Admin, AdminPayload},
+    Context, Init,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BarrierPayload {
+    /// The sender reached `phase`.
+    Ready { phase: String },
+
+    /// The sender heard that the receiver reached `phase`.
+    Ack { phase: String },
+}
+
+/// Where one phase stands on this node.
+#[derive(Debug, Default)]
+struct Phase {
+    arrived: bool,
+
+    /// Peers that reached the phase.
+    ready: BTreeSet<String>,
+
+    /// Peers that heard this node reached it.
+    acked: BTreeSet<String>,
+
+    complete: bool,
+}
+
+/// The phases this node and its peers reached, see the
+/// [module docs](self).
+#[derive(Debug)]
+pub struct Barrier {
+    node_id: String,
+    peers: BTreeSet<String>,
+    phases: BTreeMap<String, Phase>,
+}
+
+impl Barrier {
+    pub fn new(init: &Init) -> Self {
+        Self {
+            node_id: init.node_id.clone(),
+            peers: init
+                .node_ids
+                .iter()
+                .filter(|id| **id != init.node_id)
+                .cloned()
+                .collect(),
+            phases: BTreeMap::new(),
+        }
+    }
+
+    /// Announce that this node reached `phase`. Returns whether that
+    /// completed it, as every peer got there first.
+    pub fn arrive<IP>(&mut self, phase: &str, ctx: &Context<IP>) -> anyhow::Result<bool> {
+        let state = self.phases.entry(phase.to_string()).or_default();
+        if state.arrived {
+            return Ok(false);
+        }
+        state.arrived = true;
+        for peer in &self.peers {
+            let ready = AdminPayload::Barrier(BarrierPayload::Ready {
+                phase: phase.to_string(),
+            });
+            ctx.send(Admin::message(&self.node_id, peer, ready)?)
+                .with_context(|| format!("announcing barrier {phase} to {peer}"))?;
+        }
+
+        Ok(self.try_complete(phase, ctx))
+    }
+
+    /// Take an announcement or acknowledgement from `src`. Returns the phase
+    /// it completed, if any.
+    pub fn receive<IP>(
+        &mut self,
+        src: &str,
+        payload: &BarrierPayload,
+        ctx: &Context<IP>,
+    ) -> anyhow::Result<Option<String>> {
+        if !self.peers.contains(src) {
+            return Ok(None);
+        }
+        match payload {
+            BarrierPayload::Ready { phase } => {
+                let ack = AdminPayload::Barrier(BarrierPayload::Ack {
+                    phase: phase.clone(),
+                });
+                ctx.send(Admin::message(&self.node_id, src, ack)?)
+                    .with_context(|| format!("acknowledging barrier {phase} to {src}"))?;
+                let state = self.phases.entry(phase.clone()).or_default();
+                state.ready.insert(src.to_string());
+                Ok(self.try_complete(phase, ctx).then(|| phase.clone()))
+            }
+            BarrierPayload::Ack { phase } => {
+                if let Some(state) = self.phases.get_mut(phase) {
+                    state.acked.insert(src.to_string());
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /// Announce every phase this node reached again to the peers that
+    /// didn't acknowledge it yet.
+    pub fn tick<IP>(&mut self, ctx: &Context<IP>) -> anyhow::Result<()> {
+        for (phase, state) in self.phases.iter().filter(|(_, s)| s.arrived) {
+            for peer in self.peers.difference(&state.acked) {
+                let ready = AdminPayload::Barrier(BarrierPayload::Ready {
+                    phase: phase.clone(),
+                });
+                ctx.send(Admin::message(&self.node_id, peer, ready)?)
+                    .with_context(|| format!("announcing barrier {phase} to {peer}"))?;
+                ctx.metrics().incr("barrier.resent", 1);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether this node and all its peers reached `phase`.
+    pub fn is_complete(&self, phase: &str) -> bool {
+        self.phases.get(phase).is_some_and(|state| state.complete)
+    }
+
+    /// The nodes `phase` waits for, this one included if it didn't reach it.
+    pub fn waiting_on(&self, phase: &str) -> Vec<&str> {
+        let state = self.phases.get(phase);
+        let arrived = state.is_some_and(|state| state.arrived);
+        let mut waiting = Vec::new();
+        if !arrived {
+            waiting.push(self.node_id.as_str());
+        }
+        waiting.extend(
+            self.peers
+                .iter()
+                .filter(|peer| state.is_none_or(|state| !state.ready.contains(*peer)))
+                .map(String::as_str),
+        );
+        waiting
+    }
+
+    /// Drop what is known of `phase`, e.g. a while after it completed, so it
+    /// isn't announced anymore. Announcements of it arriving afterwards
+    /// start it over.
+    pub fn forget(&mut self, phase: &str) {
+        self.phases.remove(phase);
+    }
+
+    fn try_complete<IP>(&mut self, phase: &str, ctx: &Context<IP>) -> bool {
+        let Some(state) = self.phases.get_mut(phase) else {
+            return false;
+        };
+        if state.complete || !state.arrived || state.ready.len() < self.peers.len() {
+            return false;
+        }
+        state.complete = true;
+        ctx.metrics().incr("barrier.completed", 1);
+        true
+    }
+}
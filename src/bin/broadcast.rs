@@ -1,27 +1,19 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet},
     time::Duration,
 };
 
 use anyhow::Context as _;
-use base64::{
-    engine::{GeneralPurpose, GeneralPurposeConfig},
-    Engine,
-};
-use rand::Rng;
 use serde::{Deserialize, Serialize};
-use vorticity::{Context, Event, Init, Message, Node, Runtime};
-use yrs::{
-    updates::{decoder::Decode, encoder::Encode},
-    Array, ReadTxn, Transact,
+use vorticity::{
+    admin::{AdminPayload, StateDump},
+    gossip::{Fanout, GossipDoc, GossipMode, GossipSchedule},
+    prelude::*,
 };
+use yrs::{Array, Transact};
 
-const ENGINE: GeneralPurpose =
-    GeneralPurpose::new(&base64::alphabet::URL_SAFE, GeneralPurposeConfig::new());
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
+#[vorticity::payload]
+#[derive(Debug, Clone)]
 pub enum Payload {
     Broadcast {
         message: usize,
@@ -29,33 +21,46 @@ pub enum Payload {
     BroadcastOk,
     Read,
     ReadOk {
-        messages: HashSet<usize>,
+        messages: BTreeSet<usize>,
     },
     Topology {
-        topology: HashMap<String, Vec<String>>,
+        topology: BTreeMap<String, Vec<String>>,
     },
     TopologyOk,
-
-    Gossip {
-        diff: String,
-        state_vector: String,
-    },
 }
 
 #[derive(Debug, Clone)]
-enum InjectedPayload {
+pub enum InjectedPayload {
     Gossip,
 }
 
+/// Tuning knobs, see [`vorticity::config`]: e.g. `--gossip-interval-ms=100`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BroadcastConfig {
+    /// Time between gossip rounds, which is also how long a peer may stay
+    /// behind before it counts as stale.
+    pub gossip_interval_ms: u64,
+
+    /// Peers gossiped with between full mesh rounds, a count or a fraction.
+    pub fanout: Fanout,
+}
+
+impl Default for BroadcastConfig {
+    fn default() -> Self {
+        Self {
+            gossip_interval_ms: 300,
+            fanout: Fanout::default(),
+        }
+    }
+}
+
 pub struct BroadcastNode {
-    node_id: String,
-    doc: yrs::Doc,
+    gossip: GossipDoc,
     messages: yrs::ArrayRef,
-    known: HashMap<String, yrs::StateVector>,
-    neighborhood: Vec<String>,
 }
 
-impl Node<(), Payload, InjectedPayload> for BroadcastNode {
+impl Node<BroadcastConfig, Payload, InjectedPayload> for BroadcastNode {
     fn step(
         &mut self,
         input: Event<Payload, InjectedPayload>,
@@ -64,25 +69,14 @@ impl Node<(), Payload, InjectedPayload> for BroadcastNode {
         match input {
             Event::Message(input) => match input.body().payload {
                 Payload::Broadcast { message } => {
-                    let mut txn = self.doc.transact_mut();
+                    let mut txn = self.gossip.doc().transact_mut();
                     self.messages.push_back(&mut txn, message as i64);
 
                     let reply = ctx.construct_reply(&input, Payload::BroadcastOk);
                     ctx.send(reply).context("serialize response to broadcast")?;
                 }
                 Payload::Read => {
-                    let txn = self.doc.transact();
-                    let messages = self
-                        .messages
-                        .iter(&txn)
-                        .map(|v| {
-                            v.cast::<i64>()
-                                .expect("Not an integer")
-                                .try_into()
-                                .expect("all messages should be positive")
-                        })
-                        .collect();
-
+                    let messages = self.read();
                     let reply = ctx.construct_reply(&input, Payload::ReadOk { messages });
                     ctx.send(reply).context("serialize response to read")?;
                 }
@@ -90,104 +84,89 @@ impl Node<(), Payload, InjectedPayload> for BroadcastNode {
                     let reply = ctx.construct_reply(&input, Payload::TopologyOk);
                     ctx.send(reply).context("serialize response to topology")?;
                 }
-                Payload::Gossip {
-                    ref state_vector,
-                    ref diff,
-                } => {
-                    let state_vector = yrs::StateVector::decode_v1(
-                        &ENGINE
-                            .decode(state_vector)
-                            .context("base64 decode failed")?,
-                    )
-                    .context("StateVector decode failed")?;
-                    let update = yrs::Update::decode_v1(
-                        &ENGINE.decode(diff).context("base64 decode failed")?,
-                    )
-                    .context("Update decode failed")?;
-                    self.known.insert(input.src().to_string(), state_vector);
-                    let mut txn = self.doc.transact_mut();
-                    txn.apply_update(update);
-                }
                 Payload::BroadcastOk | Payload::ReadOk { .. } | Payload::TopologyOk => {}
             },
+            Event::Admin(input) => match input.body().payload {
+                AdminPayload::Gossip { ref gossip, .. } => {
+                    self.gossip.receive(input.src(), gossip, &ctx)?;
+                }
+                AdminPayload::Heartbeat(_)
+                | AdminPayload::Barrier(_)
+                | AdminPayload::Handshake(_)
+                | AdminPayload::Custom(_) => {}
+            },
             Event::Eof => {}
             Event::Injected(input) => match input {
                 InjectedPayload::Gossip => {
-                    for n in &self.neighborhood {
-                        let remote_state_vector = &self.known[n];
-                        let txn = self.doc.transact();
-                        let diff = ENGINE.encode(&txn.encode_diff_v1(remote_state_vector));
-                        let state_vector = &txn.state_vector();
-
-                        // Send the update 10% of the time, even if it's the same as the remote state
-                        let mut rng = rand::thread_rng();
-                        if remote_state_vector == state_vector && !rng.gen_bool(0.1) {
-                            continue;
-                        }
-                        let state_vector = ENGINE.encode(&state_vector.encode_v1());
-                        eprintln!(
-                            "sending state_vector to {}: {} bytes",
-                            n,
-                            state_vector.len()
-                        );
-                        eprintln!("sending diff to {}: {} bytes", n, diff.len());
-
-                        ctx.send(
-                            Message::builder()
-                                .src(self.node_id.clone())
-                                .dst(n.clone())
-                                .payload(Payload::Gossip { state_vector, diff })
-                                .build()?,
-                        )
-                        .with_context(|| format!("sending Gossip to {}", n))?;
-                    }
+                    self.gossip.gossip(&ctx)?;
                 }
             },
-            Event::Arbitrary(_) => todo!(),
+            Event::Arbitrary(input) => {
+                input.parse_as::<Payload>()?;
+            }
         }
 
         Ok(())
     }
 
-    fn from_init(_state: (), init: &Init, context: Context<InjectedPayload>) -> anyhow::Result<Self>
-    where
-        Self: Sized,
-    {
-        std::thread::spawn(move || {
-            // generate gossip events
-            // TODO: handle EOF signal
-            loop {
-                std::thread::sleep(Duration::from_millis(300));
-                if context.inject(InjectedPayload::Gossip).is_err() {
-                    break;
-                }
-            }
-        });
-
-        let doc = yrs::Doc::new();
-        let messages = doc.get_or_insert_array("messages");
-        let mut rng = rand::thread_rng();
-        let neighborhood = init
-            .node_ids
-            .iter()
-            .filter(|&_| rng.gen_bool(0.75))
-            .cloned()
-            .collect();
-        Ok(Self {
-            node_id: init.node_id.clone(),
-            doc,
-            messages,
-            known: init
-                .node_ids
-                .iter()
-                .cloned()
-                .map(|nid| (nid, Default::default()))
-                .collect(),
-            neighborhood,
-        })
+    fn from_init(
+        config: BroadcastConfig,
+        init: &Init,
+        context: Context<InjectedPayload>,
+    ) -> anyhow::Result<Self> {
+        let interval = Duration::from_millis(config.gossip_interval_ms);
+        GossipSchedule::new(interval)
+            .with_jitter(0.25)
+            .with_seed(context.seed_for("gossip-schedule"))
+            .with_ttl(interval)
+            .spawn(context.clone(), InjectedPayload::Gossip);
+
+        let gossip = GossipDoc::new("messages", init)
+            .with_seed(context.seed_for("messages"))
+            .with_fanout(config.fanout)
+            .with_clock(context.clock().clone())
+            .with_mode(GossipMode::PushPull)
+            .with_full_mesh_every(10)
+            .with_staleness(interval, 5)
+            .with_snapshot_threshold(1000)
+            .with_env_persistence()?;
+        let messages = gossip.doc().get_or_insert_array("messages");
+        Ok(Self { gossip, messages })
+    }
+}
+
+impl BroadcastNode {
+    fn read(&self) -> BTreeSet<usize> {
+        let txn = self.gossip.doc().transact();
+        self.messages
+            .iter(&txn)
+            .map(|v| {
+                v.cast::<i64>()
+                    .expect("Not an integer")
+                    .try_into()
+                    .expect("all messages should be positive")
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BroadcastState {
+    messages: BTreeSet<usize>,
+}
+
+impl SnapshotState for BroadcastNode {
+    type State = BroadcastState;
+
+    fn snapshot(&self) -> BroadcastState {
+        BroadcastState {
+            messages: self.read(),
+        }
     }
 }
 
 fn main() -> anyhow::Result<()> {
-    Runtime::run::<_, Payload, InjectedPayload, BroadcastNode>(())
+    Runtime::node::<StateDump<BroadcastNode>>()
+        .with_config::<BroadcastConfig>()
+        .run()
 }
@@ -1,12 +1,14 @@
-use anyhow::Context as _;
-use serde::{Deserialize, Serialize};
-use vorticity::{Context, Event, Init, Node, Runtime};
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
-pub enum Payload {
+use vorticity::prelude::*;
+
+#[vorticity::payload]
+#[derive(Debug, Clone)]
+pub enum Request {
     Echo { echo: String },
+}
+
+#[vorticity::payload]
+#[derive(Debug, Clone)]
+pub enum Response {
     EchoOk { echo: String },
 }
 
@@ -14,30 +16,32 @@ pub struct EchoNode {
     pub id: usize,
 }
 
-impl Node<(), Payload> for EchoNode {
-    fn step(&mut self, input: Event<Payload>, ctx: Context<()>) -> anyhow::Result<()> {
-        let Event::Message(input) = input else {
-            unreachable!()
-        };
-        match input.body().payload {
-            Payload::Echo { ref echo } => {
-                let reply = ctx.construct_reply(&input, Payload::EchoOk { echo: echo.clone() });
-                ctx.send(reply).context("serialize response to echo")?;
-            }
-            Payload::EchoOk { .. } => {}
-        }
-
-        Ok(())
-    }
+impl RequestHandler for EchoNode {
+    type State = ();
+    type Request = Request;
+    type Response = Response;
 
-    fn from_init(_state: (), _init: &Init, _ctx: Context<()>) -> anyhow::Result<Self>
-    where
-        Self: Sized,
-    {
+    fn from_init(_state: (), _init: &Init, _ctx: Context<()>) -> anyhow::Result<Self> {
         Ok(Self { id: 1 })
     }
+
+    fn handle(
+        &mut self,
+        Request::Echo { echo }: Request,
+        _ctx: &Context<()>,
+    ) -> anyhow::Result<Response> {
+        Ok(Response::EchoOk { echo })
+    }
+}
+
+impl SnapshotState for EchoNode {
+    type State = usize;
+
+    fn snapshot(&self) -> usize {
+        self.id
+    }
 }
 
 fn main() -> anyhow::Result<()> {
-    Runtime::run::<_, _, _, EchoNode>(())
+    Runtime::node::<EchoNode>().run()
 }
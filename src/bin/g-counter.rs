@@ -1,32 +1,22 @@
-use std::{collections::HashMap, time::Duration};
+use std::{collections::BTreeMap, time::Duration};
 
 use anyhow::Context as _;
-use base64::{
-    engine::{GeneralPurpose, GeneralPurposeConfig},
-    Engine,
-};
-use rand::Rng;
 use serde::{Deserialize, Serialize};
-use vorticity::{Context, Event, Init, Message, Node, Runtime};
-use yrs::{
-    updates::{decoder::Decode, encoder::Encode},
-    Map, ReadTxn, Transact,
+use vorticity::{
+    admin::{AdminPayload, StateDump},
+    gossip::{Fanout, GossipDoc, GossipMode, GossipSchedule},
+    prelude::*,
 };
+use yrs::{Map, Transact};
 
-const ENGINE: GeneralPurpose =
-    GeneralPurpose::new(&base64::alphabet::URL_SAFE, GeneralPurposeConfig::new());
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
+#[vorticity::payload]
+#[derive(Debug, Clone)]
 pub enum Payload {
     Add { delta: u64 },
     AddOk,
 
     Read,
     ReadOk { value: u64 },
-
-    Gossip { diff: String, state_vector: String },
 }
 
 #[derive(Debug, Clone)]
@@ -34,15 +24,20 @@ enum InjectedPayload {
     Gossip,
 }
 
+/// Tuning knobs, see [`vorticity::config`]: e.g. `--fanout=3`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct GCounterConfig {
+    /// Peers gossiped with between full mesh rounds, a count or a fraction.
+    pub fanout: Fanout,
+}
+
 pub struct GCounterNode {
-    node_id: String,
-    doc: yrs::Doc,
+    gossip: GossipDoc,
     counter: yrs::MapRef,
-    known: HashMap<String, yrs::StateVector>,
-    neighborhood: Vec<String>,
 }
 
-impl Node<(), Payload, InjectedPayload> for GCounterNode {
+impl Node<GCounterConfig, Payload, InjectedPayload> for GCounterNode {
     fn step(
         &mut self,
         input: Event<Payload, InjectedPayload>,
@@ -51,16 +46,16 @@ impl Node<(), Payload, InjectedPayload> for GCounterNode {
         match input {
             Event::Message(input) => match input.body().payload {
                 Payload::Add { delta } => {
-                    let mut txn = self.doc.transact_mut();
+                    let mut txn = self.gossip.doc().transact_mut();
                     let old_val = self
                         .counter
-                        .get(&txn, &self.doc.client_id().to_string())
+                        .get(&txn, &self.gossip.doc().client_id().to_string())
                         .unwrap_or(yrs::Value::Any(0.into()))
                         .cast::<i64>()
                         .unwrap();
                     self.counter.insert(
                         &mut txn,
-                        self.doc.client_id().to_string(),
+                        self.gossip.doc().client_id().to_string(),
                         old_val + delta as i64,
                     );
 
@@ -68,119 +63,98 @@ impl Node<(), Payload, InjectedPayload> for GCounterNode {
                     ctx.send(reply).context("serialize response to broadcast")?;
                 }
                 Payload::Read => {
-                    let txn = self.doc.transact();
-                    let value = self
-                        .counter
-                        .iter(&txn)
-                        .map(|(_, v)| -> u64 {
-                            v.cast::<i64>()
-                                .expect("Not an integer")
-                                .try_into()
-                                .expect("all messages should be positive")
-                        })
-                        .sum();
-
+                    let value = self.per_writer().values().sum();
                     let reply = ctx.construct_reply(&input, Payload::ReadOk { value });
                     ctx.send(reply).context("serialize response to read")?;
                 }
 
-                Payload::Gossip {
-                    ref state_vector,
-                    ref diff,
-                } => {
-                    let state_vector = yrs::StateVector::decode_v1(
-                        &ENGINE
-                            .decode(state_vector)
-                            .context("base64 decode failed")?,
-                    )
-                    .context("StateVector decode failed")?;
-                    let update = yrs::Update::decode_v1(
-                        &ENGINE.decode(diff).context("base64 decode failed")?,
-                    )
-                    .context("Update decode failed")?;
-                    self.known.insert(input.src().to_string(), state_vector);
-                    let mut txn = self.doc.transact_mut();
-                    txn.apply_update(update);
-                }
                 Payload::AddOk | Payload::ReadOk { .. } => {}
             },
+            Event::Admin(input) => match input.body().payload {
+                AdminPayload::Gossip { ref gossip, .. } => {
+                    self.gossip.receive(input.src(), gossip, &ctx)?;
+                }
+                AdminPayload::Heartbeat(_)
+                | AdminPayload::Barrier(_)
+                | AdminPayload::Handshake(_)
+                | AdminPayload::Custom(_) => {}
+            },
             Event::Eof => {}
             Event::Injected(input) => match input {
                 InjectedPayload::Gossip => {
-                    for n in &self.neighborhood {
-                        let remote_state_vector = &self.known[n];
-                        let txn = self.doc.transact();
-                        let diff = ENGINE.encode(&txn.encode_diff_v1(remote_state_vector));
-                        let state_vector = &txn.state_vector();
-
-                        // Send the update 10% of the time, even if it's the same as the remote state
-                        let mut rng = rand::thread_rng();
-                        if remote_state_vector == state_vector && !rng.gen_bool(0.1) {
-                            continue;
-                        }
-                        let state_vector = ENGINE.encode(&state_vector.encode_v1());
-                        eprintln!(
-                            "sending state_vector to {}: {} bytes",
-                            n,
-                            state_vector.len()
-                        );
-                        eprintln!("sending diff to {}: {} bytes", n, diff.len());
-                        ctx.send(
-                            Message::builder()
-                                .src(self.node_id.clone())
-                                .dst(n.clone())
-                                .payload(Payload::Gossip { state_vector, diff })
-                                .build()?,
-                        )
-                        .with_context(|| format!("sending Gossip to {}", n))?;
-                    }
+                    self.gossip.gossip(&ctx)?;
                 }
             },
-            Event::Arbitrary(_) => todo!(),
+            Event::Arbitrary(input) => {
+                input.parse_as::<Payload>()?;
+            }
         }
 
         Ok(())
     }
 
-    fn from_init(_state: (), init: &Init, context: Context<InjectedPayload>) -> anyhow::Result<Self>
-    where
-        Self: Sized,
-    {
-        std::thread::spawn(move || {
-            // generate gossip events
-            // TODO: handle EOF signal
-            loop {
-                std::thread::sleep(Duration::from_millis(300));
-                if context.inject(InjectedPayload::Gossip).is_err() {
-                    break;
-                }
-            }
-        });
-
-        let doc = yrs::Doc::new();
-        let counter = doc.get_or_insert_map("counter");
-        let mut rng = rand::thread_rng();
-        let neighborhood = init
-            .node_ids
-            .iter()
-            .filter(|&_| rng.gen_bool(0.75))
-            .cloned()
-            .collect();
-        Ok(Self {
-            node_id: init.node_id.clone(),
-            doc,
-            counter,
-            known: init
-                .node_ids
-                .iter()
-                .cloned()
-                .map(|nid| (nid, Default::default()))
-                .collect(),
-            neighborhood,
-        })
+    fn from_init(
+        config: GCounterConfig,
+        init: &Init,
+        context: Context<InjectedPayload>,
+    ) -> anyhow::Result<Self> {
+        GossipSchedule::new(Duration::from_millis(300))
+            .with_jitter(0.25)
+            .with_seed(context.seed_for("gossip-schedule"))
+            .with_ttl(Duration::from_millis(300))
+            .spawn(context.clone(), InjectedPayload::Gossip);
+
+        let gossip = GossipDoc::new("counter", init)
+            .with_seed(context.seed_for("counter"))
+            .with_fanout(config.fanout)
+            .with_clock(context.clock().clone())
+            .with_mode(GossipMode::PushPull)
+            .with_full_mesh_every(10)
+            .with_staleness(Duration::from_millis(300), 5)
+            .with_env_persistence()?;
+        let counter = gossip.doc().get_or_insert_map("counter");
+        Ok(Self { gossip, counter })
+    }
+}
+
+impl GCounterNode {
+    /// Each writer's contribution, by the writer's yrs client id.
+    fn per_writer(&self) -> BTreeMap<String, u64> {
+        let txn = self.gossip.doc().transact();
+        self.counter
+            .iter(&txn)
+            .map(|(writer, v)| {
+                let v = v
+                    .cast::<i64>()
+                    .expect("Not an integer")
+                    .try_into()
+                    .expect("all messages should be positive");
+                (writer.to_string(), v)
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct GCounterState {
+    value: u64,
+    per_writer: BTreeMap<String, u64>,
+}
+
+impl SnapshotState for GCounterNode {
+    type State = GCounterState;
+
+    fn snapshot(&self) -> GCounterState {
+        let per_writer = self.per_writer();
+        GCounterState {
+            value: per_writer.values().sum(),
+            per_writer,
+        }
     }
 }
 
 fn main() -> anyhow::Result<()> {
-    Runtime::run::<_, Payload, InjectedPayload, GCounterNode>(())
+    Runtime::node::<StateDump<GCounterNode>>()
+        .with_config::<GCounterConfig>()
+        .run()
 }
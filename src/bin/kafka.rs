@@ -1,75 +1,37 @@
-use std::{collections::HashMap, time::Duration};
+use std::{
+    cell::{Ref, RefCell},
+    collections::{BTreeMap, BTreeSet, HashMap},
+    rc::Rc,
+    time::Duration,
+};
 
 use anyhow::{bail, Context as _};
-use base64::{
-    engine::{GeneralPurpose, GeneralPurposeConfig},
-    Engine,
-};
-use rand::Rng;
+use crossbeam_channel::Sender;
 use serde::{Deserialize, Serialize};
 use vorticity::{
-    message::{Init, MessageSet},
-    Context, Event, Message, Node, Runtime,
+    admin::{AdminPayload, StateDump},
+    gossip::{
+        Divergence, Fanout, GossipDoc, GossipMode, GossipPayload, GossipSchedule, PeerStatus,
+        STATE_DIR_ENV,
+    },
+    heartbeat::Heartbeat,
+    log,
+    mailbox::{Actor, Mailboxes},
+    prelude::*,
+    rpc::Callbacks,
 };
 use yrs::{
-    types::ToJson,
-    updates::{decoder::Decode, encoder::Encode},
-    Array, ArrayPrelim, ArrayRef, Map, ReadTxn, Transact, Value,
+    types::{Change, ToJson},
+    Array, ArrayRef, Map, Observable, Subscription, Transact, Value,
 };
 
 // mod kafka_lib;
 
-const ENGINE: GeneralPurpose =
-    GeneralPurpose::new(&base64::alphabet::URL_SAFE, GeneralPurposeConfig::new());
-
 type Msg = yrs::Any;
 
-enum CallbackStatus {
-    MoreWork,
-    Finished,
-}
-
-type RpcCallback = dyn Fn(
-    &Message<Payload>,
-    &mut MessageSet<Payload>,
-    &Message<Payload>,
-    Context<InjectedPayload>,
-) -> anyhow::Result<CallbackStatus>;
-
-struct CallbackInfo {
-    unhandled_incoming_msg: Message<Payload>,
-    sent_msgs: MessageSet<Payload>,
-    callback: Box<RpcCallback>,
-}
-
-impl CallbackInfo {
-    fn new(
-        orig_msg: Message<Payload>,
-        sent_msgs: MessageSet<Payload>,
-        callback: impl Fn(
-                &Message<Payload>,
-                &mut MessageSet<Payload>,
-                &Message<Payload>,
-                Context<InjectedPayload>,
-            ) -> anyhow::Result<CallbackStatus>
-            + 'static,
-    ) -> Self {
-        Self {
-            unhandled_incoming_msg: orig_msg,
-            sent_msgs,
-            callback: Box::new(callback),
-        }
-    }
-
-    fn matches(&self, msg: &Message<Payload>) -> bool {
-        self.sent_msgs.is_matching_reply(msg) && self.unhandled_incoming_msg.dst() == msg.dst()
-    }
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
-enum Payload {
+#[vorticity::payload]
+#[derive(Debug, Clone)]
+pub enum Payload {
     Send {
         key: String,
         msg: Msg,
@@ -79,14 +41,14 @@ enum Payload {
     },
 
     Poll {
-        offsets: HashMap<String, u64>,
+        offsets: BTreeMap<String, u64>,
     },
     PollOk {
-        msgs: HashMap<String, Vec<(u64, Msg)>>,
+        msgs: BTreeMap<String, Vec<(u64, Msg)>>,
     },
 
     CommitOffsets {
-        offsets: HashMap<String, u64>,
+        offsets: BTreeMap<String, u64>,
     },
     CommitOffsetsOk,
 
@@ -94,111 +56,306 @@ enum Payload {
         keys: Vec<String>,
     },
     ListCommittedOffsetsOk {
-        offsets: HashMap<String, u64>,
+        offsets: BTreeMap<String, u64>,
     },
+}
 
-    Admin(AdminPayload),
+#[derive(Clone, Debug)]
+pub enum InjectedPayload {
+    Gossip,
+    Peer(PeerStatus),
+    Divergence(Divergence),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
-enum AdminPayload {
-    Gossip { diff: String, state_vector: String },
+/// Gossip doc names of the per-key logs start with this, followed by the key.
+const LOG_DOC_PREFIX: &str = "log-";
+
+/// A single key's log, living in its own doc so that gossip diffs and
+/// transactions don't contend with other keys.
+struct LogDoc {
+    gossip: GossipDoc,
+    log: ArrayRef,
+
+    /// The log's messages by offset, so polls don't have to walk the log
+    /// from the start. Kept up to date by `_observer`, from the changes of
+    /// every transaction, appends and merged gossip alike.
+    index: Rc<RefCell<Vec<Msg>>>,
+    _observer: Subscription,
 }
 
-#[derive(Clone, Debug)]
-enum InjectedPayload {
+impl LogDoc {
+    fn new(
+        init: &Init,
+        neighborhood: &[String],
+        key: &str,
+        ctx: &Context<InjectedPayload>,
+    ) -> anyhow::Result<Self> {
+        let name = format!("{LOG_DOC_PREFIX}{key}");
+        let gossip = GossipDoc::new(&name, init)
+            .with_seed(ctx.seed_for(&name))
+            .with_clock(ctx.clock().clone())
+            .with_mode(GossipMode::PushPull)
+            .with_full_mesh_every(10)
+            .with_neighborhood(neighborhood.to_vec())
+            .with_snapshot_threshold(1000)
+            .with_env_persistence()?;
+        let log = gossip.doc().get_or_insert_array("log");
+        let index = {
+            let txn = gossip.doc().transact();
+            Rc::new(RefCell::new(
+                log.iter(&txn).map(|v| v.to_json(&txn)).collect::<Vec<_>>(),
+            ))
+        };
+        let observer = {
+            let index = index.clone();
+            log.observe(move |txn, event| {
+                let mut index = index.borrow_mut();
+                let mut at = 0;
+                for change in event.delta(txn) {
+                    match change {
+                        Change::Retain(len) => at += *len as usize,
+                        Change::Added(values) => {
+                            let msgs = values.iter().map(|v| v.to_json(txn));
+                            index.splice(at..at, msgs);
+                            at += values.len();
+                        }
+                        Change::Removed(len) => {
+                            index.drain(at..at + *len as usize);
+                        }
+                    }
+                }
+            })
+        };
+        Ok(Self {
+            gossip,
+            log,
+            index,
+            _observer: observer,
+        })
+    }
+
+    /// Every message of the log, by offset.
+    fn entries(&self) -> Ref<'_, Vec<Msg>> {
+        self.index.borrow()
+    }
+
+    /// Append `msg` to the log, returning its offset. The offset is taken in
+    /// the same transaction as the push, so nothing can land in between.
+    fn append(&mut self, msg: &Msg) -> u64 {
+        let mut txn = self.gossip.doc().transact_mut();
+        let offset = self.log.len(&txn) as u64;
+        self.log.push_back(&mut txn, msg.clone());
+        offset
+    }
+}
+
+/// What the actor of a key's log is asked to do.
+enum LogOp {
+    /// Append the message of a `send` request and answer it.
+    Append {
+        msg: Msg,
+        request: Message<Payload>,
+    },
+
+    /// Hand over the key and the messages from offset `from` on.
+    Read {
+        from: u64,
+        reply: Sender<(String, Vec<(u64, Msg)>)>,
+    },
+
+    /// Hand over the key and every message.
+    Snapshot {
+        reply: Sender<(String, Vec<Msg>)>,
+    },
+
     Gossip,
+    Receive {
+        src: String,
+        gossip: GossipPayload,
+    },
+}
+
+/// A key's log, owned by the worker thread its key maps to, so sends to
+/// different keys append in parallel.
+struct LogActor {
+    key: String,
+    doc: LogDoc,
+    ctx: Context<InjectedPayload>,
+}
+
+impl Actor for LogActor {
+    type Msg = LogOp;
+
+    fn handle(&mut self, op: LogOp) -> anyhow::Result<()> {
+        match op {
+            LogOp::Append { msg, request } => {
+                let offset = self.doc.append(&msg);
+                let reply = self
+                    .ctx
+                    .construct_reply(&request, Payload::SendOk { offset });
+                self.ctx.send(reply).context("serialize response to send")?;
+            }
+            LogOp::Read { from, reply } => {
+                let entries = self.doc.entries();
+                let from = (from as usize).min(entries.len());
+                let msgs = (from as u64..).zip(entries[from..].iter().cloned());
+                let _ = reply.send((self.key.clone(), msgs.collect()));
+            }
+            LogOp::Snapshot { reply } => {
+                let msgs = self.doc.entries().clone();
+                let _ = reply.send((self.key.clone(), msgs));
+            }
+            LogOp::Gossip => self.doc.gossip.gossip(&self.ctx)?,
+            LogOp::Receive { src, gossip } => self.doc.gossip.receive(&src, &gossip, &self.ctx)?,
+        }
+
+        Ok(())
+    }
+}
+
+/// Keys of the logs previously persisted for this node, if persistence is on.
+fn persisted_keys(init: &Init) -> anyhow::Result<Vec<String>> {
+    let Some(dir) = std::env::var_os(STATE_DIR_ENV) else {
+        return Ok(Vec::new());
+    };
+    let prefix = format!("{}-{LOG_DOC_PREFIX}", init.node_id);
+    let mut keys = Vec::new();
+    for entry in std::fs::read_dir(&dir).context("listing persisted docs")? {
+        let name = entry.context("listing persisted docs")?.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if let Some(key) = name
+            .strip_prefix(&prefix)
+            .and_then(|n| n.strip_suffix(".ydoc"))
+        {
+            keys.push(key.to_string());
+        }
+    }
+
+    Ok(keys)
+}
+
+/// Divergence check flagging committed offsets that moved backwards after a
+/// merge, which happens when concurrent commits race in the map.
+fn offsets_regressed() -> impl FnMut(&yrs::Doc) -> Option<String> + Send {
+    let mut highest = HashMap::<String, i64>::new();
+    move |doc| {
+        let offsets = doc.get_or_insert_map("offsets");
+        let txn = doc.transact();
+        let mut regressed = Vec::new();
+        for (key, value) in offsets.iter(&txn) {
+            let Ok(offset) = value.cast::<i64>() else {
+                continue;
+            };
+            let seen = highest.entry(key.to_string()).or_insert(offset);
+            if offset < *seen {
+                regressed.push(format!("{key}: {seen} -> {offset}"));
+            } else {
+                *seen = offset;
+            }
+        }
+        regressed.sort();
+        (!regressed.is_empty()).then(|| format!("offsets regressed: {}", regressed.join(", ")))
+    }
+}
+
+/// Tuning knobs, see [`vorticity::config`]: e.g. `--fanout=3`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct KafkaConfig {
+    /// Peers gossiped with between full mesh rounds, a count or a fraction.
+    /// Every key's log gossips with the same peers.
+    pub fanout: Fanout,
+
+    /// Threads the logs of the keys are spread over, e.g. `--workers=4`. By
+    /// default there are none, and the event loop works on the logs itself,
+    /// so simulated and golden runs stay deterministic.
+    pub workers: usize,
 }
 
 pub struct KafkaNode {
-    node_id: String,
-    doc: yrs::Doc,
-    logs: yrs::MapRef,
+    gossip: GossipDoc,
     offsets: yrs::MapRef,
-    known: HashMap<String, yrs::StateVector>,
-    neighborhood: Vec<String>,
 
-    callbacks: Vec<CallbackInfo>,
+    /// Every key's log, and the keys that have one.
+    logs: Mailboxes<String, LogActor>,
+    keys: BTreeSet<String>,
+    heartbeat: Heartbeat,
+
+    callbacks: Callbacks<Payload, InjectedPayload>,
 }
 
-impl Node<(), Payload, InjectedPayload> for KafkaNode {
+impl Node<KafkaConfig, Payload, InjectedPayload> for KafkaNode {
     fn step(
         &mut self,
         input: Event<Payload, InjectedPayload>,
         ctx: Context<InjectedPayload>,
     ) -> anyhow::Result<()> {
         match input {
-            Event::Message(input) => match input.body().payload {
-                Payload::Send { ref key, ref msg } => {
-                    self.handle_send(key, msg, &ctx, &input)?;
-                }
-                Payload::Poll { ref offsets } => {
-                    self.handle_poll(offsets, &ctx, &input)?;
-                }
-                Payload::CommitOffsets { ref offsets } => {
-                    self.handle_commit_offsets(offsets, &ctx, &input)?;
-                }
-                Payload::ListCommittedOffsets { ref keys } => {
-                    self.handle_list_committed_offsets(keys, &ctx, &input)?;
-                }
-
-                Payload::Admin(_) => {
-                    self.handle_admin(&input, &ctx)?;
-                }
-                Payload::PollOk { .. }
-                | Payload::SendOk { .. }
-                | Payload::ListCommittedOffsetsOk { .. }
-                | Payload::CommitOffsetsOk => {}
-            },
+            Event::Message(input) => {
+                self.dispatch(&input, &ctx)?;
+            }
+            Event::Admin(input) => {
+                self.handle_admin(&input, &ctx)?;
+            }
             Event::Eof => {}
             Event::Injected(input) => {
                 self.handle_injected(input, &ctx)?;
             }
-            Event::Arbitrary(_) => todo!(),
+            Event::Arbitrary(input) => {
+                input.parse_as::<Payload>()?;
+            }
         }
 
         Ok(())
     }
 
-    fn from_init(_state: (), init: &Init, context: Context<InjectedPayload>) -> anyhow::Result<Self>
-    where
-        Self: Sized,
-    {
-        std::thread::spawn(move || {
-            // generate gossip events
-            // TODO: handle EOF signal
-            loop {
-                std::thread::sleep(Duration::from_millis(300));
-                if context.inject(InjectedPayload::Gossip).is_err() {
-                    break;
-                }
-            }
-        });
-
-        let doc = yrs::Doc::new();
-        let logs = doc.get_or_insert_map("counter");
-        let offsets = doc.get_or_insert_map("offsets");
-        let mut rng = rand::thread_rng();
-        let neighborhood = init
-            .node_ids
-            .iter()
-            .filter(|&_| rng.gen_bool(0.75))
-            .cloned()
-            .collect();
+    fn from_init(
+        config: KafkaConfig,
+        init: &Init,
+        context: Context<InjectedPayload>,
+    ) -> anyhow::Result<Self> {
+        GossipSchedule::new(Duration::from_millis(300))
+            .with_jitter(0.25)
+            .with_seed(context.seed_for("gossip-schedule"))
+            .with_ttl(Duration::from_millis(300))
+            .spawn(context.clone(), InjectedPayload::Gossip);
+
+        let gossip = GossipDoc::new("offsets", init)
+            .with_seed(context.seed_for("offsets"))
+            .with_fanout(config.fanout)
+            .with_clock(context.clock().clone())
+            .with_mode(GossipMode::PushPull)
+            .with_full_mesh_every(10)
+            .with_staleness(Duration::from_millis(300), 5)
+            .on_peer_status(context.clone(), InjectedPayload::Peer)
+            .on_divergence(
+                context.clone(),
+                offsets_regressed(),
+                InjectedPayload::Divergence,
+            )
+            .with_env_persistence()?;
+        let offsets = gossip.doc().get_or_insert_map("offsets");
+        let logs = {
+            let init = init.clone();
+            let neighborhood = gossip.neighborhood().to_vec();
+            let ctx = context.clone();
+            Mailboxes::new(config.workers, move |key: &String| {
+                Ok(LogActor {
+                    key: key.clone(),
+                    doc: LogDoc::new(&init, &neighborhood, key, &ctx)?,
+                    ctx: ctx.clone(),
+                })
+            })
+        };
         Ok(Self {
-            node_id: init.node_id.clone(),
-            doc,
-            logs,
+            gossip,
             offsets,
-            known: init
-                .node_ids
-                .iter()
-                .cloned()
-                .map(|nid| (nid, Default::default()))
-                .collect(),
-            neighborhood,
-            callbacks: Vec::new(),
+            logs,
+            keys: persisted_keys(init)?.into_iter().collect(),
+            heartbeat: Heartbeat::new(init),
+            callbacks: Callbacks::new(),
         })
     }
 
@@ -207,34 +364,15 @@ impl Node<(), Payload, InjectedPayload> for KafkaNode {
         input: Event<Payload, InjectedPayload>,
         context: Context<InjectedPayload>,
     ) -> anyhow::Result<()> {
-        // TODO: Make this handle callbacks stored in a data structure
         let Event::Message(input) = input else {
             bail!("expected Message")
         };
 
-        let callback = self
-            .callbacks
-            .iter_mut()
-            .find(|c| c.matches(&input))
-            .ok_or(anyhow::anyhow!("Reply to message we don't have: {input:?}"))?;
-        let f = &callback.callback;
-        let status = f(
-            &callback.unhandled_incoming_msg,
-            &mut callback.sent_msgs,
-            &input,
-            context,
-        )
-        .context("Running callback caused an error")?;
-
-        match status {
-            CallbackStatus::MoreWork => {}
-            CallbackStatus::Finished => self.callbacks.retain(|c| !c.matches(&input)),
-        }
-
-        Ok(())
+        self.callbacks.handle(&input, context)
     }
 }
 
+#[vorticity::handlers(payload = Payload, injected = InjectedPayload)]
 impl KafkaNode {
     fn handle_injected(
         &mut self,
@@ -245,41 +383,30 @@ impl KafkaNode {
             InjectedPayload::Gossip => {
                 self.send_gossip(ctx)?;
             }
+            InjectedPayload::Peer(status) => {
+                log::info(format!("peer change: {status:?}")).emit();
+            }
+            InjectedPayload::Divergence(divergence) => {
+                log::warn(format!("{divergence:?}")).emit();
+            }
         };
 
         Ok(())
     }
 
+    /// Hand `op` to the log of `key`, which gets one if it had none.
+    fn send_to_log(&mut self, key: &str, op: LogOp) -> anyhow::Result<()> {
+        if !self.keys.contains(key) {
+            self.keys.insert(key.to_string());
+        }
+        self.logs.send(key.to_string(), op)
+    }
+
     fn send_gossip(&mut self, ctx: &Context<InjectedPayload>) -> anyhow::Result<()> {
-        for n in &self.neighborhood {
-            if n == &self.node_id {
-                continue;
-            }
-            let remote_state_vector = &self.known[n];
-            let txn = self.doc.transact();
-            let diff = ENGINE.encode(&txn.encode_diff_v1(remote_state_vector));
-            let state_vector = &txn.state_vector();
-
-            // Send the update 10% of the time, even if it's the same as the remote state
-            let mut rng = rand::thread_rng();
-            if remote_state_vector == state_vector && !rng.gen_bool(0.1) {
-                continue;
-            }
-            let state_vector = ENGINE.encode(&state_vector.encode_v1());
-            eprintln!(
-                "sending state_vector to {}: {} bytes",
-                n,
-                state_vector.len()
-            );
-            eprintln!("sending diff to {}: {} bytes", n, diff.len());
-            ctx.send(
-                Message::builder()
-                    .src(self.node_id.clone())
-                    .dst(n.clone())
-                    .payload(Payload::Admin(AdminPayload::Gossip { state_vector, diff }))
-                    .build()?,
-            )
-            .with_context(|| format!("sending Gossip to {}", n))?;
+        self.heartbeat.ping(ctx)?;
+        self.gossip.gossip(ctx)?;
+        for key in &self.keys {
+            self.logs.send(key.clone(), LogOp::Gossip)?;
         }
 
         Ok(())
@@ -287,32 +414,30 @@ impl KafkaNode {
 
     fn handle_admin(
         &mut self,
-        input: &Message<Payload>,
-        _ctx: &Context<InjectedPayload>,
+        input: &Message<AdminPayload>,
+        ctx: &Context<InjectedPayload>,
     ) -> anyhow::Result<()> {
-        let Payload::Admin(admin_payload) = &input.body().payload else {
-            anyhow::bail!("expected Admin payload");
-        };
-        match admin_payload {
-            AdminPayload::Gossip { state_vector, diff } => {
-                let state_vector = yrs::StateVector::decode_v1(
-                    &ENGINE
-                        .decode(state_vector)
-                        .context("base64 decode failed")?,
-                )
-                .context("StateVector decode failed")?;
-                let update =
-                    yrs::Update::decode_v1(&ENGINE.decode(diff).context("base64 decode failed")?)
-                        .context("Update decode failed")?;
-                self.known.insert(input.src().to_string(), state_vector);
-                let mut txn = self.doc.transact_mut();
-                txn.apply_update(update);
+        match &input.body().payload {
+            AdminPayload::Gossip { doc, gossip } => match doc.strip_prefix(LOG_DOC_PREFIX) {
+                Some(key) => {
+                    let src = input.src().to_string();
+                    let gossip = gossip.clone();
+                    self.send_to_log(key, LogOp::Receive { src, gossip })?;
+                }
+                None => self.gossip.receive(input.src(), gossip, ctx)?,
+            },
+            AdminPayload::Heartbeat(heartbeat) => {
+                if let Some(peer) = self.heartbeat.receive(input.src(), heartbeat, ctx)? {
+                    self.gossip.heard_from(peer)?;
+                }
             }
+            AdminPayload::Barrier(_) | AdminPayload::Handshake(_) | AdminPayload::Custom(_) => {}
         };
 
         Ok(())
     }
 
+    #[handler]
     fn handle_send(
         &mut self,
         key: &str,
@@ -320,61 +445,45 @@ impl KafkaNode {
         ctx: &Context<InjectedPayload>,
         input: &Message<Payload>,
     ) -> Result<(), anyhow::Error> {
-        let mut txn = self.doc.transact_mut();
-        let list = self.logs.get(&txn, key);
-        let list = match list {
-            Some(Value::YArray(list)) => list,
-            _ => {
-                let list: ArrayRef = self.logs.insert(&mut txn, key, ArrayPrelim::default());
-                list
-            }
+        // The log's actor answers once it appended the message.
+        ctx.defer_reply(input);
+        let append = LogOp::Append {
+            msg: msg.clone(),
+            request: input.clone(),
         };
-        list.push_back(&mut txn, msg.clone());
-        txn.commit();
-
-        let reply = ctx.construct_reply(
-            input,
-            Payload::SendOk {
-                offset: list.len(&txn) as u64 - 1,
-            },
-        );
-        ctx.send(reply).context("serialize response to broadcast")?;
-        Ok(())
+        self.send_to_log(key, append)
     }
 
+    #[handler]
     fn handle_poll(
         &mut self,
-        offsets: &HashMap<String, u64>,
+        offsets: &BTreeMap<String, u64>,
         ctx: &Context<InjectedPayload>,
         input: &Message<Payload>,
     ) -> Result<(), anyhow::Error> {
-        let txn = self.doc.transact();
-        let offsets = offsets
-            .iter()
-            .filter_map(|(k, v)| {
-                let list = self.logs.get(&txn, k)?.cast::<ArrayRef>().ok()?;
-                Some((
-                    k.clone(),
-                    list.iter(&txn)
-                        .enumerate()
-                        .skip(*v as usize)
-                        .map(|(i, v)| (i as u64, v.to_json(&txn)))
-                        .collect::<Vec<(u64, Msg)>>(),
-                ))
-            })
-            .collect::<HashMap<String, Vec<(u64, Msg)>>>();
+        // Reads queue behind the sends to their key, so they see them.
+        let (tx, rx) = crossbeam_channel::unbounded();
+        for (key, from) in offsets.iter().filter(|(k, _)| self.keys.contains(*k)) {
+            let reply = tx.clone();
+            self.logs
+                .send(key.clone(), LogOp::Read { from: *from, reply })?;
+        }
+        drop(tx);
+        let offsets = rx.iter().collect::<BTreeMap<String, Vec<(u64, Msg)>>>();
+        self.logs.check()?;
         let reply = ctx.construct_reply(input, Payload::PollOk { msgs: offsets });
         ctx.send(reply).context("serialize response to read")?;
         Ok(())
     }
 
+    #[handler]
     fn handle_commit_offsets(
         &mut self,
-        offsets: &HashMap<String, u64>,
+        offsets: &BTreeMap<String, u64>,
         ctx: &Context<InjectedPayload>,
         input: &Message<Payload>,
     ) -> Result<(), anyhow::Error> {
-        let mut txn = self.doc.transact_mut();
+        let mut txn = self.gossip.doc().transact_mut();
         offsets.iter().for_each(|(k, v)| {
             self.offsets.insert(&mut txn, k.clone(), *v as i64);
         });
@@ -383,13 +492,14 @@ impl KafkaNode {
         Ok(())
     }
 
+    #[handler]
     fn handle_list_committed_offsets(
         &mut self,
         keys: &[String],
         ctx: &Context<InjectedPayload>,
         input: &Message<Payload>,
     ) -> Result<(), anyhow::Error> {
-        let txn = self.doc.transact();
+        let txn = self.gossip.doc().transact();
         let offsets = keys
             .iter()
             .map(|k| {
@@ -409,8 +519,36 @@ impl KafkaNode {
     }
 }
 
-impl KafkaNode {}
+#[derive(Debug, Serialize)]
+pub struct KafkaState {
+    logs: BTreeMap<String, Vec<Msg>>,
+    committed: BTreeMap<String, u64>,
+}
+
+impl SnapshotState for KafkaNode {
+    type State = KafkaState;
+
+    fn snapshot(&self) -> KafkaState {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        for key in &self.keys {
+            let reply = tx.clone();
+            // A failed actor leaves its key out.
+            let _ = self.logs.send(key.clone(), LogOp::Snapshot { reply });
+        }
+        drop(tx);
+        let logs = rx.iter().collect();
+        let txn = self.gossip.doc().transact();
+        let committed = self
+            .offsets
+            .iter(&txn)
+            .filter_map(|(key, offset)| Some((key.to_string(), offset.cast::<i64>().ok()? as u64)))
+            .collect();
+        KafkaState { logs, committed }
+    }
+}
 
 fn main() -> anyhow::Result<()> {
-    Runtime::run::<_, Payload, InjectedPayload, KafkaNode>(())
+    Runtime::node::<StateDump<KafkaNode>>()
+        .with_config::<KafkaConfig>()
+        .run()
 }
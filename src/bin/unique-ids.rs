@@ -1,12 +1,14 @@
-use anyhow::Context as _;
-use serde::{Deserialize, Serialize};
-use vorticity::{Context, Event, Init, Node, Runtime};
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
-pub enum Payload {
+use vorticity::prelude::*;
+
+#[vorticity::payload]
+#[derive(Debug, Clone)]
+pub enum Request {
     Generate,
+}
+
+#[vorticity::payload]
+#[derive(Debug, Clone)]
+pub enum Response {
     GenerateOk {
         #[serde(rename = "id")]
         guid: String,
@@ -17,34 +19,35 @@ pub struct UniqueNode {
     pub node: String,
 }
 
-impl Node<(), Payload> for UniqueNode {
-    fn step(&mut self, input: Event<Payload>, ctx: Context<()>) -> anyhow::Result<()> {
-        let Event::Message(input) = input else {
-            unreachable!();
-        };
-        match input.body().payload {
-            Payload::Generate => {
-                let guid = format!("{}-{}", self.node, ctx.msg_id());
-                let reply = ctx.construct_reply(&input, Payload::GenerateOk { guid });
-
-                ctx.send(reply).context("serialize response to generate")?;
-            }
-            Payload::GenerateOk { .. } => {}
-        }
-
-        Ok(())
-    }
+impl RequestHandler for UniqueNode {
+    type State = ();
+    type Request = Request;
+    type Response = Response;
 
-    fn from_init(_state: (), init: &Init, _ctx: Context<()>) -> anyhow::Result<Self>
-    where
-        Self: Sized,
-    {
+    fn from_init(_state: (), init: &Init, _ctx: Context<()>) -> anyhow::Result<Self> {
         Ok(Self {
             node: init.node_id.clone(),
         })
     }
+
+    fn handle(
+        &mut self,
+        Request::Generate: Request,
+        ctx: &Context<()>,
+    ) -> anyhow::Result<Response> {
+        let guid = format!("{}-{}", self.node, ctx.msg_id());
+        Ok(Response::GenerateOk { guid })
+    }
+}
+
+impl SnapshotState for UniqueNode {
+    type State = String;
+
+    fn snapshot(&self) -> String {
+        self.node.clone()
+    }
 }
 
 fn main() -> anyhow::Result<()> {
-    Runtime::run::<_, _, _, UniqueNode>(())
+    Runtime::node::<UniqueNode>().run()
 }
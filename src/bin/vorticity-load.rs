@@ -0,0 +1,306 @@
+//! Drives a single node binary as a Maelstrom client would: sends init, then
+//! a weighted mix of workload operations at a target rate, checks the
+//! replies, and reports latencies. Useful for benchmarking without the jar.
+//!
+//! Usage: vorticity-load <binary> <workload> [--rate OPS_PER_SEC]
+//!        [--time-limit SECS] [--mix op=weight,...] [--seed N]
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::{BufRead, BufReader, Write},
+    process::{Command, ExitCode, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context as _};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde_json::{json, Value};
+
+const NODE_ID: &str = "n0";
+const CLIENT_ID: &str = "c0";
+
+/// How long outstanding requests get to complete after the last send.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The operations of each workload, with their default weights.
+const WORKLOADS: &[(&str, &[(&str, u32)])] = &[
+    ("echo", &[("echo", 1)]),
+    ("unique-ids", &[("generate", 1)]),
+    ("broadcast", &[("broadcast", 3), ("read", 1)]),
+    ("g-counter", &[("add", 3), ("read", 1)]),
+    (
+        "kafka",
+        &[
+            ("send", 4),
+            ("poll", 2),
+            ("commit_offsets", 1),
+            ("list_committed_offsets", 1),
+        ],
+    ),
+];
+
+/// A request waiting for its reply.
+struct Pending {
+    op: String,
+    sent: Instant,
+    body: Value,
+}
+
+#[derive(Default)]
+struct OpStats {
+    sent: u64,
+    ok: u64,
+    errors: u64,
+    invalid: u64,
+    latencies: Vec<Duration>,
+}
+
+/// What the client believes about the node's state, to validate replies.
+#[derive(Default)]
+struct Model {
+    /// Generated ids, kept as their JSON text.
+    ids: HashSet<String>,
+    broadcast: HashSet<u64>,
+    counter: u64,
+    offsets: HashMap<String, u64>,
+}
+
+impl Model {
+    /// Account for a successful reply, describing it if it's wrong.
+    fn check(&mut self, request: &Value, reply: &Value) -> Option<String> {
+        match request["type"].as_str()? {
+            "echo" if reply["echo"] != request["echo"] => Some("echo mismatch".to_string()),
+            "generate" if !self.ids.insert(reply["id"].to_string()) => {
+                Some(format!("duplicate id {}", reply["id"]))
+            }
+            "broadcast" => {
+                self.broadcast.insert(request["message"].as_u64()?);
+                None
+            }
+            "add" => {
+                self.counter += request["delta"].as_u64()?;
+                None
+            }
+            "read" if reply.get("messages").is_some() => {
+                let read = reply["messages"]
+                    .as_array()?
+                    .iter()
+                    .filter_map(Value::as_u64)
+                    .collect::<HashSet<_>>();
+                let missing = self.broadcast.difference(&read).count();
+                (missing > 0).then(|| format!("read is missing {missing} acknowledged messages"))
+            }
+            "read" => {
+                let value = reply["value"].as_u64()?;
+                (value < self.counter)
+                    .then(|| format!("read {value}, but {} was acknowledged", self.counter))
+            }
+            "send" => {
+                let key = request["key"].as_str()?.to_string();
+                let offset = reply["offset"].as_u64()?;
+                let previous = self.offsets.insert(key.clone(), offset);
+                previous
+                    .filter(|&previous| previous >= offset)
+                    .map(|previous| format!("offset of {key} went from {previous} to {offset}"))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn main() -> anyhow::Result<ExitCode> {
+    let mut args = std::env::args().skip(1);
+    let usage = "usage: vorticity-load <binary> <workload> [--rate N] [--time-limit SECS] [--mix op=weight,...] [--seed N]";
+    let binary = args.next().context(usage)?;
+    let workload = args.next().context(usage)?;
+    let mut mix = WORKLOADS
+        .iter()
+        .find(|(name, _)| *name == workload)
+        .with_context(|| format!("unknown workload {workload}"))?
+        .1
+        .iter()
+        .map(|&(op, weight)| (op.to_string(), weight))
+        .collect::<BTreeMap<_, _>>();
+    let mut rate = 100.0;
+    let mut time_limit = Duration::from_secs(10);
+    let mut seed = rand::random();
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .with_context(|| format!("{arg} needs a value"))?;
+        match arg.as_str() {
+            "--rate" => rate = value.parse().context("--rate needs a number")?,
+            "--time-limit" => {
+                time_limit =
+                    Duration::from_secs_f64(value.parse().context("--time-limit needs a number")?)
+            }
+            "--seed" => seed = value.parse().context("--seed needs a number")?,
+            "--mix" => {
+                for setting in value.split(',') {
+                    let (op, weight) = setting
+                        .split_once('=')
+                        .with_context(|| format!("expected op=weight, got {setting}"))?;
+                    let Some(entry) = mix.get_mut(op) else {
+                        bail!("{workload} has no operation {op}");
+                    };
+                    *entry = weight.parse().context("weights are whole numbers")?;
+                }
+            }
+            other => bail!("unknown option {other}"),
+        }
+    }
+    if mix.values().all(|&w| w == 0) {
+        bail!("the operation mix is empty");
+    }
+    eprintln!("seed: {seed}");
+
+    let mut child = Command::new(&binary)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("spawning {binary}"))?;
+    let mut stdin = child.stdin.take().context("capturing node stdin")?;
+    let stdout = child.stdout.take().context("capturing node stdout")?;
+
+    let pending = Arc::new(Mutex::new(HashMap::<u64, Pending>::new()));
+    let stats = Arc::new(Mutex::new(BTreeMap::<String, OpStats>::new()));
+    let reader = {
+        let pending = Arc::clone(&pending);
+        let stats = Arc::clone(&stats);
+        thread::spawn(move || -> anyhow::Result<()> {
+            let mut model = Model::default();
+            for line in BufReader::new(stdout).lines() {
+                let msg: Value = serde_json::from_str(&line.context("reading node stdout")?)
+                    .context("node wrote invalid JSON")?;
+                let body = &msg["body"];
+                let Some(in_reply_to) = body["in_reply_to"].as_u64() else {
+                    continue;
+                };
+                let Some(request) = pending.lock().unwrap().remove(&in_reply_to) else {
+                    continue;
+                };
+                let mut stats = stats.lock().unwrap();
+                let stats = stats.entry(request.op.clone()).or_default();
+                stats.latencies.push(request.sent.elapsed());
+                let reply_type = body["type"].as_str().unwrap_or_default();
+                if reply_type == "error" {
+                    stats.errors += 1;
+                } else if reply_type != format!("{}_ok", request.op) {
+                    stats.invalid += 1;
+                    eprintln!("unexpected reply to {}: {body}", request.body);
+                } else if let Some(problem) = model.check(&request.body, body) {
+                    stats.invalid += 1;
+                    eprintln!("invalid reply to {}: {problem}", request.body);
+                } else {
+                    stats.ok += 1;
+                }
+            }
+            Ok(())
+        })
+    };
+
+    let mut send = |msg_id: u64, body: Value| -> anyhow::Result<()> {
+        let mut body = body;
+        body["msg_id"] = msg_id.into();
+        let msg = json!({ "src": CLIENT_ID, "dest": NODE_ID, "body": body });
+        writeln!(stdin, "{msg}").context("writing to node stdin")
+    };
+    send(
+        0,
+        json!({ "type": "init", "node_id": NODE_ID, "node_ids": [NODE_ID] }),
+    )?;
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let total_weight = mix.values().sum::<u32>();
+    let interval = Duration::from_secs_f64(1.0 / rate);
+    let start = Instant::now();
+    let mut msg_id = 0;
+    while start.elapsed() < time_limit {
+        msg_id += 1;
+        let mut pick = rng.gen_range(0..total_weight);
+        let op = mix
+            .iter()
+            .find(|(_, &weight)| {
+                let found = pick < weight;
+                pick = pick.saturating_sub(weight);
+                found
+            })
+            .map(|(op, _)| op.clone())
+            .expect("weights add up to the total");
+        let body = request(&op, msg_id, &mut rng);
+        pending.lock().unwrap().insert(
+            msg_id,
+            Pending {
+                op: op.clone(),
+                sent: Instant::now(),
+                body: body.clone(),
+            },
+        );
+        stats.lock().unwrap().entry(op).or_default().sent += 1;
+        send(msg_id, body)?;
+        thread::sleep((start + interval * msg_id as u32).saturating_duration_since(Instant::now()));
+    }
+
+    let drain_start = Instant::now();
+    while !pending.lock().unwrap().is_empty() && drain_start.elapsed() < DRAIN_TIMEOUT {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let timed_out = pending.lock().unwrap().len();
+    child.kill().context("stopping node")?;
+    child.wait().context("waiting for node")?;
+    reader.join().expect("failed to join stdout reader")?;
+
+    let stats = stats.lock().unwrap();
+    let mut failed = timed_out > 0;
+    println!(
+        "{:<24} {:>8} {:>8} {:>8} {:>8} {:>10} {:>10} {:>10}",
+        "op", "sent", "ok", "error", "invalid", "p50 ms", "p99 ms", "max ms"
+    );
+    for (op, stats) in stats.iter() {
+        let mut latencies = stats.latencies.clone();
+        latencies.sort();
+        let percentile = |p: f64| {
+            latencies
+                .get(((latencies.len() as f64 * p) as usize).min(latencies.len().saturating_sub(1)))
+                .map_or(0.0, |d| d.as_secs_f64() * 1000.0)
+        };
+        println!(
+            "{op:<24} {:>8} {:>8} {:>8} {:>8} {:>10.2} {:>10.2} {:>10.2}",
+            stats.sent,
+            stats.ok,
+            stats.errors,
+            stats.invalid,
+            percentile(0.5),
+            percentile(0.99),
+            percentile(1.0),
+        );
+        failed |= stats.invalid > 0;
+    }
+    println!("{timed_out} requests got no reply");
+
+    Ok(if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+/// A random request body for `op`.
+fn request(op: &str, msg_id: u64, rng: &mut StdRng) -> Value {
+    let key = format!("k{}", rng.gen_range(0..5));
+    match op {
+        "echo" => json!({ "type": "echo", "echo": format!("echo {msg_id}") }),
+        "broadcast" => json!({ "type": "broadcast", "message": msg_id }),
+        "add" => json!({ "type": "add", "delta": rng.gen_range(0..10) }),
+        "send" => json!({ "type": "send", "key": key, "msg": rng.gen_range(0..1000) }),
+        "poll" => json!({ "type": "poll", "offsets": { key: 0 } }),
+        "commit_offsets" => {
+            json!({ "type": "commit_offsets", "offsets": { key: rng.gen_range(0..10) } })
+        }
+        "list_committed_offsets" => json!({ "type": "list_committed_offsets", "keys": [key] }),
+        op => json!({ "type": op }),
+    }
+}
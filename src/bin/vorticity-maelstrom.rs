@@ -0,0 +1,179 @@
+//! Runs a challenge binary under Maelstrom with the settings of its challenge,
+//! exiting with failure unless Maelstrom judged the run valid.
+//!
+//! Usage: vorticity-maelstrom <challenge> [--node-count N] [--rate R]
+//!        [--time-limit SECS] [-- EXTRA MAELSTROM ARGS...]
+
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, ExitCode},
+};
+
+use anyhow::{bail, Context as _};
+
+/// Directory of an unpacked Maelstrom release, overriding the search.
+const MAELSTROM_HOME_ENV: &str = "MAELSTROM_HOME";
+
+const MAELSTROM_RELEASE: &str =
+    "https://github.com/jepsen-io/maelstrom/releases/download/v0.2.3/maelstrom.tar.bz2";
+
+/// What Maelstrom prints when the analysis found no problems.
+const VALID_MARKER: &str = "Everything looks good!";
+
+struct Challenge {
+    name: &'static str,
+    workload: &'static str,
+    node_count: u32,
+    time_limit: u32,
+    rate: Option<u32>,
+    extra: &'static [&'static str],
+}
+
+const CHALLENGES: &[Challenge] = &[
+    Challenge {
+        name: "echo",
+        workload: "echo",
+        node_count: 1,
+        time_limit: 10,
+        rate: None,
+        extra: &[],
+    },
+    Challenge {
+        name: "unique-ids",
+        workload: "unique-ids",
+        node_count: 3,
+        time_limit: 30,
+        rate: Some(1000),
+        extra: &["--availability", "total", "--nemesis", "partition"],
+    },
+    Challenge {
+        name: "broadcast",
+        workload: "broadcast",
+        node_count: 25,
+        time_limit: 20,
+        rate: Some(100),
+        extra: &["--latency", "100", "--nemesis", "partition"],
+    },
+    Challenge {
+        name: "g-counter",
+        workload: "g-counter",
+        node_count: 3,
+        time_limit: 20,
+        rate: Some(100),
+        extra: &["--nemesis", "partition"],
+    },
+    Challenge {
+        name: "kafka",
+        workload: "kafka",
+        node_count: 2,
+        time_limit: 20,
+        rate: Some(1000),
+        extra: &["--concurrency", "2n"],
+    },
+];
+
+fn main() -> anyhow::Result<ExitCode> {
+    let mut args = std::env::args().skip(1);
+    let Some(name) = args.next() else {
+        let names = CHALLENGES.iter().map(|c| c.name).collect::<Vec<_>>();
+        bail!("usage: vorticity-maelstrom <{}> [options]", names.join("|"));
+    };
+    let challenge = CHALLENGES
+        .iter()
+        .find(|c| c.name == name)
+        .with_context(|| format!("unknown challenge {name}"))?;
+
+    let mut node_count = challenge.node_count;
+    let mut time_limit = challenge.time_limit;
+    let mut rate = challenge.rate;
+    let mut extra = Vec::new();
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| {
+            args.next()
+                .with_context(|| format!("{flag} needs a value"))?
+                .parse::<u32>()
+                .with_context(|| format!("{flag} needs a number"))
+        };
+        match arg.as_str() {
+            "--node-count" => node_count = value("--node-count")?,
+            "--time-limit" => time_limit = value("--time-limit")?,
+            "--rate" => rate = Some(value("--rate")?),
+            "--" => extra.extend(args.by_ref()),
+            other => bail!("unknown option {other}"),
+        }
+    }
+
+    let binary = std::env::current_exe()
+        .context("locating this executable")?
+        .with_file_name(challenge.name);
+    if !binary.exists() {
+        bail!(
+            "{} not found, build it first with `cargo build --features bins --bin {}`",
+            binary.display(),
+            challenge.name
+        );
+    }
+
+    let maelstrom = locate_maelstrom()?;
+    let mut command = Command::new(maelstrom.join("maelstrom"));
+    command
+        .current_dir(&maelstrom)
+        .arg("test")
+        .args(["-w", challenge.workload])
+        .arg("--bin")
+        .arg(&binary)
+        .args(["--node-count", &node_count.to_string()])
+        .args(["--time-limit", &time_limit.to_string()])
+        .args(challenge.extra)
+        .args(&extra);
+    if let Some(rate) = rate {
+        command.args(["--rate", &rate.to_string()]);
+    }
+
+    eprintln!("running {command:?}");
+    let output = command.output().context("running maelstrom")?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    print!("{stdout}");
+    eprint!("{}", String::from_utf8_lossy(&output.stderr));
+
+    if output.status.success() && stdout.contains(VALID_MARKER) {
+        eprintln!("{}: PASS", challenge.name);
+        Ok(ExitCode::SUCCESS)
+    } else {
+        eprintln!(
+            "{}: FAIL, see {}",
+            challenge.name,
+            maelstrom.join("store/latest/results.edn").display()
+        );
+        Ok(ExitCode::FAILURE)
+    }
+}
+
+/// Find an unpacked Maelstrom, downloading a release under `target/` if there
+/// is none.
+fn locate_maelstrom() -> anyhow::Result<PathBuf> {
+    if let Some(home) = std::env::var_os(MAELSTROM_HOME_ENV) {
+        return Ok(PathBuf::from(home));
+    }
+    let sibling = Path::new("../maelstrom");
+    if sibling.join("maelstrom").exists() {
+        return Ok(sibling.to_path_buf());
+    }
+
+    let downloaded = Path::new("target/maelstrom");
+    if !downloaded.join("maelstrom").exists() {
+        eprintln!("downloading {MAELSTROM_RELEASE}");
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(format!(
+                "curl -fsSL {MAELSTROM_RELEASE} | tar -xjf - -C target"
+            ))
+            .status()
+            .context("downloading maelstrom")?;
+        if !status.success() {
+            bail!("downloading maelstrom failed, set {MAELSTROM_HOME_ENV} to an unpacked release");
+        }
+    }
+
+    Ok(downloaded.to_path_buf())
+}
@@ -0,0 +1,213 @@
+//! Scaffolding for new workloads. `vorticity new <name>` writes a module
+//! with a payload enum, a node answering it and a test driving the node
+//! through the testing harness, and registers the node under `name` in the
+//! [`NodeRegistry`](vorticity::dyn_node::NodeRegistry) of the directory's
+//! `mod.rs`, creating that if needed.
+//!
+//! Usage: vorticity new <name> [--dir DIR]
+//!
+//! `DIR` defaults to `src/workloads`, to be declared with `mod workloads;`.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context as _};
+
+const DEFAULT_DIR: &str = "src/workloads";
+
+/// Where `vorticity new` adds to the registry.
+const MODULES_MARKER: &str = "// vorticity new: modules";
+const REGISTRATIONS_MARKER: &str = "    // vorticity new: registrations";
+
+const REGISTRY_TEMPLATE: &str = r#"//! The workloads made with `vorticity new`, by name, to run the one a
+//! binary is asked for:
+//!
+//! ```ignore
+//! let selected = workloads::registry().select(&name)?;
+//! Runtime::new().start::<_, Value, (), Box<dyn DynNode>>(selected)
+//! ```
+
+use vorticity::dyn_node::NodeRegistry;
+
+// vorticity new: modules
+
+pub fn registry() -> NodeRegistry {
+    NodeRegistry::new()
+    // vorticity new: registrations
+}
+"#;
+
+const WORKLOAD_TEMPLATE: &str = r#"//! The `{{name}}` workload.
+
+use anyhow::Context as _;
+use vorticity::prelude::*;
+
+#[vorticity::payload]
+#[derive(Debug, Clone)]
+pub enum Payload {
+    Ping { value: u64 },
+    PingOk { value: u64, pings: u64 },
+}
+
+pub struct {{Type}}Node {
+    /// How many pings were answered.
+    pings: u64,
+}
+
+impl Node<(), Payload> for {{Type}}Node {
+    fn from_init(_state: (), _init: &Init, _ctx: Context<()>) -> anyhow::Result<Self> {
+        Ok(Self { pings: 0 })
+    }
+
+    fn step(&mut self, input: Event<Payload>, ctx: Context<()>) -> anyhow::Result<()> {
+        match input {
+            Event::Message(msg) => match msg.body().payload {
+                Payload::Ping { value } => {
+                    self.pings += 1;
+                    let pings = self.pings;
+                    let reply = ctx.construct_reply(&msg, Payload::PingOk { value, pings });
+                    ctx.send(reply).context("send ping_ok")?;
+                }
+                Payload::PingOk { .. } => {}
+            },
+            Event::Admin(_) | Event::Injected(()) | Event::Arbitrary(_) | Event::Eof => {}
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vorticity::testing::TestContext;
+
+    use super::*;
+
+    #[test]
+    fn answers_pings() -> anyhow::Result<()> {
+        let mut net = TestContext::<()>::new("n1", &["n2"]);
+        let mut node: {{Type}}Node = net.init_node(())?;
+
+        let ping = net.message("c1", Payload::Ping { value: 7 });
+        net.deliver(&mut node, ping.clone())?;
+        match net.expect_reply::<_, Payload>(&ping) {
+            Payload::PingOk { value, pings } => assert_eq!((value, pings), (7, 1)),
+            other => panic!("unexpected reply {other:?}"),
+        }
+
+        Ok(())
+    }
+}
+"#;
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        Some("new") => {}
+        _ => bail!("usage: vorticity new <name> [--dir DIR]"),
+    }
+    let Some(name) = args.next() else {
+        bail!("usage: vorticity new <name> [--dir DIR]");
+    };
+    let mut dir = PathBuf::from(DEFAULT_DIR);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dir" => dir = args.next().context("--dir needs a value")?.into(),
+            other => bail!("unknown option {other}"),
+        }
+    }
+
+    let module = module_name(&name)?;
+    let ty = type_name(&name);
+    let file = dir.join(format!("{module}.rs"));
+    if file.exists() {
+        bail!("{} already exists", file.display());
+    }
+    fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+    let workload = WORKLOAD_TEMPLATE
+        .replace("{{name}}", &name)
+        .replace("{{Type}}", &ty);
+    fs::write(&file, workload).with_context(|| format!("writing {}", file.display()))?;
+    eprintln!("created {}", file.display());
+
+    let registry = dir.join("mod.rs");
+    register(&registry, &name, &module, &ty)?;
+    eprintln!("registered {ty}Node as {name} in {}", registry.display());
+    if dir == Path::new(DEFAULT_DIR) {
+        eprintln!("declare the registry with `mod workloads;` if it isn't yet");
+    }
+
+    Ok(())
+}
+
+/// Add the workload to the registry at `path`, which is created if missing.
+fn register(path: &Path, name: &str, module: &str, ty: &str) -> anyhow::Result<()> {
+    let registry = match fs::read_to_string(path) {
+        Ok(registry) => registry,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => REGISTRY_TEMPLATE.to_string(),
+        Err(err) => return Err(err).with_context(|| format!("reading {}", path.display())),
+    };
+    if !registry.contains(MODULES_MARKER) || !registry.contains(REGISTRATIONS_MARKER) {
+        bail!(
+            "{} lacks the `{MODULES_MARKER}` and `{}` markers, add the workload by hand",
+            path.display(),
+            REGISTRATIONS_MARKER.trim()
+        );
+    }
+    let registry = add_module(&registry, module)
+        .replacen(
+            REGISTRATIONS_MARKER,
+            &format!(
+                "        .with::<{module}::{ty}Node, {module}::Payload>({name:?})\n{REGISTRATIONS_MARKER}"
+            ),
+            1,
+        );
+    fs::write(path, registry).with_context(|| format!("writing {}", path.display()))
+}
+
+/// Declare `module` among those right above the modules marker, keeping
+/// them sorted.
+fn add_module(registry: &str, module: &str) -> String {
+    let (head, tail) = registry
+        .split_once(MODULES_MARKER)
+        .expect("checked for the marker");
+    let mut lines = head.lines().collect::<Vec<_>>();
+    let first = lines
+        .iter()
+        .rposition(|line| !line.starts_with("pub mod "))
+        .map_or(0, |i| i + 1);
+    let declaration = format!("pub mod {module};");
+    let mut modules = lines.split_off(first);
+    modules.push(&declaration);
+    modules.sort();
+    lines.extend(modules);
+    format!("{}\n{MODULES_MARKER}{tail}", lines.join("\n"))
+}
+
+/// The module a workload lives in, e.g. `lin_kv` for `lin-kv`.
+fn module_name(name: &str) -> anyhow::Result<String> {
+    let valid = name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+    if !valid {
+        bail!("workload names are made of lowercase letters, digits, - and _, got {name:?}");
+    }
+    Ok(name.replace('-', "_"))
+}
+
+/// The prefix of the workload's type names, e.g. `LinKv` for `lin-kv`.
+fn type_name(name: &str) -> String {
+    name.split(['-', '_'])
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            chars
+                .next()
+                .map(|first| first.to_ascii_uppercase().to_string() + chars.as_str())
+                .unwrap_or_default()
+        })
+        .collect()
+}
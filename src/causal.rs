@@ -0,0 +1,131 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Count of messages originated by each node that have been delivered.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct VersionVector(BTreeMap<String, u64>);
+
+impl VersionVector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, node: &str) -> u64 {
+        self.0.get(node).copied().unwrap_or(0)
+    }
+
+    /// Bump the counter for `node`, returning its new value.
+    pub fn increment(&mut self, node: &str) -> u64 {
+        let counter = self.0.entry(node.to_string()).or_default();
+        *counter += 1;
+        *counter
+    }
+
+    /// Take the pointwise maximum of both vectors.
+    pub fn merge(&mut self, other: &VersionVector) {
+        for (node, &counter) in &other.0 {
+            let entry = self.0.entry(node.clone()).or_default();
+            *entry = (*entry).max(counter);
+        }
+    }
+
+    /// Whether every counter in `other` is covered by this vector.
+    pub fn dominates(&self, other: &VersionVector) -> bool {
+        other
+            .0
+            .iter()
+            .all(|(node, &counter)| self.get(node) >= counter)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.0
+            .iter()
+            .map(|(node, &counter)| (node.as_str(), counter))
+    }
+}
+
+/// A payload stamped with the version vector of its origin at send time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Causal<T> {
+    pub origin: String,
+    pub clock: VersionVector,
+    pub payload: T,
+}
+
+/// Holds back messages until everything they causally depend on has been
+/// delivered, then releases them in causal order.
+#[derive(Debug)]
+pub struct CausalBuffer<T> {
+    delivered: VersionVector,
+    pending: Vec<Causal<T>>,
+}
+
+impl<T> Default for CausalBuffer<T> {
+    fn default() -> Self {
+        Self {
+            delivered: VersionVector::default(),
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl<T> CausalBuffer<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Everything delivered so far.
+    pub fn delivered(&self) -> &VersionVector {
+        &self.delivered
+    }
+
+    /// Number of messages waiting for their dependencies.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Stamp a payload originating at `node_id`, counting it as delivered
+    /// locally. The result is what should be sent to the other nodes.
+    pub fn stamp(&mut self, node_id: &str, payload: T) -> Causal<T> {
+        self.delivered.increment(node_id);
+        Causal {
+            origin: node_id.to_string(),
+            clock: self.delivered.clone(),
+            payload,
+        }
+    }
+
+    /// Buffer a received message, returning every message that became
+    /// deliverable, in an order consistent with causality. Duplicates of
+    /// already delivered messages are dropped.
+    pub fn receive(&mut self, msg: Causal<T>) -> Vec<Causal<T>> {
+        if msg.clock.get(&msg.origin) <= self.delivered.get(&msg.origin) {
+            return Vec::new();
+        }
+        self.pending.push(msg);
+
+        let mut ready = Vec::new();
+        while let Some(i) = self.pending.iter().position(|m| self.is_deliverable(m)) {
+            let msg = self.pending.swap_remove(i);
+            self.delivered.increment(&msg.origin);
+            ready.push(msg);
+        }
+        // Drop anything that got delivered through another copy meanwhile.
+        let delivered = &self.delivered;
+        self.pending
+            .retain(|m| m.clock.get(&m.origin) > delivered.get(&m.origin));
+
+        ready
+    }
+
+    fn is_deliverable(&self, msg: &Causal<T>) -> bool {
+        msg.clock.get(&msg.origin) == self.delivered.get(&msg.origin) + 1
+            && msg
+                .clock
+                .iter()
+                .filter(|(node, _)| *node != msg.origin)
+                .all(|(node, counter)| counter <= self.delivered.get(node))
+    }
+}
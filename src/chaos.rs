@@ -0,0 +1,180 @@
+//! Chaos mode for the runtime: outbound messages to other nodes are randomly
+//! dropped, duplicated or delayed, to shake out retry and dedup bugs even
+//! when the harness itself is well behaved. Messages to clients are left
+//! alone.
+
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    io::Write,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context as _};
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use erased_serde::Serialize;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde_json::Value;
+
+use crate::{
+    log,
+    output::{FlushPolicy, Output},
+};
+
+/// Environment variable enabling chaos mode without code changes, e.g.
+/// `drop=0.05,duplicate=0.05,delay=0.1,max_delay_ms=500`.
+pub const CHAOS_ENV: &str = "VORTICITY_CHAOS";
+
+/// Probabilities of what happens to each message sent to another node.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chaos {
+    pub drop: f64,
+    pub duplicate: f64,
+
+    /// Probability of holding a message back for up to `max_delay`.
+    pub delay: f64,
+    pub max_delay: Duration,
+}
+
+impl Default for Chaos {
+    fn default() -> Self {
+        Self {
+            drop: 0.0,
+            duplicate: 0.0,
+            delay: 0.0,
+            max_delay: Duration::from_millis(500),
+        }
+    }
+}
+
+impl Chaos {
+    /// Parse [`CHAOS_ENV`], if it is set.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let Ok(spec) = std::env::var(CHAOS_ENV) else {
+            return Ok(None);
+        };
+        let mut chaos = Chaos::default();
+        for setting in spec.split(',').filter(|s| !s.is_empty()) {
+            let (name, value) = setting
+                .split_once('=')
+                .with_context(|| format!("{CHAOS_ENV}: expected name=value, got {setting}"))?;
+            let value: f64 = value
+                .parse()
+                .with_context(|| format!("{CHAOS_ENV}: {name} is not a number"))?;
+            match name {
+                "drop" => chaos.drop = probability(name, value)?,
+                "duplicate" => chaos.duplicate = probability(name, value)?,
+                "delay" => chaos.delay = probability(name, value)?,
+                "max_delay_ms" => {
+                    if !(value.is_finite() && value >= 0.0) {
+                        bail!(
+                            "{CHAOS_ENV}: max_delay_ms must be a non-negative number, got {value}"
+                        );
+                    }
+                    chaos.max_delay = Duration::from_secs_f64(value / 1000.0);
+                }
+                _ => bail!("{CHAOS_ENV}: unknown setting {name}"),
+            }
+        }
+
+        Ok(Some(chaos))
+    }
+
+    /// Like the plain send loop, but misbehaving for messages whose
+    /// destination is in `node_ids`.
+    pub(crate) fn send_loop<W>(
+        self,
+        seed: u64,
+        node_ids: Vec<String>,
+        flush: FlushPolicy,
+        msg_out_rx: Receiver<Box<dyn Serialize + Send + Sync>>,
+        writer: W,
+    ) -> thread::JoinHandle<anyhow::Result<W>>
+    where
+        W: Write + Send + 'static,
+    {
+        thread::spawn(move || {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut output = Output::new(writer, flush);
+
+            // Delayed lines, by when they are due and then in send order.
+            let mut delayed: BinaryHeap<Reverse<(Instant, u64, String)>> = BinaryHeap::new();
+            let mut sent = 0u64;
+            loop {
+                let due = delayed
+                    .peek()
+                    .map(|Reverse((due, _, _))| due.saturating_duration_since(Instant::now()));
+                let timeout = match (due, output.linger()) {
+                    (Some(due), Some(linger)) => Some(due.min(linger)),
+                    (due, linger) => due.or(linger),
+                };
+                let received = match timeout {
+                    Some(timeout) => msg_out_rx.recv_timeout(timeout),
+                    None => msg_out_rx
+                        .recv()
+                        .map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                let timed_out = matches!(received, Err(RecvTimeoutError::Timeout));
+                match received {
+                    Ok(msg) => {
+                        let msg =
+                            serde_json::to_value(&msg).context("serialize outgoing message")?;
+                        let line = msg.to_string();
+                        let to_node = msg
+                            .get("dest")
+                            .and_then(Value::as_str)
+                            .is_some_and(|dest| node_ids.iter().any(|n| n == dest));
+                        if !to_node {
+                            output.write_line(&line)?;
+                            continue;
+                        }
+                        if rng.gen_bool(self.drop) {
+                            log::debug("chaos: dropping message")
+                                .field("message", &msg)
+                                .emit();
+                            continue;
+                        }
+                        let copies = if rng.gen_bool(self.duplicate) { 2 } else { 1 };
+                        for _ in 0..copies {
+                            if rng.gen_bool(self.delay) {
+                                let delay = self.max_delay.mul_f64(rng.gen_range(0.0..1.0));
+                                sent += 1;
+                                delayed.push(Reverse((Instant::now() + delay, sent, line.clone())));
+                            } else {
+                                output.write_line(&line)?;
+                            }
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => {
+                        // Nothing else is coming, so the delayed lines go out now.
+                        while let Some(Reverse((_, _, line))) = delayed.pop() {
+                            output.write_line(&line)?;
+                        }
+                        return output.into_inner();
+                    }
+                }
+
+                while let Some(Reverse((due, _, _))) = delayed.peek() {
+                    if *due > Instant::now() {
+                        break;
+                    }
+                    let Reverse((_, _, line)) = delayed.pop().expect("just peeked");
+                    output.write_line(&line)?;
+                }
+                if timed_out {
+                    output.flush()?;
+                }
+            }
+        })
+    }
+}
+
+/// `value` as the probability `name`, which must be between 0 and 1.
+fn probability(name: &str, value: f64) -> anyhow::Result<f64> {
+    if !(0.0..=1.0).contains(&value) {
+        bail!("{CHAOS_ENV}: {name} must be a probability between 0 and 1, got {value}");
+    }
+    Ok(value)
+}
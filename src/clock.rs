@@ -0,0 +1,268 @@
+//! Where nodes get the time from. Everything that times out, backs off,
+//! ticks or stamps asks a [`Clock`] rather than the OS: the runtime hands
+//! one to every [`Context`](crate::Context), [`Context::clock`](crate::Context::clock)
+//! shares it, and [`HybridClock`](crate::hlc::HybridClock),
+//! [`RetrySender`](crate::rpc::RetrySender), [`Heartbeat`](crate::heartbeat::Heartbeat),
+//! [`GossipSchedule`](crate::gossip::GossipSchedule) and request latencies
+//! go by it.
+//!
+//! [`SystemClock`] is the default. A [`ManualClock`] only moves when told
+//! to, so tests and the [simulator](crate::sim) decide what time it is:
+//!
+//! ```ignore
+//! let clock = ManualClock::new();
+//! Runtime::new().with_clock(clock.clone());
+//! clock.advance(Duration::from_secs(5)); // every retry due by now fires
+//! ```
+
+use std::{
+    fmt,
+    sync::{Arc, Condvar, Mutex, MutexGuard, Weak},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// A source of time, see the [module docs](self).
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Monotonic time, for timeouts and durations.
+    fn now(&self) -> Instant;
+
+    /// Milliseconds since the Unix epoch, for timestamps sent to other
+    /// nodes.
+    fn unix_ms(&self) -> u64;
+
+    /// Block the calling thread until `duration` passed on this clock.
+    fn sleep(&self, duration: Duration);
+
+    /// Like [`Clock::sleep`], but wake up early once `interrupt` is
+    /// interrupted, returning whether it wasn't. Clocks that can't be woken
+    /// sleep the whole `duration`.
+    fn sleep_unless(&self, duration: Duration, interrupt: &Interrupt) -> bool {
+        self.sleep(duration);
+        !interrupt.is_interrupted()
+    }
+
+    /// Time passed since `earlier`, zero if it's later than now.
+    fn elapsed(&self, earlier: Instant) -> Duration {
+        self.now().saturating_duration_since(earlier)
+    }
+}
+
+/// A clock shared by a node's context and everything running on it.
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The OS clock, as a [`SharedClock`].
+pub fn system() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+/// The OS clocks: [`Instant::now`], [`SystemTime::now`] and
+/// [`thread::sleep`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn unix_ms(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0)
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+
+    fn sleep_unless(&self, duration: Duration, interrupt: &Interrupt) -> bool {
+        let interrupted = interrupt.lock();
+        let (interrupted, _) = interrupt
+            .inner
+            .wake
+            .wait_timeout_while(interrupted, duration, |interrupted| !*interrupted)
+            .expect("interrupt lock poisoned");
+        !*interrupted
+    }
+}
+
+/// Wakes threads sleeping with [`Clock::sleep_unless`] before their time,
+/// e.g. the timers of a node whose input ended. Clones share the state, and
+/// [children](Interrupt::child) are interrupted along with their parent.
+#[derive(Debug, Clone, Default)]
+pub struct Interrupt {
+    inner: Arc<Interrupted>,
+}
+
+#[derive(Debug, Default)]
+struct Interrupted {
+    interrupted: Mutex<bool>,
+    wake: Condvar,
+    children: Mutex<Vec<Weak<Interrupted>>>,
+
+    /// The manual clocks threads sleep on, which need waking up too.
+    clocks: Mutex<Vec<Weak<Elapsed>>>,
+}
+
+impl Interrupt {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// An interrupt of its own, which is also interrupted with this one.
+    pub fn child(&self) -> Self {
+        let child = Self::new();
+        let mut children = self.inner.children.lock().expect("interrupt lock poisoned");
+        children.retain(|child| child.strong_count() > 0);
+        children.push(Arc::downgrade(&child.inner));
+        drop(children);
+        // Checked after registering, so an interrupt in between isn't missed.
+        if self.is_interrupted() {
+            child.interrupt();
+        }
+        child
+    }
+
+    /// Wake every thread sleeping on this interrupt or one of its children.
+    pub fn interrupt(&self) {
+        *self.lock() = true;
+        self.inner.wake.notify_all();
+        let children = self.inner.children.lock().expect("interrupt lock poisoned");
+        for child in children.iter().filter_map(Weak::upgrade) {
+            Interrupt { inner: child }.interrupt();
+        }
+        let clocks = self.inner.clocks.lock().expect("interrupt lock poisoned");
+        for clock in clocks.iter().filter_map(Weak::upgrade) {
+            // Taking the lock ensures a sleeper either sees the interrupt or
+            // is waiting for this notification.
+            let _elapsed = clock.elapsed.lock().expect("clock lock poisoned");
+            clock.advanced.notify_all();
+        }
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        *self.lock()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, bool> {
+        self.inner
+            .interrupted
+            .lock()
+            .expect("interrupt lock poisoned")
+    }
+
+    /// Wake threads sleeping on `clock` once interrupted.
+    fn wake_on(&self, clock: &Arc<Elapsed>) {
+        let mut clocks = self.inner.clocks.lock().expect("interrupt lock poisoned");
+        clocks.retain(|clock| clock.strong_count() > 0);
+        if !clocks
+            .iter()
+            .any(|known| std::ptr::eq(known.as_ptr(), Arc::as_ptr(clock)))
+        {
+            clocks.push(Arc::downgrade(clock));
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Elapsed {
+    elapsed: Mutex<Duration>,
+    advanced: Condvar,
+}
+
+/// A clock that stands still until it's advanced. Clones share the time,
+/// and threads sleeping on it wake once it was advanced far enough.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    start: Instant,
+    start_unix_ms: u64,
+    inner: Arc<Elapsed>,
+}
+
+impl ManualClock {
+    /// A clock at the Unix epoch.
+    pub fn new() -> Self {
+        Self::starting_at(0)
+    }
+
+    /// A clock at `unix_ms` milliseconds since the Unix epoch.
+    pub fn starting_at(unix_ms: u64) -> Self {
+        Self {
+            start: Instant::now(),
+            start_unix_ms: unix_ms,
+            inner: Arc::new(Elapsed {
+                elapsed: Mutex::new(Duration::ZERO),
+                advanced: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Time passed on this clock since it was created.
+    pub fn elapsed_total(&self) -> Duration {
+        *self.inner.elapsed.lock().expect("clock lock poisoned")
+    }
+
+    /// Move the clock `duration` forward.
+    pub fn advance(&self, duration: Duration) {
+        let mut elapsed = self.inner.elapsed.lock().expect("clock lock poisoned");
+        *elapsed += duration;
+        self.inner.advanced.notify_all();
+    }
+
+    /// Move the clock to `elapsed` after its creation. It never goes back,
+    /// so earlier times are ignored.
+    pub fn set(&self, elapsed: Duration) {
+        let mut current = self.inner.elapsed.lock().expect("clock lock poisoned");
+        if elapsed > *current {
+            *current = elapsed;
+            self.inner.advanced.notify_all();
+        }
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed_total()
+    }
+
+    fn unix_ms(&self) -> u64 {
+        self.start_unix_ms + self.elapsed_total().as_millis() as u64
+    }
+
+    fn sleep(&self, duration: Duration) {
+        let mut elapsed = self.inner.elapsed.lock().expect("clock lock poisoned");
+        let until = *elapsed + duration;
+        while *elapsed < until {
+            elapsed = self
+                .inner
+                .advanced
+                .wait(elapsed)
+                .expect("clock lock poisoned");
+        }
+    }
+
+    fn sleep_unless(&self, duration: Duration, interrupt: &Interrupt) -> bool {
+        interrupt.wake_on(&self.inner);
+        let mut elapsed = self.inner.elapsed.lock().expect("clock lock poisoned");
+        let until = *elapsed + duration;
+        while *elapsed < until {
+            if interrupt.is_interrupted() {
+                return false;
+            }
+            elapsed = self
+                .inner
+                .advanced
+                .wait(elapsed)
+                .expect("clock lock poisoned");
+        }
+        !interrupt.is_interrupted()
+    }
+}
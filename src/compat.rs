@@ -0,0 +1,210 @@
+//! An API shaped like Maelstrom's own node libraries, like its Go one, so
+//! solutions written against those port over line by line. Handlers are
+//! registered by message type and work on JSON bodies, with the node handed
+//! to them instead of captured:
+//!
+//! ```ignore
+//! let mut node = MaelstromNode::new();
+//! node.handle("echo", |node, msg| {
+//!     let mut body = msg.body().payload.clone();
+//!     body["type"] = "echo_ok".into();
+//!     node.reply(msg, body)
+//! });
+//! node.run()
+//! ```
+//!
+//! Everything runs on [`Runtime`], so its environment variables, metrics and
+//! admin requests work as for any other node.
+
+use std::{cell::RefCell, collections::HashMap};
+
+use anyhow::{bail, Context as _};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    error::{ErrorCode, MaelstromError},
+    Context, Event, Init, Message, Node, Runtime,
+};
+
+/// Handles the messages of one type, like Go's `HandlerFunc`. Errors with a
+/// Maelstrom error code are sent back to the sender, as an `RPCError` would
+/// be; any other error stops the node.
+pub type HandlerFunc = Box<dyn FnMut(&NodeHandle<'_>, &Message<Value>) -> anyhow::Result<()>>;
+
+/// Handles the reply to one [`NodeHandle::rpc`].
+pub type ReplyFunc = Box<dyn FnOnce(&NodeHandle<'_>, &Message<Value>) -> anyhow::Result<()>>;
+
+/// Handlers by message type, waiting to be run.
+#[derive(Default)]
+pub struct MaelstromNode {
+    handlers: HashMap<String, HandlerFunc>,
+    runtime: Runtime,
+}
+
+impl MaelstromNode {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run with a configured runtime instead of the default one.
+    pub fn with_runtime(mut self, runtime: Runtime) -> Self {
+        self.runtime = runtime;
+        self
+    }
+
+    /// Handle every message of type `message_type` with `handler`, replacing
+    /// the one registered before, if any.
+    pub fn handle(
+        &mut self,
+        message_type: impl Into<String>,
+        handler: impl FnMut(&NodeHandle<'_>, &Message<Value>) -> anyhow::Result<()> + 'static,
+    ) -> &mut Self {
+        self.handlers.insert(message_type.into(), Box::new(handler));
+        self
+    }
+
+    /// Run until the end of input.
+    pub fn run(self) -> anyhow::Result<()> {
+        Runtime::node::<Shim>()
+            .with_runtime(self.runtime)
+            .with_state(self.handlers)
+            .run::<Value, ()>()
+    }
+}
+
+/// The node as handlers see it, like Go's `*Node`.
+pub struct NodeHandle<'a> {
+    init: &'a Init,
+    context: &'a Context<()>,
+    callbacks: &'a RefCell<HashMap<usize, ReplyFunc>>,
+}
+
+impl NodeHandle<'_> {
+    /// This node's id.
+    pub fn id(&self) -> &str {
+        &self.init.node_id
+    }
+
+    /// Every node's id, this one's included.
+    pub fn node_ids(&self) -> &[String] {
+        &self.init.node_ids
+    }
+
+    /// The context underneath, for what this API has no counterpart of.
+    pub fn context(&self) -> &Context<()> {
+        self.context
+    }
+
+    /// Answer `request` with `body`, which must serialize to a JSON object
+    /// with a `type`.
+    pub fn reply(&self, request: &Message<Value>, body: impl Serialize) -> anyhow::Result<()> {
+        let reply = self.context.construct_reply(request, to_body(body)?);
+        self.context.send(reply)
+    }
+
+    /// Send `body` to `dest`, expecting no reply.
+    pub fn send(&self, dest: &str, body: impl Serialize) -> anyhow::Result<()> {
+        let msg = Message::builder()
+            .src(self.id())
+            .dst(dest)
+            .payload(to_body(body)?)
+            .build()?;
+        self.context.send(msg)
+    }
+
+    /// Send `body` to `dest` as a request, handing its reply to `on_reply`
+    /// instead of to the handler of the reply's type.
+    pub fn rpc(
+        &self,
+        dest: &str,
+        body: impl Serialize,
+        on_reply: impl FnOnce(&NodeHandle<'_>, &Message<Value>) -> anyhow::Result<()> + 'static,
+    ) -> anyhow::Result<()> {
+        let id = self.context.next_msg_id();
+        let msg = Message::builder()
+            .src(self.id())
+            .dst(dest)
+            .with_id(id)
+            .payload(to_body(body)?)
+            .build()?;
+        self.callbacks.borrow_mut().insert(id, Box::new(on_reply));
+        self.context.send(msg)
+    }
+}
+
+/// `body` as the JSON object of a message body, without the ids the
+/// runtime fills in, as they may come along from a request's body.
+fn to_body(body: impl Serialize) -> anyhow::Result<Value> {
+    let mut body = serde_json::to_value(body).context("serializing message body")?;
+    let Some(fields) = body.as_object_mut() else {
+        bail!("message body must be a JSON object, got {body}");
+    };
+    if !fields.get("type").is_some_and(Value::is_string) {
+        bail!("message body must have a type, got {body}");
+    }
+    fields.remove("msg_id");
+    fields.remove("in_reply_to");
+    Ok(body)
+}
+
+/// The [`Node`] running a [`MaelstromNode`]'s handlers.
+struct Shim {
+    init: Init,
+    handlers: HashMap<String, HandlerFunc>,
+
+    /// What to do with the replies to requests sent with
+    /// [`NodeHandle::rpc`], by the requests' ids.
+    callbacks: RefCell<HashMap<usize, ReplyFunc>>,
+}
+
+impl Node<HashMap<String, HandlerFunc>, Value> for Shim {
+    fn from_init(
+        handlers: HashMap<String, HandlerFunc>,
+        init: &Init,
+        _context: Context<()>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            init: init.clone(),
+            handlers,
+            callbacks: RefCell::default(),
+        })
+    }
+
+    fn step(&mut self, input: Event<Value>, context: Context<()>) -> anyhow::Result<()> {
+        let Event::Message(msg) = input else {
+            return Ok(());
+        };
+        let node = NodeHandle {
+            init: &self.init,
+            context: &context,
+            callbacks: &self.callbacks,
+        };
+
+        let callback = msg
+            .body()
+            .in_reply_to
+            .and_then(|id| self.callbacks.borrow_mut().remove(&id));
+        if let Some(callback) = callback {
+            return callback(&node, &msg);
+        }
+
+        let message_type = msg.body().payload["type"].as_str().unwrap_or_default();
+        let result = match self.handlers.get_mut(message_type) {
+            Some(handler) => handler(&node, &msg),
+            None if msg.body().in_reply_to.is_some() => Ok(()),
+            None => Err(MaelstromError::new(
+                ErrorCode::NotSupported,
+                format!("no handler for {message_type}"),
+            )
+            .into()),
+        };
+        let Err(err) = result else {
+            return Ok(());
+        };
+        match err.chain().find_map(|e| e.downcast_ref::<MaelstromError>()) {
+            Some(error) => context.send(context.construct_reply(&msg, error.clone())),
+            None => Err(err),
+        }
+    }
+}
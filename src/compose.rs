@@ -0,0 +1,213 @@
+//! Several nodes served by one process, e.g. to run combined workloads or a
+//! service made of parts. A [`Composite`] holds nodes of a
+//! [`NodeRegistry`], all sharing its context, and routes every request to
+//! the first of them whose payload type it parses as:
+//!
+//! ```ignore
+//! let registry = NodeRegistry::new()
+//!     .with::<BroadcastNode, broadcast::Payload>("broadcast")
+//!     .with::<KvNode, kv::Payload>("kv");
+//! let composition = registry.compose(&["broadcast", "kv"])?;
+//! Runtime::new().start::<_, Value, (), Composite>(composition)
+//! ```
+//!
+//! Replies go to the node that sent the request, known by the msg_ids each
+//! node sent while it was stepped. Replies to requests sent elsewhere, e.g.
+//! from a thread of a node's own, are routed like requests, unless the
+//! request was sent with [`Context::call`], whose replies always go to
+//! their callbacks.
+//!
+//! Requests no node takes are answered with a `not-supported` error.
+//! Injected events, admin messages and the end of input go to every node,
+//! so nodes composed share their injected payload type, and tell their
+//! admin traffic apart themselves, e.g. by the names of their gossip docs.
+//!
+//! [`NodeRegistry`]: crate::dyn_node::NodeRegistry
+
+use std::{collections::BTreeMap, ops::Range};
+
+use anyhow::Context as _;
+use serde_json::Value;
+
+use crate::{
+    dyn_node::{DynNode, Selected},
+    error::{ErrorCode, MaelstromError},
+    log, Context, Event, Init, Message, Node,
+};
+
+/// The nodes a [`Composite`] is made of, by name, from
+/// [`NodeRegistry::compose`](crate::dyn_node::NodeRegistry::compose).
+pub struct Composition<IP = ()> {
+    pub(crate) parts: Vec<(String, Selected<IP>)>,
+}
+
+impl<IP> Clone for Composition<IP> {
+    fn clone(&self) -> Self {
+        Self {
+            parts: self.parts.clone(),
+        }
+    }
+}
+
+/// How many runs of msg_ids sent by the nodes are remembered. Replies to
+/// requests older than that are routed like requests.
+const MAX_SENT_RUNS: usize = 4096;
+
+/// Nodes running side by side, see the [module docs](self).
+pub struct Composite<IP = ()> {
+    nodes: Vec<(String, Box<dyn DynNode<IP>>)>,
+
+    /// The msg_ids the nodes sent, by the first of each run, with the end of
+    /// the run and the index of the node that sent it.
+    sent: BTreeMap<usize, (usize, usize)>,
+}
+
+impl<IP> Composite<IP> {
+    /// The names of the nodes, in the order they get to pick messages.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.nodes.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// The node `name`, if there is one.
+    pub fn node(&self, name: &str) -> Option<&dyn DynNode<IP>> {
+        self.nodes
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, node)| node.as_ref())
+    }
+
+    /// Hand a reply to the node that sent the request, or a message to the
+    /// first node taking it.
+    fn route(
+        &mut self,
+        msg: Message<Value>,
+        reply: bool,
+        context: Context<IP>,
+    ) -> anyhow::Result<()> {
+        let sender = match reply {
+            true => msg.body().in_reply_to.and_then(|id| self.sender_of(id)),
+            false => None,
+        };
+        let Some(index) = sender.or_else(|| {
+            self.nodes
+                .iter()
+                .position(|(_, node)| node.accepts(&msg.body().payload))
+        }) else {
+            return unrouted(&msg, &context);
+        };
+        let input = Event::Message(msg);
+        self.run(index, &context, |node, context| match reply {
+            true => node.handle_reply(input, context),
+            false => node.step(input, context),
+        })
+    }
+
+    /// Hand `input` to every node.
+    fn broadcast(&mut self, input: Event<Value, IP>, context: Context<IP>) -> anyhow::Result<()>
+    where
+        IP: Clone,
+    {
+        for index in 0..self.nodes.len() {
+            self.run(index, &context, |node, context| {
+                node.step(input.clone(), context)
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Run `f` on the node at `index`, remembering the msg_ids it sent.
+    fn run(
+        &mut self,
+        index: usize,
+        context: &Context<IP>,
+        f: impl FnOnce(&mut dyn DynNode<IP>, Context<IP>) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let first = context.msg_id();
+        let (name, node) = &mut self.nodes[index];
+        let result =
+            f(node.as_mut(), context.clone()).with_context(|| format!("node {name} failed"));
+        self.record_sent(first..context.msg_id(), index);
+        result
+    }
+
+    fn record_sent(&mut self, ids: Range<usize>, index: usize) {
+        if ids.is_empty() {
+            return;
+        }
+        self.sent.insert(ids.start, (ids.end, index));
+        if self.sent.len() > MAX_SENT_RUNS {
+            self.sent.pop_first();
+        }
+    }
+
+    /// The index of the node that sent msg_id `id`, if it's remembered.
+    fn sender_of(&self, id: usize) -> Option<usize> {
+        let (_, &(end, index)) = self.sent.range(..=id).next_back()?;
+        (id < end).then_some(index)
+    }
+}
+
+/// Answer a message no node takes with an error, if it is a request.
+fn unrouted<IP>(msg: &Message<Value>, context: &Context<IP>) -> anyhow::Result<()> {
+    context.metrics().incr("compose.unrouted", 1);
+    log::warn("no node takes message")
+        .field("src", msg.src())
+        .field("type", msg.body().payload.get("type"))
+        .emit();
+    if msg.body().id.is_none() || msg.body().in_reply_to.is_some() {
+        return Ok(());
+    }
+    let error = MaelstromError::new(ErrorCode::NotSupported, "no node takes this message");
+    context.send(context.construct_reply(msg, error))
+}
+
+impl<IP: Clone> Node<Composition<IP>, Value, IP> for Composite<IP> {
+    fn from_init(
+        composition: Composition<IP>,
+        init: &Init,
+        context: Context<IP>,
+    ) -> anyhow::Result<Self> {
+        let mut composite = Self {
+            nodes: Vec::new(),
+            sent: BTreeMap::new(),
+        };
+        for (name, selected) in composition.parts {
+            let first = context.msg_id();
+            let node = selected
+                .build(init, context.clone())
+                .with_context(|| format!("initializing node {name}"))?;
+            composite.record_sent(first..context.msg_id(), composite.nodes.len());
+            composite.nodes.push((name, node));
+        }
+        Ok(composite)
+    }
+
+    fn step(&mut self, input: Event<Value, IP>, context: Context<IP>) -> anyhow::Result<()> {
+        match input {
+            Event::Message(msg) | Event::Arbitrary(msg) => self.route(msg, false, context),
+            input @ (Event::Injected(_) | Event::Admin(_) | Event::Eof) => {
+                self.broadcast(input, context)
+            }
+        }
+    }
+
+    fn handle_reply(
+        &mut self,
+        input: Event<Value, IP>,
+        context: Context<IP>,
+    ) -> anyhow::Result<()> {
+        match input {
+            Event::Message(msg) | Event::Arbitrary(msg) => self.route(msg, true, context),
+            Event::Admin(msg) => {
+                for index in 0..self.nodes.len() {
+                    self.run(index, &context, |node, context| {
+                        node.handle_reply(Event::Admin(msg.clone()), context)
+                    })?;
+                }
+                Ok(())
+            }
+            input @ (Event::Injected(_) | Event::Eof) => self.broadcast(input, context),
+        }
+    }
+}
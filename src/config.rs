@@ -0,0 +1,61 @@
+//! Typed configuration for nodes, so tuning knobs like a gossip interval can
+//! change between runs without editing code. A config is any deserializable
+//! type, filled from, with later sources winning:
+//!
+//! - `VORTICITY_CONFIG_<FIELD>` environment variables,
+//! - `--field=value` or `--field value` command line arguments, dashes in the
+//!   name standing for underscores,
+//! - extra fields of the init message, see [`Init::extensions`].
+//!
+//! Values are parsed as JSON where they can be and taken as strings
+//! otherwise, so `--fanout=3` sets a number and `--peer=n1` a string. Fields
+//! no source sets need a `#[serde(default)]`.
+
+use anyhow::{bail, Context as _};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+use crate::Init;
+
+/// Prefix of the environment variables [`load`] reads.
+pub const CONFIG_ENV_PREFIX: &str = "VORTICITY_CONFIG_";
+
+/// Load a `C` for the node `init` is for, from the sources in the module docs.
+pub fn load<C: DeserializeOwned>(init: &Init) -> anyhow::Result<C> {
+    let mut config = Map::new();
+    for (name, value) in std::env::vars() {
+        if let Some(field) = name.strip_prefix(CONFIG_ENV_PREFIX) {
+            config.insert(field.to_lowercase(), parse_value(&value));
+        }
+    }
+    config.extend(parse_args(std::env::args().skip(1))?);
+    config.extend(init.extensions.clone());
+
+    serde_json::from_value(Value::Object(config))
+        .with_context(|| format!("invalid config for {}", init.node_id))
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> anyhow::Result<Map<String, Value>> {
+    let mut config = Map::new();
+    while let Some(arg) = args.next() {
+        let Some(arg) = arg.strip_prefix("--") else {
+            bail!("unexpected argument {arg}, expected --name=value");
+        };
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name.to_string(), value.to_string()),
+            None => {
+                let value = args
+                    .next()
+                    .with_context(|| format!("--{arg} needs a value"))?;
+                (arg.to_string(), value)
+            }
+        };
+        config.insert(name.replace('-', "_"), parse_value(&value));
+    }
+
+    Ok(config)
+}
+
+fn parse_value(raw: &str) -> Value {
+    serde_json::from_str(raw).unwrap_or_else(|_| Value::String(raw.to_string()))
+}
@@ -0,0 +1,133 @@
+use std::{collections::BTreeMap, marker::PhantomData};
+
+use anyhow::Context as _;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use yrs::{Map, Transact};
+
+use crate::{
+    gossip::GossipDoc,
+    hlc::{HybridClock, Timestamp},
+};
+
+/// The value one node last wrote for a key, or a tombstone.
+#[derive(Debug, Serialize, Deserialize)]
+struct Register<V> {
+    ts: Timestamp,
+    value: Option<V>,
+}
+
+/// A last-writer-wins map living inside a [`GossipDoc`], so it's synchronized
+/// by the usual gossip rounds.
+///
+/// Every node only ever writes its own register for a key, so yrs never has to
+/// resolve concurrent writes. Reads pick the register with the greatest
+/// [`Timestamp`], breaking ties by node id.
+pub struct LwwMap<K, V> {
+    node_id: String,
+    doc: yrs::Doc,
+    map: yrs::MapRef,
+    clock: HybridClock,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> LwwMap<K, V>
+where
+    K: Serialize + DeserializeOwned + Ord,
+    V: Serialize + DeserializeOwned,
+{
+    /// Store the map under `name` in the gossiped doc.
+    pub fn new(gossip: &GossipDoc, name: &str) -> Self {
+        Self {
+            node_id: gossip.node_id().to_string(),
+            doc: gossip.doc().clone(),
+            map: gossip.doc().get_or_insert_map(name),
+            clock: HybridClock::with_clock(gossip.clock().clone()),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn insert(&mut self, key: &K, value: V) -> anyhow::Result<()> {
+        self.write(key, Some(value))
+    }
+
+    pub fn remove(&mut self, key: &K) -> anyhow::Result<()> {
+        self.write(key, None)
+    }
+
+    pub fn get(&mut self, key: &K) -> anyhow::Result<Option<V>> {
+        let key = serde_json::to_string(key).context("serialize LwwMap key")?;
+        Ok(self
+            .winners()?
+            .remove(&key)
+            .and_then(|register| register.value))
+    }
+
+    /// The current winning value of every key that hasn't been removed.
+    pub fn to_map(&mut self) -> anyhow::Result<BTreeMap<K, V>> {
+        self.winners()?
+            .into_iter()
+            .filter_map(|(key, register)| Some((key, register.value?)))
+            .map(|(key, value)| {
+                let key = serde_json::from_str(&key).context("deserialize LwwMap key")?;
+                Ok((key, value))
+            })
+            .collect()
+    }
+
+    fn write(&mut self, key: &K, value: Option<V>) -> anyhow::Result<()> {
+        // Read first so the clock has observed every remote write to the key.
+        self.registers()?;
+        let slot = format!(
+            "{}/{}",
+            self.node_id,
+            serde_json::to_string(key).context("serialize LwwMap key")?
+        );
+        let register = Register {
+            ts: self.clock.now(),
+            value,
+        };
+        let register = serde_json::to_string(&register).context("serialize LwwMap value")?;
+        let mut txn = self.doc.transact_mut();
+        self.map.insert(&mut txn, slot, register);
+
+        Ok(())
+    }
+
+    /// The winning register of every key, keyed by the serialized key.
+    fn winners(&mut self) -> anyhow::Result<BTreeMap<String, Register<V>>> {
+        let mut winners: BTreeMap<String, (String, Register<V>)> = BTreeMap::new();
+        for (slot, register) in self.registers()? {
+            let Some((node, key)) = slot.split_once('/') else {
+                continue;
+            };
+            let newer = winners.get(key).is_none_or(|(winner, current)| {
+                (register.ts, node) > (current.ts, winner.as_str())
+            });
+            if newer {
+                winners.insert(key.to_string(), (node.to_string(), register));
+            }
+        }
+
+        Ok(winners
+            .into_iter()
+            .map(|(key, (_, register))| (key, register))
+            .collect())
+    }
+
+    /// Every register in the doc, keyed by `"{node}/{key}"`.
+    fn registers(&mut self) -> anyhow::Result<Vec<(String, Register<V>)>> {
+        let txn = self.doc.transact();
+        let mut registers = Vec::new();
+        for (slot, value) in self.map.iter(&txn) {
+            let yrs::Value::Any(yrs::Any::String(register)) = value else {
+                continue;
+            };
+            let register: Register<V> =
+                serde_json::from_str(&register).context("deserialize LwwMap value")?;
+            self.clock.observe(register.ts);
+            registers.push((slot.to_string(), register));
+        }
+
+        Ok(registers)
+    }
+}
@@ -0,0 +1,217 @@
+//! Nodes chosen at runtime. [`Node`] can't be boxed, as its constructor
+//! returns `Self` and its payload types differ between nodes, so
+//! [`DynNode`] hands every node plain JSON messages instead, and an
+//! [`Erased`] node parses them into its own payload type.
+//!
+//! A [`NodeRegistry`] builds boxed nodes by name, and a
+//! `Box<dyn DynNode<IP>>` is itself a [`Node`] that [`Runtime`](crate::Runtime)
+//! or a [`Simulation`](crate::sim::Simulation) can run, with the [`Selected`]
+//! registry entry as its initial state:
+//!
+//! ```ignore
+//! let registry = NodeRegistry::new()
+//!     .with::<EchoNode, echo::Payload>("echo")
+//!     .with::<UniqueIdsNode, unique_ids::Payload>("unique-ids");
+//! Runtime::new().start::<_, Value, (), Box<dyn DynNode>>(registry.select(&workload)?)
+//! ```
+//!
+//! Several of them can also run side by side in one process, see
+//! [`crate::compose`].
+
+use std::{collections::BTreeMap, marker::PhantomData, sync::Arc};
+
+use anyhow::Context as _;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::{compose::Composition, Context, Event, Init, Node};
+
+/// The object-safe part of [`Node`], with payloads left as JSON.
+pub trait DynNode<IP = ()> {
+    /// Whether `payload` is one of the node's own, e.g. for
+    /// [`crate::compose`] to route messages by.
+    fn accepts(&self, _payload: &Value) -> bool {
+        true
+    }
+
+    fn step(&mut self, input: Event<Value, IP>, context: Context<IP>) -> anyhow::Result<()>;
+
+    fn handle_reply(&mut self, input: Event<Value, IP>, context: Context<IP>)
+        -> anyhow::Result<()>;
+}
+
+/// A node of type `N`, taking its payloads as JSON.
+pub struct Erased<N, S, P> {
+    node: N,
+    payload: PhantomData<fn(S, P)>,
+}
+
+impl<N, S, P> Erased<N, S, P> {
+    pub fn new(node: N) -> Self {
+        Self {
+            node,
+            payload: PhantomData,
+        }
+    }
+
+    pub fn node(&self) -> &N {
+        &self.node
+    }
+}
+
+impl<N, S, P, IP> DynNode<IP> for Erased<N, S, P>
+where
+    N: Node<S, P, IP>,
+    P: DeserializeOwned,
+{
+    fn accepts(&self, payload: &Value) -> bool {
+        P::deserialize(payload).is_ok()
+    }
+
+    fn step(&mut self, input: Event<Value, IP>, context: Context<IP>) -> anyhow::Result<()> {
+        self.node.step(input.parse(), context)
+    }
+
+    fn handle_reply(
+        &mut self,
+        input: Event<Value, IP>,
+        context: Context<IP>,
+    ) -> anyhow::Result<()> {
+        self.node.handle_reply(input.parse(), context)
+    }
+}
+
+type Constructor<IP> =
+    dyn Fn(&Init, Context<IP>) -> anyhow::Result<Box<dyn DynNode<IP>>> + Send + Sync;
+
+/// Node types by name, for binaries and simulations that pick the node to
+/// run from their arguments.
+pub struct NodeRegistry<IP = ()> {
+    constructors: BTreeMap<String, Arc<Constructor<IP>>>,
+}
+
+impl<IP> Default for NodeRegistry<IP> {
+    fn default() -> Self {
+        Self {
+            constructors: BTreeMap::new(),
+        }
+    }
+}
+
+impl<IP> Clone for NodeRegistry<IP> {
+    fn clone(&self) -> Self {
+        Self {
+            constructors: self.constructors.clone(),
+        }
+    }
+}
+
+impl<IP: 'static> NodeRegistry<IP> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `N`, which starts from `()`, as `name`.
+    pub fn with<N, P>(self, name: &str) -> Self
+    where
+        N: Node<(), P, IP> + 'static,
+        P: DeserializeOwned + 'static,
+    {
+        self.with_state::<N, (), P>(name, ())
+    }
+
+    /// Register `N` as `name`, every instance starting from a clone of
+    /// `state`.
+    pub fn with_state<N, S, P>(mut self, name: &str, state: S) -> Self
+    where
+        N: Node<S, P, IP> + 'static,
+        S: Clone + Send + Sync + 'static,
+        P: DeserializeOwned + 'static,
+    {
+        let constructor = move |init: &Init, context: Context<IP>| {
+            let node = N::from_init(state.clone(), init, context)?;
+            Ok(Box::new(Erased::<N, S, P>::new(node)) as Box<dyn DynNode<IP>>)
+        };
+        self.constructors
+            .insert(name.to_string(), Arc::new(constructor));
+        self
+    }
+
+    /// The initial state of a [`Composite`](crate::compose::Composite)
+    /// running the nodes `names` side by side, the first of them getting
+    /// first pick of messages.
+    pub fn compose(&self, names: &[&str]) -> anyhow::Result<Composition<IP>> {
+        let parts = names
+            .iter()
+            .map(|name| Ok((name.to_string(), self.select(name)?)))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Composition { parts })
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.constructors.keys().map(String::as_str)
+    }
+
+    /// The initial state of a `Box<dyn DynNode<IP>>` that is a `name` node.
+    pub fn select(&self, name: &str) -> anyhow::Result<Selected<IP>> {
+        let constructor = self.constructors.get(name).with_context(|| {
+            let names = self.names().collect::<Vec<_>>().join(", ");
+            format!("no node named {name}, expected one of {names}")
+        })?;
+        Ok(Selected {
+            constructor: constructor.clone(),
+        })
+    }
+
+    /// Initialize a `name` node.
+    pub fn build(
+        &self,
+        name: &str,
+        init: &Init,
+        context: Context<IP>,
+    ) -> anyhow::Result<Box<dyn DynNode<IP>>> {
+        self.select(name)?.build(init, context)
+    }
+}
+
+/// A node type picked from a [`NodeRegistry`], to be built once the init
+/// message is in.
+pub struct Selected<IP = ()> {
+    constructor: Arc<Constructor<IP>>,
+}
+
+impl<IP> Clone for Selected<IP> {
+    fn clone(&self) -> Self {
+        Self {
+            constructor: self.constructor.clone(),
+        }
+    }
+}
+
+impl<IP> Selected<IP> {
+    pub(crate) fn build(
+        &self,
+        init: &Init,
+        context: Context<IP>,
+    ) -> anyhow::Result<Box<dyn DynNode<IP>>> {
+        (self.constructor)(init, context)
+    }
+}
+
+impl<IP> Node<Selected<IP>, Value, IP> for Box<dyn DynNode<IP>> {
+    fn from_init(state: Selected<IP>, init: &Init, context: Context<IP>) -> anyhow::Result<Self> {
+        state.build(init, context)
+    }
+
+    fn step(&mut self, input: Event<Value, IP>, context: Context<IP>) -> anyhow::Result<()> {
+        (**self).step(input, context)
+    }
+
+    fn handle_reply(
+        &mut self,
+        input: Event<Value, IP>,
+        context: Context<IP>,
+    ) -> anyhow::Result<()> {
+        (**self).handle_reply(input, context)
+    }
+}
@@ -0,0 +1,252 @@
+//! Errors that map to a Maelstrom error reply. Node code returns plain
+//! [`Error`](crate::Error)s; one built with [`bail!`](crate::bail) or
+//! [`ensure!`](crate::ensure) and a `code` also carries the code to answer
+//! the client with, found again with [`MaelstromError::code_of`].
+//!
+//! Input that doesn't parse fails with a [`ParseError`] showing where.
+
+use std::fmt;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Maelstrom's error codes, serialized as their numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    /// The request timed out, and may or may not have taken effect.
+    Timeout,
+
+    /// The request was addressed to a node that doesn't exist.
+    NodeNotFound,
+
+    /// The request type isn't supported.
+    NotSupported,
+
+    /// The request couldn't be served right now.
+    TemporarilyUnavailable,
+
+    /// The request wasn't well-formed.
+    MalformedRequest,
+
+    /// Something failed badly, and the request may or may not have taken
+    /// effect.
+    Crash,
+
+    /// The request definitely failed.
+    Abort,
+
+    /// Reading or CASing a key that was never written.
+    KeyDoesNotExist,
+
+    /// Creating a key that already exists.
+    KeyAlreadyExists,
+
+    /// A CAS whose `from` didn't match, or a similar failed precondition.
+    PreconditionFailed,
+
+    /// A transaction was aborted because it conflicted with another.
+    TxnConflict,
+
+    /// A code Maelstrom doesn't define, for custom errors; Maelstrom
+    /// reserves the codes below 1000.
+    Other(u64),
+}
+
+impl ErrorCode {
+    pub const fn code(self) -> u64 {
+        match self {
+            ErrorCode::Timeout => 0,
+            ErrorCode::NodeNotFound => 1,
+            ErrorCode::NotSupported => 10,
+            ErrorCode::TemporarilyUnavailable => 11,
+            ErrorCode::MalformedRequest => 12,
+            ErrorCode::Crash => 13,
+            ErrorCode::Abort => 14,
+            ErrorCode::KeyDoesNotExist => 20,
+            ErrorCode::KeyAlreadyExists => 21,
+            ErrorCode::PreconditionFailed => 22,
+            ErrorCode::TxnConflict => 30,
+            ErrorCode::Other(code) => code,
+        }
+    }
+
+    /// Whether a request failing with this code certainly had no effect, as
+    /// opposed to a timeout or crash, after which it may have.
+    pub const fn is_definite(self) -> bool {
+        !matches!(self, ErrorCode::Timeout | ErrorCode::Crash)
+    }
+}
+
+impl From<u64> for ErrorCode {
+    fn from(code: u64) -> Self {
+        match code {
+            0 => ErrorCode::Timeout,
+            1 => ErrorCode::NodeNotFound,
+            10 => ErrorCode::NotSupported,
+            11 => ErrorCode::TemporarilyUnavailable,
+            12 => ErrorCode::MalformedRequest,
+            13 => ErrorCode::Crash,
+            14 => ErrorCode::Abort,
+            20 => ErrorCode::KeyDoesNotExist,
+            21 => ErrorCode::KeyAlreadyExists,
+            22 => ErrorCode::PreconditionFailed,
+            30 => ErrorCode::TxnConflict,
+            code => ErrorCode::Other(code),
+        }
+    }
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.code())
+    }
+}
+
+impl<'de> Deserialize<'de> for ErrorCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u64::deserialize(deserializer).map(ErrorCode::from)
+    }
+}
+
+/// An error with a Maelstrom error code, serializing as the body of an
+/// `error` reply and deserializing from one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "error")]
+pub struct MaelstromError {
+    pub code: ErrorCode,
+    pub text: String,
+}
+
+impl MaelstromError {
+    pub fn new(code: impl Into<ErrorCode>, text: impl Into<String>) -> Self {
+        Self {
+            code: code.into(),
+            text: text.into(),
+        }
+    }
+
+    /// The Maelstrom error code of `err`, if it or any error it wraps has
+    /// one.
+    pub fn code_of(err: &crate::Error) -> Option<ErrorCode> {
+        err.chain()
+            .find_map(|e| e.downcast_ref::<MaelstromError>())
+            .map(|e| e.code)
+    }
+}
+
+impl fmt::Display for MaelstromError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (error code {})", self.text, self.code.code())
+    }
+}
+
+impl std::error::Error for MaelstromError {}
+
+/// Return early with an [`Error`](crate::Error), formatted like
+/// [`format!`], and with a Maelstrom error code if it starts with
+/// `code = ...,`:
+///
+/// ```ignore
+/// vorticity::bail!(code = ErrorCode::KeyDoesNotExist, "no log for {key}");
+/// ```
+#[macro_export]
+macro_rules! bail {
+    (code = $code:expr, $($arg:tt)+) => {
+        return ::core::result::Result::Err($crate::Error::new(
+            $crate::error::MaelstromError::new($code, ::std::format!($($arg)+)),
+        ))
+    };
+    ($($arg:tt)+) => {
+        return ::core::result::Result::Err($crate::Error::msg(::std::format!($($arg)+)))
+    };
+}
+
+/// Like [`bail!`](crate::bail), unless `cond` holds.
+#[macro_export]
+macro_rules! ensure {
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            $crate::bail!($($arg)+);
+        }
+    };
+}
+
+/// How much of the offending JSON a [`ParseError`] shows on each side of
+/// where parsing failed.
+const SNIPPET_CONTEXT: usize = 60;
+
+/// Input that didn't parse, e.g. a line from the harness in another format
+/// than expected, or a payload none of the node's variants match. Shows the
+/// JSON around where parsing failed rather than only serde's complaint.
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    what: String,
+    reason: String,
+
+    /// The offending JSON, cut down to the part around `offset`.
+    snippet: String,
+
+    /// Byte offset into the JSON where parsing failed, if known.
+    offset: Option<usize>,
+
+    /// Where `offset` falls within `snippet`.
+    caret: usize,
+}
+
+impl ParseError {
+    /// `json` failed to parse as `what` because of `reason`, at `offset`.
+    pub fn new(
+        what: impl Into<String>,
+        json: &str,
+        offset: Option<usize>,
+        reason: impl fmt::Display,
+    ) -> Self {
+        let at = offset.unwrap_or(0).min(json.len());
+        let mut start = at.saturating_sub(SNIPPET_CONTEXT);
+        while !json.is_char_boundary(start) {
+            start -= 1;
+        }
+        let mut end = (at + SNIPPET_CONTEXT).min(json.len());
+        while !json.is_char_boundary(end) {
+            end += 1;
+        }
+        let (prefix, suffix) = (
+            if start > 0 { "..." } else { "" },
+            if end < json.len() { "..." } else { "" },
+        );
+        Self {
+            what: what.into(),
+            reason: reason.to_string(),
+            snippet: format!("{prefix}{}{suffix}", &json[start..end]),
+            offset,
+            caret: prefix.len() + json[start..at].chars().count(),
+        }
+    }
+
+    /// A serde_json error from parsing the single line `json` as `what`.
+    pub fn from_json(what: impl Into<String>, json: &str, err: &serde_json::Error) -> Self {
+        // Columns are 1-based, and 0 when the error has no position.
+        let offset = (err.line() == 1 && err.column() > 0).then(|| err.column() - 1);
+        Self::new(what, json, offset, err)
+    }
+
+    /// Byte offset into the JSON where parsing failed, if known.
+    pub fn offset(&self) -> Option<usize> {
+        self.offset
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "invalid {}: {}", self.what, self.reason)?;
+        match self.offset {
+            Some(offset) => {
+                writeln!(f, "  at byte {offset}: {}", self.snippet)?;
+                let indent = "  at byte : ".len() + offset.to_string().len() + self.caret;
+                write!(f, "{:indent$}^ here", "")
+            }
+            None => write!(f, "  in: {}", self.snippet),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
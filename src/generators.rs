@@ -0,0 +1,398 @@
+//! `arbitrary` and `proptest` generators for the protocol types, producing
+//! node ids that look like Maelstrom's and JSON without floats, so that
+//! generated values round-trip through serde.
+
+use std::collections::BTreeSet;
+
+use crate::{
+    admin::AdminPayload,
+    barrier::BarrierPayload,
+    handshake::{Capabilities, HandshakePayload},
+    heartbeat::HeartbeatPayload,
+    message::InitPayload,
+    Body, Init, Message,
+};
+
+fn message<P>(src: String, dst: String, body: Body<P>) -> Message<P> {
+    let builder = Message::builder().src(src).dst(dst).payload(body.payload);
+    let builder = match body.id {
+        Some(id) => builder.with_id(id),
+        None => builder,
+    };
+    match body.in_reply_to {
+        Some(in_reply_to) => builder.in_reply_to(in_reply_to),
+        None => builder,
+    }
+    .build()
+    .expect("all message fields are set")
+}
+
+#[cfg(feature = "crdt-yrs")]
+fn wire_encoding(z85: bool) -> crate::gossip::WireEncoding {
+    match z85 {
+        true => crate::gossip::WireEncoding::Z85,
+        false => crate::gossip::WireEncoding::Base64,
+    }
+}
+
+fn init_payload(init: Option<Init>) -> InitPayload {
+    match init {
+        Some(init) => InitPayload::Init(init),
+        None => InitPayload::InitOk,
+    }
+}
+
+fn handshake(
+    ok: bool,
+    version: u32,
+    encodings: Vec<String>,
+    features: BTreeSet<String>,
+) -> AdminPayload {
+    let capabilities = Capabilities {
+        version,
+        encodings,
+        features,
+    };
+    AdminPayload::Handshake(match ok {
+        true => HandshakePayload::HelloOk { capabilities },
+        false => HandshakePayload::Hello { capabilities },
+    })
+}
+
+#[cfg(feature = "arbitrary")]
+mod fuzz {
+    use arbitrary::{Arbitrary, Result, Unstructured};
+    use serde_json::Value;
+
+    use super::*;
+    use crate::Event;
+
+    fn node_id(u: &mut Unstructured<'_>) -> Result<String> {
+        let prefix = u.choose(&["n", "c"])?;
+        Ok(format!("{prefix}{}", u.int_in_range(0..=9)?))
+    }
+
+    fn json(u: &mut Unstructured<'_>, depth: u32) -> Result<Value> {
+        let kinds = if depth == 0 { 4 } else { 6 };
+        Ok(match u.int_in_range(0..=kinds - 1)? {
+            0 => Value::Null,
+            1 => Value::Bool(u.arbitrary()?),
+            2 => Value::from(i64::arbitrary(u)?),
+            3 => Value::String(u.arbitrary()?),
+            4 => Value::Array(
+                (0..u.int_in_range(0..=3)?)
+                    .map(|_| json(u, depth - 1))
+                    .collect::<Result<_>>()?,
+            ),
+            _ => Value::Object(
+                (0..u.int_in_range(0..=3)?)
+                    .map(|_| Ok((u.arbitrary()?, json(u, depth - 1)?)))
+                    .collect::<Result<_>>()?,
+            ),
+        })
+    }
+
+    impl<'a, P: Arbitrary<'a>> Arbitrary<'a> for Body<P> {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            Ok(Body {
+                id: u.arbitrary()?,
+                in_reply_to: u.arbitrary()?,
+                lamport: u.arbitrary()?,
+                payload: u.arbitrary()?,
+            })
+        }
+    }
+
+    impl<'a, P: Arbitrary<'a>> Arbitrary<'a> for Message<P> {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            Ok(message(node_id(u)?, node_id(u)?, u.arbitrary()?))
+        }
+    }
+
+    impl<'a> Arbitrary<'a> for Init {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            Ok(Init {
+                node_id: node_id(u)?,
+                node_ids: (0..u.int_in_range(0..=5)?)
+                    .map(|_| node_id(u))
+                    .collect::<Result<_>>()?,
+                extensions: Default::default(),
+            })
+        }
+    }
+
+    impl<'a> Arbitrary<'a> for InitPayload {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            Ok(init_payload(u.arbitrary()?))
+        }
+    }
+
+    #[cfg(feature = "crdt-yrs")]
+    impl<'a> Arbitrary<'a> for crate::gossip::GossipPayload {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            use crate::gossip::GossipPayload;
+
+            Ok(match u.int_in_range(0..=4)? {
+                0 => GossipPayload::Push {
+                    diff: u.arbitrary()?,
+                    state_vector: u.arbitrary()?,
+                    encoding: wire_encoding(u.arbitrary()?),
+                },
+                1 => GossipPayload::Digest {
+                    state_vector: u.arbitrary()?,
+                },
+                2 => GossipPayload::DigestReply {
+                    state_vector: u.arbitrary()?,
+                },
+                3 => GossipPayload::SnapshotRequest,
+                _ => GossipPayload::Snapshot {
+                    update: u.arbitrary()?,
+                    state_vector: u.arbitrary()?,
+                    encoding: wire_encoding(u.arbitrary()?),
+                },
+            })
+        }
+    }
+
+    impl<'a> Arbitrary<'a> for AdminPayload {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            #[cfg(feature = "crdt-yrs")]
+            if u.arbitrary()? {
+                return Ok(AdminPayload::Gossip {
+                    doc: u.arbitrary()?,
+                    gossip: u.arbitrary()?,
+                });
+            }
+            if u.arbitrary()? {
+                let seq = u.arbitrary()?;
+                return Ok(AdminPayload::Heartbeat(if u.arbitrary()? {
+                    HeartbeatPayload::Ping { seq }
+                } else {
+                    HeartbeatPayload::Pong { seq }
+                }));
+            }
+            if u.arbitrary()? {
+                let phase = u.arbitrary()?;
+                return Ok(AdminPayload::Barrier(if u.arbitrary()? {
+                    BarrierPayload::Ready { phase }
+                } else {
+                    BarrierPayload::Ack { phase }
+                }));
+            }
+            if u.arbitrary()? {
+                return Ok(handshake(
+                    u.arbitrary()?,
+                    u.arbitrary()?,
+                    u.arbitrary()?,
+                    u.arbitrary()?,
+                ));
+            }
+            Ok(AdminPayload::Custom(json(u, 2)?))
+        }
+    }
+
+    impl<'a, P, IP> Arbitrary<'a> for Event<P, IP>
+    where
+        P: Arbitrary<'a>,
+        IP: Arbitrary<'a>,
+    {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            Ok(match u.int_in_range(0..=4)? {
+                0 => Event::Message(u.arbitrary()?),
+                1 => Event::Injected(u.arbitrary()?),
+                2 => Event::Admin(u.arbitrary()?),
+                3 => {
+                    let body = Body {
+                        id: u.arbitrary()?,
+                        in_reply_to: u.arbitrary()?,
+                        lamport: u.arbitrary()?,
+                        payload: json(u, 2)?,
+                    };
+                    Event::Arbitrary(message(node_id(u)?, node_id(u)?, body))
+                }
+                _ => Event::Eof,
+            })
+        }
+    }
+}
+
+#[cfg(feature = "proptest")]
+mod prop {
+    use std::fmt::Debug;
+
+    use proptest::{
+        arbitrary::{any, Arbitrary},
+        prop_oneof,
+        strategy::{BoxedStrategy, Just, LazyJust, Strategy},
+    };
+    use serde_json::Value;
+
+    use super::*;
+    use crate::Event;
+
+    fn node_id() -> impl Strategy<Value = String> {
+        "[nc][0-9]"
+    }
+
+    fn json() -> impl Strategy<Value = Value> {
+        let leaf = prop_oneof![
+            Just(Value::Null),
+            any::<bool>().prop_map(Value::Bool),
+            any::<i64>().prop_map(Value::from),
+            ".*".prop_map(Value::String),
+        ];
+        leaf.prop_recursive(2, 16, 4, |inner| {
+            prop_oneof![
+                proptest::collection::vec(inner.clone(), 0..4).prop_map(Value::Array),
+                proptest::collection::btree_map(".*", inner, 0..4)
+                    .prop_map(|map| Value::Object(map.into_iter().collect())),
+            ]
+        })
+    }
+
+    fn body<P: Debug>(payload: impl Strategy<Value = P>) -> impl Strategy<Value = Body<P>> {
+        (
+            any::<Option<usize>>(),
+            any::<Option<usize>>(),
+            any::<Option<u64>>(),
+            payload,
+        )
+            .prop_map(|(id, in_reply_to, lamport, payload)| Body {
+                id,
+                in_reply_to,
+                lamport,
+                payload,
+            })
+    }
+
+    fn message_of<P: Debug>(
+        payload: impl Strategy<Value = P>,
+    ) -> impl Strategy<Value = Message<P>> {
+        (node_id(), node_id(), body(payload)).prop_map(|(src, dst, body)| message(src, dst, body))
+    }
+
+    impl<P: Arbitrary + 'static> Arbitrary for Body<P> {
+        type Parameters = ();
+        type Strategy = BoxedStrategy<Self>;
+
+        fn arbitrary_with(_: ()) -> Self::Strategy {
+            body(any::<P>()).boxed()
+        }
+    }
+
+    impl<P: Arbitrary + 'static> Arbitrary for Message<P> {
+        type Parameters = ();
+        type Strategy = BoxedStrategy<Self>;
+
+        fn arbitrary_with(_: ()) -> Self::Strategy {
+            message_of(any::<P>()).boxed()
+        }
+    }
+
+    impl Arbitrary for Init {
+        type Parameters = ();
+        type Strategy = BoxedStrategy<Self>;
+
+        fn arbitrary_with(_: ()) -> Self::Strategy {
+            (node_id(), proptest::collection::vec(node_id(), 0..5))
+                .prop_map(|(node_id, node_ids)| Init {
+                    node_id,
+                    node_ids,
+                    extensions: Default::default(),
+                })
+                .boxed()
+        }
+    }
+
+    impl Arbitrary for InitPayload {
+        type Parameters = ();
+        type Strategy = BoxedStrategy<Self>;
+
+        fn arbitrary_with(_: ()) -> Self::Strategy {
+            any::<Option<Init>>().prop_map(init_payload).boxed()
+        }
+    }
+
+    #[cfg(feature = "crdt-yrs")]
+    impl Arbitrary for crate::gossip::GossipPayload {
+        type Parameters = ();
+        type Strategy = BoxedStrategy<Self>;
+
+        fn arbitrary_with(_: ()) -> Self::Strategy {
+            use crate::gossip::GossipPayload;
+
+            prop_oneof![
+                (any::<String>(), any::<String>(), any::<bool>()).prop_map(
+                    |(diff, state_vector, z85)| GossipPayload::Push {
+                        diff,
+                        state_vector,
+                        encoding: wire_encoding(z85),
+                    }
+                ),
+                any::<String>().prop_map(|state_vector| GossipPayload::Digest { state_vector }),
+                any::<String>()
+                    .prop_map(|state_vector| GossipPayload::DigestReply { state_vector }),
+                Just(GossipPayload::SnapshotRequest),
+                (any::<String>(), any::<String>(), any::<bool>()).prop_map(
+                    |(update, state_vector, z85)| GossipPayload::Snapshot {
+                        update,
+                        state_vector,
+                        encoding: wire_encoding(z85),
+                    }
+                ),
+            ]
+            .boxed()
+        }
+    }
+
+    impl Arbitrary for AdminPayload {
+        type Parameters = ();
+        type Strategy = BoxedStrategy<Self>;
+
+        fn arbitrary_with(_: ()) -> Self::Strategy {
+            let custom = prop_oneof![
+                json().prop_map(AdminPayload::Custom),
+                any::<u64>()
+                    .prop_map(|seq| AdminPayload::Heartbeat(HeartbeatPayload::Ping { seq })),
+                any::<u64>()
+                    .prop_map(|seq| AdminPayload::Heartbeat(HeartbeatPayload::Pong { seq })),
+                any::<String>()
+                    .prop_map(|phase| AdminPayload::Barrier(BarrierPayload::Ready { phase })),
+                any::<String>()
+                    .prop_map(|phase| AdminPayload::Barrier(BarrierPayload::Ack { phase })),
+                any::<(bool, u32, Vec<String>, BTreeSet<String>)>().prop_map(
+                    |(ok, version, encodings, features)| handshake(
+                        ok, version, encodings, features
+                    )
+                ),
+            ];
+            #[cfg(feature = "crdt-yrs")]
+            let custom = prop_oneof![
+                custom,
+                (any::<String>(), any::<crate::gossip::GossipPayload>())
+                    .prop_map(|(doc, gossip)| AdminPayload::Gossip { doc, gossip }),
+            ];
+            custom.boxed()
+        }
+    }
+
+    impl<P, IP> Arbitrary for Event<P, IP>
+    where
+        P: Arbitrary + 'static,
+        IP: Arbitrary + 'static,
+    {
+        type Parameters = ();
+        type Strategy = BoxedStrategy<Self>;
+
+        fn arbitrary_with(_: ()) -> Self::Strategy {
+            prop_oneof![
+                any::<Message<P>>().prop_map(Event::Message),
+                any::<IP>().prop_map(Event::Injected),
+                any::<Message<AdminPayload>>().prop_map(Event::Admin),
+                message_of(json()).prop_map(Event::Arbitrary),
+                LazyJust::new(|| Event::Eof),
+            ]
+            .boxed()
+        }
+    }
+}
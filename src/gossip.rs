@@ -0,0 +1,1165 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use base64::{
+    engine::{GeneralPurpose, GeneralPurposeConfig},
+    Engine,
+};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use yrs::{
+    updates::{decoder::Decode, encoder::Encode},
+    ReadTxn, StateVector, Transact,
+};
+
+use crate::{
+    admin::{Admin, AdminPayload},
+    clock::{self, SharedClock},
+    log, Context, Init,
+};
+
+const ENGINE: GeneralPurpose =
+    GeneralPurpose::new(&base64::alphabet::URL_SAFE, GeneralPurposeConfig::new());
+
+/// How long to wait for a requested snapshot before asking again.
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub use crate::STATE_DIR_ENV;
+
+/// How a [`GossipDoc`] spreads its updates to the neighborhood.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GossipMode {
+    /// Blindly push a diff against the last known remote state every round.
+    #[default]
+    Push,
+
+    /// Exchange state vector digests first, and only send diffs in the
+    /// direction that is actually missing data.
+    PushPull,
+}
+
+/// Timer driving gossip rounds, jittered so that nodes don't all fire on the
+/// same boundaries and produce synchronized bursts of traffic.
+#[derive(Debug, Clone)]
+pub struct GossipSchedule {
+    interval: Duration,
+    jitter: f64,
+    seed: Option<u64>,
+
+    /// How long a tick may wait for the event loop before it's dropped.
+    ttl: Option<Duration>,
+}
+
+impl GossipSchedule {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            jitter: 0.0,
+            seed: None,
+            ttl: None,
+        }
+    }
+
+    /// Randomize every tick by up to `jitter` (a fraction of the interval) in
+    /// either direction, and start at a random phase within the first interval.
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Drop ticks the event loop only gets to after `ttl`, so a node that
+    /// fell behind doesn't run a burst of rounds once it catches up.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Spawn a thread that injects `payload` on every tick, until the node's
+    /// input ends, like the timers of [`crate::timer`].
+    pub fn spawn<IP>(self, context: Context<IP>, payload: IP) -> thread::JoinHandle<()>
+    where
+        IP: Clone + Send + Sync + 'static,
+    {
+        let mut rng = match self.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        thread::spawn(move || {
+            let timers = context.timers();
+            if self.jitter > 0.0 {
+                let phase = self.interval.mul_f64(rng.gen_range(0.0..1.0));
+                if !timers.sleep(context.clock(), phase) {
+                    return;
+                }
+            }
+            while timers.sleep(context.clock(), self.next_delay(&mut rng)) {
+                let injected = match self.ttl {
+                    Some(ttl) => context.inject_with_ttl(payload.clone(), ttl),
+                    None => context.inject(payload.clone()),
+                };
+                if injected.is_err() {
+                    break;
+                }
+            }
+        })
+    }
+
+    fn next_delay(&self, rng: &mut StdRng) -> Duration {
+        if self.jitter == 0.0 {
+            return self.interval;
+        }
+        self.interval
+            .mul_f64(1.0 + rng.gen_range(-self.jitter..=self.jitter))
+    }
+}
+
+/// How many peers a node gossips with outside of full mesh rounds. A number
+/// in config, like `--fanout=3`, is a count and one with a fraction, like
+/// `--fanout=0.5`, a fraction.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Fanout {
+    /// This many peers, or all of them in smaller clusters.
+    Count(usize),
+
+    /// Every peer with this probability, drawn once per peer.
+    Fraction(f64),
+}
+
+impl Default for Fanout {
+    fn default() -> Self {
+        Fanout::Fraction(0.75)
+    }
+}
+
+impl Fanout {
+    /// Draw the peers of `node_id` to gossip with.
+    pub fn sample(&self, node_ids: &[String], node_id: &str, rng: &mut StdRng) -> Vec<String> {
+        let peers = node_ids.iter().filter(|peer| *peer != node_id);
+        match *self {
+            Fanout::Count(count) => peers
+                .collect::<Vec<_>>()
+                .choose_multiple(rng, count)
+                .map(|peer| peer.to_string())
+                .collect(),
+            Fanout::Fraction(fraction) => peers
+                .filter(|_| rng.gen_bool(fraction.clamp(0.0, 1.0)))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+/// How the binary diffs and snapshots of [`GossipPayload`] are written into
+/// JSON strings. State vectors of digests are small and always base64.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WireEncoding {
+    /// URL-safe base64, a third larger than the data.
+    #[default]
+    Base64,
+
+    /// ZeroMQ's Z85, a quarter larger than the data, and made of characters
+    /// that JSON doesn't need to escape.
+    Z85,
+}
+
+impl WireEncoding {
+    fn is_base64(&self) -> bool {
+        *self == WireEncoding::Base64
+    }
+
+    /// The encoding a [handshake](crate::handshake) names, as in
+    /// [`Capabilities::encodings`](crate::handshake::Capabilities::encodings).
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "base64" => Some(WireEncoding::Base64),
+            "z85" => Some(WireEncoding::Z85),
+            _ => None,
+        }
+    }
+
+    fn encode(self, data: &[u8]) -> String {
+        match self {
+            WireEncoding::Base64 => ENGINE.encode(data),
+            WireEncoding::Z85 => z85::encode(data),
+        }
+    }
+
+    fn decode(self, encoded: &str) -> anyhow::Result<Vec<u8>> {
+        match self {
+            WireEncoding::Base64 => ENGINE.decode(encoded).context("base64 decode failed"),
+            WireEncoding::Z85 => z85::decode(encoded).context("z85 decode failed"),
+        }
+    }
+
+    /// Roughly how long `encoded` would be in base64.
+    fn base64_len(self, encoded: &str) -> usize {
+        match self {
+            WireEncoding::Base64 => encoded.len(),
+            WireEncoding::Z85 => (encoded.len() * 4 / 5).div_ceil(3) * 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GossipPayload {
+    /// An update for the receiver, along with the sender's state vector.
+    Push {
+        diff: String,
+        state_vector: String,
+        #[serde(default, skip_serializing_if = "WireEncoding::is_base64")]
+        encoding: WireEncoding,
+    },
+
+    /// The sender's state vector, expecting a `Push` and/or a `DigestReply`.
+    Digest { state_vector: String },
+
+    /// Answer to a `Digest`, or to a `Push` between docs tracking staleness,
+    /// which only ever triggers a `Push`.
+    DigestReply { state_vector: String },
+
+    /// Ask for the sender's whole doc, because the requester is far behind.
+    SnapshotRequest,
+
+    /// The sender's whole doc encoded as a single update.
+    Snapshot {
+        update: String,
+        state_vector: String,
+        #[serde(default, skip_serializing_if = "WireEncoding::is_base64")]
+        encoding: WireEncoding,
+    },
+}
+
+/// A change in how the gossip layer sees one of its peers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerStatus {
+    /// Nothing was heard from the peer for too many gossip intervals.
+    Suspected(String),
+
+    /// A previously suspected peer was heard from again.
+    Recovered(String),
+}
+
+type PeerNotify = Box<dyn Fn(PeerStatus) -> anyhow::Result<()> + Send>;
+
+/// A merged update left the doc violating an invariant that the CRDT itself
+/// doesn't know about, e.g. a committed offset moving backwards.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub doc: String,
+
+    /// The peer whose update was being merged.
+    pub peer: String,
+    pub reason: String,
+}
+
+/// Inspects the doc after a merge, describing the broken invariant if any.
+type DivergenceCheck = Box<dyn FnMut(&yrs::Doc) -> Option<String> + Send>;
+type DivergenceNotify = Box<dyn Fn(Divergence) -> anyhow::Result<()> + Send>;
+
+/// Unanswered gossip, used to suspect silent peers.
+struct Staleness {
+    interval: Duration,
+    max_missed: u32,
+
+    /// When each peer was first sent gossip it hasn't answered yet.
+    awaiting: BTreeMap<String, Instant>,
+    suspected: BTreeSet<String>,
+    rounds: u64,
+}
+
+/// Peers assumed to have applied the diffs pushed to them, until they report
+/// a state vector covering them or `timeout` passes.
+struct Optimistic {
+    timeout: Duration,
+
+    /// The last state vector each peer actually reported, and when a push
+    /// first got ahead of it.
+    in_flight: HashMap<String, (StateVector, Instant)>,
+}
+
+/// Bytes of gossip sent to each peer, overall and during the current round.
+#[derive(Debug, Default)]
+struct Bandwidth {
+    /// Maximum bytes sent to a single peer per round, if limited.
+    budget: Option<usize>,
+    round: HashMap<String, usize>,
+    total: HashMap<String, u64>,
+    deferred: u64,
+}
+
+impl GossipPayload {
+    /// Whether a doc tracking staleness answers this payload.
+    fn expects_answer(&self) -> bool {
+        matches!(
+            self,
+            GossipPayload::Push { .. }
+                | GossipPayload::Digest { .. }
+                | GossipPayload::SnapshotRequest
+        )
+    }
+
+    /// Approximate number of bytes this payload puts on the wire.
+    pub fn wire_size(&self) -> usize {
+        match self {
+            GossipPayload::Push {
+                diff, state_vector, ..
+            } => diff.len() + state_vector.len(),
+            GossipPayload::Digest { state_vector }
+            | GossipPayload::DigestReply { state_vector } => state_vector.len(),
+            GossipPayload::SnapshotRequest => 0,
+            GossipPayload::Snapshot {
+                update,
+                state_vector,
+                ..
+            } => update.len() + state_vector.len(),
+        }
+    }
+
+    /// Roughly how many bytes this payload would put on the wire in base64.
+    pub fn base64_size(&self) -> usize {
+        match self {
+            GossipPayload::Push {
+                diff,
+                state_vector,
+                encoding,
+            } => encoding.base64_len(diff) + encoding.base64_len(state_vector),
+            GossipPayload::Snapshot {
+                update,
+                state_vector,
+                encoding,
+            } => encoding.base64_len(update) + encoding.base64_len(state_vector),
+            _ => self.wire_size(),
+        }
+    }
+}
+
+pub struct GossipDoc {
+    /// Identifies the doc in admin gossip messages and persistence files.
+    name: String,
+    node_id: String,
+    doc: yrs::Doc,
+    known: HashMap<String, StateVector>,
+    neighborhood: Vec<String>,
+    mode: GossipMode,
+
+    /// Where the encoded doc is written after each gossip round, if anywhere.
+    persist_path: Option<PathBuf>,
+
+    /// The state vector of the doc as of the last write to `persist_path`.
+    persisted: StateVector,
+
+    staleness: Option<Staleness>,
+    optimistic: Option<Optimistic>,
+    notify: Option<PeerNotify>,
+    divergence: Option<(DivergenceCheck, DivergenceNotify)>,
+    bandwidth: Bandwidth,
+
+    /// How many missing operations make a peer request a full snapshot
+    /// instead of incremental diffs.
+    snapshot_threshold: Option<u64>,
+
+    /// The peer asked for a snapshot, and when.
+    snapshot_pending: Option<(String, Instant)>,
+
+    /// Gossip with every peer instead of just the neighborhood on every Nth
+    /// round, so updates can't get stranded in a disconnected neighbor graph.
+    full_mesh_every: Option<u64>,
+    rounds: u64,
+
+    /// Local clocks not yet seen by every peer, and when they were noticed.
+    unconverged: VecDeque<(u32, Instant)>,
+    last_local_clock: u32,
+
+    rng: StdRng,
+
+    /// Every node in the cluster, in a stable order for sampling.
+    node_ids: Vec<String>,
+
+    /// How diffs and snapshots this node sends are encoded.
+    encoding: WireEncoding,
+
+    /// What timeouts and staleness are measured by.
+    clock: SharedClock,
+
+    /// How the neighborhood is drawn.
+    fanout: Fanout,
+}
+
+impl GossipDoc {
+    pub fn new(name: &str, init: &Init) -> Self {
+        let mut rng = StdRng::from_entropy();
+        let fanout = Fanout::default();
+        let neighborhood = fanout.sample(&init.node_ids, &init.node_id, &mut rng);
+        Self {
+            name: name.to_string(),
+            node_id: init.node_id.clone(),
+            doc: yrs::Doc::new(),
+            known: init
+                .node_ids
+                .iter()
+                .cloned()
+                .map(|nid| (nid, Default::default()))
+                .collect(),
+            neighborhood,
+            mode: GossipMode::default(),
+            persist_path: None,
+            persisted: StateVector::default(),
+            staleness: None,
+            optimistic: None,
+            notify: None,
+            divergence: None,
+            bandwidth: Bandwidth::default(),
+            snapshot_threshold: None,
+            snapshot_pending: None,
+            full_mesh_every: None,
+            rounds: 0,
+            unconverged: VecDeque::new(),
+            last_local_clock: 0,
+            rng,
+            node_ids: init.node_ids.clone(),
+            encoding: WireEncoding::default(),
+            clock: clock::system(),
+            fanout,
+        }
+    }
+
+    /// Measure timeouts and staleness by `clock`, usually the context's.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn clock(&self) -> &SharedClock {
+        &self.clock
+    }
+
+    /// Make every random decision from `seed`, resampling the neighborhood.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = StdRng::seed_from_u64(seed);
+        self.resample_neighborhood();
+        self
+    }
+
+    /// Gossip with `fanout` peers outside of full mesh rounds, resampling
+    /// the neighborhood.
+    pub fn with_fanout(mut self, fanout: Fanout) -> Self {
+        self.fanout = fanout;
+        self.resample_neighborhood();
+        self
+    }
+
+    fn resample_neighborhood(&mut self) {
+        self.neighborhood = self
+            .fanout
+            .sample(&self.node_ids, &self.node_id, &mut self.rng);
+    }
+
+    /// Encode the diffs and snapshots sent to peers with `encoding`. Peers
+    /// decode whatever encoding a payload says it uses, so nodes don't need
+    /// to agree on this. Peers that agreed on an encoding in the
+    /// [handshake](crate::handshake) get that one instead.
+    pub fn with_wire_encoding(mut self, encoding: WireEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Reconcile with every peer, not just the neighborhood, once every
+    /// `rounds` gossip rounds.
+    pub fn with_full_mesh_every(mut self, rounds: u64) -> Self {
+        self.full_mesh_every = Some(rounds.max(1));
+        self
+    }
+
+    /// Bootstrap from a full snapshot of a peer's doc whenever this node is
+    /// missing at least `missing_ops` operations that the peer has seen.
+    pub fn with_snapshot_threshold(mut self, missing_ops: u64) -> Self {
+        self.snapshot_threshold = Some(missing_ops);
+        self
+    }
+
+    /// Limit the bytes sent to any single peer per gossip round. Payloads that
+    /// would exceed the budget are deferred to a later round, except for the
+    /// first payload of a round so that oversized diffs still make progress.
+    pub fn with_bandwidth_budget(mut self, bytes_per_round: usize) -> Self {
+        self.bandwidth.budget = Some(bytes_per_round);
+        self
+    }
+
+    /// Total gossip bytes sent to `peer` so far.
+    pub fn bytes_sent(&self, peer: &str) -> u64 {
+        self.bandwidth.total.get(peer).copied().unwrap_or(0)
+    }
+
+    /// How many payloads were deferred because of the bandwidth budget.
+    pub fn deferred_sends(&self) -> u64 {
+        self.bandwidth.deferred
+    }
+
+    /// Suspect peers that haven't answered the gossip sent to them for
+    /// `max_missed` gossip rounds of `interval`. Suspected peers are only
+    /// probed every `max_missed` rounds instead of receiving every round's
+    /// traffic.
+    ///
+    /// Peers this node doesn't gossip with aren't suspected, and docs
+    /// tracking staleness acknowledge the pushes and digests they get, so
+    /// peers that are in sync still answer. Enable it on every node sharing
+    /// the doc.
+    pub fn with_staleness(mut self, interval: Duration, max_missed: u32) -> Self {
+        self.staleness = Some(Staleness {
+            interval,
+            max_missed: max_missed.max(1),
+            awaiting: BTreeMap::new(),
+            suspected: BTreeSet::new(),
+            rounds: 0,
+        });
+        self
+    }
+
+    /// Assume peers apply the diffs pushed to them, so the same data isn't
+    /// pushed again every round until they gossip back. A peer that hasn't
+    /// reported a state vector covering the push within `timeout` is rolled
+    /// back to the one it last reported, and gets the data again.
+    pub fn with_optimistic_push(mut self, timeout: Duration) -> Self {
+        self.optimistic = Some(Optimistic {
+            timeout,
+            in_flight: HashMap::new(),
+        });
+        self
+    }
+
+    /// Inject every [`PeerStatus`] change into the event loop, wrapped into
+    /// the node's injected payload type with `wrap`.
+    pub fn on_peer_status<IP>(
+        mut self,
+        ctx: Context<IP>,
+        wrap: impl Fn(PeerStatus) -> IP + Send + 'static,
+    ) -> Self
+    where
+        IP: Send + Sync + 'static,
+    {
+        self.notify = Some(Box::new(move |status| ctx.inject(wrap(status))));
+        self
+    }
+
+    /// Run `check` against the doc after every update merged from a peer,
+    /// injecting a [`Divergence`] wrapped into the node's payload type when it
+    /// reports a problem. The check keeps whatever earlier view of the doc it
+    /// needs to compare against.
+    pub fn on_divergence<IP>(
+        mut self,
+        ctx: Context<IP>,
+        check: impl FnMut(&yrs::Doc) -> Option<String> + Send + 'static,
+        wrap: impl Fn(Divergence) -> IP + Send + 'static,
+    ) -> Self
+    where
+        IP: Send + Sync + 'static,
+    {
+        self.divergence = Some((
+            Box::new(check),
+            Box::new(move |divergence| ctx.inject(wrap(divergence))),
+        ));
+        self
+    }
+
+    pub fn is_suspected(&self, peer: &str) -> bool {
+        self.staleness
+            .as_ref()
+            .is_some_and(|s| s.suspected.contains(peer))
+    }
+
+    /// Persist the doc to `path`, restoring whatever was previously written
+    /// there so a restarted node rejoins with its history.
+    pub fn with_persistence(mut self, path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        if path.exists() {
+            let bytes = fs::read(&path)
+                .with_context(|| format!("reading persisted doc {}", path.display()))?;
+            let update = yrs::Update::decode_v1(&bytes)
+                .with_context(|| format!("decoding persisted doc {}", path.display()))?;
+            let mut txn = self.doc.transact_mut();
+            txn.apply_update(update);
+            self.persisted = txn.state_vector();
+        }
+        self.persist_path = Some(path);
+        Ok(self)
+    }
+
+    /// Persist the doc inside the directory named by [`STATE_DIR_ENV`], if
+    /// that variable is set.
+    pub fn with_env_persistence(self) -> anyhow::Result<Self> {
+        match std::env::var_os(STATE_DIR_ENV) {
+            Some(dir) => {
+                let path = persist_file(Path::new(&dir), &self.node_id, &self.name);
+                self.with_persistence(path)
+            }
+            None => Ok(self),
+        }
+    }
+
+    /// Write the full encoded doc to the persistence file, if it changed since
+    /// the last write.
+    pub fn persist(&mut self) -> anyhow::Result<()> {
+        let Some(path) = &self.persist_path else {
+            return Ok(());
+        };
+        let txn = self.doc.transact();
+        let state_vector = txn.state_vector();
+        if state_vector == self.persisted {
+            return Ok(());
+        }
+
+        // Write to a temporary file first so a crash can't leave a torn doc.
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, txn.encode_state_as_update_v1(&StateVector::default()))
+            .with_context(|| format!("writing persisted doc {}", tmp.display()))?;
+        fs::rename(&tmp, path)
+            .with_context(|| format!("replacing persisted doc {}", path.display()))?;
+        self.persisted = state_vector;
+
+        Ok(())
+    }
+
+    pub fn with_mode(mut self, mode: GossipMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Replace the randomly sampled neighborhood, e.g. to share one across
+    /// several docs.
+    pub fn with_neighborhood(mut self, neighborhood: Vec<String>) -> Self {
+        self.neighborhood = neighborhood;
+        self
+    }
+
+    pub fn neighborhood(&self) -> &[String] {
+        &self.neighborhood
+    }
+
+    pub fn doc(&self) -> &yrs::Doc {
+        &self.doc
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Run one gossip round against the neighborhood.
+    pub fn gossip<IP>(&mut self, ctx: &Context<IP>) -> anyhow::Result<()> {
+        self.rounds += 1;
+        self.check_staleness()?;
+        self.expire_optimistic(ctx);
+        self.bandwidth.round.clear();
+        let outgoing = match self.mode {
+            GossipMode::Push => self.push_round(ctx)?,
+            GossipMode::PushPull => self.digest_round(),
+        };
+        self.send_all(ctx, outgoing)?;
+        self.track_convergence(ctx);
+        self.persist()
+    }
+
+    /// Handle a [`GossipPayload`] sent by `src`, answering it if needed.
+    pub fn receive<IP>(
+        &mut self,
+        src: &str,
+        payload: &GossipPayload,
+        ctx: &Context<IP>,
+    ) -> anyhow::Result<()> {
+        self.heard_from(src)?;
+        ctx.metrics()
+            .incr("gossip.bytes_received", payload.wire_size() as u64);
+        let mut outgoing = Vec::new();
+        match payload {
+            GossipPayload::Push {
+                diff,
+                state_vector,
+                encoding,
+            } => {
+                let state_vector = decode_state_vector(state_vector, *encoding)?;
+                let update = yrs::Update::decode_v1(&encoding.decode(diff)?)
+                    .context("Update decode failed")?;
+                let mut txn = self.doc.transact_mut();
+                txn.apply_update(update);
+                let local = txn.state_vector();
+                drop(txn);
+                ctx.metrics().incr("gossip.diffs_applied", 1);
+                self.check_divergence(src, ctx)?;
+                outgoing.extend(self.maybe_request_snapshot(src, &local, &state_vector));
+                self.record_known(src, state_vector);
+            }
+            GossipPayload::Digest { state_vector }
+            | GossipPayload::DigestReply { state_vector } => {
+                let remote = decode_state_vector(state_vector, WireEncoding::Base64)?;
+                let txn = self.doc.transact();
+                let local = txn.state_vector();
+                // A peer that's far behind gets our digest instead of a huge
+                // diff, so it can ask for a snapshot.
+                let remote_lags = self
+                    .snapshot_threshold
+                    .is_some_and(|threshold| missing_ops(&remote, &local) >= threshold);
+                if is_ahead(&local, &remote) && !remote_lags {
+                    let encoding = self.encoding_for(src, ctx);
+                    let push = self.push_payload(&txn, &remote, &local, encoding);
+                    outgoing.push((src.to_string(), push));
+                }
+                let is_digest = matches!(payload, GossipPayload::Digest { .. });
+                if is_digest && (is_ahead(&remote, &local) || remote_lags) {
+                    outgoing.push((
+                        src.to_string(),
+                        GossipPayload::DigestReply {
+                            state_vector: ENGINE.encode(local.encode_v1()),
+                        },
+                    ));
+                }
+                drop(txn);
+                outgoing.extend(self.maybe_request_snapshot(src, &local, &remote));
+                self.record_known(src, remote);
+            }
+            GossipPayload::SnapshotRequest => {
+                let encoding = self.encoding_for(src, ctx);
+                let txn = self.doc.transact();
+                outgoing.push((
+                    src.to_string(),
+                    GossipPayload::Snapshot {
+                        update: encoding
+                            .encode(&txn.encode_state_as_update_v1(&StateVector::default())),
+                        state_vector: encoding.encode(&txn.state_vector().encode_v1()),
+                        encoding,
+                    },
+                ));
+            }
+            GossipPayload::Snapshot {
+                update,
+                state_vector,
+                encoding,
+            } => {
+                let state_vector = decode_state_vector(state_vector, *encoding)?;
+                let update = yrs::Update::decode_v1(&encoding.decode(update)?)
+                    .context("Snapshot decode failed")?;
+                log::info("bootstrapping from snapshot")
+                    .field("doc", &self.name)
+                    .field("src", src)
+                    .emit();
+                let mut txn = self.doc.transact_mut();
+                txn.apply_update(update);
+                drop(txn);
+                ctx.metrics().incr("gossip.snapshots_applied", 1);
+                self.check_divergence(src, ctx)?;
+                self.record_known(src, state_vector);
+                if self
+                    .snapshot_pending
+                    .as_ref()
+                    .is_some_and(|(peer, _)| peer == src)
+                {
+                    self.snapshot_pending = None;
+                }
+            }
+        }
+
+        if let Some(ack) = self.acknowledge(src, payload, &outgoing) {
+            outgoing.push(ack);
+        }
+        self.track_convergence(ctx);
+        self.send_all(ctx, outgoing)
+    }
+
+    /// A digest of the doc for `src`, telling it that its push or digest
+    /// arrived when nothing else answers it, so the peer doesn't suspect
+    /// this node.
+    fn acknowledge(
+        &self,
+        src: &str,
+        payload: &GossipPayload,
+        outgoing: &[(String, GossipPayload)],
+    ) -> Option<(String, GossipPayload)> {
+        if self.staleness.is_none() || !payload.expects_answer() {
+            return None;
+        }
+        if outgoing.iter().any(|(peer, _)| peer == src) {
+            return None;
+        }
+        let state_vector = ENGINE.encode(self.doc.transact().state_vector().encode_v1());
+        Some((src.to_string(), GossipPayload::DigestReply { state_vector }))
+    }
+
+    /// Record how long local updates took to be seen by every peer. Updates
+    /// are timed from the first gossip round that noticed them.
+    fn track_convergence<IP>(&mut self, ctx: &Context<IP>) {
+        let client = self.doc.client_id();
+        let clock = self.doc.transact().state_vector().get(&client);
+        if clock > self.last_local_clock {
+            self.unconverged.push_back((clock, self.clock.now()));
+            self.last_local_clock = clock;
+        }
+
+        let seen_everywhere = self
+            .known
+            .iter()
+            .filter(|(peer, _)| *peer != &self.node_id)
+            .map(|(peer, state_vector)| {
+                // Only what peers reported counts, not what was pushed to them.
+                let confirmed = self
+                    .optimistic
+                    .as_ref()
+                    .and_then(|o| o.in_flight.get(peer))
+                    .map_or(state_vector, |(confirmed, _)| confirmed);
+                confirmed.get(&client)
+            })
+            .min()
+            .unwrap_or(clock);
+        while let Some(&(clock, noticed)) = self.unconverged.front() {
+            if clock > seen_everywhere {
+                break;
+            }
+            ctx.metrics().observe(
+                "gossip.convergence_ms",
+                self.clock.elapsed(noticed).as_secs_f64() * 1000.0,
+            );
+            self.unconverged.pop_front();
+        }
+    }
+
+    /// Record the state vector `src` reported. While a push to it is in
+    /// flight and the report doesn't cover it yet, the report only replaces
+    /// what a rollback falls back to.
+    fn record_known(&mut self, src: &str, remote: StateVector) {
+        if let Some(optimistic) = &mut self.optimistic {
+            if let Some((confirmed, _)) = optimistic.in_flight.get_mut(src) {
+                let assumed = self.known.get(src);
+                if assumed.is_some_and(|assumed| is_ahead(assumed, &remote)) {
+                    *confirmed = remote;
+                    return;
+                }
+                optimistic.in_flight.remove(src);
+            }
+        }
+        self.known.insert(src.to_string(), remote);
+    }
+
+    /// Assume `peer` applied a push, bringing it up to `local`.
+    fn assume_applied(&mut self, peer: &str, local: &StateVector) {
+        let Some(optimistic) = &mut self.optimistic else {
+            return;
+        };
+        let Some(known) = self.known.get_mut(peer) else {
+            return;
+        };
+        optimistic
+            .in_flight
+            .entry(peer.to_string())
+            .or_insert_with(|| (known.clone(), self.clock.now()));
+        *known = local.clone();
+    }
+
+    /// Roll back peers whose pushes went unconfirmed for too long.
+    fn expire_optimistic<IP>(&mut self, ctx: &Context<IP>) {
+        let Some(optimistic) = &mut self.optimistic else {
+            return;
+        };
+        let timeout = optimistic.timeout;
+        let known = &mut self.known;
+        let clock = &self.clock;
+        optimistic.in_flight.retain(|peer, (confirmed, pushed)| {
+            if clock.elapsed(*pushed) <= timeout {
+                return true;
+            }
+            known.insert(peer.clone(), std::mem::take(confirmed));
+            ctx.metrics().incr("gossip.optimistic_rollbacks", 1);
+            false
+        });
+    }
+
+    /// Ask `src` for a snapshot if this node lags too far behind it, unless a
+    /// snapshot is already on its way.
+    fn maybe_request_snapshot(
+        &mut self,
+        src: &str,
+        local: &StateVector,
+        remote: &StateVector,
+    ) -> Option<(String, GossipPayload)> {
+        let threshold = self.snapshot_threshold?;
+        if missing_ops(local, remote) < threshold {
+            return None;
+        }
+        if let Some((_, requested)) = &self.snapshot_pending {
+            if self.clock.elapsed(*requested) < SNAPSHOT_TIMEOUT {
+                return None;
+            }
+        }
+        self.snapshot_pending = Some((src.to_string(), self.clock.now()));
+        Some((src.to_string(), GossipPayload::SnapshotRequest))
+    }
+
+    /// Note that `src` is alive, e.g. because a heartbeat came in from it.
+    /// Gossip from a peer counts without calling this.
+    pub fn heard_from(&mut self, src: &str) -> anyhow::Result<()> {
+        let Some(staleness) = &mut self.staleness else {
+            return Ok(());
+        };
+        staleness.awaiting.remove(src);
+        if staleness.suspected.remove(src) {
+            self.notify(PeerStatus::Recovered(src.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    fn check_staleness(&mut self) -> anyhow::Result<()> {
+        let Some(staleness) = &mut self.staleness else {
+            return Ok(());
+        };
+        staleness.rounds += 1;
+        let deadline = staleness.interval * staleness.max_missed;
+        let newly_suspected: Vec<String> = staleness
+            .awaiting
+            .iter()
+            .filter(|(peer, sent)| {
+                self.clock.elapsed(**sent) > deadline && !staleness.suspected.contains(*peer)
+            })
+            .map(|(peer, _)| peer.clone())
+            .filter(|peer| peer != &self.node_id)
+            .collect();
+        staleness.suspected.extend(newly_suspected.iter().cloned());
+        for peer in newly_suspected {
+            self.notify(PeerStatus::Suspected(peer))?;
+        }
+
+        Ok(())
+    }
+
+    fn check_divergence<IP>(&mut self, src: &str, ctx: &Context<IP>) -> anyhow::Result<()> {
+        let Some((check, notify)) = &mut self.divergence else {
+            return Ok(());
+        };
+        let Some(reason) = check(&self.doc) else {
+            return Ok(());
+        };
+        ctx.metrics().incr("gossip.divergences", 1);
+        let divergence = Divergence {
+            doc: self.name.clone(),
+            peer: src.to_string(),
+            reason,
+        };
+        log::warn("divergence detected")
+            .field("doc", &divergence.doc)
+            .field("peer", &divergence.peer)
+            .field("reason", &divergence.reason)
+            .emit();
+        notify(divergence).context("notifying divergence")
+    }
+
+    fn notify(&self, status: PeerStatus) -> anyhow::Result<()> {
+        log::info(format!("peer status changed: {status:?}")).emit();
+        match &self.notify {
+            Some(notify) => notify(status).context("notifying peer status change"),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether this round should send anything to `peer`.
+    fn should_send(&self, peer: &str) -> bool {
+        if peer == self.node_id {
+            return false;
+        }
+        match &self.staleness {
+            Some(s) if s.suspected.contains(peer) => s.rounds % s.max_missed as u64 == 0,
+            _ => true,
+        }
+    }
+
+    /// The peers to gossip with this round.
+    fn targets(&self) -> Vec<&String> {
+        let full_mesh = self
+            .full_mesh_every
+            .is_some_and(|every| self.rounds.is_multiple_of(every));
+        let peers: Vec<&String> = if full_mesh {
+            self.node_ids.iter().collect()
+        } else {
+            self.neighborhood.iter().collect()
+        };
+        peers.into_iter().filter(|n| self.should_send(n)).collect()
+    }
+
+    /// The encoding `peer` gets diffs and snapshots in.
+    fn encoding_for<IP>(&self, peer: &str, ctx: &Context<IP>) -> WireEncoding {
+        ctx.agreed(peer)
+            .and_then(|agreed| agreed.encoding)
+            .and_then(|name| WireEncoding::from_name(&name))
+            .unwrap_or(self.encoding)
+    }
+
+    fn push_round<IP>(
+        &mut self,
+        ctx: &Context<IP>,
+    ) -> anyhow::Result<Vec<(String, GossipPayload)>> {
+        let mut outgoing = Vec::new();
+        let targets = self.targets().into_iter().cloned().collect::<Vec<_>>();
+        let txn = self.doc.transact();
+        let state_vector = txn.state_vector();
+
+        // Neighbors that are equally far behind get the same diff, so it is
+        // only encoded once per round and encoding.
+        let mut diffs: Vec<(&StateVector, WireEncoding, String)> = Vec::new();
+        for n in targets {
+            let remote_state_vector = self
+                .known
+                .get(&n)
+                .with_context(|| format!("unknown neighbor {n}"))?;
+
+            // Send the update 10% of the time, even if it's the same as the remote state
+            if remote_state_vector == &state_vector && !self.rng.gen_bool(0.1) {
+                continue;
+            }
+            let encoding = self.encoding_for(&n, ctx);
+            let diff = match diffs
+                .iter()
+                .find(|(sv, e, _)| *sv == remote_state_vector && *e == encoding)
+            {
+                Some((_, _, diff)) => diff.clone(),
+                None => {
+                    let diff = encoding.encode(&txn.encode_diff_v1(remote_state_vector));
+                    diffs.push((remote_state_vector, encoding, diff.clone()));
+                    diff
+                }
+            };
+            outgoing.push((
+                n,
+                GossipPayload::Push {
+                    diff,
+                    state_vector: encoding.encode(&state_vector.encode_v1()),
+                    encoding,
+                },
+            ));
+        }
+
+        Ok(outgoing)
+    }
+
+    fn digest_round(&self) -> Vec<(String, GossipPayload)> {
+        let state_vector = ENGINE.encode(self.doc.transact().state_vector().encode_v1());
+        self.targets()
+            .into_iter()
+            .map(|n| {
+                (
+                    n.clone(),
+                    GossipPayload::Digest {
+                        state_vector: state_vector.clone(),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    fn push_payload<T: ReadTxn>(
+        &self,
+        txn: &T,
+        remote: &StateVector,
+        local: &StateVector,
+        encoding: WireEncoding,
+    ) -> GossipPayload {
+        GossipPayload::Push {
+            diff: encoding.encode(&txn.encode_diff_v1(remote)),
+            state_vector: encoding.encode(&local.encode_v1()),
+            encoding,
+        }
+    }
+
+    fn send_all<IP>(
+        &mut self,
+        ctx: &Context<IP>,
+        outgoing: Vec<(String, GossipPayload)>,
+    ) -> anyhow::Result<()> {
+        let mut local = None;
+        for (n, payload) in outgoing {
+            let size = payload.wire_size();
+            let sent = self.bandwidth.round.get(&n).copied().unwrap_or(0);
+            if let Some(budget) = self.bandwidth.budget {
+                if sent > 0 && sent + size > budget {
+                    // The peer's known state vector is untouched, so the same
+                    // data gets picked up again next round.
+                    self.bandwidth.deferred += 1;
+                    continue;
+                }
+            }
+            *self.bandwidth.round.entry(n.clone()).or_default() += size;
+            *self.bandwidth.total.entry(n.clone()).or_default() += size as u64;
+            ctx.metrics().incr("gossip.bytes_sent", size as u64);
+            ctx.metrics().incr("gossip.messages_sent", 1);
+            ctx.metrics().incr(
+                "gossip.encoding_bytes_saved",
+                payload.base64_size().saturating_sub(size) as u64,
+            );
+
+            if let GossipPayload::Push {
+                diff, state_vector, ..
+            } = &payload
+            {
+                log::debug("sending diff")
+                    .field("doc", &self.name)
+                    .field("peer", &n)
+                    .field("state_vector_bytes", state_vector.len())
+                    .field("diff_bytes", diff.len())
+                    .emit();
+            }
+            let is_push = matches!(payload, GossipPayload::Push { .. });
+            if let Some(staleness) = &mut self.staleness {
+                if payload.expects_answer() {
+                    let now = self.clock.now();
+                    staleness.awaiting.entry(n.clone()).or_insert(now);
+                }
+            }
+            let gossip = AdminPayload::Gossip {
+                doc: self.name.clone(),
+                gossip: payload,
+            };
+            ctx.send(Admin::message(&self.node_id, &n, gossip)?)
+                .with_context(|| format!("sending Gossip to {}", n))?;
+            if is_push && self.optimistic.is_some() {
+                let local = local.get_or_insert_with(|| self.doc.transact().state_vector());
+                self.assume_applied(&n, local);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The file a doc called `name` is persisted to inside `dir`.
+pub fn persist_file(dir: &Path, node_id: &str, name: &str) -> PathBuf {
+    dir.join(format!("{node_id}-{name}.ydoc"))
+}
+
+fn decode_state_vector(state_vector: &str, encoding: WireEncoding) -> anyhow::Result<StateVector> {
+    StateVector::decode_v1(&encoding.decode(state_vector)?).context("StateVector decode failed")
+}
+
+/// How many operations `remote` has seen that `local` hasn't.
+fn missing_ops(local: &StateVector, remote: &StateVector) -> u64 {
+    remote
+        .iter()
+        .map(|(client, clock)| clock.saturating_sub(local.get(client)) as u64)
+        .sum()
+}
+
+/// Whether `a` has seen updates that `b` hasn't.
+fn is_ahead(a: &StateVector, b: &StateVector) -> bool {
+    a.iter().any(|(client, clock)| *clock > b.get(client))
+}
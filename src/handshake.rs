@@ -0,0 +1,198 @@
+//! Negotiation of optional protocol features between nodes. With
+//! [`Runtime::with_handshake`](crate::Runtime::with_handshake), a node greets
+//! every peer with the [`Capabilities`] it has once it's initialized, as an
+//! [`AdminPayload::Handshake`], and the runtime answers greetings itself, so
+//! both ends learn what the other supports on first contact. What they
+//! [agreed](Agreed) on is available from [`Context::agreed`].
+//!
+//! The library itself only acts on the wire encoding: gossip diffs and
+//! snapshots go out in the best one both ends decode. The protocol version
+//! and the features agreed on are for node code to act on, e.g. with
+//! [`Agreed::supports`].
+//!
+//! Until a peer answered, or if it doesn't take part in the handshake,
+//! nothing is agreed and senders stick to what every node understands.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{bail, Context as _};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    admin::{Admin, AdminPayload, ADMIN_TYPE},
+    log,
+    message::NodeId,
+    Context, Init, Message,
+};
+
+/// The version of the inter-node protocol this build speaks.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// What a node supports beyond the basics.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub version: u32,
+
+    /// Wire encodings the node decodes, most preferred first, e.g. `z85`.
+    #[serde(default)]
+    pub encodings: Vec<String>,
+
+    /// Optional features implemented by node code, which the runtime only
+    /// exchanges.
+    #[serde(default)]
+    pub features: BTreeSet<String>,
+}
+
+impl Default for Capabilities {
+    /// Everything this build supports.
+    fn default() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            #[cfg(feature = "crdt-yrs")]
+            encodings: vec!["z85".to_string(), "base64".to_string()],
+            #[cfg(not(feature = "crdt-yrs"))]
+            encodings: Vec::new(),
+            features: BTreeSet::new(),
+        }
+    }
+}
+
+impl Capabilities {
+    pub fn with_feature(mut self, feature: impl Into<String>) -> Self {
+        self.features.insert(feature.into());
+        self
+    }
+
+    /// The best of these that `theirs` supports too.
+    pub fn agree(&self, theirs: &Capabilities) -> Agreed {
+        Agreed {
+            version: self.version.min(theirs.version),
+            encoding: self
+                .encodings
+                .iter()
+                .find(|encoding| theirs.encodings.contains(encoding))
+                .cloned(),
+            features: self
+                .features
+                .intersection(&theirs.features)
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+/// What a node and one of its peers both support.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Agreed {
+    /// The older of their protocol versions.
+    pub version: u32,
+
+    /// The wire encoding to send in, the sender's favorite among those
+    /// the peer decodes.
+    pub encoding: Option<String>,
+    pub features: BTreeSet<String>,
+}
+
+impl Agreed {
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.contains(feature)
+    }
+}
+
+/// A greeting, answered with a `HelloOk` carrying the receiver's
+/// capabilities.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HandshakePayload {
+    Hello { capabilities: Capabilities },
+    HelloOk { capabilities: Capabilities },
+}
+
+/// Whether `payload` is an admin message of the handshake.
+pub(crate) fn is_handshake(payload: &Value) -> bool {
+    payload.get("type").and_then(Value::as_str) == Some(ADMIN_TYPE)
+        && payload
+            .get("admin")
+            .is_some_and(|admin| admin.get("handshake").is_some())
+}
+
+/// A node's capabilities and what it agreed on with each peer, shared by
+/// the clones of its context.
+#[derive(Debug, Clone)]
+pub(crate) struct Handshake {
+    ours: Arc<Capabilities>,
+    agreed: Arc<RwLock<HashMap<NodeId, Agreed>>>,
+}
+
+impl Handshake {
+    pub(crate) fn new(ours: Capabilities) -> Self {
+        Self {
+            ours: Arc::new(ours),
+            agreed: Arc::default(),
+        }
+    }
+
+    pub(crate) fn capabilities(&self) -> &Capabilities {
+        &self.ours
+    }
+
+    pub(crate) fn agreed(&self, peer: &str) -> Option<Agreed> {
+        self.agreed
+            .read()
+            .expect("handshake lock poisoned")
+            .get(peer)
+            .cloned()
+    }
+
+    /// Greet every peer of the node `init` names.
+    pub(crate) fn start<IP>(&self, init: &Init, ctx: &Context<IP>) -> anyhow::Result<()> {
+        for peer in init.node_ids.iter().filter(|id| **id != init.node_id) {
+            let hello = AdminPayload::Handshake(HandshakePayload::Hello {
+                capabilities: Capabilities::clone(&self.ours),
+            });
+            ctx.send(Admin::message(&init.node_id, peer, hello)?)
+                .with_context(|| format!("greeting {peer}"))?;
+        }
+
+        Ok(())
+    }
+
+    /// Take a peer's greeting, answering it, or its answer to ours.
+    pub(crate) fn receive<IP>(
+        &self,
+        msg: &Message<Value>,
+        ctx: &Context<IP>,
+    ) -> anyhow::Result<()> {
+        let admin = Admin::deserialize(&msg.body().payload).context("parsing handshake")?;
+        let theirs = match admin.admin {
+            AdminPayload::Handshake(HandshakePayload::Hello { capabilities }) => {
+                let ok = AdminPayload::Handshake(HandshakePayload::HelloOk {
+                    capabilities: Capabilities::clone(&self.ours),
+                });
+                ctx.send(Admin::message(msg.dst(), msg.src(), ok)?)
+                    .with_context(|| format!("answering greeting of {}", msg.src()))?;
+                capabilities
+            }
+            AdminPayload::Handshake(HandshakePayload::HelloOk { capabilities }) => capabilities,
+            other => bail!("not a handshake: {other:?}"),
+        };
+
+        let agreed = self.ours.agree(&theirs);
+        log::debug("agreed on capabilities")
+            .field("peer", msg.src())
+            .field("version", agreed.version)
+            .field("encoding", &agreed.encoding)
+            .emit();
+        ctx.metrics().incr("handshake.agreed", 1);
+        self.agreed
+            .write()
+            .expect("handshake lock poisoned")
+            .insert(NodeId::from(msg.src()), agreed);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,166 @@
+//! Periodic pings between peers on the admin namespace, measuring the
+//! round-trip time to every peer and when it was last heard from. Unlike
+//! the gossip layer's staleness, which only sees the peers a node gossips
+//! with, heartbeats reach every peer.
+//!
+//! Call [`Heartbeat::ping`] from a timer, and hand
+//! [`AdminPayload::Heartbeat`] messages to [`Heartbeat::receive`]; the peer
+//! it returns can be fed to a failure detector like
+//! `GossipDoc::heard_from`.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admin::{Admin, AdminPayload},
+    Context, Init,
+};
+
+/// How many rounds of pings are remembered, so a pong that arrives later
+/// than this is ignored.
+const MAX_IN_FLIGHT: usize = 16;
+
+/// Weight of the latest sample in the smoothed RTT, as in TCP's SRTT.
+const RTT_SMOOTHING: f64 = 0.125;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HeartbeatPayload {
+    Ping { seq: u64 },
+    Pong { seq: u64 },
+}
+
+/// What is known about one peer.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PeerLiveness {
+    /// Smoothed round-trip time, once a pong came back.
+    pub rtt: Option<Duration>,
+
+    /// The latest round-trip time.
+    pub last_rtt: Option<Duration>,
+
+    /// When anything heartbeat related was last heard from the peer.
+    pub last_seen: Option<Instant>,
+}
+
+pub struct Heartbeat {
+    node_id: String,
+    peers: BTreeMap<String, PeerLiveness>,
+
+    /// When each of the last rounds of pings went out, by sequence number.
+    sent: VecDeque<(u64, Instant)>,
+    next_seq: u64,
+}
+
+impl Heartbeat {
+    pub fn new(init: &Init) -> Self {
+        let peers = init
+            .node_ids
+            .iter()
+            .filter(|id| **id != init.node_id)
+            .map(|id| (id.clone(), PeerLiveness::default()))
+            .collect();
+        Self {
+            node_id: init.node_id.clone(),
+            peers,
+            sent: VecDeque::new(),
+            next_seq: 0,
+        }
+    }
+
+    /// Ping every peer.
+    pub fn ping<IP>(&mut self, ctx: &Context<IP>) -> anyhow::Result<()> {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.sent.push_back((seq, ctx.clock().now()));
+        if self.sent.len() > MAX_IN_FLIGHT {
+            self.sent.pop_front();
+        }
+        for peer in self.peers.keys() {
+            let ping = AdminPayload::Heartbeat(HeartbeatPayload::Ping { seq });
+            ctx.send(Admin::message(&self.node_id, peer, ping)?)
+                .with_context(|| format!("sending heartbeat to {peer}"))?;
+        }
+        ctx.metrics()
+            .incr("heartbeat.pings_sent", self.peers.len() as u64);
+
+        Ok(())
+    }
+
+    /// Answer a ping from `src`, or take the round-trip time of its pong.
+    /// Returns `src` if it is a peer, as it was just heard from.
+    pub fn receive<'a, IP>(
+        &mut self,
+        src: &'a str,
+        payload: &HeartbeatPayload,
+        ctx: &Context<IP>,
+    ) -> anyhow::Result<Option<&'a str>> {
+        let now = ctx.clock().now();
+        let Some(peer) = self.peers.get_mut(src) else {
+            return Ok(None);
+        };
+        peer.last_seen = Some(now);
+        match payload {
+            HeartbeatPayload::Ping { seq } => {
+                let pong = AdminPayload::Heartbeat(HeartbeatPayload::Pong { seq: *seq });
+                ctx.send(Admin::message(&self.node_id, src, pong)?)
+                    .with_context(|| format!("answering heartbeat from {src}"))?;
+            }
+            HeartbeatPayload::Pong { seq } => {
+                let Some((_, sent)) = self.sent.iter().find(|(s, _)| s == seq) else {
+                    return Ok(Some(src));
+                };
+                let sample = now.duration_since(*sent);
+                let rtt = match peer.rtt {
+                    Some(rtt) => rtt.mul_f64(1.0 - RTT_SMOOTHING) + sample.mul_f64(RTT_SMOOTHING),
+                    None => sample,
+                };
+                peer.rtt = Some(rtt);
+                peer.last_rtt = Some(sample);
+                ctx.metrics()
+                    .observe("heartbeat.rtt_ms", sample.as_secs_f64() * 1000.0);
+                ctx.metrics().gauge(
+                    &format!("heartbeat.rtt_us.{src}"),
+                    rtt.as_micros().try_into().unwrap_or(i64::MAX),
+                );
+            }
+        }
+
+        Ok(Some(src))
+    }
+
+    pub fn peer(&self, peer: &str) -> Option<&PeerLiveness> {
+        self.peers.get(peer)
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = (&str, &PeerLiveness)> {
+        self.peers
+            .iter()
+            .map(|(id, liveness)| (id.as_str(), liveness))
+    }
+
+    /// The smoothed round-trip time to `peer`, if a pong came back.
+    pub fn rtt(&self, peer: &str) -> Option<Duration> {
+        self.peers.get(peer)?.rtt
+    }
+
+    /// Peers not heard from for longer than `timeout`, including those never
+    /// heard from at all.
+    pub fn silent_for<IP>(&self, timeout: Duration, ctx: &Context<IP>) -> Vec<&str> {
+        let clock = ctx.clock();
+        self.peers
+            .iter()
+            .filter(|(_, liveness)| {
+                liveness
+                    .last_seen
+                    .is_none_or(|t| clock.elapsed(t) > timeout)
+            })
+            .map(|(id, _)| id.as_str())
+            .collect()
+    }
+}
@@ -0,0 +1,78 @@
+use serde::{Deserialize, Serialize};
+
+use crate::clock::{self, SharedClock};
+
+/// A hybrid logical clock reading: wall clock milliseconds plus a logical
+/// counter that orders events sharing the same millisecond.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct Timestamp {
+    pub wall: u64,
+    pub logical: u32,
+}
+
+/// Hybrid logical clock, producing timestamps that never go backwards and
+/// that always follow every timestamp observed from other nodes.
+#[derive(Debug)]
+pub struct HybridClock {
+    last: Timestamp,
+
+    /// Where the wall clock part comes from.
+    clock: SharedClock,
+}
+
+impl Default for HybridClock {
+    fn default() -> Self {
+        Self::with_clock(clock::system())
+    }
+}
+
+impl HybridClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take the wall clock part from `clock`, usually the context's.
+    pub fn with_clock(clock: SharedClock) -> Self {
+        Self {
+            last: Timestamp::default(),
+            clock,
+        }
+    }
+
+    /// The latest timestamp handed out or observed.
+    pub fn last(&self) -> Timestamp {
+        self.last
+    }
+
+    /// Timestamp a local event.
+    pub fn now(&mut self) -> Timestamp {
+        let wall = self.clock.unix_ms();
+        if wall > self.last.wall {
+            self.last = Timestamp { wall, logical: 0 };
+        } else {
+            self.last.logical += 1;
+        }
+        self.last
+    }
+
+    /// Account for a timestamp received from another node.
+    pub fn observe(&mut self, remote: Timestamp) {
+        let wall = self.clock.unix_ms();
+        let max_wall = wall.max(self.last.wall).max(remote.wall);
+        let logical = if max_wall == self.last.wall && max_wall == remote.wall {
+            self.last.logical.max(remote.logical) + 1
+        } else if max_wall == self.last.wall {
+            self.last.logical + 1
+        } else if max_wall == remote.wall {
+            remote.logical + 1
+        } else {
+            0
+        };
+        self.last = Timestamp {
+            wall: max_wall,
+            logical,
+        };
+    }
+}
@@ -0,0 +1,305 @@
+//! JSON-RPC 2.0 on the wire instead of Maelstrom's message format, so
+//! nodes can be driven by tooling that already speaks it. [`JsonRpc`] wraps
+//! any [`Transport`] and translates in both directions:
+//!
+//! - a body with a `msg_id` is a request, its `type` the method and the
+//!   rest of it the params: `{"jsonrpc":"2.0","method":"echo","params":{"echo":"hi"},"id":1}`
+//! - a body without one is a notification, a request without an `id`
+//! - a reply is a response, with the body as its `result`, `type`
+//!   included, or as its `error` for `error` bodies
+//!
+//! JSON-RPC has no addresses, so `src` and `dest` travel as extra members
+//! of the same names, which JSON-RPC peers ignore. Messages without them
+//! come from [`DEFAULT_CLIENT_ID`] and go to the node named by the `init`
+//! request, which starts every run like any other:
+//!
+//! ```ignore
+//! Runtime::node::<EchoNode>().run_on(JsonRpc::new(Stdio::new()))
+//! ```
+//!
+//! Batches are taken apart into their requests, whose responses are sent
+//! one by one rather than as a batch.
+
+use std::collections::{HashMap, VecDeque};
+
+use anyhow::{anyhow, bail, Context as _};
+use serde_json::{json, Map, Value};
+
+use crate::{error::ErrorCode, transport::Transport};
+
+pub const JSONRPC_VERSION: &str = "2.0";
+
+/// Sender of the messages that name none, i.e. the JSON-RPC peer.
+pub const DEFAULT_CLIENT_ID: &str = "c-jsonrpc";
+
+/// Error codes defined by JSON-RPC 2.0.
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+
+/// Where the msg_ids standing in for ids that aren't numbers start, far
+/// above the ones the peer picks itself.
+const FOREIGN_ID_BASE: usize = usize::MAX / 2;
+
+/// The JSON-RPC code for a Maelstrom error code. Codes with a JSON-RPC
+/// counterpart get that one, the others are kept as they are, as JSON-RPC
+/// leaves the positive ones to applications.
+pub fn to_jsonrpc_code(code: ErrorCode) -> i64 {
+    match code {
+        ErrorCode::NotSupported => METHOD_NOT_FOUND,
+        ErrorCode::MalformedRequest => INVALID_PARAMS,
+        code => code.code().try_into().unwrap_or(i64::MAX),
+    }
+}
+
+/// The Maelstrom error code for a JSON-RPC one, the inverse of
+/// [`to_jsonrpc_code`]. Other negative codes are server errors after which
+/// the request's effect is unknown, so they are taken for a crash.
+pub fn from_jsonrpc_code(code: i64) -> ErrorCode {
+    match code {
+        METHOD_NOT_FOUND => ErrorCode::NotSupported,
+        PARSE_ERROR | INVALID_REQUEST | INVALID_PARAMS => ErrorCode::MalformedRequest,
+        code => u64::try_from(code).map_or(ErrorCode::Crash, ErrorCode::from),
+    }
+}
+
+/// A transport speaking JSON-RPC 2.0, see the [module docs](self).
+pub struct JsonRpc<T> {
+    inner: T,
+    client_id: String,
+
+    /// The node's id, once the `init` request named it.
+    node_id: Option<String>,
+
+    /// Messages of a batch not yet handed to the node.
+    pending: VecDeque<String>,
+
+    /// Request ids that aren't msg_ids, like strings, by the msg_ids
+    /// standing in for them until they are answered.
+    foreign_ids: HashMap<usize, Value>,
+    next_foreign_id: usize,
+}
+
+impl<T: Transport> JsonRpc<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            client_id: DEFAULT_CLIENT_ID.to_string(),
+            node_id: None,
+            pending: VecDeque::new(),
+            foreign_ids: HashMap::new(),
+            next_foreign_id: FOREIGN_ID_BASE,
+        }
+    }
+
+    /// Take messages without a `src` to come from `client_id` instead of
+    /// [`DEFAULT_CLIENT_ID`].
+    pub fn with_client_id(mut self, client_id: impl Into<String>) -> Self {
+        self.client_id = client_id.into();
+        self
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// The Maelstrom message for a JSON-RPC request, notification or
+    /// response.
+    pub fn from_jsonrpc(&mut self, rpc: Value) -> anyhow::Result<Value> {
+        let Value::Object(mut rpc) = rpc else {
+            bail!("expected an object, got {rpc}");
+        };
+        if rpc.remove("jsonrpc").as_ref().and_then(Value::as_str) != Some(JSONRPC_VERSION) {
+            bail!("expected jsonrpc {JSONRPC_VERSION}");
+        }
+        let src = match rpc.remove("src") {
+            Some(Value::String(src)) => src,
+            _ => self.client_id.clone(),
+        };
+        let dest = rpc.remove("dest");
+        let id = rpc.remove("id");
+
+        let mut body = Map::new();
+        if let Some(method) = rpc.remove("method") {
+            let Value::String(method) = method else {
+                bail!("method must be a string, got {method}");
+            };
+            match rpc.remove("params") {
+                Some(Value::Object(params)) => body = params,
+                Some(params) => {
+                    body.insert("params".to_string(), params);
+                }
+                None => {}
+            }
+            if method == "init" {
+                self.node_id = body
+                    .get("node_id")
+                    .and_then(Value::as_str)
+                    .map(str::to_string);
+            }
+            body.insert("type".to_string(), method.into());
+            if let Some(id) = id {
+                body.insert("msg_id".to_string(), self.msg_id(id)?.into());
+            }
+        } else {
+            let in_reply_to = id
+                .as_ref()
+                .and_then(Value::as_u64)
+                .context("response id must be the msg_id of a request")?;
+            if let Some(result) = rpc.remove("result") {
+                body = match result {
+                    Value::Object(result) if result.contains_key("type") => result,
+                    result => {
+                        let mut body = Map::new();
+                        body.insert("type".to_string(), "result".into());
+                        body.insert("result".to_string(), result);
+                        body
+                    }
+                };
+            } else if let Some(error) = rpc.remove("error") {
+                let code = error["code"].as_i64().context("error without a code")?;
+                body.insert("type".to_string(), "error".into());
+                body.insert("code".to_string(), json!(from_jsonrpc_code(code)));
+                body.insert("text".to_string(), error["message"].clone());
+            } else {
+                bail!("expected a method, result or error");
+            }
+            body.insert("in_reply_to".to_string(), in_reply_to.into());
+        }
+
+        let dest = match dest {
+            Some(Value::String(dest)) => dest,
+            _ => self.node_id.clone().unwrap_or_default(),
+        };
+        Ok(json!({ "src": src, "dest": dest, "body": body }))
+    }
+
+    /// The JSON-RPC request, notification or response for a Maelstrom
+    /// message.
+    pub fn to_jsonrpc(&mut self, msg: Value) -> anyhow::Result<Value> {
+        let Value::Object(mut msg) = msg else {
+            bail!("expected a message, got {msg}");
+        };
+        let Some(Value::Object(mut body)) = msg.remove("body") else {
+            bail!("message without a body");
+        };
+        let Some(Value::String(message_type)) = body.remove("type") else {
+            bail!("message body without a type");
+        };
+        let msg_id = body.remove("msg_id").filter(|id| !id.is_null());
+        let in_reply_to = body.remove("in_reply_to").and_then(|id| id.as_u64());
+
+        let mut rpc = Map::new();
+        rpc.insert("jsonrpc".to_string(), JSONRPC_VERSION.into());
+        for address in ["src", "dest"] {
+            if let Some(value) = msg.remove(address) {
+                rpc.insert(address.to_string(), value);
+            }
+        }
+        match in_reply_to {
+            Some(in_reply_to) => {
+                let id = usize::try_from(in_reply_to)
+                    .ok()
+                    .and_then(|id| self.foreign_ids.remove(&id))
+                    .unwrap_or_else(|| in_reply_to.into());
+                rpc.insert("id".to_string(), id);
+                if message_type == "error" {
+                    let code = body
+                        .get("code")
+                        .and_then(Value::as_u64)
+                        .map_or(INTERNAL_ERROR, |code| to_jsonrpc_code(code.into()));
+                    let message = body.remove("text").unwrap_or_else(|| "".into());
+                    rpc.insert(
+                        "error".to_string(),
+                        json!({ "code": code, "message": message, "data": body }),
+                    );
+                } else {
+                    body.insert("type".to_string(), message_type.into());
+                    rpc.insert("result".to_string(), body.into());
+                }
+            }
+            None => {
+                rpc.insert("method".to_string(), message_type.into());
+                rpc.insert("params".to_string(), body.into());
+                if let Some(msg_id) = msg_id {
+                    rpc.insert("id".to_string(), msg_id);
+                }
+            }
+        }
+        Ok(rpc.into())
+    }
+
+    /// The msg_id for the request id `id`, standing in for it if it isn't
+    /// one itself.
+    fn msg_id(&mut self, id: Value) -> anyhow::Result<usize> {
+        if let Some(id) = id.as_u64().and_then(|id| usize::try_from(id).ok()) {
+            return Ok(id);
+        }
+        if !matches!(id, Value::String(_) | Value::Number(_)) {
+            bail!("id must be a string or number, got {id}");
+        }
+        let msg_id = self.next_foreign_id;
+        self.next_foreign_id += 1;
+        self.foreign_ids.insert(msg_id, id);
+        Ok(msg_id)
+    }
+
+    /// Queue what `line` holds for the node, answering the peer directly
+    /// where it isn't valid JSON-RPC.
+    fn receive_line(&mut self, line: &str) -> anyhow::Result<()> {
+        let rpc: Value = match serde_json::from_str(line) {
+            Ok(rpc) => rpc,
+            Err(err) => return self.send_error(Value::Null, PARSE_ERROR, &err.to_string()),
+        };
+        let batch = match rpc {
+            Value::Array(batch) if batch.is_empty() => {
+                return self.send_error(Value::Null, INVALID_REQUEST, "empty batch");
+            }
+            Value::Array(batch) => batch,
+            rpc => vec![rpc],
+        };
+        for rpc in batch {
+            let id = rpc.get("id").cloned().unwrap_or(Value::Null);
+            match self.from_jsonrpc(rpc) {
+                Ok(msg) => self.pending.push_back(msg.to_string()),
+                Err(err) => self.send_error(id, INVALID_REQUEST, &format!("{err:#}"))?,
+            }
+        }
+        Ok(())
+    }
+
+    fn send_error(&mut self, id: Value, code: i64, message: &str) -> anyhow::Result<()> {
+        let error = json!({
+            "jsonrpc": JSONRPC_VERSION,
+            "id": id,
+            "error": { "code": code, "message": message },
+        });
+        self.inner.send(&error.to_string())
+    }
+}
+
+impl<T: Transport> Transport for JsonRpc<T> {
+    fn recv(&mut self) -> anyhow::Result<Option<String>> {
+        loop {
+            if let Some(msg) = self.pending.pop_front() {
+                return Ok(Some(msg));
+            }
+            let Some(line) = self.inner.recv()? else {
+                return Ok(None);
+            };
+            self.receive_line(&line)?;
+        }
+    }
+
+    fn send(&mut self, line: &str) -> anyhow::Result<()> {
+        let msg = serde_json::from_str(line).map_err(|err| anyhow!("{err}: {line}"))?;
+        let rpc = self.to_jsonrpc(msg)?;
+        self.inner.send(&rpc.to_string())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.inner.flush()
+    }
+}
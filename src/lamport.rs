@@ -0,0 +1,164 @@
+//! Lamport clocks, and the total order they give events across a cluster.
+//! Every message a node sends carries its [`LamportClock`]'s time in the
+//! body's `lamport` field, and every node receiving one moves its own clock
+//! past it, so an event that could have caused another has a smaller time.
+//!
+//! Ties are broken by node id: a [`Stamp`] orders by `(time, node_id)`, the
+//! same way on every node, which is what conflict resolution like last
+//! writer wins needs. [`TotalOrder`] goes further, for total order
+//! broadcast: it holds messages back until no peer can still send one that
+//! orders before them, and hands them out in stamp order:
+//!
+//! ```ignore
+//! // Broadcasting an op:
+//! let stamp = self.clock.tick();
+//! self.order.insert(stamp.clone(), op.clone());
+//! for peer in &self.peers {
+//!     ctx.send(self.op_message(peer, op.clone())?.with_lamport(stamp.time))?;
+//! }
+//! // Receiving one:
+//! if let Some(stamp) = self.clock.receive(&msg) {
+//!     self.order.insert(stamp, msg.body().payload.clone());
+//! }
+//! for (_, op) in self.order.ready() {
+//!     self.apply(op);
+//! }
+//! ```
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Init, Message};
+
+/// A Lamport time and the node it is of, ordered by time, then by node id.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Stamp {
+    pub time: u64,
+    pub node_id: String,
+}
+
+impl Stamp {
+    pub fn new(time: u64, node_id: impl Into<String>) -> Self {
+        Self {
+            time,
+            node_id: node_id.into(),
+        }
+    }
+}
+
+/// A node's logical clock, see the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct LamportClock {
+    node_id: String,
+    time: u64,
+}
+
+impl LamportClock {
+    pub fn new(init: &Init) -> Self {
+        Self {
+            node_id: init.node_id.clone(),
+            time: 0,
+        }
+    }
+
+    /// The time of the latest event, zero before the first one.
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    /// Move the clock on for a local event, like a send, and stamp it.
+    pub fn tick(&mut self) -> Stamp {
+        self.time += 1;
+        Stamp::new(self.time, &self.node_id)
+    }
+
+    /// Move the clock past `time`, received from another node.
+    pub fn observe(&mut self, time: u64) {
+        self.time = self.time.max(time) + 1;
+    }
+
+    /// `msg` stamped with the time of its sending.
+    pub fn stamp<P>(&mut self, msg: Message<P>) -> Message<P> {
+        let stamp = self.tick();
+        msg.with_lamport(stamp.time)
+    }
+
+    /// Take the time `msg` carries, if it has one, returning the stamp of
+    /// its sending.
+    pub fn receive<P>(&mut self, msg: &Message<P>) -> Option<Stamp> {
+        let time = msg.body().lamport?;
+        if msg.src() != self.node_id {
+            self.observe(time);
+        }
+        Some(Stamp::new(time, msg.src()))
+    }
+}
+
+/// Items held back until they can be handed out in the total order of their
+/// stamps, see the [module docs](self).
+///
+/// An item is ready once every peer was heard from at a later stamp, as a
+/// peer's messages are taken to arrive in the order it sent them. Peers
+/// with nothing to send need to send something stamped every now and then
+/// anyway, or nothing gets ready.
+#[derive(Debug)]
+pub struct TotalOrder<T> {
+    /// The latest time each peer was heard from at.
+    latest: BTreeMap<String, u64>,
+    pending: BTreeMap<Stamp, T>,
+}
+
+impl<T> TotalOrder<T> {
+    pub fn new(init: &Init) -> Self {
+        Self {
+            latest: init
+                .node_ids
+                .iter()
+                .filter(|id| **id != init.node_id)
+                .map(|id| (id.clone(), 0))
+                .collect(),
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Items held back.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Note that the node of `stamp` got as far as it, e.g. from a message
+    /// that carries nothing to order.
+    pub fn heard(&mut self, stamp: &Stamp) {
+        if let Some(latest) = self.latest.get_mut(&stamp.node_id) {
+            *latest = (*latest).max(stamp.time);
+        }
+    }
+
+    /// Hold `item` back until it's its turn. Stamps of this node's own items
+    /// must come from a clock that received every stamp inserted before.
+    pub fn insert(&mut self, stamp: Stamp, item: T) {
+        self.heard(&stamp);
+        self.pending.insert(stamp, item);
+    }
+
+    /// The items whose turn came, in order.
+    pub fn ready(&mut self) -> Vec<(Stamp, T)> {
+        let mut ready = Vec::new();
+        while let Some(entry) = self.pending.first_entry() {
+            let stamp = entry.key();
+            let passed = self.latest.iter().all(|(peer, time)| {
+                *peer == stamp.node_id || (*time, peer.as_str()) > (stamp.time, &stamp.node_id)
+            });
+            if !passed {
+                break;
+            }
+            ready.push(entry.remove_entry());
+        }
+        ready
+    }
+}
@@ -0,0 +1,187 @@
+//! How long requests wait for their reply, by payload type, so a run that
+//! blows Maelstrom's latency budget shows which operation is to blame. The
+//! event loop notes when each request comes in, the context when a reply
+//! to it is built, and the histograms are logged when the run ends:
+//!
+//! ```text
+//! n1 info: latency count=1032 max_ms=1.534 p50_ms=0.031 p95_ms=0.088 p99_ms=0.207 type="send"
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde_json::Value;
+
+use crate::{
+    clock::{self, SharedClock},
+    log, Message, NodeId,
+};
+
+/// How many requests may wait for a reply at once before new ones are no
+/// longer timed, so requests that are never answered can't pile up.
+const MAX_PENDING: usize = 1 << 16;
+
+/// Each power of two is split into `2^SUB_BUCKET_BITS` buckets, keeping
+/// percentiles within about 12% of the real value.
+const SUB_BUCKET_BITS: u32 = 3;
+
+/// Durations in microseconds, in log-linear buckets.
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    buckets: Vec<u64>,
+    count: u64,
+    max: u64,
+}
+
+impl Histogram {
+    pub fn record(&mut self, duration: Duration) {
+        let us = duration.as_micros().try_into().unwrap_or(u64::MAX);
+        let bucket = bucket_of(us);
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.max = self.max.max(us);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max)
+    }
+
+    /// The duration `quantile` (from 0 to 1) of the recorded ones are at
+    /// most, give or take the bucket width.
+    pub fn percentile(&self, quantile: f64) -> Duration {
+        let rank = ((quantile * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_micros(bucket_upper(bucket).min(self.max));
+            }
+        }
+        self.max()
+    }
+}
+
+fn bucket_of(us: u64) -> usize {
+    if us < 1 << SUB_BUCKET_BITS {
+        return us as usize;
+    }
+    let shift = us.ilog2() - SUB_BUCKET_BITS;
+    let sub = (us >> shift) as usize & ((1 << SUB_BUCKET_BITS) - 1);
+    ((shift as usize + 1) << SUB_BUCKET_BITS) + sub
+}
+
+/// The largest value falling into `bucket`.
+fn bucket_upper(bucket: usize) -> u64 {
+    if bucket < 1 << SUB_BUCKET_BITS {
+        return bucket as u64;
+    }
+    let shift = (bucket >> SUB_BUCKET_BITS) - 1;
+    let sub = (bucket & ((1 << SUB_BUCKET_BITS) - 1)) as u64;
+    let lower = ((1 << SUB_BUCKET_BITS) + sub) << shift;
+    lower + (1 << shift) - 1
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Requests waiting for a reply, with their payload type and when they
+    /// came in.
+    pending: HashMap<(NodeId, usize), (String, Instant)>,
+    histograms: BTreeMap<String, Histogram>,
+}
+
+/// Request latencies shared by every clone of a [`crate::Context`].
+#[derive(Debug, Clone)]
+pub struct Latency {
+    inner: Arc<Mutex<Inner>>,
+    clock: SharedClock,
+}
+
+impl Default for Latency {
+    fn default() -> Self {
+        Self::with_clock(clock::system())
+    }
+}
+
+impl Latency {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Time requests by `clock` instead of the OS clock.
+    pub fn with_clock(clock: SharedClock) -> Self {
+        Self {
+            inner: Arc::default(),
+            clock,
+        }
+    }
+
+    /// Start timing `msg`, if it's a request.
+    pub(crate) fn received(&self, msg: &Message<Value>) {
+        let body = msg.body();
+        let (Some(id), None) = (body.id, body.in_reply_to) else {
+            return;
+        };
+        let kind = body
+            .payload
+            .get("type")
+            .and_then(Value::as_str)
+            .unwrap_or("unknown");
+        let mut inner = self.inner.lock().expect("latency lock poisoned");
+        if inner.pending.len() < MAX_PENDING {
+            inner.pending.insert(
+                (NodeId::from(msg.src()), id),
+                (kind.to_string(), self.clock.now()),
+            );
+        }
+    }
+
+    /// Stop timing the request `id` from `to`, which is being answered.
+    pub(crate) fn replied(&self, to: &str, id: Option<usize>) {
+        let Some(id) = id else {
+            return;
+        };
+        let mut inner = self.inner.lock().expect("latency lock poisoned");
+        let Some((kind, received)) = inner.pending.remove(&(NodeId::from(to), id)) else {
+            return;
+        };
+        inner
+            .histograms
+            .entry(kind)
+            .or_default()
+            .record(self.clock.elapsed(received));
+    }
+
+    /// A copy of every histogram, by payload type.
+    pub fn snapshot(&self) -> BTreeMap<String, Histogram> {
+        self.inner
+            .lock()
+            .expect("latency lock poisoned")
+            .histograms
+            .clone()
+    }
+
+    /// Log a line per payload type that got any replies.
+    pub fn report(&self) {
+        for (kind, histogram) in self.snapshot() {
+            let ms = |d: Duration| (d.as_secs_f64() * 1000.0 * 1000.0).round() / 1000.0;
+            log::info("latency")
+                .field("type", &kind)
+                .field("count", histogram.count())
+                .field("p50_ms", ms(histogram.percentile(0.50)))
+                .field("p95_ms", ms(histogram.percentile(0.95)))
+                .field("p99_ms", ms(histogram.percentile(0.99)))
+                .field("max_ms", ms(histogram.max()))
+                .emit();
+        }
+    }
+}
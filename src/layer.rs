@@ -0,0 +1,147 @@
+//! Cross-cutting behavior wrapped around any node. A [`Layer`] sees every
+//! event before the node it wraps, and may keep it from the node, so
+//! features like [`Dedup`] are written once and stacked onto workloads
+//! instead of being copied into each of them:
+//!
+//! ```ignore
+//! Runtime::node::<Layered<Dedup, KafkaNode>>().run()
+//! ```
+
+use std::collections::{HashSet, VecDeque};
+
+use crate::{Context, Event, Init, Node, NodeId, SnapshotState};
+
+/// Behavior wrapped around a node by [`Layered`].
+pub trait Layer<P, IP>: Sized {
+    fn from_init(init: &Init, context: &Context<IP>) -> anyhow::Result<Self>;
+
+    /// Look at `input` before the node does, returning the event to hand
+    /// to it, if any.
+    fn before(
+        &mut self,
+        input: Event<P, IP>,
+        _context: &Context<IP>,
+    ) -> anyhow::Result<Option<Event<P, IP>>> {
+        Ok(Some(input))
+    }
+
+    /// Called after the node handled an event `before` passed on.
+    fn after(&mut self, _context: &Context<IP>) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// The node `N`, with the layer `L` around it. Layers stack, the outermost
+/// seeing events first: `Layered<A, Layered<B, N>>`.
+pub struct Layered<L, N> {
+    layer: L,
+    inner: N,
+}
+
+impl<L, N> Layered<L, N> {
+    pub fn layer(&self) -> &L {
+        &self.layer
+    }
+
+    pub fn inner(&self) -> &N {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut N {
+        &mut self.inner
+    }
+}
+
+impl<S, P, IP, L, N> Node<S, P, IP> for Layered<L, N>
+where
+    L: Layer<P, IP>,
+    N: Node<S, P, IP>,
+{
+    fn from_init(state: S, init: &Init, context: Context<IP>) -> anyhow::Result<Self> {
+        Ok(Self {
+            layer: L::from_init(init, &context)?,
+            inner: N::from_init(state, init, context)?,
+        })
+    }
+
+    fn step(&mut self, input: Event<P, IP>, context: Context<IP>) -> anyhow::Result<()> {
+        let Some(input) = self.layer.before(input, &context)? else {
+            return Ok(());
+        };
+        self.inner.step(input, context.clone())?;
+        self.layer.after(&context)
+    }
+
+    fn handle_reply(&mut self, input: Event<P, IP>, context: Context<IP>) -> anyhow::Result<()> {
+        let Some(input) = self.layer.before(input, &context)? else {
+            return Ok(());
+        };
+        self.inner.handle_reply(input, context.clone())?;
+        self.layer.after(&context)
+    }
+}
+
+impl<L, N> SnapshotState for Layered<L, N>
+where
+    N: SnapshotState,
+{
+    type State = N::State;
+
+    fn snapshot(&self) -> N::State {
+        self.inner.snapshot()
+    }
+}
+
+/// How many messages [`Dedup`] remembers.
+const DEDUP_WINDOW: usize = 4096;
+
+/// Drops messages the node already got, going by their sender and
+/// `msg_id`, e.g. retries of a request that was merely slow. Only the last
+/// few thousand messages are remembered.
+#[derive(Debug, Default)]
+pub struct Dedup {
+    seen: HashSet<(NodeId, usize)>,
+
+    /// `seen`, oldest first, to forget them in order.
+    order: VecDeque<(NodeId, usize)>,
+}
+
+impl Dedup {
+    /// Whether the message `id` from `src` is new, remembering it if so.
+    fn first_time(&mut self, src: &str, id: usize) -> bool {
+        let key = (NodeId::from(src), id);
+        if !self.seen.insert(key.clone()) {
+            return false;
+        }
+        self.order.push_back(key);
+        if self.order.len() > DEDUP_WINDOW {
+            let oldest = self.order.pop_front().expect("window is not empty");
+            self.seen.remove(&oldest);
+        }
+        true
+    }
+}
+
+impl<P, IP> Layer<P, IP> for Dedup {
+    fn from_init(_init: &Init, _context: &Context<IP>) -> anyhow::Result<Self> {
+        Ok(Self::default())
+    }
+
+    fn before(
+        &mut self,
+        input: Event<P, IP>,
+        context: &Context<IP>,
+    ) -> anyhow::Result<Option<Event<P, IP>>> {
+        let Event::Message(msg) = &input else {
+            return Ok(Some(input));
+        };
+        let Some(id) = msg.body().id else {
+            return Ok(Some(input));
+        };
+        if self.first_time(msg.src(), id) {
+            return Ok(Some(input));
+        }
+        context.metrics().incr("dedup.dropped", 1);
+        Ok(None)
+    }
+}
@@ -1,29 +1,158 @@
 use std::{
-    io::{BufRead, Write},
-    sync::{
-        atomic::AtomicUsize,
-        mpsc::{Receiver, Sender},
-        Arc,
-    },
+    collections::HashMap,
+    fs,
+    io::{BufRead, BufReader, Write},
+    marker::PhantomData,
+    path::Path,
+    sync::{atomic::AtomicUsize, Arc},
     thread,
 };
 
 use anyhow::Context as _;
+use crossbeam_channel::{select, Receiver, RecvTimeoutError, Sender};
 use erased_serde::Serialize;
 use serde::{de::DeserializeOwned, Deserialize};
 
-pub use message::{Body, Context, Event, Init, Message};
-use message::{InitPayload, ToEvent};
+use chaos::Chaos;
+use log::LogFormat;
+pub use message::{Body, Context, Event, Init, Message, NodeId, ServiceId};
+use message::{InitPayload, ReplyGuard, ToEvent};
+use output::{FlushPolicy, Output};
+pub use vorticity_derive::{handlers, payload, Payload};
 
+/// The crate's error type, which `Context`, the message builder and node
+/// code all share, so there is only ever one kind of error to propagate.
+pub use anyhow::{Error, Result};
+
+pub mod admin;
+pub mod barrier;
+pub mod causal;
+pub mod chaos;
+pub mod clock;
+pub mod compat;
+pub mod compose;
+pub mod config;
+#[cfg(feature = "crdt-yrs")]
+pub mod crdt;
+pub mod dyn_node;
+pub mod error;
+#[cfg(any(feature = "arbitrary", feature = "proptest"))]
+mod generators;
+#[cfg(feature = "crdt-yrs")]
+pub mod gossip;
+pub mod handshake;
+pub mod heartbeat;
+pub mod hlc;
+pub mod jsonrpc;
+pub mod lamport;
+pub mod latency;
+pub mod layer;
+pub mod log;
+pub mod loopback;
+pub mod mailbox;
 pub mod message;
-// pub mod rpc;
+pub mod metrics;
+pub mod output;
+pub mod peer_queue;
+pub mod prelude;
+pub mod reply_cache;
+pub mod rpc;
+pub mod session;
+pub mod sim;
+pub mod storage;
+pub mod supervisor;
+pub mod testing;
+pub mod timer;
+pub mod transport;
 
+/// Something answering messages besides the node, like a mock of one of
+/// Maelstrom's services. Handlers are owned by whoever routes to them, so
+/// `step` gets `&mut self` and state lives in plain fields, without cells.
+///
+/// A node hands the runtime its handlers with [`Context::set_handlers`];
+/// [`TestContext::route`](testing::TestContext::route) routes what a node
+/// sent to a handler in tests.
 pub trait Handler<IP> {
+    /// Whether `json`, a whole message, is meant for this handler.
     fn can_handle(&self, json: &serde_json::Value) -> bool;
+
+    /// Handle a message `can_handle` accepted.
     fn step(&mut self, json: serde_json::Value, ctx: Context<IP>) -> anyhow::Result<()>;
 }
 
+/// Several handlers behind one. A message is parsed once and routed by its
+/// body's `type` to the handler registered for it, and only otherwise
+/// offered to the remaining handlers in turn.
+pub struct Handlers<IP> {
+    by_type: HashMap<String, Box<dyn Handler<IP> + Send>>,
+    others: Vec<Box<dyn Handler<IP> + Send>>,
+}
+
+impl<IP> Default for Handlers<IP> {
+    fn default() -> Self {
+        Self {
+            by_type: HashMap::new(),
+            others: Vec::new(),
+        }
+    }
+}
+
+impl<IP> Handlers<IP> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hand every message of type `message_type` to `handler`, without
+    /// asking its `can_handle`.
+    pub fn with_type(
+        mut self,
+        message_type: impl Into<String>,
+        handler: impl Handler<IP> + Send + 'static,
+    ) -> Self {
+        self.by_type.insert(message_type.into(), Box::new(handler));
+        self
+    }
+
+    /// Offer messages of no registered type to `handler`, after the
+    /// handlers added before it.
+    pub fn with(mut self, handler: impl Handler<IP> + Send + 'static) -> Self {
+        self.others.push(Box::new(handler));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_type.is_empty() && self.others.is_empty()
+    }
+
+    fn by_type<'a>(&self, json: &'a serde_json::Value) -> Option<&'a str> {
+        json["body"]["type"]
+            .as_str()
+            .filter(|message_type| self.by_type.contains_key(*message_type))
+    }
+}
+
+impl<IP> Handler<IP> for Handlers<IP> {
+    fn can_handle(&self, json: &serde_json::Value) -> bool {
+        self.by_type(json).is_some() || self.others.iter().any(|h| h.can_handle(json))
+    }
+
+    fn step(&mut self, json: serde_json::Value, ctx: Context<IP>) -> anyhow::Result<()> {
+        if let Some(message_type) = self.by_type(&json) {
+            let handler = self.by_type.get_mut(message_type).expect("just looked up");
+            return handler.step(json, ctx);
+        }
+        match self.others.iter_mut().find(|h| h.can_handle(&json)) {
+            Some(handler) => handler.step(json, ctx),
+            None => anyhow::bail!("no handler for {json}"),
+        }
+    }
+}
+
 pub trait Node<S, Payload, InjectedPayload = ()> {
+    /// The only constructor of a node, called once the runtime got the init
+    /// message, with everything the node may need to start: its initial
+    /// `state`, the cluster from `init`, and the `context` to spawn timers
+    /// or send from.
     fn from_init(state: S, init: &Init, context: Context<InjectedPayload>) -> anyhow::Result<Self>
     where
         Self: Sized;
@@ -43,75 +172,536 @@ pub trait Node<S, Payload, InjectedPayload = ()> {
     }
 }
 
-pub struct Runtime;
+/// A node that does nothing but answer requests, like echo. Every request
+/// gets exactly one reply, and everything else the runtime hands the node is
+/// ignored.
+///
+/// The initial state is an associated type rather than a parameter, so that
+/// wrappers like [`layer::Layered`] can be nodes without overlapping the
+/// blanket [`Node`] impl of request handlers.
+pub trait RequestHandler: Sized {
+    type State;
+    type Request;
+    type Response;
+
+    fn from_init(state: Self::State, init: &Init, context: Context<()>) -> anyhow::Result<Self>;
+
+    fn handle(
+        &mut self,
+        request: Self::Request,
+        ctx: &Context<()>,
+    ) -> anyhow::Result<Self::Response>;
+}
+
+impl<S, N> Node<S, N::Request> for N
+where
+    N: RequestHandler<State = S>,
+    N::Response: serde::Serialize + Send + Sync + 'static,
+{
+    fn from_init(state: S, init: &Init, context: Context<()>) -> anyhow::Result<Self> {
+        <N as RequestHandler>::from_init(state, init, context)
+    }
+
+    fn step(&mut self, input: Event<N::Request>, ctx: Context<()>) -> anyhow::Result<()> {
+        let Event::Message(request) = input else {
+            return Ok(());
+        };
+        let reply = ctx.try_reply_with(request, |request| self.handle(request, &ctx))?;
+        ctx.send(reply).context("send reply to request")
+    }
+}
+
+/// A serializable view of a node's internals, so tests and
+/// [`sim::replay::Replay`] can assert on and diff node state without poking
+/// at CRDT handles.
+pub trait SnapshotState {
+    type State: serde::Serialize;
+
+    fn snapshot(&self) -> Self::State;
+}
+
+/// How many messages may queue up between stdin, the node and stdout before
+/// the faster side waits for the slower one.
+pub(crate) const CHANNEL_CAPACITY: usize = 1024;
+
+/// Environment variable holding the seed of every random decision, so a run
+/// can be reproduced. A random seed is picked and logged when it's unset.
+pub const SEED_ENV: &str = "VORTICITY_SEED";
+
+/// Environment variable naming a directory where nodes keep what must
+/// survive a restart, like gossiped docs and their msg_id epoch.
+pub const STATE_DIR_ENV: &str = "VORTICITY_STATE_DIR";
+
+/// Each start of a node gets `2^MSG_ID_EPOCH_BITS` msg_ids of its own.
+const MSG_ID_EPOCH_BITS: u32 = 32;
+
+#[derive(Debug, Clone, Default)]
+pub struct Runtime {
+    seed: Option<u64>,
+
+    /// Misbehave when sending to other nodes, see [`chaos`].
+    chaos: Option<Chaos>,
+    flush: Option<FlushPolicy>,
+    eager_serialization: bool,
+
+    /// Threads serializing outgoing messages, none meaning the output thread
+    /// serializes them itself.
+    serialization_workers: usize,
+
+    reply_guard: Option<ReplyGuard>,
+
+    /// How many replies to remember for duplicate requests, if any.
+    reply_cache: Option<usize>,
+    log_format: Option<LogFormat>,
+
+    /// Where nodes get the time from, the OS clock if unset.
+    clock: Option<clock::SharedClock>,
+
+    /// What nodes advertise to their peers, if they take part in the
+    /// handshake.
+    handshake: Option<handshake::Capabilities>,
+}
 
 impl Runtime {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Seed every random decision, overriding [`SEED_ENV`].
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Randomly drop, duplicate and delay messages sent to other nodes,
+    /// overriding [`chaos::CHAOS_ENV`].
+    pub fn with_chaos(mut self, chaos: Chaos) -> Self {
+        self.chaos = Some(chaos);
+        self
+    }
+
+    /// Buffer stdout according to `policy`, overriding [`output::FLUSH_ENV`].
+    pub fn with_flush_policy(mut self, policy: FlushPolicy) -> Self {
+        self.flush = Some(policy);
+        self
+    }
+
+    /// Serialize messages on the node's thread as they are sent, see
+    /// [`Context::with_eager_serialization`].
+    pub fn with_eager_serialization(mut self) -> Self {
+        self.eager_serialization = true;
+        self
+    }
+
+    /// Serialize outgoing messages on `workers` threads in parallel, for
+    /// nodes whose output is dominated by large messages like gossip diffs.
+    /// Messages are still written in the order they were sent.
+    pub fn with_serialization_workers(mut self, workers: usize) -> Self {
+        self.serialization_workers = workers;
+        self
+    }
+
+    /// Catch client requests that a step left without a reply, see
+    /// [`Context::with_reply_guard`].
+    pub fn with_reply_guard(mut self, guard: ReplyGuard) -> Self {
+        self.reply_guard = Some(guard);
+        self
+    }
+
+    /// Answer duplicates of the last `capacity` requests with the reply sent
+    /// to the original, instead of stepping the node through them again,
+    /// see [`reply_cache`].
+    pub fn with_reply_cache(mut self, capacity: usize) -> Self {
+        self.reply_cache = Some(capacity);
+        self
+    }
+
+    /// Write logs in `format`, overriding [`log::LOG_FORMAT_ENV`].
+    pub fn with_log_format(mut self, format: LogFormat) -> Self {
+        self.log_format = Some(format);
+        self
+    }
+
+    /// Give nodes `clock` instead of the OS clock, e.g. a
+    /// [`clock::ManualClock`] to step time by hand.
+    pub fn with_clock(mut self, clock: impl clock::Clock + 'static) -> Self {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Greet every peer with `capabilities` once the node is initialized, and
+    /// agree with each on what to use, see [`handshake`].
+    pub fn with_handshake(mut self, capabilities: handshake::Capabilities) -> Self {
+        self.handshake = Some(capabilities);
+        self
+    }
+
+    /// Run a node of type `N`, with its payload types inferred from its
+    /// [`Node`] impl: `Runtime::node::<KafkaNode>().run()`.
+    pub fn node<N>() -> NodeRunner<N, ()> {
+        NodeRunner {
+            runtime: Runtime::new(),
+            state: Box::new(|_| Ok(())),
+            node: PhantomData,
+        }
+    }
+
+    /// Run a node with the default configuration.
     pub fn run<S, P, IP, N>(init_state: S) -> anyhow::Result<()>
     where
         P: DeserializeOwned + Send + 'static,
         N: Node<S, P, IP>,
-        IP: Clone + Send + 'static,
+        IP: Send + 'static,
     {
-        let (msg_in_tx, msg_in_rx): (Sender<ToEvent<IP>>, Receiver<ToEvent<IP>>) =
-            std::sync::mpsc::channel();
+        Self::new().start::<S, P, IP, N>(init_state)
+    }
 
-        let (msg_out_tx, msg_out_rx) = std::sync::mpsc::channel();
+    pub fn start<S, P, IP, N>(self, init_state: S) -> anyhow::Result<()>
+    where
+        P: DeserializeOwned + Send + 'static,
+        N: Node<S, P, IP>,
+        IP: Send + 'static,
+    {
+        self.start_with::<S, P, IP, N>(|_| Ok(init_state))
+    }
 
-        let context = Context::new(
-            msg_in_tx.clone(),
-            msg_out_tx.clone(),
-            Arc::new(AtomicUsize::new(0)),
-        );
+    /// Run a node whose initial state is a config of type `C`, loaded once
+    /// the init message is in, see [`config::load`].
+    pub fn start_configured<C, P, IP, N>(self) -> anyhow::Result<()>
+    where
+        C: DeserializeOwned,
+        P: DeserializeOwned + Send + 'static,
+        N: Node<C, P, IP>,
+        IP: Send + 'static,
+    {
+        self.start_with::<C, P, IP, N>(config::load::<C>)
+    }
 
-        let node: N = Self::init_node(init_state, context.clone())?;
-        let node = node;
+    fn start_with<S, P, IP, N>(
+        self,
+        init_state: impl FnOnce(&Init) -> anyhow::Result<S>,
+    ) -> anyhow::Result<()>
+    where
+        P: DeserializeOwned + Send + 'static,
+        N: Node<S, P, IP>,
+        IP: Send + 'static,
+    {
+        // Without threads, the node is stepped on the only one there is.
+        if cfg!(target_family = "wasm") {
+            return transport::run::<S, P, IP, N>(self, transport::Stdio::new(), init_state);
+        }
+        let stdin = BufReader::new(std::io::stdin());
+        self.start_io::<S, P, IP, N, _>(stdin, std::io::stdout(), init_state)
+            .map(drop)
+    }
 
-        let stdin_tx = msg_in_tx.clone();
-        let input_handle = receive_loop::<IP>(stdin_tx, msg_in_tx);
+    /// Run a node with the default configuration, reading from `reader`
+    /// and writing to `writer` instead of stdin and stdout, e.g. to embed it
+    /// in another program or test it end to end. Returns the writer once
+    /// the end of input was handled.
+    pub fn run_with_io<S, P, IP, N, W>(
+        reader: impl BufRead + Send + 'static,
+        writer: W,
+        init_state: S,
+    ) -> anyhow::Result<W>
+    where
+        P: DeserializeOwned + Send + 'static,
+        N: Node<S, P, IP>,
+        IP: Send + 'static,
+        W: Write + Send + 'static,
+    {
+        Self::new().start_with_io::<S, P, IP, N, W>(reader, writer, init_state)
+    }
 
-        let output_handle = send_loop(msg_out_rx);
+    /// Like [`Runtime::run_with_io`], with this configuration.
+    pub fn start_with_io<S, P, IP, N, W>(
+        self,
+        reader: impl BufRead + Send + 'static,
+        writer: W,
+        init_state: S,
+    ) -> anyhow::Result<W>
+    where
+        P: DeserializeOwned + Send + 'static,
+        N: Node<S, P, IP>,
+        IP: Send + 'static,
+        W: Write + Send + 'static,
+    {
+        self.start_io::<S, P, IP, N, W>(reader, writer, |_| Ok(init_state))
+    }
+
+    fn start_io<S, P, IP, N, W>(
+        self,
+        mut reader: impl BufRead + Send + 'static,
+        writer: W,
+        init_state: impl FnOnce(&Init) -> anyhow::Result<S>,
+    ) -> anyhow::Result<W>
+    where
+        P: DeserializeOwned + Send + 'static,
+        N: Node<S, P, IP>,
+        IP: Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let (stdin_tx, stdin_rx) = crossbeam_channel::bounded(CHANNEL_CAPACITY);
+        // The node injects into its own event loop, so this one mustn't block.
+        let (msg_in_tx, msg_in_rx) = crossbeam_channel::unbounded();
+        let (msg_out_tx, msg_out_rx) = crossbeam_channel::bounded(CHANNEL_CAPACITY);
+
+        let init_msg = read_init(&mut reader)?;
+        let InitPayload::Init(ref init) = init_msg.body().payload else {
+            panic!("first message should be init")
+        };
+        let context = self.context_for(init, msg_in_tx, msg_out_tx)?;
+
+        let init_state = init_state(init)?;
+        let node: N = Self::init_node(init_state, &init_msg, context.clone())?;
+
+        let input_handle = receive_loop::<IP>(reader, stdin_tx);
+
+        let chaos = match self.chaos {
+            Some(chaos) => Some(chaos),
+            None => Chaos::from_env()?,
+        };
+        let flush = match self.flush {
+            Some(flush) => flush,
+            None => FlushPolicy::from_env()?.unwrap_or_default(),
+        };
+        let (msg_out_rx, pool_handle) = match self.serialization_workers {
+            0 => (msg_out_rx, None),
+            workers => {
+                let (msg_out_rx, handle) = output::serialize_pool(workers, msg_out_rx);
+                (msg_out_rx, Some(handle))
+            }
+        };
+        let output_handle = match chaos {
+            Some(chaos) => {
+                log::info(format!("running in chaos mode: {chaos:?}")).emit();
+                let seed = context.seed_for("chaos");
+                chaos.send_loop(seed, init.node_ids.clone(), flush, msg_out_rx, writer)
+            }
+            None => send_loop(flush, msg_out_rx, writer),
+        };
 
-        event_loop(msg_in_rx, node, context)?;
+        event_loop(stdin_rx, msg_in_rx, node, context)?;
 
         input_handle
             .join()
             .expect("failed to join input thread")
             .context("error from stdin thread")?;
-        output_handle
+        let writer = output_handle
             .join()
             .expect("failed to join output thread")
             .context("error from stdout thread")?;
+        if let Some(pool_handle) = pool_handle {
+            pool_handle
+                .join()
+                .expect("failed to join serialization threads")
+                .context("error from serialization threads")?;
+        }
 
-        Ok(())
+        Ok(writer)
     }
 
-    fn init_node<S, P, IP, N>(init_state: S, context: Context<IP>) -> Result<N, anyhow::Error>
+    /// Set up logging for the node `init` names, and the context it runs
+    /// with.
+    pub(crate) fn context_for<IP>(
+        &self,
+        init: &Init,
+        msg_in_tx: Sender<ToEvent<IP>>,
+        msg_out_tx: Sender<Box<dyn Serialize + Send + Sync>>,
+    ) -> anyhow::Result<Context<IP>>
+    where
+        IP: Send + 'static,
+    {
+        let seed = self.seed()?;
+        let log_format = match self.log_format {
+            Some(format) => format,
+            None => LogFormat::from_env()?.unwrap_or_default(),
+        };
+        log::init(log_format, &init.node_id);
+        log::info(format!("running with {SEED_ENV}={seed}")).emit();
+        let msg_id = Arc::new(AtomicUsize::new(first_msg_id(&init.node_id)?));
+        let mut context = Context::new(msg_in_tx, msg_out_tx, msg_id)
+            .with_seed(message::mix_seed(seed, &init.node_id))
+            .with_init(init)
+            .with_eager_serialization(self.eager_serialization);
+        if let Some(guard) = self.reply_guard {
+            context = context.with_reply_guard(guard);
+        }
+        if let Some(capacity) = self.reply_cache {
+            context = context.with_reply_cache(reply_cache::ReplyCache::new(capacity));
+        }
+        if let Some(clock) = &self.clock {
+            context = context.with_clock(clock.clone());
+        }
+        if let Some(capabilities) = &self.handshake {
+            context = context.with_handshake(capabilities.clone());
+        }
+        Ok(context)
+    }
+
+    fn seed(&self) -> anyhow::Result<u64> {
+        if let Some(seed) = self.seed {
+            return Ok(seed);
+        }
+        match std::env::var(SEED_ENV) {
+            Ok(seed) => seed
+                .parse()
+                .with_context(|| format!("{SEED_ENV} is not a number: {seed}")),
+            Err(_) => Ok(rand::random()),
+        }
+    }
+
+    pub(crate) fn init_node<S, P, IP, N>(
+        init_state: S,
+        init_msg: &Message<InitPayload>,
+        context: Context<IP>,
+    ) -> Result<N, anyhow::Error>
     where
         P: DeserializeOwned + Send + 'static,
         N: Node<S, P, IP>,
-        IP: Clone + Send + 'static,
+        IP: Send + 'static,
     {
-        let stdin = std::io::stdin().lock();
-        let mut stdin = stdin.lines();
-        let init_msg: Message<InitPayload> = serde_json::from_str::<Message<InitPayload>>(
-            &stdin
-                .next()
-                .expect("no init message received")
-                .context("failed to read init message from stdin")?,
-        )
-        .context("read init message from STDIN")?;
         let InitPayload::Init(ref init) = init_msg.body().payload else {
             panic!("first message should be init")
         };
         let node = N::from_init(init_state, init, context.clone())
             .context("node initialization failed")?;
-        let reply = context.construct_reply(&init_msg, InitPayload::InitOk);
+        let reply = context.construct_reply(init_msg, InitPayload::InitOk);
 
         context.send(reply).context("send init reply to stdout")?;
+        if let Some(handshake) = context.handshake() {
+            handshake.start(init, &context)?;
+        }
         Ok(node)
     }
 }
 
+/// Builds a node's initial state once its init message is in.
+type InitState<S> = Box<dyn FnOnce(&Init) -> anyhow::Result<S>>;
+
+/// A node type about to be run, see [`Runtime::node`].
+pub struct NodeRunner<N, S> {
+    runtime: Runtime,
+    state: InitState<S>,
+    node: PhantomData<fn() -> N>,
+}
+
+impl<N, S> NodeRunner<N, S> {
+    /// The initial state handed to the node's `from_init`, `()` by default.
+    pub fn with_state<T: 'static>(self, state: T) -> NodeRunner<N, T> {
+        NodeRunner {
+            runtime: self.runtime,
+            state: Box::new(|_| Ok(state)),
+            node: PhantomData,
+        }
+    }
+
+    /// Hand the node a config of type `C` as its initial state, loaded from
+    /// the environment, the command line and the init message, see
+    /// [`config::load`].
+    pub fn with_config<C: DeserializeOwned + 'static>(self) -> NodeRunner<N, C> {
+        NodeRunner {
+            runtime: self.runtime,
+            state: Box::new(config::load::<C>),
+            node: PhantomData,
+        }
+    }
+
+    /// Run with a configured runtime instead of the default one.
+    pub fn with_runtime(mut self, runtime: Runtime) -> Self {
+        self.runtime = runtime;
+        self
+    }
+
+    pub fn run<P, IP>(self) -> anyhow::Result<()>
+    where
+        P: DeserializeOwned + Send + 'static,
+        N: Node<S, P, IP>,
+        IP: Send + 'static,
+    {
+        self.runtime.start_with::<S, P, IP, N>(self.state)
+    }
+
+    /// Run reading from `reader` and writing to `writer` instead of stdin
+    /// and stdout, see [`Runtime::run_with_io`].
+    pub fn run_with_io<P, IP, W>(
+        self,
+        reader: impl BufRead + Send + 'static,
+        writer: W,
+    ) -> anyhow::Result<W>
+    where
+        P: DeserializeOwned + Send + 'static,
+        N: Node<S, P, IP>,
+        IP: Send + 'static,
+        W: Write + Send + 'static,
+    {
+        self.runtime
+            .start_io::<S, P, IP, N, W>(reader, writer, self.state)
+    }
+
+    /// Run on the current thread, reading and writing through `transport`
+    /// instead of stdin and stdout, see [`transport`].
+    pub fn run_on<P, IP>(self, transport: impl transport::Transport) -> anyhow::Result<()>
+    where
+        P: DeserializeOwned + Send + 'static,
+        N: Node<S, P, IP>,
+        IP: Send + 'static,
+    {
+        transport::run::<S, P, IP, N>(self.runtime, transport, self.state)
+    }
+}
+
+/// Where this start of the node begins numbering its messages. Without
+/// [`STATE_DIR_ENV`] that is 0, so a restarted node would reuse msg_ids,
+/// confusing replies to its earlier requests with ones to its new requests,
+/// and repeating ids built from them. With it, every start bumps an epoch
+/// kept there and numbers from the epoch's own range.
+fn first_msg_id(node_id: &str) -> anyhow::Result<usize> {
+    let Some(dir) = std::env::var_os(STATE_DIR_ENV) else {
+        return Ok(0);
+    };
+    let path = Path::new(&dir).join(format!("{node_id}.epoch"));
+    let epoch: usize = match fs::read_to_string(&path) {
+        Ok(epoch) => epoch
+            .trim()
+            .parse()
+            .with_context(|| format!("parsing msg_id epoch {}", path.display()))?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(err) => {
+            return Err(err).with_context(|| format!("reading msg_id epoch {}", path.display()))
+        }
+    };
+
+    // The next epoch must be on disk before any id of this one goes out.
+    fs::create_dir_all(&dir)
+        .with_context(|| format!("creating state dir {}", Path::new(&dir).display()))?;
+    let tmp = path.with_extension("epoch.tmp");
+    let mut file = fs::File::create(&tmp)
+        .with_context(|| format!("writing msg_id epoch {}", tmp.display()))?;
+    write!(file, "{}", epoch + 1)
+        .and_then(|()| file.sync_all())
+        .with_context(|| format!("writing msg_id epoch {}", tmp.display()))?;
+    fs::rename(&tmp, &path)
+        .with_context(|| format!("replacing msg_id epoch {}", path.display()))?;
+
+    1usize
+        .checked_shl(MSG_ID_EPOCH_BITS)
+        .and_then(|range| epoch.checked_mul(range))
+        .with_context(|| format!("msg_id epoch {epoch} out of range"))
+}
+
+fn read_init(reader: &mut impl BufRead) -> anyhow::Result<Message<InitPayload>> {
+    let mut line = String::new();
+    let read = reader
+        .read_line(&mut line)
+        .context("failed to read init message from stdin")?;
+    if read == 0 {
+        anyhow::bail!("no init message received");
+    }
+    message::parse_line::<Message<InitPayload>>(line.trim_end_matches(['\r', '\n']))
+        .context("read init message from STDIN")
+}
+
 #[allow(dead_code)]
 fn rpc_loop<P>(
     _rpc_in_rx: Receiver<Message<P>>,
@@ -129,68 +719,159 @@ where
 }
 
 fn receive_loop<IP>(
+    reader: impl BufRead + Send + 'static,
     stdin_tx: Sender<ToEvent<IP>>,
-    msg_in_tx: Sender<ToEvent<IP>>,
 ) -> thread::JoinHandle<Result<(), anyhow::Error>>
 where
-    IP: Clone + Send + 'static,
+    IP: Send + 'static,
 {
     thread::spawn(move || {
-        let stdin = std::io::stdin().lock();
-        for line in stdin.lines() {
+        for line in reader.lines() {
             let line = line.context("Maestrom input from STDIN could not be deserialized")?;
-            let input: Message<serde_json::Value> =
-                serde_json::from_str(&line).context("read input message from STDIN")?;
-            if stdin_tx.send(ToEvent::Message(input)).is_err() {
+            let input = ToEvent::from_line(&line).context("read input message from STDIN")?;
+            if stdin_tx.send(input).is_err() {
                 break;
             }
         }
-        let _ = msg_in_tx.send(ToEvent::Eof);
+        let _ = stdin_tx.send(ToEvent::Eof);
 
         Ok::<_, anyhow::Error>(())
     })
 }
 
-fn send_loop(
+fn send_loop<W>(
+    flush: FlushPolicy,
     msg_out_rx: Receiver<Box<dyn Serialize + Send + Sync>>,
-) -> thread::JoinHandle<Result<(), anyhow::Error>> {
+    writer: W,
+) -> thread::JoinHandle<Result<W, anyhow::Error>>
+where
+    W: Write + Send + 'static,
+{
     thread::spawn(move || {
-        let mut stdout = std::io::stdout().lock();
-        for send_msg in msg_out_rx {
-            serde_json::to_writer(&mut stdout, &send_msg).context("serialize response to init")?;
-            stdout.write_all(b"\n").context("write newline to output")?;
+        let mut output = Output::new(writer, flush);
+        loop {
+            let received = match output.linger() {
+                Some(linger) => msg_out_rx.recv_timeout(linger),
+                None => msg_out_rx
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match received {
+                Ok(send_msg) => output.write_json(&send_msg)?,
+                Err(RecvTimeoutError::Timeout) => output.flush()?,
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
         }
-        Ok::<_, anyhow::Error>(())
+        output.into_inner()
     })
 }
 
+/// Step `node` through events read from `stdin` and those `injected` by the
+/// node itself, until the end of input.
 fn event_loop<N, S, P, IP>(
-    msg_in_rx: Receiver<ToEvent<IP>>,
+    stdin: Receiver<ToEvent<IP>>,
+    injected: Receiver<ToEvent<IP>>,
     mut node: N,
     context: Context<IP>,
 ) -> Result<(), anyhow::Error>
 where
     N: Node<S, P, IP>,
     P: for<'de> Deserialize<'de> + Send + 'static,
-    IP: Clone + Send + 'static,
+    IP: Send + 'static,
 {
-    for input in msg_in_rx {
-        if let Ok(input) = input.to_event() {
-            if input.is_reply() {
-                // TODO: Figure out how to get original Message from our RPC system
-                node.handle_reply(input, context.clone())
-                    .context("Node handle reply function failed")?;
-                continue;
-            }
-            node.step(input, context.clone())
-                .context("Node step function failed")?;
-        } else {
-            let ToEvent::Message(message) = input else {
-                panic!("Impossible position");
-            };
-            todo!("Handle message: {:?}", message);
+    loop {
+        let input = select! {
+            recv(stdin) -> input => input,
+            recv(injected) -> input => input,
+        };
+        // Without an end of input, e.g. when stdin failed, there is nothing
+        // more to step.
+        let Ok(input) = input else {
+            break;
+        };
+        // Nothing can arrive after the end of input, so stop once the node
+        // has seen it rather than waiting on timers that hold a sender.
+        if step_input::<N, S, P, IP>(&mut node, &context, input)? {
+            break;
         }
     }
+    context.timers().stop();
+    context.latency().report();
 
     Ok(())
 }
+
+/// Hand one input to `node`, returning whether it was the end of input.
+fn step_input<N, S, P, IP>(
+    node: &mut N,
+    context: &Context<IP>,
+    input: ToEvent<IP>,
+) -> anyhow::Result<bool>
+where
+    N: Node<S, P, IP>,
+    P: for<'de> Deserialize<'de> + Send + 'static,
+    IP: Send + 'static,
+{
+    context.expire_calls()?;
+    if let ToEvent::Message(msg) = &input {
+        context.metrics().incr("runtime.messages_received", 1);
+        if admin::is_stats_request(&msg.body().payload) {
+            let reply = context.construct_reply(msg, admin::AdminStats::of(context));
+            context.send(reply).context("send admin stats")?;
+            return Ok(false);
+        }
+        // Nodes not taking part ignore greetings, so peers don't agree on
+        // anything with them.
+        if handshake::is_handshake(&msg.body().payload) {
+            if let Some(handshake) = context.handshake() {
+                handshake.receive(msg, context)?;
+            }
+            return Ok(false);
+        }
+        if let Some(on_reply) = context.calls().take(msg) {
+            on_reply(Ok(msg.clone()), context.clone()).context("Call callback failed")?;
+            return Ok(false);
+        }
+        match context.reply_cache().map(|cache| cache.begin(msg)) {
+            Some(reply_cache::Seen::Replied(reply)) => {
+                context.metrics().incr("reply_cache.resent", 1);
+                context.send(reply).context("resend cached reply")?;
+                return Ok(false);
+            }
+            Some(reply_cache::Seen::Pending) => {
+                context.metrics().incr("reply_cache.dropped", 1);
+                return Ok(false);
+            }
+            Some(reply_cache::Seen::New) | None => {}
+        }
+        context.latency().received(msg);
+    }
+    if let ToEvent::InjectedUntil(_, deadline) = &input {
+        if context.clock().now() > *deadline {
+            context.metrics().incr("runtime.injected_expired", 1);
+            return Ok(false);
+        }
+    }
+    let input: Event<P, IP> = input
+        .into_event()
+        .context("Could not parse incoming event")?;
+    let eof = matches!(input, Event::Eof);
+    if let Event::Arbitrary(msg) = &input {
+        if context.offer_handlers(msg)? {
+            return Ok(false);
+        }
+    }
+    if input.is_reply() {
+        node.handle_reply(input, context.clone())
+            .context("Node handle reply function failed")?;
+        return Ok(false);
+    }
+    if let Event::Message(msg) = &input {
+        context.expect_reply(msg);
+    }
+    node.step(input, context.clone())
+        .context("Node step function failed")?;
+    context.check_replied()?;
+
+    Ok(eof)
+}
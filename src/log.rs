@@ -0,0 +1,171 @@
+//! Diagnostics on stderr, where Maelstrom keeps them per node. Besides
+//! plain text, logs can be written as JSON lines, so those of a 25-node run
+//! can be merged and queried rather than grepped:
+//!
+//! ```text
+//! {"timestamp_ms":1718000000000,"node_id":"n3","level":"warn","msg_id":12,"message":"...","fields":{}}
+//! ```
+
+use std::{
+    fmt::Write as _,
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::bail;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// Environment variable picking the log format without code changes:
+/// `text` or `json`.
+pub const LOG_FORMAT_ENV: &str = "VORTICITY_LOG_FORMAT";
+
+/// How log records are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// `n3 warn: message key=value`, for reading by eye.
+    #[default]
+    Text,
+
+    /// One JSON object per line.
+    Json,
+}
+
+impl LogFormat {
+    /// Parse [`LOG_FORMAT_ENV`], if it is set.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let Ok(format) = std::env::var(LOG_FORMAT_ENV) else {
+            return Ok(None);
+        };
+        match format.as_str() {
+            "text" => Ok(Some(LogFormat::Text)),
+            "json" => Ok(Some(LogFormat::Json)),
+            _ => bail!("{LOG_FORMAT_ENV}: expected text or json, got {format}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+}
+
+struct Logger {
+    format: LogFormat,
+    node_id: String,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Log as `node_id` in `format` from now on. Only the first call has an
+/// effect, so a process running several nodes, like a simulation, keeps
+/// the first one's id; records logged before any call go out as text.
+pub fn init(format: LogFormat, node_id: &str) {
+    let _ = LOGGER.set(Logger {
+        format,
+        node_id: node_id.to_string(),
+    });
+}
+
+/// A record to fill in and [`Record::emit`].
+#[must_use = "a record is only written by emit"]
+pub struct Record {
+    level: Level,
+    message: String,
+    msg_id: Option<usize>,
+    fields: Map<String, Value>,
+}
+
+pub fn record(level: Level, message: impl Into<String>) -> Record {
+    Record {
+        level,
+        message: message.into(),
+        msg_id: None,
+        fields: Map::new(),
+    }
+}
+
+pub fn debug(message: impl Into<String>) -> Record {
+    record(Level::Debug, message)
+}
+
+pub fn info(message: impl Into<String>) -> Record {
+    record(Level::Info, message)
+}
+
+pub fn warn(message: impl Into<String>) -> Record {
+    record(Level::Warn, message)
+}
+
+pub fn error(message: impl Into<String>) -> Record {
+    record(Level::Error, message)
+}
+
+impl Record {
+    /// The message the record is about.
+    pub fn msg_id(mut self, msg_id: usize) -> Self {
+        self.msg_id = Some(msg_id);
+        self
+    }
+
+    /// Attach `value` as `name`, or its `Debug` form in the unlikely case it
+    /// doesn't serialize.
+    pub fn field(mut self, name: &str, value: impl Serialize + std::fmt::Debug) -> Self {
+        let value = serde_json::to_value(&value).unwrap_or_else(|_| format!("{value:?}").into());
+        self.fields.insert(name.to_string(), value);
+        self
+    }
+
+    pub fn emit(self) {
+        let (format, node_id) = match LOGGER.get() {
+            Some(logger) => (logger.format, logger.node_id.as_str()),
+            None => (LogFormat::Text, "-"),
+        };
+        let line = match format {
+            LogFormat::Text => self.text(node_id),
+            LogFormat::Json => self.json(node_id),
+        };
+        eprintln!("{line}");
+    }
+
+    fn text(&self, node_id: &str) -> String {
+        let mut line = format!("{node_id} {}: {}", self.level.as_str(), self.message);
+        if let Some(msg_id) = self.msg_id {
+            let _ = write!(line, " msg_id={msg_id}");
+        }
+        for (name, value) in &self.fields {
+            let _ = write!(line, " {name}={value}");
+        }
+        line
+    }
+
+    fn json(self, node_id: &str) -> String {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_millis() as u64);
+        serde_json::json!({
+            "timestamp_ms": timestamp_ms,
+            "node_id": node_id,
+            "level": self.level,
+            "msg_id": self.msg_id,
+            "message": self.message,
+            "fields": self.fields,
+        })
+        .to_string()
+    }
+}
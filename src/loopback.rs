@@ -0,0 +1,127 @@
+//! An in-process transport for a single node: JSON lines go through channels
+//! instead of stdin/stdout, but parsing, the event loop and serialization are
+//! the ones [`Runtime`] uses. Meant for benchmarks of the runtime itself.
+
+use std::{
+    sync::{atomic::AtomicUsize, Arc},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    event_loop,
+    message::{self, InitPayload, ToEvent},
+    Context, Init, Message, Node, Runtime, CHANNEL_CAPACITY,
+};
+
+/// Source of the init message, standing in for the Maelstrom controller.
+const CONTROLLER_ID: &str = "loopback";
+
+/// How long `start` waits for the node to acknowledge its init.
+const INIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A node running on its own thread, fed and drained through channels.
+pub struct Loopback<IP> {
+    input: Sender<ToEvent<IP>>,
+    output: Receiver<String>,
+    node: Option<JoinHandle<anyhow::Result<()>>>,
+}
+
+impl<IP> Loopback<IP>
+where
+    IP: Send + 'static,
+{
+    /// Initialize a node as `init.node_id` and start its event loop, waiting
+    /// for the `init_ok` so the first `recv` sees the node's own output.
+    pub fn start<S, P, N>(state: S, init: Init, seed: u64) -> anyhow::Result<Self>
+    where
+        S: Send + 'static,
+        P: DeserializeOwned + Send + 'static,
+        N: Node<S, P, IP>,
+    {
+        let (input, input_rx) = crossbeam_channel::bounded(CHANNEL_CAPACITY);
+        let (msg_in_tx, msg_in_rx) = crossbeam_channel::unbounded();
+        let (msg_out_tx, msg_out_rx) = crossbeam_channel::bounded(CHANNEL_CAPACITY);
+        let (line_tx, output) = crossbeam_channel::unbounded();
+
+        thread::spawn(move || {
+            for msg in msg_out_rx {
+                let line = serde_json::to_string(&msg).context("serialize outgoing message")?;
+                if line_tx.send(line).is_err() {
+                    break;
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        });
+
+        let context = Context::new(msg_in_tx, msg_out_tx, Arc::new(AtomicUsize::new(0)))
+            .with_seed(message::mix_seed(seed, &init.node_id))
+            .with_init(&init);
+        let init_msg = Message::builder()
+            .src(CONTROLLER_ID)
+            .dst(init.node_id.clone())
+            .with_id(0)
+            .payload(InitPayload::Init(init))
+            .build()?;
+        let node = thread::spawn(move || {
+            let node: N = Runtime::init_node(state, &init_msg, context.clone())?;
+            event_loop(input_rx, msg_in_rx, node, context)
+        });
+
+        let mut loopback = Self {
+            input,
+            output,
+            node: Some(node),
+        };
+        if loopback.recv(INIT_TIMEOUT)?.is_none() {
+            loopback.shutdown()?;
+            bail!("node did not reply to init");
+        }
+
+        Ok(loopback)
+    }
+
+    /// Hand a line of JSON to the node, as if it came in on stdin.
+    pub fn send(&self, line: &str) -> anyhow::Result<()> {
+        let input = ToEvent::from_line(line).context("read input message from loopback")?;
+        self.input
+            .send(input)
+            .map_err(|_| anyhow!("node event loop has stopped"))
+    }
+
+    /// The next line the node wrote, or `None` if it wrote nothing within
+    /// `timeout`.
+    pub fn recv(&mut self, timeout: Duration) -> anyhow::Result<Option<String>> {
+        match self.output.recv_timeout(timeout) {
+            Ok(line) => Ok(Some(line)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => {
+                self.shutdown()?;
+                bail!("node stopped writing output")
+            }
+        }
+    }
+
+    /// Signal end of input and wait for the event loop to finish, returning
+    /// its error if it failed.
+    pub fn shutdown(&mut self) -> anyhow::Result<()> {
+        let Some(node) = self.node.take() else {
+            return Ok(());
+        };
+        let _ = self.input.send(ToEvent::Eof);
+        node.join().expect("failed to join node thread")
+    }
+}
+
+impl<IP> Drop for Loopback<IP> {
+    fn drop(&mut self) {
+        if let Some(node) = self.node.take() {
+            let _ = self.input.send(ToEvent::Eof);
+            let _ = node.join();
+        }
+    }
+}
@@ -0,0 +1,173 @@
+//! Per-key actors, so work on independent keys runs in parallel while work
+//! on one key stays in order. Every key gets an [`Actor`] of its own,
+//! created on its first message, and keys are spread over a fixed set of
+//! worker threads, each working through its mailbox first in, first out.
+//! Messages for one key always land on the same worker, so they are
+//! handled in the order they were sent, one at a time:
+//!
+//! ```ignore
+//! let logs = Mailboxes::new(4, move |key: &String| LogActor::open(key, &ctx));
+//! logs.send(key, LogOp::Append { msg, request })?;
+//! ```
+//!
+//! Actors answer on their own, e.g. through a clone of the node's
+//! [`Context`](crate::Context), or through a channel passed along in the
+//! message. With no workers, actors run on the caller's thread as the
+//! messages are sent, which keeps [simulations](crate::sim) deterministic.
+//!
+//! An actor is created on the thread that runs it and never leaves it, so
+//! it needn't be `Send`, e.g. to own a yrs doc. Only messages cross
+//! threads.
+
+use std::{
+    cell::RefCell,
+    collections::{hash_map::Entry, HashMap},
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use anyhow::{anyhow, Context as _};
+use crossbeam_channel::Sender;
+
+/// State owned by one key, handling the messages sent to that key.
+pub trait Actor: 'static {
+    type Msg: Send + 'static;
+
+    fn handle(&mut self, msg: Self::Msg) -> anyhow::Result<()>;
+}
+
+/// Creates the actor of a key on its first message.
+type Spawn<K, A> = Arc<dyn Fn(&K) -> anyhow::Result<A> + Send + Sync>;
+
+/// The first error an actor returned, after which its worker stops.
+type Failure = Arc<Mutex<Option<String>>>;
+
+enum Mode<K, A: Actor> {
+    Inline(RefCell<HashMap<K, A>>),
+    Workers {
+        mailboxes: Vec<Sender<(K, A::Msg)>>,
+        handles: Vec<thread::JoinHandle<()>>,
+        failure: Failure,
+    },
+}
+
+/// Actors of type `A` by key, see the [module docs](self).
+pub struct Mailboxes<K, A: Actor> {
+    spawn: Spawn<K, A>,
+    mode: Mode<K, A>,
+}
+
+impl<K, A> Mailboxes<K, A>
+where
+    K: Hash + Eq + Clone + Send + 'static,
+    A: Actor,
+{
+    /// Run actors made by `spawn` on `workers` threads, or inline if that
+    /// is zero.
+    pub fn new(
+        workers: usize,
+        spawn: impl Fn(&K) -> anyhow::Result<A> + Send + Sync + 'static,
+    ) -> Self {
+        let spawn: Spawn<K, A> = Arc::new(spawn);
+        if workers == 0 {
+            return Self {
+                spawn,
+                mode: Mode::Inline(RefCell::default()),
+            };
+        }
+
+        let failure = Failure::default();
+        let (mailboxes, handles) = (0..workers)
+            .map(|_| {
+                let (tx, rx) = crossbeam_channel::unbounded::<(K, A::Msg)>();
+                let spawn = spawn.clone();
+                let failure = failure.clone();
+                let handle = thread::spawn(move || {
+                    let mut actors = HashMap::new();
+                    for (key, msg) in rx {
+                        if let Err(err) = handle(&mut actors, &spawn, key, msg) {
+                            failure
+                                .lock()
+                                .expect("mailbox failure lock poisoned")
+                                .get_or_insert(format!("{err:#}"));
+                            break;
+                        }
+                    }
+                });
+                (tx, handle)
+            })
+            .unzip();
+        Self {
+            spawn,
+            mode: Mode::Workers {
+                mailboxes,
+                handles,
+                failure,
+            },
+        }
+    }
+
+    /// Hand `msg` to the actor of `key`, creating it if needed. Fails if
+    /// any actor failed before, as its worker is gone.
+    pub fn send(&self, key: K, msg: A::Msg) -> anyhow::Result<()> {
+        match &self.mode {
+            Mode::Inline(actors) => handle(&mut actors.borrow_mut(), &self.spawn, key, msg),
+            Mode::Workers { mailboxes, .. } => {
+                self.check()?;
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                let worker = (hasher.finish() % mailboxes.len() as u64) as usize;
+                mailboxes[worker]
+                    .send((key, msg))
+                    .map_err(|_| anyhow!("mailbox worker {worker} stopped"))
+            }
+        }
+    }
+
+    /// The error of the first actor that failed, if one did.
+    pub fn check(&self) -> anyhow::Result<()> {
+        let Mode::Workers { failure, .. } = &self.mode else {
+            return Ok(());
+        };
+        match &*failure.lock().expect("mailbox failure lock poisoned") {
+            Some(err) => Err(anyhow!("actor failed: {err}")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<K, A: Actor> Drop for Mailboxes<K, A> {
+    /// Let the workers finish what is in their mailboxes.
+    fn drop(&mut self) {
+        if let Mode::Workers {
+            mailboxes, handles, ..
+        } = &mut self.mode
+        {
+            mailboxes.clear();
+            for handle in handles.drain(..) {
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+fn handle<K, A>(
+    actors: &mut HashMap<K, A>,
+    spawn: &Spawn<K, A>,
+    key: K,
+    msg: A::Msg,
+) -> anyhow::Result<()>
+where
+    K: Hash + Eq + Clone,
+    A: Actor,
+{
+    let actor = match actors.entry(key) {
+        Entry::Occupied(actor) => actor.into_mut(),
+        Entry::Vacant(entry) => {
+            let actor = spawn(entry.key()).context("creating actor")?;
+            entry.insert(actor)
+        }
+    };
+    actor.handle(msg)
+}
@@ -1,18 +1,220 @@
 use std::{
-    collections::HashMap,
-    sync::{atomic::AtomicUsize, mpsc::Sender, Arc},
+    borrow::Borrow,
+    collections::{HashMap, HashSet},
+    fmt,
+    ops::Deref,
+    sync::{atomic::AtomicUsize, Arc, Mutex, OnceLock},
+    thread,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context as _;
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use crossbeam_channel::Sender;
+use serde::{
+    de::{self, DeserializeOwned, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 use serde_json::Value;
 
+use crate::{
+    admin::{Admin, AdminPayload, ADMIN_TYPE},
+    clock::{self, SharedClock},
+    error::{ErrorCode, MaelstromError, ParseError},
+    handshake::{Agreed, Capabilities, Handshake},
+    latency::Latency,
+    log,
+    metrics::Metrics,
+    reply_cache::ReplyCache,
+    rpc::{Calls, DEFAULT_CALL_TIMEOUT},
+    timer::{Timer, Timers},
+    Handler, Handlers,
+};
+
+/// Past this many interned ids, new ones are allocated on their own, so
+/// garbage input can't grow the interner without bound.
+const MAX_INTERNED: usize = 4096;
+
+/// The id of a node or client. Ids are interned, so cloning one, e.g. to
+/// address a reply, doesn't allocate.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(Arc<str>);
+
+impl NodeId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+fn intern(id: &str) -> Arc<str> {
+    static INTERNED: OnceLock<Mutex<HashSet<Arc<str>>>> = OnceLock::new();
+    let mut interned = INTERNED
+        .get_or_init(Default::default)
+        .lock()
+        .expect("node id interner poisoned");
+    if let Some(id) = interned.get(id) {
+        return Arc::clone(id);
+    }
+    let id = Arc::<str>::from(id);
+    if interned.len() < MAX_INTERNED {
+        interned.insert(Arc::clone(&id));
+    }
+    id
+}
+
+impl From<&str> for NodeId {
+    fn from(id: &str) -> Self {
+        Self(intern(id))
+    }
+}
+
+impl From<String> for NodeId {
+    fn from(id: String) -> Self {
+        Self(intern(&id))
+    }
+}
+
+impl From<&String> for NodeId {
+    fn from(id: &String) -> Self {
+        Self(intern(id))
+    }
+}
+
+impl Deref for NodeId {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for NodeId {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for NodeId {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq<str> for NodeId {
+    fn eq(&self, other: &str) -> bool {
+        &*self.0 == other
+    }
+}
+
+impl PartialEq<&str> for NodeId {
+    fn eq(&self, other: &&str) -> bool {
+        &*self.0 == *other
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&*self.0, f)
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Serialize for NodeId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for NodeId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct NodeIdVisitor;
+
+        impl Visitor<'_> for NodeIdVisitor {
+            type Value = NodeId;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a node id")
+            }
+
+            fn visit_str<E: de::Error>(self, id: &str) -> Result<NodeId, E> {
+                Ok(NodeId::from(id))
+            }
+        }
+
+        deserializer.deserialize_str(NodeIdVisitor)
+    }
+}
+
+/// One of Maelstrom's services, to address requests to without spelling
+/// out their node ids.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ServiceId {
+    LinKv,
+    SeqKv,
+    LwwKv,
+    LinTso,
+    Custom(String),
+}
+
+impl ServiceId {
+    pub fn as_str(&self) -> &str {
+        match self {
+            ServiceId::LinKv => "lin-kv",
+            ServiceId::SeqKv => "seq-kv",
+            ServiceId::LwwKv => "lww-kv",
+            ServiceId::LinTso => "lin-tso",
+            ServiceId::Custom(id) => id,
+        }
+    }
+}
+
+impl From<&str> for ServiceId {
+    fn from(id: &str) -> Self {
+        match id {
+            "lin-kv" => ServiceId::LinKv,
+            "seq-kv" => ServiceId::SeqKv,
+            "lww-kv" => ServiceId::LwwKv,
+            "lin-tso" => ServiceId::LinTso,
+            _ => ServiceId::Custom(id.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for ServiceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<ServiceId> for NodeId {
+    fn from(service: ServiceId) -> Self {
+        Self(intern(service.as_str()))
+    }
+}
+
+impl From<&ServiceId> for NodeId {
+    fn from(service: &ServiceId) -> Self {
+        Self(intern(service.as_str()))
+    }
+}
+
+impl PartialEq<ServiceId> for NodeId {
+    fn eq(&self, other: &ServiceId) -> bool {
+        &*self.0 == other.as_str()
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct MessageBuilder<Payload> {
-    src: Option<String>,
-    dst: Option<String>,
+    src: Option<NodeId>,
+    dst: Option<NodeId>,
     id: Option<usize>,
     in_reply_to: Option<usize>,
+    lamport: Option<u64>,
     payload: Option<Payload>,
 }
 
@@ -23,17 +225,18 @@ impl<Payload> MessageBuilder<Payload> {
             dst: None,
             id: None,
             in_reply_to: None,
+            lamport: None,
             payload: None,
         }
     }
 
-    pub fn src(mut self, src: String) -> Self {
-        self.src = Some(src);
+    pub fn src(mut self, src: impl Into<NodeId>) -> Self {
+        self.src = Some(src.into());
         self
     }
 
-    pub fn dst(mut self, dst: String) -> Self {
-        self.dst = Some(dst);
+    pub fn dst(mut self, dst: impl Into<NodeId>) -> Self {
+        self.dst = Some(dst.into());
         self
     }
 
@@ -42,11 +245,24 @@ impl<Payload> MessageBuilder<Payload> {
         self
     }
 
+    /// Use an explicit message id instead of drawing one from a context.
+    pub fn with_id(mut self, id: usize) -> Self {
+        self.id = Some(id);
+        self
+    }
+
     pub fn in_reply_to(mut self, in_reply_to: usize) -> Self {
         self.in_reply_to = Some(in_reply_to);
         self
     }
 
+    /// Stamp the message with the sender's Lamport time, see
+    /// [`crate::lamport::LamportClock::stamp`].
+    pub fn lamport(mut self, time: u64) -> Self {
+        self.lamport = Some(time);
+        self
+    }
+
     pub fn payload(mut self, payload: Payload) -> Self {
         self.payload = Some(payload);
         self
@@ -59,6 +275,7 @@ impl<Payload> MessageBuilder<Payload> {
             body: Body {
                 id: self.id,
                 in_reply_to: self.in_reply_to,
+                lamport: self.lamport,
                 payload: self
                     .payload
                     .context("payload is required to build a message")?,
@@ -70,11 +287,11 @@ impl<Payload> MessageBuilder<Payload> {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message<Payload> {
     /// The id of the node that sent the message.
-    src: String,
+    src: NodeId,
 
     /// The id of the node that the message is intended for.
     #[serde(rename = "dest")]
-    dst: String,
+    dst: NodeId,
 
     /// The body of the message.
     body: Body<Payload>,
@@ -96,6 +313,56 @@ impl<Payload> Message<Payload> {
     pub fn body(&self) -> &Body<Payload> {
         &self.body
     }
+
+    pub fn into_body(self) -> Body<Payload> {
+        self.body
+    }
+
+    /// This message stamped with the sender's Lamport time.
+    pub fn with_lamport(mut self, time: u64) -> Self {
+        self.body.lamport = Some(time);
+        self
+    }
+}
+
+impl Message<Value> {
+    /// This message with its payload parsed as `Payload`, or an error
+    /// pointing at where the payload's JSON stopped matching, e.g. for an
+    /// [`Event::Arbitrary`] that was expected to be one of the node's own.
+    pub fn parse_as<Payload>(&self) -> Result<Message<Payload>, ParseError>
+    where
+        Payload: DeserializeOwned,
+    {
+        let json = self.body.payload.to_string();
+        let payload = serde_json::from_str(&json)
+            .map_err(|e| ParseError::from_json(format!("payload from {}", self.src), &json, &e))?;
+        Ok(Message {
+            src: self.src.clone(),
+            dst: self.dst.clone(),
+            body: Body {
+                id: self.body.id,
+                in_reply_to: self.body.in_reply_to,
+                lamport: self.body.lamport,
+                payload,
+            },
+        })
+    }
+
+    /// The whole message as JSON, built from its parts rather than
+    /// serialized.
+    pub(crate) fn to_json(&self) -> Value {
+        let mut body = self.body.payload.as_object().cloned().unwrap_or_default();
+        body.insert("msg_id".to_string(), self.body.id.into());
+        body.insert("in_reply_to".to_string(), self.body.in_reply_to.into());
+        if let Some(lamport) = self.body.lamport {
+            body.insert("lamport".to_string(), lamport.into());
+        }
+        serde_json::json!({
+            "src": self.src(),
+            "dest": self.dst(),
+            "body": body,
+        })
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -107,6 +374,11 @@ pub struct Body<Payload> {
     /// The id of the message that this message is in reply to.
     pub in_reply_to: Option<usize>,
 
+    /// The sender's [Lamport time](crate::lamport) when it sent the message,
+    /// if it keeps one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lamport: Option<u64>,
+
     /// The payload of the message.
     #[serde(flatten)]
     pub payload: Payload,
@@ -130,6 +402,21 @@ pub struct Init {
 
     /// The ids of the nodes that are connected to this node.
     pub node_ids: Vec<String>,
+
+    /// Any other fields of the init message, which Maelstrom doesn't send
+    /// but a harness may, to configure the node. See [`crate::config`].
+    #[serde(flatten)]
+    pub extensions: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Payloads whose variants pair requests with their replies, like `Echo` and
+/// `EchoOk`. Derived by [`crate::Payload`].
+pub trait RequestReply {
+    /// Whether this answers some request.
+    fn is_reply(&self) -> bool;
+
+    /// Whether this is the kind of reply that answers `request`.
+    fn is_reply_to(&self, request: &Self) -> bool;
 }
 
 #[derive(Debug, Clone)]
@@ -140,6 +427,9 @@ pub enum Event<Payload, InjectedPayload = ()> {
     /// An inected message from a Node specific event loop.
     Injected(InjectedPayload),
 
+    /// An inter-node control message, routed by the runtime.
+    Admin(Message<AdminPayload>),
+
     /// Intended to be used for things like lin-kv and seq-kv.
     Arbitrary(Message<Value>),
 
@@ -150,56 +440,163 @@ pub enum Event<Payload, InjectedPayload = ()> {
 impl<Payload, InjectedPayload> Event<Payload, InjectedPayload>
 where
     Payload: for<'de> Deserialize<'de> + Send + 'static,
-    InjectedPayload: Clone + Send + 'static,
+    InjectedPayload: Send + 'static,
 {
     pub(crate) fn is_reply(&self) -> bool {
         match self {
             Event::Message(msg) => msg.body.in_reply_to.is_some(),
             Event::Arbitrary(msg) => msg.body.in_reply_to.is_some(),
+            Event::Admin(msg) => msg.body.in_reply_to.is_some(),
             _ => false,
         }
     }
 }
 
+impl<IP> Event<Value, IP> {
+    /// Parse the payload of a message read as plain JSON as `Payload`, or
+    /// leave it as [`Event::Arbitrary`] if it doesn't parse.
+    pub(crate) fn parse<Payload>(self) -> Event<Payload, IP>
+    where
+        Payload: DeserializeOwned,
+    {
+        match self {
+            Event::Message(msg) | Event::Arbitrary(msg) => {
+                parse_payload(&msg).unwrap_or(Event::Arbitrary(msg))
+            }
+            Event::Injected(payload) => Event::Injected(payload),
+            Event::Admin(msg) => Event::Admin(msg),
+            Event::Eof => Event::Eof,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum ToEvent<InjectedPayload = ()> {
     Message(Message<serde_json::Value>),
     Injected(InjectedPayload),
+
+    /// Injected, but dropped rather than stepped if it comes up after the
+    /// deadline, by the context's clock.
+    InjectedUntil(InjectedPayload, Instant),
     Eof,
 }
 
 impl<IP> ToEvent<IP> {
+    /// Parse a line of input from the harness.
+    pub fn from_line(line: &str) -> anyhow::Result<Self> {
+        let message = parse_line(line).context("read input message")?;
+        Ok(ToEvent::Message(message))
+    }
+
+    /// Parse the event without consuming it, deserializing payloads straight
+    /// from the borrowed JSON.
     pub fn to_event<Payload>(&self) -> anyhow::Result<Event<Payload, IP>>
     where
         Payload: DeserializeOwned,
         IP: Clone,
     {
         let event = match self {
-            ToEvent::Message(e) => {
-                let body: Result<Payload, _> = serde_json::from_value(e.body.payload.clone());
-                if let Ok(body) = body {
-                    let message = Message {
-                        src: e.src.clone(),
-                        dst: e.dst.clone(),
-                        body: Body {
-                            id: e.body.id,
-                            in_reply_to: e.body.in_reply_to,
-                            payload: body,
-                        },
-                    };
-                    Event::Message(message)
-                } else {
-                    Event::Arbitrary(e.clone())
-                }
-            }
-            ToEvent::Injected(i) => Event::Injected(i.clone()),
+            ToEvent::Message(e) => match parse_payload(e) {
+                Some(event) => event,
+                None => Event::Arbitrary(e.clone()),
+            },
+            ToEvent::Injected(i) | ToEvent::InjectedUntil(i, _) => Event::Injected(i.clone()),
             ToEvent::Eof => Event::Eof,
         };
         Ok(event)
     }
+
+    /// Like [`ToEvent::to_event`], but moving the message into
+    /// [`Event::Arbitrary`] when it doesn't parse instead of cloning it.
+    pub fn into_event<Payload>(self) -> anyhow::Result<Event<Payload, IP>>
+    where
+        Payload: DeserializeOwned,
+    {
+        let event = match self {
+            ToEvent::Message(e) => match parse_payload(&e) {
+                Some(event) => event,
+                None => Event::Arbitrary(e),
+            },
+            ToEvent::Injected(i) | ToEvent::InjectedUntil(i, _) => Event::Injected(i),
+            ToEvent::Eof => Event::Eof,
+        };
+        Ok(event)
+    }
+}
+
+/// The admin or node event carried by `e`, if its payload parses as either.
+fn parse_payload<Payload, IP>(e: &Message<Value>) -> Option<Event<Payload, IP>>
+where
+    Payload: DeserializeOwned,
+{
+    fn with_payload<T>(e: &Message<Value>, payload: T) -> Message<T> {
+        Message {
+            src: e.src.clone(),
+            dst: e.dst.clone(),
+            body: Body {
+                id: e.body.id,
+                in_reply_to: e.body.in_reply_to,
+                lamport: e.body.lamport,
+                payload,
+            },
+        }
+    }
+
+    if is_admin(&e.body.payload) {
+        let admin = Admin::deserialize(&e.body.payload).ok()?;
+        Some(Event::Admin(with_payload(e, admin.admin)))
+    } else {
+        let payload = Payload::deserialize(&e.body.payload).ok()?;
+        Some(Event::Message(with_payload(e, payload)))
+    }
+}
+
+/// Parse a line of JSON from the harness.
+#[cfg(not(feature = "fast-json"))]
+pub(crate) fn parse_line<T: DeserializeOwned>(line: &str) -> anyhow::Result<T> {
+    serde_json::from_str(line).map_err(|e| ParseError::from_json("input line", line, &e).into())
+}
+
+/// Parse a line of JSON from the harness. simd-json parses in place, so the
+/// line is copied first.
+#[cfg(feature = "fast-json")]
+pub(crate) fn parse_line<T: DeserializeOwned>(line: &str) -> anyhow::Result<T> {
+    let mut bytes = line.as_bytes().to_vec();
+    simd_json::serde::from_slice(&mut bytes)
+        .map_err(|e| ParseError::new("input line", line, None, e).into())
 }
 
-#[derive(Clone)]
+/// Derive a seed from `seed` and `label`, stable across runs and Rust
+/// releases: splitmix64 over the seed mixed with the FNV-1a hash of the
+/// label.
+pub(crate) fn mix_seed(seed: u64, label: &str) -> u64 {
+    let label = label.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    let mut z = (seed ^ label).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+fn is_admin(payload: &Value) -> bool {
+    payload.get("type").and_then(Value::as_str) == Some(ADMIN_TYPE)
+}
+
+/// What to do when stepping a node through a client request sends no reply
+/// to it, which otherwise only shows up as a Maelstrom timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplyGuard {
+    /// Log a warning and carry on.
+    Warn,
+
+    /// Fail the event loop.
+    Strict,
+}
+
+/// The client request a node is being stepped through, until it is answered.
+type AwaitingReply = Arc<Mutex<Option<(NodeId, usize)>>>;
+
 pub struct Context<IP> {
     /// Allows sending messages as RPCs
     msg_out_tx: Sender<Box<dyn erased_serde::Serialize + Send + Sync + 'static>>,
@@ -209,6 +606,83 @@ pub struct Context<IP> {
 
     /// The id of the next message to be sent.
     msg_id: Arc<AtomicUsize>,
+
+    /// Metrics shared by everything holding this context.
+    metrics: Metrics,
+
+    /// Time from each request coming in to its reply being built.
+    latency: Latency,
+
+    /// Root of every random decision made on behalf of the node.
+    seed: u64,
+
+    /// Serialize messages in `send` rather than in the output thread.
+    serialize_eagerly: bool,
+
+    reply_guard: Option<(ReplyGuard, AwaitingReply)>,
+
+    /// Replies to resend for duplicate requests, if enabled.
+    reply_cache: Option<ReplyCache>,
+
+    /// The node this context belongs to, once it's known.
+    node_id: Option<NodeId>,
+
+    /// How many other nodes are in the cluster.
+    peers: usize,
+
+    /// Where the node gets the time from.
+    clock: SharedClock,
+
+    /// What the node supports and agreed on with its peers, if it takes
+    /// part in the handshake.
+    handshake: Option<Handshake>,
+
+    /// Stopped by the runtime at the end of input.
+    timers: Timers,
+
+    /// Requests awaiting their replies, see [`Context::call`].
+    calls: Calls<IP>,
+
+    /// Taking the messages the node's payload type doesn't parse, see
+    /// [`Context::set_handlers`]. Taken out while they handle a message.
+    handlers: Arc<Mutex<Option<Handlers<IP>>>>,
+}
+
+// Not derived, as that would require injected payloads to be `Clone` too.
+impl<IP> Clone for Context<IP> {
+    fn clone(&self) -> Self {
+        Self {
+            msg_out_tx: self.msg_out_tx.clone(),
+            msg_in_tx: self.msg_in_tx.clone(),
+            msg_id: self.msg_id.clone(),
+            metrics: self.metrics.clone(),
+            latency: self.latency.clone(),
+            seed: self.seed,
+            serialize_eagerly: self.serialize_eagerly,
+            reply_guard: self.reply_guard.clone(),
+            reply_cache: self.reply_cache.clone(),
+            node_id: self.node_id.clone(),
+            peers: self.peers,
+            clock: self.clock.clone(),
+            handshake: self.handshake.clone(),
+            timers: self.timers.clone(),
+            calls: self.calls.clone(),
+            handlers: self.handlers.clone(),
+        }
+    }
+}
+
+impl<IP> fmt::Debug for Context<IP> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Context")
+            .field("node_id", &self.node_id)
+            .field("peers", &self.peers)
+            .field("next_msg_id", &self.msg_id())
+            .field("outgoing_queued", &self.outgoing_queued())
+            .field("injected_queued", &self.injected_queued())
+            .field("seed", &self.seed)
+            .finish_non_exhaustive()
+    }
 }
 
 impl<IP> Context<IP> {
@@ -218,15 +692,195 @@ impl<IP> Context<IP> {
         msg_id: Arc<AtomicUsize>,
     ) -> Self
     where
-        IP: Clone + Send + 'static,
+        IP: Send + 'static,
     {
         Self {
             msg_out_tx,
             msg_in_tx,
             msg_id,
+            metrics: Metrics::new(),
+            latency: Latency::new(),
+            seed: 0,
+            serialize_eagerly: false,
+            reply_guard: None,
+            reply_cache: None,
+            node_id: None,
+            peers: 0,
+            clock: clock::system(),
+            handshake: None,
+            timers: Timers::default(),
+            calls: Calls::default(),
+            handlers: Arc::default(),
         }
     }
 
+    /// Tell the context which node it belongs to, for logs and errors.
+    pub fn with_init(mut self, init: &Init) -> Self {
+        self.node_id = Some(NodeId::from(&init.node_id));
+        self.peers = init
+            .node_ids
+            .iter()
+            .filter(|id| **id != init.node_id)
+            .count();
+        self
+    }
+
+    /// The node this context belongs to, if [`Context::with_init`] told it.
+    pub fn node_id(&self) -> Option<&str> {
+        self.node_id.as_deref()
+    }
+
+    /// How many other nodes are in the cluster, as of the init message.
+    pub fn peer_count(&self) -> usize {
+        self.peers
+    }
+
+    /// Messages sent but not yet picked up by the output thread.
+    pub fn outgoing_queued(&self) -> usize {
+        self.msg_out_tx.len()
+    }
+
+    /// Injected events not yet picked up by the event loop.
+    pub fn injected_queued(&self) -> usize {
+        self.msg_in_tx.len()
+    }
+
+    /// Check that every client request gets a reply built for it, through
+    /// [`Context::construct_reply`] or its siblings, or is explicitly
+    /// answered later with [`Context::defer_reply`].
+    pub fn with_reply_guard(mut self, guard: ReplyGuard) -> Self {
+        self.reply_guard = Some((guard, Arc::default()));
+        self
+    }
+
+    /// Answer duplicates of requests from `cache`, see [`crate::reply_cache`].
+    /// Every message is serialized in `send`, to be remembered if it's a
+    /// reply.
+    pub fn with_reply_cache(mut self, cache: ReplyCache) -> Self {
+        self.reply_cache = Some(cache);
+        self
+    }
+
+    pub(crate) fn reply_cache(&self) -> Option<&ReplyCache> {
+        self.reply_cache.as_ref()
+    }
+
+    /// Tell the reply guard that `msg` will be answered later, e.g. once the
+    /// requests it triggered were answered.
+    pub fn defer_reply<Payload>(&self, msg: &Message<Payload>) {
+        self.replied(&msg.src, msg.body.id);
+    }
+
+    fn replied(&self, to: &NodeId, id: Option<usize>) {
+        let Some((_, awaiting)) = &self.reply_guard else {
+            return;
+        };
+        let mut awaiting = awaiting.lock().expect("reply guard lock poisoned");
+        if awaiting
+            .as_ref()
+            .is_some_and(|(src, awaited)| src == to && Some(*awaited) == id)
+        {
+            *awaiting = None;
+        }
+    }
+
+    /// Start waiting for a reply to `msg`, if it's a client request.
+    pub(crate) fn expect_reply<Payload>(&self, msg: &Message<Payload>) {
+        let Some((_, awaiting)) = &self.reply_guard else {
+            return;
+        };
+        if !msg.src.starts_with('c') || msg.body.in_reply_to.is_some() {
+            return;
+        }
+        if let Some(id) = msg.body.id {
+            *awaiting.lock().expect("reply guard lock poisoned") = Some((msg.src.clone(), id));
+        }
+    }
+
+    /// Complain about the request passed to `expect_reply`, if it wasn't
+    /// answered since.
+    pub(crate) fn check_replied(&self) -> anyhow::Result<()> {
+        let Some((guard, awaiting)) = &self.reply_guard else {
+            return Ok(());
+        };
+        let Some((src, id)) = awaiting.lock().expect("reply guard lock poisoned").take() else {
+            return Ok(());
+        };
+        match guard {
+            ReplyGuard::Warn => {
+                log::warn("step sent no reply")
+                    .msg_id(id)
+                    .field("src", &src)
+                    .emit();
+                Ok(())
+            }
+            ReplyGuard::Strict => anyhow::bail!("step sent no reply to message {id} from {src}"),
+        }
+    }
+
+    /// Serialize every message in `send`, on the caller's thread, so the
+    /// output thread only copies bytes and serialization errors are reported
+    /// to the caller.
+    pub fn with_eager_serialization(mut self, eager: bool) -> Self {
+        self.serialize_eagerly = eager;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// A seed for one particular random decision, so that independent users
+    /// of the context don't draw the same numbers.
+    pub fn seed_for(&self, purpose: &str) -> u64 {
+        mix_seed(self.seed, purpose)
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn latency(&self) -> &Latency {
+        &self.latency
+    }
+
+    /// Go by `clock` instead of the OS clock, see [`crate::clock`].
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.latency = Latency::with_clock(clock.clone());
+        self.clock = clock;
+        self
+    }
+
+    pub fn clock(&self) -> &SharedClock {
+        &self.clock
+    }
+
+    /// Take part in the handshake with `capabilities`, see
+    /// [`crate::handshake`].
+    pub fn with_handshake(mut self, capabilities: Capabilities) -> Self {
+        self.handshake = Some(Handshake::new(capabilities));
+        self
+    }
+
+    pub(crate) fn handshake(&self) -> Option<&Handshake> {
+        self.handshake.as_ref()
+    }
+
+    /// What the node supports, if it takes part in the handshake.
+    pub fn capabilities(&self) -> Option<&Capabilities> {
+        self.handshake.as_ref().map(Handshake::capabilities)
+    }
+
+    /// What this node and `peer` agreed on in the handshake, once they did.
+    pub fn agreed(&self, peer: &str) -> Option<Agreed> {
+        self.handshake.as_ref()?.agreed(peer)
+    }
+
     pub fn msg_id(&self) -> usize {
         self.msg_id.load(std::sync::atomic::Ordering::SeqCst)
     }
@@ -235,9 +889,19 @@ impl<IP> Context<IP> {
     where
         S: Serialize + Sync + Send + 'static,
     {
-        self.msg_out_tx
-            .send(Box::new(s))
-            .context("send message to stdout")
+        let msg: Box<dyn erased_serde::Serialize + Send + Sync> = if let Some(cache) =
+            &self.reply_cache
+        {
+            let msg = serde_json::value::to_raw_value(&s).context("serialize outgoing message")?;
+            cache.record(&msg);
+            Box::new(msg)
+        } else if self.serialize_eagerly {
+            Box::new(serde_json::value::to_raw_value(&s).context("serialize outgoing message")?)
+        } else {
+            Box::new(s)
+        };
+        self.metrics.incr("runtime.messages_sent", 1);
+        self.msg_out_tx.send(msg).context("send message to stdout")
     }
 
     pub fn inject(&self, s: IP) -> anyhow::Result<()>
@@ -249,19 +913,122 @@ impl<IP> Context<IP> {
             .context("inject message into event loop")
     }
 
+    /// Inject `s`, unless the event loop only gets to it after `ttl` passed,
+    /// e.g. a tick that sat behind a backlog and would only cause a burst of
+    /// obsolete work.
+    pub fn inject_with_ttl(&self, s: IP, ttl: Duration) -> anyhow::Result<()>
+    where
+        IP: Sync + Send + 'static,
+    {
+        self.inject_until(s, self.clock.now() + ttl)
+    }
+
+    /// Inject `s`, unless the event loop only gets to it after `deadline`.
+    pub fn inject_until(&self, s: IP, deadline: Instant) -> anyhow::Result<()>
+    where
+        IP: Sync + Send + 'static,
+    {
+        self.msg_in_tx
+            .send(ToEvent::InjectedUntil(s, deadline))
+            .context("inject message into event loop")
+    }
+
+    /// Run `work` on a thread of its own, e.g. disk I/O or a long
+    /// computation, and hand its result back to `step` as the injected
+    /// payload `wrap` makes of it, like `InjectedPayload::Loaded`. The result
+    /// is dropped if the event loop stopped in the meantime.
+    pub fn spawn<T>(
+        &self,
+        work: impl FnOnce() -> T + Send + 'static,
+        wrap: impl FnOnce(T) -> IP + Send + 'static,
+    ) -> thread::JoinHandle<()>
+    where
+        T: 'static,
+        IP: Sync + Send + 'static,
+    {
+        let ctx = self.clone();
+        thread::spawn(move || {
+            let _ = ctx.inject(wrap(work()));
+        })
+    }
+
+    /// Inject `payload` every `interval`, until the timer is cancelled or the
+    /// node's input ends, see [`crate::timer`]. A tick still queued when the
+    /// next one is due is dropped, as that one covers it.
+    pub fn every(&self, interval: Duration, payload: IP) -> Timer
+    where
+        IP: Clone + Sync + Send + 'static,
+    {
+        let timer = self.timers.timer();
+        let ctx = self.clone();
+        let handle = timer.clone();
+        thread::spawn(move || {
+            while handle.sleep(&ctx.clock, interval) {
+                if ctx.inject_with_ttl(payload.clone(), interval).is_err() {
+                    break;
+                }
+            }
+        });
+        timer
+    }
+
+    /// Inject `payload` once `delay` passed, unless the timer was cancelled
+    /// or the node's input ended by then.
+    pub fn after(&self, delay: Duration, payload: IP) -> Timer
+    where
+        IP: Sync + Send + 'static,
+    {
+        let timer = self.timers.timer();
+        let ctx = self.clone();
+        let handle = timer.clone();
+        thread::spawn(move || {
+            if handle.sleep(&ctx.clock, delay) {
+                let _ = ctx.inject(payload);
+            }
+        });
+        timer
+    }
+
+    pub(crate) fn timers(&self) -> &Timers {
+        &self.timers
+    }
+
+    /// Hand a message to the event loop as if it came in on stdin.
+    pub(crate) fn deliver(&self, msg: Message<Value>) -> anyhow::Result<()>
+    where
+        IP: Sync + Send + 'static,
+    {
+        self.msg_in_tx
+            .send(ToEvent::Message(msg))
+            .context("deliver message into event loop")
+    }
+
     pub fn next_msg_id(&self) -> usize {
         self.msg_id
             .fetch_add(1, std::sync::atomic::Ordering::SeqCst)
     }
 
-    pub fn construct_reply<Payload>(
+    /// Make sure msg_ids handed out from now on come after `id`, e.g. the
+    /// id of a request recovered from disk, so their replies can't be
+    /// confused.
+    pub fn skip_msg_ids_through(&self, id: usize) {
+        self.msg_id
+            .fetch_max(id + 1, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    /// A reply to `msg` carrying `payload`, which may be of another type than
+    /// the request's, e.g. for nodes with separate request and response
+    /// enums.
+    pub fn construct_reply<Request, Reply>(
         &self,
-        msg: &Message<Payload>,
-        payload: Payload,
-    ) -> Message<Payload>
+        msg: &Message<Request>,
+        payload: Reply,
+    ) -> Message<Reply>
     where
-        Payload: Serialize,
+        Reply: Serialize,
     {
+        self.replied(&msg.src, msg.body.id);
+        self.latency.replied(&msg.src, msg.body.id);
         let id = self.next_msg_id();
         Message {
             src: msg.dst.clone(),
@@ -269,17 +1036,196 @@ impl<IP> Context<IP> {
             body: Body {
                 id: Some(id),
                 in_reply_to: msg.body.id,
+                lamport: None,
                 payload,
             },
         }
     }
 
+    /// Turn `msg` into its own reply, with the payload mapped by `reply`.
+    /// Unlike [`Context::construct_reply`] this takes the message by value,
+    /// so the ids and the payload's own allocations are reused, which is
+    /// the cheaper choice for nodes that only ever answer requests.
+    pub fn reply_with<Payload, Reply>(
+        &self,
+        msg: Message<Payload>,
+        reply: impl FnOnce(Payload) -> Reply,
+    ) -> Message<Reply> {
+        self.replied(&msg.src, msg.body.id);
+        self.latency.replied(&msg.src, msg.body.id);
+        let Message { src, dst, body } = msg;
+        Message {
+            src: dst,
+            dst: src,
+            body: Body {
+                id: Some(self.next_msg_id()),
+                in_reply_to: body.id,
+                lamport: None,
+                payload: reply(body.payload),
+            },
+        }
+    }
+
+    /// Like [`Context::reply_with`], for a reply that may fail to be made.
+    pub fn try_reply_with<Payload, Reply>(
+        &self,
+        msg: Message<Payload>,
+        reply: impl FnOnce(Payload) -> anyhow::Result<Reply>,
+    ) -> anyhow::Result<Message<Reply>> {
+        let Message { src, dst, body } = msg;
+        let payload = reply(body.payload)?;
+        self.replied(&src, body.id);
+        self.latency.replied(&src, body.id);
+        Ok(Message {
+            src: dst,
+            dst: src,
+            body: Body {
+                id: Some(self.next_msg_id()),
+                in_reply_to: body.id,
+                lamport: None,
+                payload,
+            },
+        })
+    }
+
+    /// Send a request whose reply goes to
+    /// [`Node::handle_reply`](crate::Node::handle_reply). Use
+    /// [`Context::call`] to have it go to a callback instead.
     pub fn send_rpc<Payload>(&self, msg: Message<Payload>) -> anyhow::Result<()>
     where
         Payload: Serialize + Sync + Send + 'static,
     {
         self.send(msg)
     }
+
+    /// Send `payload` to `dst` as a request, and run `callback` on its
+    /// reply, parsed as `Reply`, or on the error it was answered with,
+    /// instead of handing it to the node. See [`crate::rpc`].
+    ///
+    /// Without a reply within [`DEFAULT_CALL_TIMEOUT`], the callback gets a
+    /// `Timeout` error instead, as soon as the node handles its next event.
+    pub fn call<Request, Reply>(
+        &self,
+        dst: impl Into<NodeId>,
+        payload: Request,
+        callback: impl FnOnce(Result<Message<Reply>, MaelstromError>, Context<IP>) -> anyhow::Result<()>
+            + Send
+            + 'static,
+    ) -> anyhow::Result<()>
+    where
+        Request: Serialize + Sync + Send + 'static,
+        Reply: DeserializeOwned,
+    {
+        self.call_with_timeout(dst, payload, DEFAULT_CALL_TIMEOUT, callback)
+    }
+
+    /// Like [`Context::call`], giving up on the reply after `timeout`.
+    pub fn call_with_timeout<Request, Reply>(
+        &self,
+        dst: impl Into<NodeId>,
+        payload: Request,
+        timeout: Duration,
+        callback: impl FnOnce(Result<Message<Reply>, MaelstromError>, Context<IP>) -> anyhow::Result<()>
+            + Send
+            + 'static,
+    ) -> anyhow::Result<()>
+    where
+        Request: Serialize + Sync + Send + 'static,
+        Reply: DeserializeOwned,
+    {
+        let src = self
+            .node_id
+            .clone()
+            .context("calling before the node is initialized")?;
+        let dst = dst.into();
+        let id = self.next_msg_id();
+        let msg = Message::builder()
+            .src(src)
+            .dst(dst.clone())
+            .with_id(id)
+            .payload(payload)
+            .build()?;
+        let on_reply = move |reply: Result<Message<Value>, MaelstromError>, ctx: Context<IP>| {
+            let reply = match reply {
+                Ok(reply)
+                    if reply.body.payload.get("type").and_then(Value::as_str) == Some("error") =>
+                {
+                    Err(MaelstromError::deserialize(&reply.body.payload)
+                        .with_context(|| format!("parsing error from {}", reply.src))?)
+                }
+                Ok(reply) => Ok(reply.parse_as()?),
+                Err(err) => Err(err),
+            };
+            callback(reply, ctx)
+        };
+        // Registered first, so the reply can't come in before it's expected.
+        let deadline = self.clock.now() + timeout;
+        self.calls.insert(id, dst, deadline, Box::new(on_reply));
+        self.send(msg)
+    }
+
+    /// Run the callbacks of the calls that timed out with a `Timeout` error.
+    pub(crate) fn expire_calls(&self) -> anyhow::Result<()> {
+        for on_timeout in self.calls.expire(self.clock.now()) {
+            self.metrics.incr("rpc.calls_expired", 1);
+            let err = MaelstromError::new(ErrorCode::Timeout, "no reply in time");
+            on_timeout(Err(err), self.clone()).context("Call callback failed")?;
+        }
+
+        Ok(())
+    }
+
+    /// Requests sent with [`Context::call`] still awaiting their replies.
+    pub fn pending_calls(&self) -> usize {
+        self.calls.len()
+    }
+
+    pub(crate) fn calls(&self) -> &Calls<IP> {
+        &self.calls
+    }
+
+    /// Hand the messages the node's payload type doesn't parse to
+    /// `handlers` rather than to the node as [`Event::Arbitrary`], replacing
+    /// any handlers set before, e.g. from `from_init`. Handlers may set
+    /// handlers too, which take over from the next message on.
+    pub fn set_handlers(&self, handlers: Handlers<IP>) {
+        *self.handlers.lock().expect("handlers lock poisoned") = Some(handlers);
+    }
+
+    /// Hand `msg` to the handler taking it, returning whether one did.
+    pub(crate) fn offer_handlers(&self, msg: &Message<Value>) -> anyhow::Result<bool> {
+        // Stepped outside of the lock, so they can set handlers themselves.
+        let Some(mut handlers) = self.handlers.lock().expect("handlers lock poisoned").take()
+        else {
+            return Ok(false);
+        };
+        let handled = self.step_handlers(&mut handlers, msg);
+        // Handlers set while these stepped replace them.
+        self.handlers
+            .lock()
+            .expect("handlers lock poisoned")
+            .get_or_insert(handlers);
+        handled
+    }
+
+    fn step_handlers(
+        &self,
+        handlers: &mut Handlers<IP>,
+        msg: &Message<Value>,
+    ) -> anyhow::Result<bool> {
+        if handlers.is_empty() {
+            return Ok(false);
+        }
+        let json = msg.to_json();
+        if !handlers.can_handle(&json) {
+            return Ok(false);
+        }
+        self.metrics.incr("runtime.handled", 1);
+        handlers
+            .step(json, self.clone())
+            .context("Handler failed")?;
+        Ok(true)
+    }
 }
 
 pub struct MessageSet<Payload> {
@@ -305,10 +1251,24 @@ where
         }
     }
 
+    /// The messages still waiting for a reply, by increasing id.
+    pub fn pending(&self) -> Vec<&Message<Payload>> {
+        let mut pending = self.messages.values().collect::<Vec<_>>();
+        pending.sort_by_key(|msg| msg.body.id);
+        pending
+    }
+
     pub fn is_matching_reply(&self, msg: &Message<Payload>) -> bool {
         msg.body
             .in_reply_to
             .map(|id| self.messages.contains_key(&id))
             .unwrap_or(false)
     }
+
+    /// The message of the set that `reply` answers, if it came from the
+    /// node or service the message was sent to.
+    pub fn request_for<R>(&self, reply: &Message<R>) -> Option<&Message<Payload>> {
+        let request = self.messages.get(&reply.body.in_reply_to?)?;
+        (request.dst == reply.src).then_some(request)
+    }
 }
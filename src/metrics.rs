@@ -0,0 +1,101 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Running statistics of an observed value.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Summary {
+    pub count: u64,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Summary {
+    fn observe(&mut self, value: f64) {
+        if self.count == 0 || value < self.min {
+            self.min = value;
+        }
+        if self.count == 0 || value > self.max {
+            self.max = value;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum / self.count as f64
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    Counter(u64),
+    Gauge(i64),
+    Summary(Summary),
+}
+
+/// Named metrics shared by every clone of a [`crate::Context`].
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    metrics: Arc<Mutex<BTreeMap<String, Metric>>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `by` to the counter `name`.
+    pub fn incr(&self, name: &str, by: u64) {
+        let mut metrics = self.metrics.lock().expect("metrics lock poisoned");
+        match metrics.get_mut(name) {
+            Some(Metric::Counter(count)) => *count += by,
+            _ => {
+                metrics.insert(name.to_string(), Metric::Counter(by));
+            }
+        }
+    }
+
+    /// Set the gauge `name` to `value`.
+    pub fn gauge(&self, name: &str, value: i64) {
+        self.metrics
+            .lock()
+            .expect("metrics lock poisoned")
+            .insert(name.to_string(), Metric::Gauge(value));
+    }
+
+    /// Record one observation of `value` into the summary `name`.
+    pub fn observe(&self, name: &str, value: f64) {
+        let mut metrics = self.metrics.lock().expect("metrics lock poisoned");
+        match metrics.get_mut(name) {
+            Some(Metric::Summary(summary)) => summary.observe(value),
+            _ => {
+                let mut summary = Summary::default();
+                summary.observe(value);
+                metrics.insert(name.to_string(), Metric::Summary(summary));
+            }
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Metric> {
+        self.metrics
+            .lock()
+            .expect("metrics lock poisoned")
+            .get(name)
+            .copied()
+    }
+
+    /// A copy of every metric, ordered by name.
+    pub fn snapshot(&self) -> BTreeMap<String, Metric> {
+        self.metrics.lock().expect("metrics lock poisoned").clone()
+    }
+}
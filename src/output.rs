@@ -0,0 +1,243 @@
+//! Buffering of the runtime's stdout. Flushing after every message costs a
+//! syscall each, which adds up for high-rate workloads; a [`FlushPolicy`]
+//! trades that against how long a reply may sit in the buffer.
+
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    io::{BufWriter, Write},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use crossbeam_channel::{Receiver, Sender};
+use erased_serde::Serialize;
+use serde_json::value::RawValue;
+
+use crate::CHANNEL_CAPACITY;
+
+type Outgoing = Box<dyn Serialize + Send + Sync>;
+
+/// Environment variable picking the flush policy without code changes:
+/// `every-message`, `idle`, or `batch=64,linger_ms=1`.
+pub const FLUSH_ENV: &str = "VORTICITY_FLUSH";
+
+/// When buffered output is written out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlushPolicy {
+    /// After every message, for the lowest latency.
+    #[default]
+    EveryMessage,
+
+    /// Once `messages` are buffered, or once nothing new was sent for
+    /// `linger`, whichever comes first.
+    Batch { messages: usize, linger: Duration },
+
+    /// Whenever no other message is waiting to be written, so bursts go out
+    /// together without holding anything back.
+    OnIdle,
+}
+
+impl FlushPolicy {
+    /// Parse [`FLUSH_ENV`], if it is set.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let Ok(spec) = std::env::var(FLUSH_ENV) else {
+            return Ok(None);
+        };
+        match spec.as_str() {
+            "every-message" => return Ok(Some(FlushPolicy::EveryMessage)),
+            "idle" => return Ok(Some(FlushPolicy::OnIdle)),
+            _ => {}
+        }
+
+        let mut messages = None;
+        let mut linger = Duration::from_millis(1);
+        for setting in spec.split(',').filter(|s| !s.is_empty()) {
+            let (name, value) = setting
+                .split_once('=')
+                .with_context(|| format!("{FLUSH_ENV}: expected name=value, got {setting}"))?;
+            let value: u64 = value
+                .parse()
+                .with_context(|| format!("{FLUSH_ENV}: {name} is not a number"))?;
+            match name {
+                "batch" => messages = Some(value as usize),
+                "linger_ms" => linger = Duration::from_millis(value),
+                _ => bail!("{FLUSH_ENV}: unknown setting {name}"),
+            }
+        }
+        let Some(messages) = messages else {
+            bail!("{FLUSH_ENV}: expected every-message, idle or batch=N, got {spec}");
+        };
+
+        Ok(Some(FlushPolicy::Batch { messages, linger }))
+    }
+}
+
+/// Line-oriented output, buffered according to a [`FlushPolicy`].
+pub(crate) struct Output<W: Write> {
+    out: BufWriter<W>,
+    policy: FlushPolicy,
+
+    /// Lines written since the last flush.
+    buffered: usize,
+}
+
+impl<W: Write> Output<W> {
+    pub(crate) fn new(out: W, policy: FlushPolicy) -> Self {
+        Self {
+            out: BufWriter::new(out),
+            policy,
+            buffered: 0,
+        }
+    }
+
+    pub(crate) fn write_line(&mut self, line: &str) -> anyhow::Result<()> {
+        self.out
+            .write_all(line.as_bytes())
+            .context("write message to output")?;
+        self.end_line()
+    }
+
+    /// Serialize `msg` straight into the buffer, as a line.
+    pub(crate) fn write_json<T>(&mut self, msg: &T) -> anyhow::Result<()>
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        serde_json::to_writer(&mut self.out, msg).context("serialize outgoing message")?;
+        self.end_line()
+    }
+
+    /// How long to wait for another message before flushing, or `None` to
+    /// wait indefinitely because nothing is buffered.
+    pub(crate) fn linger(&self) -> Option<Duration> {
+        if self.buffered == 0 {
+            return None;
+        }
+        match self.policy {
+            FlushPolicy::EveryMessage | FlushPolicy::OnIdle => Some(Duration::ZERO),
+            FlushPolicy::Batch { linger, .. } => Some(linger),
+        }
+    }
+
+    pub(crate) fn flush(&mut self) -> anyhow::Result<()> {
+        self.buffered = 0;
+        self.out.flush().context("flush output")
+    }
+
+    /// Flush and hand back the writer.
+    pub(crate) fn into_inner(self) -> anyhow::Result<W> {
+        self.out
+            .into_inner()
+            .map_err(|err| anyhow!("flush output: {}", err.error()))
+    }
+
+    fn end_line(&mut self) -> anyhow::Result<()> {
+        self.out
+            .write_all(b"\n")
+            .context("write newline to output")?;
+        self.buffered += 1;
+        match self.policy {
+            FlushPolicy::EveryMessage => self.flush(),
+            FlushPolicy::Batch { messages, .. } if self.buffered >= messages => self.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Serialize the messages of `msg_out_rx` on `workers` threads, so a few
+/// large gossip diffs don't hold up the single writer. The returned channel
+/// carries them already serialized, in the order they were sent, so every
+/// destination still sees its messages in order.
+///
+/// The handle finishes once every message went out, or as soon as one
+/// fails to serialize, with its error, like the output thread does without
+/// a pool.
+pub(crate) fn serialize_pool(
+    workers: usize,
+    msg_out_rx: Receiver<Outgoing>,
+) -> (Receiver<Outgoing>, JoinHandle<anyhow::Result<()>>) {
+    let (work_tx, work_rx) = crossbeam_channel::bounded::<(u64, Outgoing)>(CHANNEL_CAPACITY);
+    let (done_tx, done_rx) = crossbeam_channel::bounded(CHANNEL_CAPACITY);
+    let (ordered_tx, ordered_rx) = crossbeam_channel::bounded(CHANNEL_CAPACITY);
+
+    let sequencer = thread::spawn(move || {
+        for (seq, msg) in (0..).zip(msg_out_rx) {
+            if work_tx.send((seq, msg)).is_err() {
+                break;
+            }
+        }
+    });
+    let workers: Vec<_> = (0..workers.max(1))
+        .map(|_| {
+            let work_rx = work_rx.clone();
+            let done_tx: Sender<(u64, anyhow::Result<Box<RawValue>>)> = done_tx.clone();
+            // Failures go to the reordering thread too, as a worker that
+            // just stopped would leave a gap holding back every later line.
+            thread::spawn(move || {
+                for (seq, msg) in work_rx {
+                    let line =
+                        serde_json::value::to_raw_value(&msg).context("serialize outgoing message");
+                    if done_tx.send((seq, line)).is_err() {
+                        break;
+                    }
+                }
+            })
+        })
+        .collect();
+    drop(done_tx);
+
+    let handle = thread::spawn(move || {
+        // Lines that overtook an earlier message, by sequence number.
+        let mut early = BinaryHeap::new();
+        let mut next = 0;
+        for (seq, line) in done_rx {
+            early.push(Reverse(Sequenced(seq, line?)));
+            while early
+                .peek()
+                .is_some_and(|Reverse(Sequenced(seq, _))| *seq == next)
+            {
+                let Reverse(Sequenced(_, line)) = early.pop().expect("just peeked");
+                if ordered_tx.send(Box::new(line) as Outgoing).is_err() {
+                    break;
+                }
+                next += 1;
+            }
+        }
+
+        sequencer
+            .join()
+            .map_err(|_| anyhow!("serialization sequencer panicked"))?;
+        for worker in workers {
+            worker
+                .join()
+                .map_err(|_| anyhow!("serialization worker panicked"))?;
+        }
+        Ok(())
+    });
+
+    (ordered_rx, handle)
+}
+
+/// A serialized message ordered by its sequence number alone.
+struct Sequenced(u64, Box<RawValue>);
+
+impl PartialEq for Sequenced {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for Sequenced {}
+
+impl PartialOrd for Sequenced {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Sequenced {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.cmp(&other.0)
+    }
+}
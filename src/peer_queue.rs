@@ -0,0 +1,202 @@
+//! Outbound queues for peers that are suspected down. Sending into a
+//! partition only floods a dead link, and replaying everything once it
+//! heals may deliver data nobody wants anymore. A [`PeerQueue`] sends
+//! straight through to healthy peers, holds messages for suspected ones
+//! until they recover, and drops those that waited past their deadline,
+//! handing them to a callback instead:
+//!
+//! ```ignore
+//! let queue = PeerQueue::new(Duration::from_secs(5))
+//!     .on_expired(|msg, ctx| { /* give up on msg */ Ok(()) });
+//! // ... on the failure detector's verdicts:
+//! self.queue.peer_status(&status, &ctx)?;
+//! // ... and from a timer:
+//! self.queue.tick(&ctx)?;
+//! ```
+//!
+//! Any failure detector will do: call [`PeerQueue::suspect`] and
+//! [`PeerQueue::recover`], e.g. on peers [`Heartbeat::silent_for`] lists,
+//! or hand gossip's [`PeerStatus`] to [`PeerQueue::peer_status`].
+//!
+//! [`Heartbeat::silent_for`]: crate::heartbeat::Heartbeat::silent_for
+//! [`PeerStatus`]: crate::gossip::PeerStatus
+
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+#[cfg(feature = "crdt-yrs")]
+use crate::gossip::PeerStatus;
+use crate::{Context, Message};
+
+/// Run on a message that expired before its peer recovered.
+pub type ExpiredCallback<P, IP> = dyn FnMut(Message<P>, &Context<IP>) -> anyhow::Result<()>;
+
+struct Queued<P> {
+    msg: Message<P>,
+    deadline: Instant,
+}
+
+/// Messages held back for suspected peers, see the [module docs](self).
+pub struct PeerQueue<P, IP> {
+    /// How long a message may wait for its peer by default.
+    ttl: Duration,
+
+    /// Most messages held per peer, if limited, the oldest expiring early.
+    capacity: Option<usize>,
+    suspected: BTreeSet<String>,
+    queued: BTreeMap<String, VecDeque<Queued<P>>>,
+    on_expired: Option<Box<ExpiredCallback<P, IP>>>,
+}
+
+impl<P, IP> PeerQueue<P, IP> {
+    /// Hold messages for up to `ttl` each.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            capacity: None,
+            suspected: BTreeSet::new(),
+            queued: BTreeMap::new(),
+            on_expired: None,
+        }
+    }
+
+    /// Hold at most `capacity` messages per peer, expiring the oldest to
+    /// make room.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = Some(capacity.max(1));
+        self
+    }
+
+    /// Run `callback` on every message that expired, instead of only
+    /// counting it in the metric `peer_queue.expired`.
+    pub fn on_expired(
+        mut self,
+        callback: impl FnMut(Message<P>, &Context<IP>) -> anyhow::Result<()> + 'static,
+    ) -> Self {
+        self.on_expired = Some(Box::new(callback));
+        self
+    }
+
+    pub fn is_suspected(&self, peer: &str) -> bool {
+        self.suspected.contains(peer)
+    }
+
+    /// Messages held for `peer`.
+    pub fn queued(&self, peer: &str) -> usize {
+        self.queued.get(peer).map_or(0, VecDeque::len)
+    }
+
+    /// Messages held for every peer.
+    pub fn len(&self) -> usize {
+        self.queued.values().map(VecDeque::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queued.values().all(VecDeque::is_empty)
+    }
+
+    /// Hold messages for `peer` from now on.
+    pub fn suspect(&mut self, peer: &str) {
+        self.suspected.insert(peer.to_string());
+    }
+}
+
+impl<P, IP> PeerQueue<P, IP>
+where
+    P: Serialize + Send + Sync + 'static,
+{
+    /// Send `msg` now if its peer is healthy, or hold it for up to the
+    /// queue's ttl.
+    pub fn send(&mut self, msg: Message<P>, ctx: &Context<IP>) -> anyhow::Result<()> {
+        let deadline = ctx.clock().now() + self.ttl;
+        self.send_until(msg, deadline, ctx)
+    }
+
+    /// Like [`PeerQueue::send`], holding `msg` until `deadline` at most.
+    pub fn send_until(
+        &mut self,
+        msg: Message<P>,
+        deadline: Instant,
+        ctx: &Context<IP>,
+    ) -> anyhow::Result<()> {
+        if !self.suspected.contains(msg.dst()) {
+            return ctx.send(msg);
+        }
+
+        let queue = self.queued.entry(msg.dst().to_string()).or_default();
+        queue.push_back(Queued { msg, deadline });
+        ctx.metrics().incr("peer_queue.queued", 1);
+        let overflow = match self.capacity {
+            Some(capacity) if queue.len() > capacity => queue.pop_front(),
+            _ => None,
+        };
+        if let Some(overflow) = overflow {
+            self.expire(overflow.msg, ctx)?;
+        }
+
+        Ok(())
+    }
+
+    /// Send what was held for `peer`, in order, now that it's back, but
+    /// expire what waited too long.
+    pub fn recover(&mut self, peer: &str, ctx: &Context<IP>) -> anyhow::Result<()> {
+        self.suspected.remove(peer);
+        let Some(queue) = self.queued.remove(peer) else {
+            return Ok(());
+        };
+        let now = ctx.clock().now();
+        for queued in queue {
+            if queued.deadline < now {
+                self.expire(queued.msg, ctx)?;
+                continue;
+            }
+            ctx.metrics().incr("peer_queue.flushed", 1);
+            ctx.send(queued.msg)?;
+        }
+
+        Ok(())
+    }
+
+    /// Follow the gossip layer's failure detector.
+    #[cfg(feature = "crdt-yrs")]
+    pub fn peer_status(&mut self, status: &PeerStatus, ctx: &Context<IP>) -> anyhow::Result<()> {
+        match status {
+            PeerStatus::Suspected(peer) => {
+                self.suspect(peer);
+                Ok(())
+            }
+            PeerStatus::Recovered(peer) => self.recover(peer, ctx),
+        }
+    }
+
+    /// Expire the messages whose deadline passed.
+    pub fn tick(&mut self, ctx: &Context<IP>) -> anyhow::Result<()> {
+        let now = ctx.clock().now();
+        let mut expired = Vec::new();
+        for queue in self.queued.values_mut() {
+            // Deadlines mostly grow along a queue, but not always, with
+            // `send_until`.
+            let (late, waiting) = queue.drain(..).partition(|queued| queued.deadline < now);
+            *queue = waiting;
+            expired.extend(late.into_iter().map(|queued: Queued<P>| queued.msg));
+        }
+        self.queued.retain(|_, queue| !queue.is_empty());
+        for msg in expired {
+            self.expire(msg, ctx)?;
+        }
+
+        Ok(())
+    }
+
+    fn expire(&mut self, msg: Message<P>, ctx: &Context<IP>) -> anyhow::Result<()> {
+        ctx.metrics().incr("peer_queue.expired", 1);
+        match &mut self.on_expired {
+            Some(on_expired) => on_expired(msg, ctx),
+            None => Ok(()),
+        }
+    }
+}
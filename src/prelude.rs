@@ -0,0 +1,10 @@
+//! The names nearly every node needs, for a single glob import:
+//!
+//! ```ignore
+//! use vorticity::prelude::*;
+//! ```
+
+pub use crate::{
+    handlers, message::RequestReply, payload, Context, Event, Init, Message, Node, Payload,
+    RequestHandler, Result, Runtime, SnapshotState,
+};
@@ -0,0 +1,126 @@
+//! Exactly-once request handling. Clients retry requests that seem lost,
+//! and the network, or [`chaos`](crate::chaos) mode, duplicates messages,
+//! so a node may get the same request twice; handling it again would e.g.
+//! append a second copy of a message to a log. With a [`ReplyCache`], the
+//! runtime remembers the reply it sent to every request and sends it again
+//! for a duplicate, without stepping the node.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use serde::Deserialize;
+use serde_json::{value::RawValue, Value};
+
+use crate::{Message, NodeId};
+
+/// What a [`ReplyCache`] knows of a request that came in.
+pub(crate) enum Seen {
+    /// The request is new, and its reply will be remembered.
+    New,
+
+    /// The request was answered with this reply before.
+    Replied(Box<RawValue>),
+
+    /// The request came in before and is yet to be answered, e.g. because
+    /// the node deferred the reply.
+    Pending,
+}
+
+#[derive(Debug)]
+struct Inner {
+    capacity: usize,
+
+    /// Replies by the sender and msg_id of their request, `None` while the
+    /// request is waiting for its reply.
+    replies: HashMap<(NodeId, usize), Option<Box<RawValue>>>,
+
+    /// The keys of `replies`, oldest first, to forget them in order.
+    order: VecDeque<(NodeId, usize)>,
+}
+
+/// The replies sent to the last requests, shared by every clone of a
+/// [`crate::Context`]. Only the last `capacity` requests are remembered.
+#[derive(Debug, Clone)]
+pub struct ReplyCache {
+    inner: Arc<Mutex<Inner>>,
+}
+
+/// The parts of an outgoing message the cache looks at.
+#[derive(Deserialize)]
+struct Envelope {
+    dest: NodeId,
+    body: EnvelopeBody,
+}
+
+#[derive(Deserialize)]
+struct EnvelopeBody {
+    in_reply_to: Option<usize>,
+}
+
+impl ReplyCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                capacity,
+                replies: HashMap::new(),
+                order: VecDeque::new(),
+            })),
+        }
+    }
+
+    /// How many requests are remembered.
+    pub fn len(&self) -> usize {
+        self.inner
+            .lock()
+            .expect("reply cache lock poisoned")
+            .replies
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Look `msg` up, starting to remember it if it's a request not seen
+    /// before.
+    pub(crate) fn begin(&self, msg: &Message<Value>) -> Seen {
+        let body = msg.body();
+        let (Some(id), None) = (body.id, body.in_reply_to) else {
+            return Seen::New;
+        };
+        let key = (NodeId::from(msg.src()), id);
+        let mut inner = self.inner.lock().expect("reply cache lock poisoned");
+        match inner.replies.get(&key) {
+            Some(Some(reply)) => return Seen::Replied(reply.clone()),
+            Some(None) => return Seen::Pending,
+            None => {}
+        }
+        inner.replies.insert(key.clone(), None);
+        inner.order.push_back(key);
+        if inner.order.len() > inner.capacity {
+            let oldest = inner.order.pop_front().expect("cache is not empty");
+            inner.replies.remove(&oldest);
+        }
+        Seen::New
+    }
+
+    /// Remember `msg`, an outgoing message, if it answers a request that is
+    /// waiting for its reply.
+    pub(crate) fn record(&self, msg: &RawValue) {
+        let Ok(Envelope {
+            dest,
+            body: EnvelopeBody {
+                in_reply_to: Some(in_reply_to),
+            },
+        }) = serde_json::from_str(msg.get())
+        else {
+            return;
+        };
+        let mut inner = self.inner.lock().expect("reply cache lock poisoned");
+        if let Some(reply @ None) = inner.replies.get_mut(&(dest, in_reply_to)) {
+            *reply = Some(msg.to_owned());
+        }
+    }
+}
@@ -1,52 +1,597 @@
-use std::{collections::HashMap, marker::PhantomData};
-
-use anyhow::Context as _;
-use serde::{de::DeserializeOwned, Serialize};
-
-use crate::{Handler, Message};
-
-type HandlerFunc<Payload> = fn(Message<Payload>, Payload) -> Option<Message<Payload>>;
-
-pub struct RpcHandler<Payload> {
-    callbacks: HashMap<usize, CallbackData<Payload>>,
-    state: PhantomData<Payload>,
-}
-
-#[derive(Debug, Clone)]
-struct CallbackData<Payload> {
-    reply_to: Message<Payload>,
-    callback: Box<HandlerFunc<Payload>>,
-}
-
-// impl<Payload> Handler for RpcHandler<Payload>
-// where
-//     Payload: Serialize + DeserializeOwned + Sync + Send + 'static,
-// {
-//     fn can_handle(&self, json: &serde_json::Value) -> bool {
-//         serde_json::from_value::<Message<Payload>>(json.clone()).is_ok()
-//     }
-
-//     fn step(
-//         &mut self,
-//         json: serde_json::Value,
-//         ctx: crate::Context<Payload>,
-//     ) -> anyhow::Result<()> {
-//         let input: Message<Payload> = serde_json::from_value(json)?;
-//         let msg_id = input
-//             .body
-//             .in_reply_to
-//             .ok_or_else(|| anyhow::anyhow!("Message reply id not specified"))?;
-//         let Some(callback) = self.callbacks.remove(&msg_id) else {
-//             anyhow::bail!("Message wasn't registered");
-//         };
-//         let reply = (callback.callback)(callback.reply_to, input.body.payload);
-//         if let Some(reply) = reply {
-//             ctx.send(reply)
-//                 .context("Sending response RPC from handler")?;
-//         }
-
-//         Ok(())
-//     }
-// }
-
-// impl<In> RpcHandler<In> {}
+//! Callbacks for the replies to requests a node sent, be it to one of
+//! Maelstrom's services like `lin-kv` or to a peer node. The simplest way
+//! is [`Context::call`], which hands the reply to a callback rather than to
+//! [`Node::handle_reply`](crate::Node::handle_reply):
+//!
+//! ```ignore
+//! let client = msg.clone();
+//! ctx.call("lin-kv", KvPayload::Read { key }, move |reply, ctx| {
+//!     let value = match reply?.body().payload { ... };
+//!     ctx.send(ctx.construct_reply(&client, Payload::ReadOk { value }))
+//! })?;
+//! ```
+//!
+//! A call that gets no reply within [`DEFAULT_CALL_TIMEOUT`], or the
+//! timeout given to [`Context::call_with_timeout`], is answered with a
+//! `Timeout` error instead. Expiry is checked whenever the node handles an
+//! event.
+//!
+//! Callbacks don't get to the node's state. Those that need it inject an
+//! event for it instead. Requests fanned out to several nodes can also be
+//! tracked by the node itself: it registers a [`CallbackInfo`] for them,
+//! and hands every reply to its [`Callbacks`] from `handle_reply`.
+//!
+//! Maelstrom's `seq-kv` service has a client of its own in [`seq_kv`].
+//!
+//! Over Maelstrom's lossy network, a request that must arrive is sent with
+//! a [`RetrySender`], which sends it again until it's acknowledged. Given
+//! an outbox, it also keeps the requests on disk until then, and sends them
+//! again after the node restarted:
+//!
+//! ```ignore
+//! let outbox = Wal::open_env(init, "outbox")?.expect("state dir is set");
+//! let retries = RetrySender::default().with_outbox(outbox, &ctx)?;
+//! ```
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    error::MaelstromError,
+    log,
+    message::{MessageSet, NodeId},
+    storage::Wal,
+    Context, Message,
+};
+
+pub mod seq_kv;
+
+/// Outbox records beyond which it's compacted, if most of them are stale.
+const OUTBOX_COMPACT_THRESHOLD: usize = 1024;
+
+/// Whether a callback expects more replies to its requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallbackStatus {
+    MoreWork,
+    Finished,
+}
+
+/// A callback run on every reply to one of its requests, with the message
+/// that led to the requests if there was one, the requests themselves and
+/// the reply.
+pub type RpcCallback<P, IP> = dyn Fn(
+    Option<&Message<P>>,
+    &mut MessageSet<P>,
+    &Message<P>,
+    Context<IP>,
+) -> anyhow::Result<CallbackStatus>;
+
+/// Requests waiting for their replies, and what to do with them.
+pub struct CallbackInfo<P, IP> {
+    /// The message that led to the requests, usually a client request that
+    /// is only answered once the replies are in.
+    unhandled_incoming_msg: Option<Message<P>>,
+    sent_msgs: MessageSet<P>,
+    callback: Box<RpcCallback<P, IP>>,
+}
+
+impl<P, IP> CallbackInfo<P, IP>
+where
+    P: Clone,
+{
+    /// Run `callback` on the replies to `sent_msgs`, on behalf of
+    /// `orig_msg`.
+    pub fn new(
+        orig_msg: Message<P>,
+        sent_msgs: MessageSet<P>,
+        callback: impl Fn(
+                Option<&Message<P>>,
+                &mut MessageSet<P>,
+                &Message<P>,
+                Context<IP>,
+            ) -> anyhow::Result<CallbackStatus>
+            + 'static,
+    ) -> Self {
+        Self {
+            unhandled_incoming_msg: Some(orig_msg),
+            sent_msgs,
+            callback: Box::new(callback),
+        }
+    }
+
+    /// Run `callback` on the replies to `sent_msgs`, for requests the node
+    /// sent on its own, like a timer-driven sync with its peers.
+    pub fn detached(
+        sent_msgs: MessageSet<P>,
+        callback: impl Fn(
+                Option<&Message<P>>,
+                &mut MessageSet<P>,
+                &Message<P>,
+                Context<IP>,
+            ) -> anyhow::Result<CallbackStatus>
+            + 'static,
+    ) -> Self {
+        Self {
+            unhandled_incoming_msg: None,
+            sent_msgs,
+            callback: Box::new(callback),
+        }
+    }
+
+    /// Whether `msg` is a reply to one of the requests, from the node or
+    /// service it was sent to.
+    pub fn matches(&self, msg: &Message<P>) -> bool {
+        self.sent_msgs.request_for(msg).is_some()
+    }
+
+    /// Run the callback on `reply`.
+    pub fn run(&mut self, reply: &Message<P>, ctx: Context<IP>) -> anyhow::Result<CallbackStatus> {
+        (self.callback)(
+            self.unhandled_incoming_msg.as_ref(),
+            &mut self.sent_msgs,
+            reply,
+            ctx,
+        )
+    }
+}
+
+/// The callbacks of a node, each dropped once it is finished.
+pub struct Callbacks<P, IP> {
+    callbacks: Vec<CallbackInfo<P, IP>>,
+}
+
+impl<P, IP> Default for Callbacks<P, IP> {
+    fn default() -> Self {
+        Self {
+            callbacks: Vec::new(),
+        }
+    }
+}
+
+impl<P, IP> Callbacks<P, IP>
+where
+    P: Clone + std::fmt::Debug,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, callback: CallbackInfo<P, IP>) {
+        self.callbacks.push(callback);
+    }
+
+    pub fn len(&self) -> usize {
+        self.callbacks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.callbacks.is_empty()
+    }
+
+    /// Run the callback waiting for `reply`, failing if there is none.
+    pub fn handle(&mut self, reply: &Message<P>, ctx: Context<IP>) -> anyhow::Result<()> {
+        let i = self
+            .callbacks
+            .iter()
+            .position(|c| c.matches(reply))
+            .ok_or_else(|| anyhow!("Reply to message we don't have: {reply:?}"))?;
+        let status = self.callbacks[i]
+            .run(reply, ctx)
+            .context("Running callback caused an error")?;
+
+        if status == CallbackStatus::Finished {
+            self.callbacks.remove(i);
+        }
+
+        Ok(())
+    }
+}
+
+/// How long [`Context::call`] waits for a reply before giving up on it.
+pub const DEFAULT_CALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Run on the reply to a request sent with [`Context::call`], or on a
+/// timeout error if none came in time.
+pub(crate) type CallCallback<IP> =
+    dyn FnOnce(Result<Message<Value>, MaelstromError>, Context<IP>) -> anyhow::Result<()> + Send;
+
+/// A call awaiting its reply.
+struct PendingCall<IP> {
+    /// The node or service the call went to, the only one that may answer.
+    dst: NodeId,
+    deadline: Instant,
+    callback: Box<CallCallback<IP>>,
+}
+
+/// Pending calls by msg_id, and their msg_ids by deadline.
+struct PendingCalls<IP> {
+    by_id: HashMap<usize, PendingCall<IP>>,
+    deadlines: BTreeSet<(Instant, usize)>,
+}
+
+/// Requests sent with [`Context::call`] awaiting their replies, shared by
+/// the clones of a node's context.
+pub(crate) struct Calls<IP> {
+    pending: Arc<Mutex<PendingCalls<IP>>>,
+}
+
+impl<IP> Clone for Calls<IP> {
+    fn clone(&self) -> Self {
+        Self {
+            pending: self.pending.clone(),
+        }
+    }
+}
+
+impl<IP> Default for Calls<IP> {
+    fn default() -> Self {
+        Self {
+            pending: Arc::new(Mutex::new(PendingCalls {
+                by_id: HashMap::new(),
+                deadlines: BTreeSet::new(),
+            })),
+        }
+    }
+}
+
+impl<IP> Calls<IP> {
+    pub(crate) fn insert(
+        &self,
+        id: usize,
+        dst: NodeId,
+        deadline: Instant,
+        callback: Box<CallCallback<IP>>,
+    ) {
+        let mut pending = self.pending.lock().expect("calls lock poisoned");
+        pending.deadlines.insert((deadline, id));
+        pending.by_id.insert(
+            id,
+            PendingCall {
+                dst,
+                deadline,
+                callback,
+            },
+        );
+    }
+
+    /// The callback waiting for `reply`, if it answers a call, from the node
+    /// or service the call went to.
+    pub(crate) fn take(&self, reply: &Message<Value>) -> Option<Box<CallCallback<IP>>> {
+        let id = reply.body().in_reply_to?;
+        let mut pending = self.pending.lock().expect("calls lock poisoned");
+        if pending.by_id.get(&id)?.dst.as_str() != reply.src() {
+            return None;
+        }
+        let call = pending.by_id.remove(&id)?;
+        pending.deadlines.remove(&(call.deadline, id));
+        Some(call.callback)
+    }
+
+    /// The callbacks of the calls whose deadline passed by `now`, which no
+    /// longer wait for their replies.
+    pub(crate) fn expire(&self, now: Instant) -> Vec<Box<CallCallback<IP>>> {
+        let mut pending = self.pending.lock().expect("calls lock poisoned");
+        let mut expired = Vec::new();
+        while let Some(&(deadline, id)) = pending.deadlines.first() {
+            if deadline > now {
+                break;
+            }
+            pending.deadlines.pop_first();
+            expired.extend(pending.by_id.remove(&id).map(|call| call.callback));
+        }
+        expired
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.pending
+            .lock()
+            .expect("calls lock poisoned")
+            .by_id
+            .len()
+    }
+}
+
+/// How a [`RetrySender`] backs off between attempts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// How long to wait for the first acknowledgement.
+    pub initial_backoff: Duration,
+
+    /// How much longer to wait after every further attempt.
+    pub multiplier: f64,
+    pub max_backoff: Duration,
+
+    /// Attempts, the first send included, before giving up.
+    pub max_attempts: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(200),
+            multiplier: 2.0,
+            max_backoff: Duration::from_secs(5),
+            max_attempts: 8,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait after the `attempt`th send, counting from 1.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.powi(attempt.saturating_sub(1) as i32);
+        self.initial_backoff
+            .mul_f64(factor.min(u32::MAX as f64))
+            .min(self.max_backoff)
+    }
+}
+
+/// Run on a request a [`RetrySender`] gave up on.
+pub type GiveUpCallback<P, IP> = dyn FnMut(Message<P>, &Context<IP>) -> anyhow::Result<()>;
+
+struct Retried<P> {
+    msg: Message<P>,
+    attempts: u32,
+    due: Instant,
+}
+
+/// What the outbox of a [`RetrySender`] holds: the requests sent, and the
+/// msg_ids of those acknowledged or given up on since.
+#[derive(Serialize, Deserialize)]
+enum OutboxRecord<P> {
+    Sent(Message<P>),
+    Settled(usize),
+}
+
+/// Requests sent again and again, backing off, until a reply acknowledges
+/// them or the [`RetryPolicy`] gives up on them. Every attempt has the same
+/// msg_id, so a reply to any of them acknowledges the request, and the
+/// receiver can recognize repeats, e.g. with a
+/// [`ReplyCache`](crate::reply_cache::ReplyCache).
+///
+/// Offer every reply to [`RetrySender::ack`] before other callbacks, and
+/// call [`RetrySender::tick`] regularly, e.g. on an injected event from
+/// [`RetrySender::spawn_ticks`].
+///
+/// With [`RetrySender::with_outbox`], every request is persisted before it's
+/// sent, so one the node crashed before it was acknowledged is sent again
+/// once the node is back, rather than lost.
+pub struct RetrySender<P, IP> {
+    policy: RetryPolicy,
+
+    /// Requests by msg_id, and their msg_ids by when they're due.
+    pending: BTreeMap<usize, Retried<P>>,
+    due: BTreeSet<(Instant, usize)>,
+    on_give_up: Option<Box<GiveUpCallback<P, IP>>>,
+    outbox: Option<Wal>,
+}
+
+impl<P, IP> Default for RetrySender<P, IP> {
+    fn default() -> Self {
+        Self::new(RetryPolicy::default())
+    }
+}
+
+impl<P, IP> RetrySender<P, IP> {
+    pub fn new(policy: RetryPolicy) -> Self {
+        Self {
+            policy,
+            pending: BTreeMap::new(),
+            due: BTreeSet::new(),
+            on_give_up: None,
+            outbox: None,
+        }
+    }
+
+    /// Run `callback` on every request given up on, instead of only logging
+    /// it in the metric `retry.gave_up`.
+    pub fn on_give_up(
+        mut self,
+        callback: impl FnMut(Message<P>, &Context<IP>) -> anyhow::Result<()> + 'static,
+    ) -> Self {
+        self.on_give_up = Some(Box::new(callback));
+        self
+    }
+
+    /// Requests not acknowledged yet.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Inject `payload()` every `interval` until the node's input ends, to
+    /// call [`RetrySender::tick`] on. A tick still queued when the next one
+    /// is due is dropped, as that one covers it.
+    pub fn spawn_ticks(
+        &self,
+        ctx: Context<IP>,
+        interval: Duration,
+        payload: impl Fn() -> IP + Send + 'static,
+    ) -> thread::JoinHandle<()>
+    where
+        IP: Sync + Send + 'static,
+    {
+        thread::spawn(move || {
+            while ctx.timers().sleep(ctx.clock(), interval) {
+                if ctx.inject_with_ttl(payload(), interval).is_err() {
+                    break;
+                }
+            }
+        })
+    }
+}
+
+impl<P, IP> RetrySender<P, IP>
+where
+    P: Clone + Serialize + Send + Sync + 'static,
+{
+    /// Persist requests to `outbox` until they're settled, and take over
+    /// those left in it by an earlier run, to be sent again on the next
+    /// [`RetrySender::tick`].
+    pub fn with_outbox(mut self, mut outbox: Wal, ctx: &Context<IP>) -> anyhow::Result<Self>
+    where
+        P: DeserializeOwned,
+    {
+        let now = ctx.clock().now();
+        for record in outbox.iter()? {
+            let record = serde_json::from_slice(&record?).context("reading outbox record")?;
+            match record {
+                OutboxRecord::Sent(msg) => {
+                    let id = msg.body().id.context("outbox request without msg_id")?;
+                    ctx.skip_msg_ids_through(id);
+                    let retried = Retried {
+                        msg,
+                        attempts: 0,
+                        due: now,
+                    };
+                    self.insert(id, retried);
+                }
+                OutboxRecord::Settled(id) => {
+                    self.remove(id);
+                }
+            }
+        }
+        if !self.pending.is_empty() {
+            log::info("recovered unacknowledged requests")
+                .field("path", outbox.path().display().to_string())
+                .field("requests", self.pending.len())
+                .emit();
+            ctx.metrics()
+                .incr("retry.recovered", self.pending.len() as u64);
+        }
+        self.outbox = Some(outbox);
+        self.compact_outbox()?;
+
+        Ok(self)
+    }
+
+    /// Send `msg`, which needs a msg_id, and keep sending it until it's
+    /// acknowledged.
+    pub fn send(&mut self, msg: Message<P>, ctx: &Context<IP>) -> anyhow::Result<()> {
+        let Some(id) = msg.body().id else {
+            bail!("retried requests need a msg_id, to recognize their replies");
+        };
+        if let Some(outbox) = &mut self.outbox {
+            outbox.append_json(&OutboxRecord::Sent(msg.clone()))?;
+            outbox.sync()?;
+        }
+        ctx.send(msg.clone())?;
+        let retried = Retried {
+            msg,
+            attempts: 1,
+            due: ctx.clock().now() + self.policy.backoff(1),
+        };
+        self.insert(id, retried);
+        Ok(())
+    }
+
+    /// Take `reply` as the acknowledgement of the request it answers, if
+    /// that is waiting for one, returning the request.
+    pub fn ack(&mut self, reply: &Message<P>) -> Option<Message<P>> {
+        let id = reply.body().in_reply_to?;
+        if self.pending.get(&id)?.msg.dst() != reply.src() {
+            return None;
+        }
+        let retried = self.remove(id)?;
+        self.settle(id);
+        Some(retried.msg)
+    }
+
+    /// Resend the requests whose backoff ran out, and give up on those out
+    /// of attempts.
+    pub fn tick(&mut self, ctx: &Context<IP>) -> anyhow::Result<()> {
+        let now = ctx.clock().now();
+        let mut due = Vec::new();
+        while let Some(&(at, id)) = self.due.first() {
+            if at > now {
+                break;
+            }
+            self.due.pop_first();
+            due.push(id);
+        }
+        for id in due {
+            let retried = self.pending.get_mut(&id).expect("due requests are pending");
+            if retried.attempts >= self.policy.max_attempts {
+                let retried = self.pending.remove(&id).expect("just found");
+                self.settle(id);
+                ctx.metrics().incr("retry.gave_up", 1);
+                if let Some(on_give_up) = &mut self.on_give_up {
+                    on_give_up(retried.msg, ctx)?;
+                }
+                continue;
+            }
+            retried.attempts += 1;
+            retried.due = now + self.policy.backoff(retried.attempts);
+            self.due.insert((retried.due, id));
+            ctx.metrics().incr("retry.resent", 1);
+            ctx.send(retried.msg.clone())?;
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, id: usize, retried: Retried<P>) {
+        let due = retried.due;
+        if let Some(replaced) = self.pending.insert(id, retried) {
+            self.due.remove(&(replaced.due, id));
+        }
+        self.due.insert((due, id));
+    }
+
+    fn remove(&mut self, id: usize) -> Option<Retried<P>> {
+        let retried = self.pending.remove(&id)?;
+        self.due.remove(&(retried.due, id));
+        Some(retried)
+    }
+
+    /// Note in the outbox that the request `id` needs no more sending. This
+    /// isn't synced: if the note is lost, the request is only sent once
+    /// more, and receivers recognize repeats anyway.
+    fn settle(&mut self, id: usize) {
+        let Some(outbox) = &mut self.outbox else {
+            return;
+        };
+        if let Err(err) = outbox
+            .append_json(&OutboxRecord::<P>::Settled(id))
+            .and_then(|_| self.compact_outbox())
+        {
+            log::warn("failed to update outbox")
+                .field("error", format!("{err:#}"))
+                .emit();
+        }
+    }
+
+    /// Drop what's settled from the outbox once it's empty, or mostly
+    /// stale and big.
+    fn compact_outbox(&mut self) -> anyhow::Result<()> {
+        let Some(outbox) = &mut self.outbox else {
+            return Ok(());
+        };
+        if self.pending.is_empty() {
+            if !outbox.is_empty() {
+                outbox.clear()?;
+            }
+            return Ok(());
+        }
+        if outbox.len() < OUTBOX_COMPACT_THRESHOLD || outbox.len() < 2 * self.pending.len() {
+            return Ok(());
+        }
+        let records = self
+            .pending
+            .values()
+            .map(|retried| serde_json::to_vec(&OutboxRecord::Sent(retried.msg.clone())))
+            .collect::<Result<Vec<_>, _>>()
+            .context("serializing outbox record")?;
+        outbox.replace(records)
+    }
+}
@@ -0,0 +1,144 @@
+//! A client of Maelstrom's `seq-kv` service, a sequentially consistent
+//! key-value store. Every operation is a [`Context::call`] whose callback
+//! gets the outcome, or the error the service answered with:
+//!
+//! ```ignore
+//! let kv = SeqKv::new();
+//! let client = msg.clone();
+//! kv.read(&ctx, "counter", move |value, ctx| {
+//!     let value = match value {
+//!         Ok(value) => value.as_u64().unwrap_or(0),
+//!         Err(err) if err.code == ErrorCode::KeyDoesNotExist => 0,
+//!         Err(err) => return Err(err.into()),
+//!     };
+//!     ctx.send(ctx.construct_reply(&client, Payload::ReadOk { value }))
+//! })?;
+//! ```
+//!
+//! Being only sequentially consistent, a read may miss writes that already
+//! completed elsewhere. A read-modify-write loop on [`SeqKv::cas`], e.g. to
+//! add to a counter, is safe anyway, as the compare fails on stale values.
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{error::MaelstromError, message::ServiceId, Context, Message};
+
+/// The requests `seq-kv` takes and its replies to them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SeqKvPayload {
+    Read {
+        key: Value,
+    },
+    ReadOk {
+        value: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    WriteOk,
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        create_if_not_exists: bool,
+    },
+    CasOk,
+}
+
+/// A handle on the `seq-kv` service, see the [module docs](self).
+#[derive(Debug, Clone, Default)]
+pub struct SeqKv;
+
+impl SeqKv {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Read the value under `key`. Keys never written are answered with
+    /// `KeyDoesNotExist`.
+    pub fn read<IP>(
+        &self,
+        ctx: &Context<IP>,
+        key: impl Into<Value>,
+        callback: impl FnOnce(Result<Value, MaelstromError>, Context<IP>) -> anyhow::Result<()>
+            + Send
+            + 'static,
+    ) -> anyhow::Result<()> {
+        let request = SeqKvPayload::Read { key: key.into() };
+        self.call(ctx, request, move |reply, ctx| match reply {
+            Ok(SeqKvPayload::ReadOk { value }) => callback(Ok(value), ctx),
+            Ok(other) => bail!("unexpected reply to read: {other:?}"),
+            Err(err) => callback(Err(err), ctx),
+        })
+    }
+
+    /// Store `value` under `key`.
+    pub fn write<IP>(
+        &self,
+        ctx: &Context<IP>,
+        key: impl Into<Value>,
+        value: impl Into<Value>,
+        callback: impl FnOnce(Result<(), MaelstromError>, Context<IP>) -> anyhow::Result<()>
+            + Send
+            + 'static,
+    ) -> anyhow::Result<()> {
+        let request = SeqKvPayload::Write {
+            key: key.into(),
+            value: value.into(),
+        };
+        self.call(ctx, request, move |reply, ctx| match reply {
+            Ok(SeqKvPayload::WriteOk) => callback(Ok(()), ctx),
+            Ok(other) => bail!("unexpected reply to write: {other:?}"),
+            Err(err) => callback(Err(err), ctx),
+        })
+    }
+
+    /// Replace the value under `key` with `to` if it is still `from`,
+    /// failing with `PreconditionFailed` otherwise. A missing key fails with
+    /// `KeyDoesNotExist`, unless `create_if_not_exists`, which stores `to`.
+    pub fn cas<IP>(
+        &self,
+        ctx: &Context<IP>,
+        key: impl Into<Value>,
+        from: impl Into<Value>,
+        to: impl Into<Value>,
+        create_if_not_exists: bool,
+        callback: impl FnOnce(Result<(), MaelstromError>, Context<IP>) -> anyhow::Result<()>
+            + Send
+            + 'static,
+    ) -> anyhow::Result<()> {
+        let request = SeqKvPayload::Cas {
+            key: key.into(),
+            from: from.into(),
+            to: to.into(),
+            create_if_not_exists,
+        };
+        self.call(ctx, request, move |reply, ctx| match reply {
+            Ok(SeqKvPayload::CasOk) => callback(Ok(()), ctx),
+            Ok(other) => bail!("unexpected reply to cas: {other:?}"),
+            Err(err) => callback(Err(err), ctx),
+        })
+    }
+
+    fn call<IP>(
+        &self,
+        ctx: &Context<IP>,
+        request: SeqKvPayload,
+        on_reply: impl FnOnce(Result<SeqKvPayload, MaelstromError>, Context<IP>) -> anyhow::Result<()>
+            + Send
+            + 'static,
+    ) -> anyhow::Result<()> {
+        ctx.call(
+            ServiceId::SeqKv,
+            request,
+            move |reply: Result<Message<SeqKvPayload>, _>, ctx| {
+                on_reply(reply.map(|reply| reply.into_body().payload), ctx)
+            },
+        )
+    }
+}
@@ -0,0 +1,119 @@
+//! Session guarantees for clients of replicas that converge asynchronously,
+//! like ones fed by gossip. A client writing to one node and then reading
+//! from a lagging one would not see its own write, or a read could go back
+//! in time; [`Sessions`] keeps a version vector per client to rule out both:
+//!
+//! - read-your-writes: a write's reply raises its client's session to the
+//!   version the write was assigned, with [`Sessions::wrote`],
+//! - monotonic reads: a read raises the session to the version it saw.
+//!
+//! A read is served once the node's own version covers the session, and
+//! held back until then:
+//!
+//! ```ignore
+//! if let Some(read) = self.sessions.read(msg.src(), msg, &self.version) {
+//!     ctx.send(ctx.construct_reply(&read, self.value()))?;
+//! }
+//! // ... merged gossip moved self.version on:
+//! for read in self.sessions.caught_up(&self.version) {
+//!     ctx.send(ctx.construct_reply(&read, self.value()))?;
+//! }
+//! ```
+//!
+//! Sessions are kept by the node clients talk to, so they hold for clients
+//! that stick to one node, as Maelstrom's do. Versions travel to other nodes
+//! with the gossip that carries the writes.
+
+use std::collections::HashMap;
+
+use crate::causal::VersionVector;
+
+/// The version vector of every client's session, and the reads waiting for
+/// the node to catch up with theirs. `R` is whatever it takes to answer a
+/// read later, usually its request.
+#[derive(Debug)]
+pub struct Sessions<R> {
+    sessions: HashMap<String, VersionVector>,
+    waiting: Vec<(String, R)>,
+}
+
+impl<R> Default for Sessions<R> {
+    fn default() -> Self {
+        Self {
+            sessions: HashMap::new(),
+            waiting: Vec::new(),
+        }
+    }
+}
+
+impl<R> Sessions<R> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// What `client` has written or read so far, if it did anything.
+    pub fn session(&self, client: &str) -> Option<&VersionVector> {
+        self.sessions.get(client)
+    }
+
+    /// How many reads wait for the node to catch up.
+    pub fn waiting(&self) -> usize {
+        self.waiting.len()
+    }
+
+    /// Note that a write of `client` was assigned `version`, e.g. the
+    /// node's version right after applying it. Reads of the client only see
+    /// versions covering it from now on.
+    pub fn wrote(&mut self, client: &str, version: &VersionVector) {
+        self.advance(client, version);
+    }
+
+    /// Whether a node at `version` may answer a read of `client`.
+    pub fn covers(&self, client: &str, version: &VersionVector) -> bool {
+        self.sessions
+            .get(client)
+            .is_none_or(|session| version.dominates(session))
+    }
+
+    /// Take a read of `client` on a node at `version`, handing it back if it
+    /// can be answered now and holding it back until [`Sessions::caught_up`]
+    /// releases it otherwise.
+    pub fn read(&mut self, client: &str, read: R, version: &VersionVector) -> Option<R> {
+        if !self.covers(client, version) {
+            self.waiting.push((client.to_string(), read));
+            return None;
+        }
+        self.advance(client, version);
+        Some(read)
+    }
+
+    /// The reads a node now at `version` can answer, in the order they came
+    /// in.
+    pub fn caught_up(&mut self, version: &VersionVector) -> Vec<R> {
+        let (ready, waiting) = std::mem::take(&mut self.waiting)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(client, _)| self.covers(client, version));
+        self.waiting = waiting;
+        ready
+            .into_iter()
+            .map(|(client, read)| {
+                self.advance(&client, version);
+                read
+            })
+            .collect()
+    }
+
+    /// Forget `client`'s session, and drop its waiting reads, e.g. once it
+    /// went away.
+    pub fn end(&mut self, client: &str) {
+        self.sessions.remove(client);
+        self.waiting.retain(|(waiting, _)| waiting != client);
+    }
+
+    fn advance(&mut self, client: &str, version: &VersionVector) {
+        self.sessions
+            .entry(client.to_string())
+            .or_default()
+            .merge(version);
+    }
+}
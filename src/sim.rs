@@ -0,0 +1,674 @@
+//! Deterministic discrete-event simulation of a cluster of nodes.
+//!
+//! Nodes exchange messages over a virtual network in virtual time, and every
+//! ordering decision is drawn from a seeded RNG, so a run is reproduced
+//! exactly by its seed. Messages between nodes go through configurable
+//! [`Faults`] and named [`Partition`]s, nodes can be frozen by a [`Pause`]
+//! or have their clocks skewed, and [`nemesis`] draws all of these at random.
+//! Every node's [`Context::clock`] is a [`ManualClock`] following virtual
+//! time, so timeouts and timestamps are as reproducible as the rest. Timers
+//! have to be registered with [`Simulation::every`]; threads spawned by the
+//! nodes themselves run on their own and break determinism.
+
+pub mod history;
+pub mod nemesis;
+pub mod replay;
+pub mod scenario;
+pub mod trace;
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BinaryHeap, HashMap},
+    ops::{Range, RangeInclusive},
+    sync::{atomic::AtomicUsize, Arc},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context as _};
+use crossbeam_channel::Receiver;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use self::{
+    history::History,
+    trace::{Recorded, Trace, TraceEvent},
+};
+use crate::{
+    clock::ManualClock,
+    message::{mix_seed, ToEvent},
+    step_input, Context, Init, Message, Node,
+};
+
+type Outgoing = Box<dyn erased_serde::Serialize + Send + Sync>;
+
+/// Distribution of the one-way delay of messages.
+#[derive(Debug, Clone)]
+pub enum Delay {
+    Fixed(Duration),
+    Uniform(RangeInclusive<Duration>),
+
+    /// Exponentially distributed around `mean`, giving a long tail of slow
+    /// messages.
+    Exponential {
+        mean: Duration,
+    },
+}
+
+impl Default for Delay {
+    fn default() -> Self {
+        Delay::Uniform(Duration::from_millis(1)..=Duration::from_millis(5))
+    }
+}
+
+impl Delay {
+    fn validate(&self) -> anyhow::Result<()> {
+        match self {
+            Delay::Uniform(range) if range.is_empty() => {
+                bail!("uniform delay range {range:?} is empty")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn sample(&self, rng: &mut StdRng) -> Duration {
+        match self {
+            Delay::Fixed(delay) => *delay,
+            Delay::Uniform(range) => rng.gen_range(range.clone()),
+            Delay::Exponential { mean } => {
+                let u: f64 = rng.gen_range(f64::EPSILON..1.0);
+                mean.mul_f64(-u.ln())
+            }
+        }
+    }
+}
+
+/// How the simulated network misbehaves for messages between nodes.
+/// Messages from and to clients are always delivered.
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    /// Probability of a message being lost.
+    pub drop: f64,
+
+    /// Probability of a message being delivered twice, with independent
+    /// delays.
+    pub duplicate: f64,
+
+    /// Probability of a message being held back by an extra delay, letting
+    /// later messages overtake it.
+    pub reorder: f64,
+    pub delay: Delay,
+}
+
+impl Faults {
+    /// Fails unless every probability is between 0 and 1 and the delay can
+    /// be drawn from.
+    fn validate(&self) -> anyhow::Result<()> {
+        let probabilities = [
+            ("drop", self.drop),
+            ("duplicate", self.duplicate),
+            ("reorder", self.reorder),
+        ];
+        for (name, value) in probabilities {
+            if !(0.0..=1.0).contains(&value) {
+                bail!("{name} must be a probability between 0 and 1, got {value}");
+            }
+        }
+        self.delay.validate()
+    }
+}
+
+/// Groups of nodes that can't talk across group boundaries during a window
+/// of virtual time. Nodes not in any group can still reach everyone.
+#[derive(Debug, Clone)]
+pub struct Partition {
+    pub groups: Vec<Vec<String>>,
+    pub window: Range<Duration>,
+}
+
+impl Partition {
+    pub fn new(groups: &[&[&str]], window: Range<Duration>) -> Self {
+        Self {
+            groups: groups
+                .iter()
+                .map(|group| group.iter().map(|n| n.to_string()).collect())
+                .collect(),
+            window,
+        }
+    }
+
+    fn separates(&self, at: Duration, a: &str, b: &str) -> bool {
+        if !self.window.contains(&at) {
+            return false;
+        }
+        let group_of = |node: &str| self.groups.iter().position(|g| g.iter().any(|n| n == node));
+        match (group_of(a), group_of(b)) {
+            (Some(a), Some(b)) => a != b,
+            _ => false,
+        }
+    }
+}
+
+/// A node frozen during a window of virtual time: whatever would happen to
+/// it then, deliveries and timers alike, happens when the window ends.
+#[derive(Debug, Clone)]
+pub struct Pause {
+    pub node: String,
+    pub window: Range<Duration>,
+}
+
+/// A node whose clock runs `rate` times as fast as virtual time during a
+/// window, stretching or squeezing both the intervals of
+/// [`Simulation::every`] and the time its [`Context::clock`] tells.
+#[derive(Debug, Clone)]
+pub struct ClockSkew {
+    pub node: String,
+    pub rate: f64,
+    pub window: Range<Duration>,
+}
+
+/// Counts of what the faults did to messages between nodes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FaultStats {
+    pub sent: u64,
+    pub dropped: u64,
+    pub partitioned: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+}
+
+/// Something that happens to a node at a point in virtual time.
+enum Action<IP> {
+    Deliver(Message<Value>),
+
+    /// Inject the payload, unless the node's clock is past the deadline by
+    /// then.
+    Inject(IP, Option<Instant>),
+
+    /// Inject the payload, then again every `interval`.
+    Tick {
+        payload: IP,
+        interval: Duration,
+    },
+}
+
+struct Scheduled<IP> {
+    at: Duration,
+
+    /// Breaks ties between actions scheduled at the same time, in the order
+    /// they were scheduled.
+    seq: u64,
+    node: String,
+    action: Action<IP>,
+}
+
+impl<IP> PartialEq for Scheduled<IP> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.seq) == (other.at, other.seq)
+    }
+}
+
+impl<IP> Eq for Scheduled<IP> {}
+
+impl<IP> PartialOrd for Scheduled<IP> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<IP> Ord for Scheduled<IP> {
+    /// Reversed, so the max-heap pops the earliest action first.
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.at, other.seq).cmp(&(self.at, self.seq))
+    }
+}
+
+struct SimNode<N, IP> {
+    node: N,
+    ctx: Context<IP>,
+
+    /// The node's clock, and the virtual time it was last brought up to.
+    clock: ManualClock,
+    clock_synced: Duration,
+    outgoing: Receiver<Outgoing>,
+    injected: Receiver<ToEvent<IP>>,
+}
+
+impl<N, IP> SimNode<N, IP>
+where
+    IP: Clone + Send + 'static,
+{
+    /// Initialize `node_id`, seeded the same way in every run with `seed`.
+    fn init<S, P>(seed: u64, node_id: &str, node_ids: &[String], state: S) -> anyhow::Result<Self>
+    where
+        N: Node<S, P, IP>,
+    {
+        let (msg_in_tx, injected) = crossbeam_channel::unbounded();
+        let (msg_out_tx, outgoing) = crossbeam_channel::unbounded();
+        let init = Init {
+            node_id: node_id.to_string(),
+            node_ids: node_ids.to_vec(),
+            extensions: Default::default(),
+        };
+        let clock = ManualClock::new();
+        let ctx = Context::new(msg_in_tx, msg_out_tx, Arc::new(AtomicUsize::new(0)))
+            .with_seed(mix_seed(seed, node_id))
+            .with_init(&init)
+            .with_clock(Arc::new(clock.clone()));
+        let node = N::from_init(state, &init, ctx.clone())
+            .with_context(|| format!("initializing {node_id}"))?;
+        Ok(Self {
+            node,
+            ctx,
+            clock,
+            clock_synced: Duration::ZERO,
+            outgoing,
+            injected,
+        })
+    }
+
+    /// Drop whatever the node sent or injected.
+    fn discard_output(&self) {
+        self.outgoing.try_iter().for_each(drop);
+        self.injected.try_iter().for_each(drop);
+    }
+
+    /// Bring the node's clock up to virtual time `at`, as it ran at `rate`
+    /// since it was last brought up.
+    fn sync_clock(&mut self, at: Duration, rate: f64) {
+        let passed = at.saturating_sub(self.clock_synced);
+        self.clock.advance(passed.mul_f64(rate));
+        self.clock_synced = at;
+    }
+
+    /// Hand a recorded event to the node, the same way the event loop does.
+    fn handle<S, P>(&mut self, event: Recorded<IP>) -> anyhow::Result<()>
+    where
+        P: DeserializeOwned + Send + 'static,
+        N: Node<S, P, IP>,
+    {
+        let input = match event {
+            Recorded::Message(msg) => ToEvent::Message(msg),
+            Recorded::Injected(payload) => ToEvent::Injected(payload),
+        };
+        step_input::<N, S, P, IP>(&mut self.node, &self.ctx, input)?;
+
+        Ok(())
+    }
+}
+
+/// A simulated cluster of `N` nodes, fed by simulated clients.
+pub struct Simulation<S, P, IP, N> {
+    seed: u64,
+    rng: StdRng,
+    now: Duration,
+    seq: u64,
+    faults: Faults,
+    partitions: HashMap<String, Partition>,
+    pauses: Vec<Pause>,
+    skews: Vec<ClockSkew>,
+    stats: FaultStats,
+    nodes: BTreeMap<String, SimNode<N, IP>>,
+    queue: BinaryHeap<Scheduled<IP>>,
+
+    /// Messages nodes sent to anything that isn't a node, i.e. clients.
+    client_inbox: Vec<Message<Value>>,
+    history: History,
+    trace: Option<Trace<IP>>,
+    next_client_msg_id: usize,
+    _marker: std::marker::PhantomData<fn(S, P)>,
+}
+
+impl<S, P, IP, N> Simulation<S, P, IP, N>
+where
+    P: DeserializeOwned + Serialize + Send + 'static,
+    IP: Clone + Send + 'static,
+    N: Node<S, P, IP>,
+{
+    /// Initialize nodes `n0` through `n{count - 1}`, each with the state
+    /// returned by `state`.
+    pub fn new(seed: u64, count: usize, mut state: impl FnMut(&str) -> S) -> anyhow::Result<Self> {
+        let node_ids = (0..count).map(|i| format!("n{i}")).collect::<Vec<_>>();
+        let mut nodes = BTreeMap::new();
+        for node_id in &node_ids {
+            let node = SimNode::init(seed, node_id, &node_ids, state(node_id))?;
+            nodes.insert(node_id.clone(), node);
+        }
+
+        let mut sim = Self {
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            now: Duration::ZERO,
+            seq: 0,
+            faults: Faults::default(),
+            partitions: HashMap::new(),
+            pauses: Vec::new(),
+            skews: Vec::new(),
+            stats: FaultStats::default(),
+            nodes,
+            queue: BinaryHeap::new(),
+            client_inbox: Vec::new(),
+            history: History::default(),
+            trace: None,
+            next_client_msg_id: 0,
+            _marker: std::marker::PhantomData,
+        };
+        // Anything sent or injected during initialization goes out first.
+        for node_id in node_ids {
+            sim.flush(&node_id)?;
+        }
+
+        Ok(sim)
+    }
+
+    /// One-way delay of every message, drawn uniformly from `latency`,
+    /// which mustn't be empty.
+    pub fn with_latency(mut self, latency: RangeInclusive<Duration>) -> anyhow::Result<Self> {
+        let delay = Delay::Uniform(latency);
+        delay.validate()?;
+        self.faults.delay = delay;
+        Ok(self)
+    }
+
+    /// Record every event handed to a node, for [`replay::Replay`].
+    pub fn with_trace(mut self) -> Self {
+        let node_ids = self.nodes.keys().cloned().collect();
+        self.trace = Some(Trace::new(self.seed, node_ids));
+        self
+    }
+
+    /// The events recorded so far, if recording is on.
+    pub fn trace(&self) -> Option<&Trace<IP>> {
+        self.trace.as_ref()
+    }
+
+    /// Misbehave as `faults` say. Their probabilities must be between 0 and
+    /// 1, and their delay range, if uniform, mustn't be empty.
+    pub fn with_faults(mut self, faults: Faults) -> anyhow::Result<Self> {
+        faults.validate()?;
+        self.faults = faults;
+        Ok(self)
+    }
+
+    /// Add a partition under `name`, replacing any previous one by that name.
+    pub fn partition(&mut self, name: &str, partition: Partition) {
+        self.partitions.insert(name.to_string(), partition);
+    }
+
+    /// Remove the partition named `name` right away, whatever its window.
+    pub fn heal(&mut self, name: &str) -> Option<Partition> {
+        self.partitions.remove(name)
+    }
+
+    pub fn pause(&mut self, pause: Pause) {
+        self.pauses.push(pause);
+    }
+
+    /// Skew the clock of a node. Its rate must be positive and finite.
+    pub fn skew_clock(&mut self, skew: ClockSkew) -> anyhow::Result<()> {
+        if !(skew.rate.is_finite() && skew.rate > 0.0) {
+            bail!(
+                "clock rate of {} must be positive, got {}",
+                skew.node,
+                skew.rate
+            );
+        }
+        self.skews.push(skew);
+        Ok(())
+    }
+
+    pub fn fault_stats(&self) -> FaultStats {
+        self.stats
+    }
+
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    pub fn node_ids(&self) -> impl Iterator<Item = &str> {
+        self.nodes.keys().map(String::as_str)
+    }
+
+    pub fn node(&self, node_id: &str) -> Option<&N> {
+        self.nodes.get(node_id).map(|n| &n.node)
+    }
+
+    /// Inject `payload` into every node every `interval`, starting at a
+    /// random phase within the first interval.
+    pub fn every(&mut self, interval: Duration, payload: IP) {
+        let node_ids = self.nodes.keys().cloned().collect::<Vec<_>>();
+        for node_id in node_ids {
+            let at = self.now + interval.mul_f64(self.rng.gen_range(0.0..1.0));
+            let action = Action::Tick {
+                payload: payload.clone(),
+                interval,
+            };
+            self.schedule(at, node_id, action);
+        }
+    }
+
+    /// Inject `payload` into a single node at the current time.
+    pub fn inject(&mut self, node_id: &str, payload: IP) {
+        self.schedule(self.now, node_id.to_string(), Action::Inject(payload, None));
+    }
+
+    /// Send a request from client `client` to node `dst`, returning the sent
+    /// message so replies can be matched against it.
+    pub fn request(&mut self, client: &str, dst: &str, payload: P) -> anyhow::Result<Message<P>> {
+        self.next_client_msg_id += 1;
+        let msg = Message::builder()
+            .src(client)
+            .dst(dst)
+            .with_id(self.next_client_msg_id)
+            .payload(payload)
+            .build()?;
+        let raw = serde_json::to_value(&msg)
+            .and_then(serde_json::from_value)
+            .context("serializing client request")?;
+        let body = serde_json::to_value(msg.body()).context("serializing client request")?;
+        self.history
+            .invoke(client, dst, self.next_client_msg_id, body, self.now);
+        let at = self.now + self.latency();
+        self.schedule(at, dst.to_string(), Action::Deliver(raw));
+
+        Ok(msg)
+    }
+
+    /// Every request made through [`Simulation::request`], and its reply.
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    /// Every message nodes sent to clients whose payload parses as `R`.
+    pub fn client_messages<R: DeserializeOwned>(&self) -> Vec<Message<R>> {
+        self.client_inbox
+            .iter()
+            .filter_map(|msg| serde_json::from_value(serde_json::to_value(msg).ok()?).ok())
+            .collect()
+    }
+
+    /// The payload of the reply to `request`, if it arrived yet.
+    pub fn reply_to<R: DeserializeOwned>(&self, request: &Message<P>) -> Option<R> {
+        let id = request.body().id?;
+        self.client_messages::<R>()
+            .into_iter()
+            .find(|msg| msg.dst() == request.src() && msg.body().in_reply_to == Some(id))
+            .map(|msg| msg.into_body().payload)
+    }
+
+    /// Process the earliest pending action, returning false once there is
+    /// nothing left to do.
+    pub fn step(&mut self) -> anyhow::Result<bool> {
+        let Some(Scheduled {
+            at, node, action, ..
+        }) = self.queue.pop()
+        else {
+            return Ok(false);
+        };
+        self.now = at;
+        if let Some(resume) = self.paused_until(&node, at) {
+            self.schedule(resume, node, action);
+            return Ok(true);
+        }
+        let rate = self.clock_rate(&node, at);
+        let sim_node = self
+            .nodes
+            .get_mut(&node)
+            .with_context(|| format!("no such node {node}"))?;
+        sim_node.sync_clock(at, rate);
+        if let Action::Inject(_, Some(deadline)) = &action {
+            if sim_node.ctx.clock().now() > *deadline {
+                sim_node.ctx.metrics().incr("runtime.injected_expired", 1);
+                return Ok(true);
+            }
+        }
+        let event = match action {
+            Action::Deliver(msg) => Recorded::Message(msg),
+            Action::Inject(payload, _) => Recorded::Injected(payload),
+            Action::Tick { payload, interval } => {
+                let next = Action::Tick {
+                    payload: payload.clone(),
+                    interval,
+                };
+                let rate = self.clock_rate(&node, at);
+                self.schedule(at + interval.div_f64(rate), node.clone(), next);
+                Recorded::Injected(payload)
+            }
+        };
+        if let Some(trace) = &mut self.trace {
+            trace.push(TraceEvent {
+                at,
+                node: node.clone(),
+                event: event.clone(),
+            });
+        }
+        let sim_node = self
+            .nodes
+            .get_mut(&node)
+            .with_context(|| format!("no such node {node}"))?;
+        sim_node
+            .handle(event)
+            .with_context(|| format!("{node} failed at {at:?}"))?;
+        self.flush(&node)?;
+
+        Ok(true)
+    }
+
+    /// Run every action scheduled up to and including virtual time `until`.
+    pub fn run_until(&mut self, until: Duration) -> anyhow::Result<()> {
+        while self.queue.peek().is_some_and(|next| next.at <= until) {
+            self.step()?;
+        }
+        self.now = self.now.max(until);
+        Ok(())
+    }
+
+    /// Run for `duration` of virtual time.
+    pub fn run_for(&mut self, duration: Duration) -> anyhow::Result<()> {
+        self.run_until(self.now + duration)
+    }
+
+    /// Schedule whatever `node_id` sent or injected while handling an action.
+    fn flush(&mut self, node_id: &str) -> anyhow::Result<()> {
+        let sim_node = &self.nodes[node_id];
+        let sent = sim_node
+            .outgoing
+            .try_iter()
+            .map(|msg| {
+                serde_json::to_value(&msg)
+                    .and_then(serde_json::from_value::<Message<Value>>)
+                    .context("nodes only send maelstrom messages")
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let injected = sim_node
+            .injected
+            .try_iter()
+            .filter_map(|event| match event {
+                ToEvent::Injected(payload) => Some(Action::Inject(payload, None)),
+                ToEvent::InjectedUntil(payload, deadline) => {
+                    Some(Action::Inject(payload, Some(deadline)))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        for action in injected {
+            self.schedule(self.now, node_id.to_string(), action);
+        }
+        for msg in sent {
+            if self.nodes.contains_key(msg.dst()) {
+                self.transmit(msg);
+            } else {
+                self.history
+                    .complete(msg.dst(), &serde_json::to_value(msg.body())?, self.now);
+                self.client_inbox.push(msg);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Put a message between nodes on the faulty network.
+    fn transmit(&mut self, msg: Message<Value>) {
+        self.stats.sent += 1;
+        if self
+            .partitions
+            .values()
+            .any(|p| p.separates(self.now, msg.src(), msg.dst()))
+        {
+            self.stats.partitioned += 1;
+            return;
+        }
+        if self.rng.gen_bool(self.faults.drop) {
+            self.stats.dropped += 1;
+            return;
+        }
+
+        let copies = if self.rng.gen_bool(self.faults.duplicate) {
+            self.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let mut delay = self.latency();
+            if self.rng.gen_bool(self.faults.reorder) {
+                self.stats.reordered += 1;
+                delay += self.latency() * 10;
+            }
+            let at = self.now + delay;
+            self.schedule(at, msg.dst().to_string(), Action::Deliver(msg.clone()));
+        }
+    }
+
+    /// When `node` resumes, if it is paused at `at`.
+    fn paused_until(&self, node: &str, at: Duration) -> Option<Duration> {
+        self.pauses
+            .iter()
+            .filter(|p| p.node == node && p.window.contains(&at))
+            .map(|p| p.window.end)
+            .max()
+    }
+
+    fn clock_rate(&self, node: &str, at: Duration) -> f64 {
+        self.skews
+            .iter()
+            .filter(|s| s.node == node && s.window.contains(&at))
+            .map(|s| s.rate)
+            .product()
+    }
+
+    fn latency(&mut self) -> Duration {
+        self.faults.delay.sample(&mut self.rng)
+    }
+
+    fn schedule(&mut self, at: Duration, node: String, action: Action<IP>) {
+        self.seq += 1;
+        self.queue.push(Scheduled {
+            at,
+            seq: self.seq,
+            node,
+            action,
+        });
+    }
+}
@@ -0,0 +1,526 @@
+//! Client-observed histories of simulator runs, and consistency checkers
+//! over them.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    time::Duration,
+};
+
+use anyhow::bail;
+use serde_json::Value;
+
+/// A client request and, if it arrived, the reply to it.
+#[derive(Debug, Clone)]
+pub struct Operation {
+    pub client: String,
+    pub node: String,
+    pub msg_id: usize,
+
+    /// Body of the request, including its `type`.
+    pub request: Value,
+    pub invoked: Duration,
+
+    /// Body of the reply and when it was sent, if the operation completed.
+    pub reply: Option<Value>,
+    pub completed: Option<Duration>,
+}
+
+impl Operation {
+    fn request_type(&self) -> Option<&str> {
+        self.request.get("type")?.as_str()
+    }
+
+    fn reply_type(&self) -> Option<&str> {
+        self.reply.as_ref()?.get("type")?.as_str()
+    }
+
+    /// Whether this operation completed before `other` was invoked, i.e. it
+    /// must be ordered first in any linearization.
+    fn precedes(&self, other: &Operation) -> bool {
+        self.completed.is_some_and(|done| done < other.invoked)
+    }
+}
+
+/// Every client operation of a simulation, in invocation order.
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    ops: Vec<Operation>,
+
+    /// Index into `ops` of each pending request, by client and msg id.
+    pending: HashMap<(String, usize), usize>,
+}
+
+impl History {
+    pub fn operations(&self) -> &[Operation] {
+        &self.ops
+    }
+
+    pub(crate) fn invoke(
+        &mut self,
+        client: &str,
+        node: &str,
+        msg_id: usize,
+        request: Value,
+        at: Duration,
+    ) {
+        self.pending
+            .insert((client.to_string(), msg_id), self.ops.len());
+        self.ops.push(Operation {
+            client: client.to_string(),
+            node: node.to_string(),
+            msg_id,
+            request,
+            invoked: at,
+            reply: None,
+            completed: None,
+        });
+    }
+
+    /// Record the reply `body` a client got; anything that doesn't answer a
+    /// pending request is ignored.
+    pub(crate) fn complete(&mut self, client: &str, body: &Value, at: Duration) {
+        let Some(in_reply_to) = body.get("in_reply_to").and_then(Value::as_u64) else {
+            return;
+        };
+        let Some(i) = self
+            .pending
+            .remove(&(client.to_string(), in_reply_to as usize))
+        else {
+            return;
+        };
+        self.ops[i].reply = Some(body.clone());
+        self.ops[i].completed = Some(at);
+    }
+}
+
+/// An operation on a single register, as understood by [`check_register`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegisterOp {
+    /// A read that returned the value, `None` if the register was unset.
+    Read(Option<Value>),
+    Write(Value),
+
+    /// A compare-and-set, and whether it reported success.
+    Cas {
+        from: Value,
+        to: Value,
+        ok: bool,
+    },
+}
+
+/// Check that the operations on every register are linearizable, in the
+/// style of Knossos: search for a total order consistent with real time in
+/// which every operation sees the effects of the ones before it.
+///
+/// `interpret` maps an operation to the key of the register it touches and
+/// what it did, skipping operations it returns `None` for. Operations that
+/// never completed may or may not have taken effect.
+pub fn check_register(
+    history: &History,
+    interpret: impl Fn(&Operation) -> Option<(String, RegisterOp)>,
+) -> anyhow::Result<()> {
+    let mut registers: BTreeMap<String, Vec<(&Operation, RegisterOp)>> = BTreeMap::new();
+    for op in history.operations() {
+        if let Some((key, register_op)) = interpret(op) {
+            // A read without a reply says nothing.
+            if op.completed.is_none() && matches!(register_op, RegisterOp::Read(_)) {
+                continue;
+            }
+            registers.entry(key).or_default().push((op, register_op));
+        }
+    }
+
+    for (key, ops) in registers {
+        let mut search = Linearization {
+            ops: &ops,
+            linearized: vec![false; ops.len()],
+            failed: HashSet::new(),
+        };
+        if !search.run(None) {
+            bail!(
+                "operations on register {key} are not linearizable: {:?}",
+                ops.iter().map(|(_, op)| op).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Depth-first search for a linearization of one register's operations.
+struct Linearization<'a> {
+    ops: &'a [(&'a Operation, RegisterOp)],
+    linearized: Vec<bool>,
+
+    /// Configurations already known to be dead ends.
+    failed: HashSet<(Vec<bool>, String)>,
+}
+
+impl Linearization<'_> {
+    fn run(&mut self, state: Option<Value>) -> bool {
+        let done = self
+            .ops
+            .iter()
+            .zip(&self.linearized)
+            .all(|((op, _), &linearized)| linearized || op.completed.is_none());
+        if done {
+            return true;
+        }
+        let config = (self.linearized.clone(), format!("{state:?}"));
+        if self.failed.contains(&config) {
+            return false;
+        }
+
+        for i in 0..self.ops.len() {
+            if self.linearized[i] || !self.is_minimal(i) {
+                continue;
+            }
+            let Some(next) = apply(&state, &self.ops[i].1) else {
+                continue;
+            };
+            self.linearized[i] = true;
+            if self.run(next) {
+                return true;
+            }
+            self.linearized[i] = false;
+        }
+
+        self.failed.insert(config);
+        false
+    }
+
+    /// Whether no other pending operation has to come before operation `i`.
+    fn is_minimal(&self, i: usize) -> bool {
+        let (op, _) = &self.ops[i];
+        self.ops
+            .iter()
+            .zip(&self.linearized)
+            .all(|((other, _), &linearized)| linearized || !other.precedes(op))
+    }
+}
+
+/// The register's state after `op`, or `None` if `op` couldn't have observed
+/// `state`.
+fn apply(state: &Option<Value>, op: &RegisterOp) -> Option<Option<Value>> {
+    match op {
+        RegisterOp::Read(value) => (value == state).then(|| state.clone()),
+        RegisterOp::Write(value) => Some(Some(value.clone())),
+        RegisterOp::Cas { from, to, ok: true } => {
+            (state.as_ref() == Some(from)).then(|| Some(to.clone()))
+        }
+        RegisterOp::Cas {
+            from, ok: false, ..
+        } => (state.as_ref() != Some(from)).then(|| state.clone()),
+    }
+}
+
+/// Check a history of Maelstrom kafka operations:
+/// - every acknowledged send got a distinct offset within its key,
+/// - sends ordered in real time got increasing offsets,
+/// - polls return each key's messages in increasing offset order, matching
+///   what the sends were acknowledged with,
+/// - committed offsets never move backwards between reads ordered in real
+///   time.
+pub fn check_kafka(history: &History) -> anyhow::Result<()> {
+    let ops = history.operations();
+
+    // (key, offset) -> the acknowledged send and its message
+    let mut sent: HashMap<(String, u64), (&Operation, &Value)> = HashMap::new();
+    let mut sends: BTreeMap<&str, Vec<(&Operation, u64)>> = BTreeMap::new();
+    for op in ops {
+        if op.request_type() != Some("send") || op.reply_type() != Some("send_ok") {
+            continue;
+        }
+        let (Some(key), Some(msg), Some(offset)) = (
+            op.request.get("key").and_then(Value::as_str),
+            op.request.get("msg"),
+            op.reply
+                .as_ref()
+                .and_then(|r| r.get("offset"))
+                .and_then(Value::as_u64),
+        ) else {
+            bail!("malformed send: {:?} -> {:?}", op.request, op.reply);
+        };
+        if let Some((other, _)) = sent.insert((key.to_string(), offset), (op, msg)) {
+            bail!(
+                "sends {} and {} to key {key} both got offset {offset}",
+                other.request,
+                op.request
+            );
+        }
+        sends.entry(key).or_default().push((op, offset));
+    }
+
+    for (key, sends) in &sends {
+        for (a, a_offset) in sends {
+            for (b, b_offset) in sends {
+                if a.precedes(b) && a_offset >= b_offset {
+                    bail!(
+                        "send {} to key {key} got offset {a_offset}, but the later send {} got {b_offset}",
+                        a.request,
+                        b.request,
+                    );
+                }
+            }
+        }
+    }
+
+    for op in ops {
+        if op.reply_type() != Some("poll_ok") {
+            continue;
+        }
+        let msgs = op
+            .reply
+            .as_ref()
+            .and_then(|r| r.get("msgs"))
+            .and_then(Value::as_object);
+        for (key, entries) in msgs.into_iter().flatten() {
+            let mut last = None;
+            for entry in entries.as_array().into_iter().flatten() {
+                let (Some(offset), Some(msg)) =
+                    (entry.get(0).and_then(Value::as_u64), entry.get(1))
+                else {
+                    bail!("malformed poll entry {entry} for key {key}");
+                };
+                if last.is_some_and(|last| offset <= last) {
+                    bail!("poll of key {key} returned offset {offset} after {last:?}");
+                }
+                last = Some(offset);
+                if let Some((_, expected)) = sent.get(&(key.clone(), offset)) {
+                    if *expected != msg {
+                        bail!("poll of key {key} offset {offset} returned {msg}, but {expected} was sent there");
+                    }
+                }
+            }
+        }
+    }
+
+    let listed = ops
+        .iter()
+        .filter(|op| op.reply_type() == Some("list_committed_offsets_ok"))
+        .filter_map(|op| Some((op, op.reply.as_ref()?.get("offsets")?.as_object()?)))
+        .collect::<Vec<_>>();
+    for (a, a_offsets) in &listed {
+        for (b, b_offsets) in &listed {
+            if !a.precedes(b) {
+                continue;
+            }
+            for (key, a_offset) in a_offsets.iter() {
+                let b_offset = b_offsets.get(key).and_then(Value::as_u64);
+                if let (Some(a_offset), Some(b_offset)) = (a_offset.as_u64(), b_offset) {
+                    if b_offset < a_offset {
+                        bail!(
+                            "committed offset of key {key} went from {a_offset} back to {b_offset}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// Record that `client` sent `request` at `invoked` ms and, if `reply` is
+    /// given, got it at `completed` ms.
+    fn record(
+        history: &mut History,
+        client: &str,
+        request: Value,
+        invoked: u64,
+        reply: Option<(Value, u64)>,
+    ) {
+        let msg_id = history.operations().len();
+        history.invoke(
+            client,
+            "n1",
+            msg_id,
+            request,
+            Duration::from_millis(invoked),
+        );
+        if let Some((mut reply, completed)) = reply {
+            reply["in_reply_to"] = msg_id.into();
+            history.complete(client, &reply, Duration::from_millis(completed));
+        }
+    }
+
+    /// Lin-kv's read, write and cas, all on the register `key`.
+    fn interpret(op: &Operation) -> Option<(String, RegisterOp)> {
+        let key = op.request["key"].to_string();
+        let register_op = match op.request_type()? {
+            "read" => RegisterOp::Read(op.reply.as_ref()?.get("value").cloned()),
+            "write" => RegisterOp::Write(op.request["value"].clone()),
+            "cas" => RegisterOp::Cas {
+                from: op.request["from"].clone(),
+                to: op.request["to"].clone(),
+                ok: op.reply_type() == Some("cas_ok"),
+            },
+            _ => return None,
+        };
+        Some((key, register_op))
+    }
+
+    fn register_history(reads: &[(u64, u64, Value)]) -> History {
+        let mut history = History::default();
+        record(
+            &mut history,
+            "c1",
+            json!({"type": "write", "key": 0, "value": 1}),
+            0,
+            Some((json!({"type": "write_ok"}), 10)),
+        );
+        record(
+            &mut history,
+            "c2",
+            json!({"type": "write", "key": 0, "value": 2}),
+            20,
+            Some((json!({"type": "write_ok"}), 40)),
+        );
+        for (invoked, completed, value) in reads {
+            record(
+                &mut history,
+                "c3",
+                json!({"type": "read", "key": 0}),
+                *invoked,
+                Some((json!({"type": "read_ok", "value": value}), *completed)),
+            );
+        }
+        history
+    }
+
+    #[test]
+    fn reads_concurrent_with_a_write_may_see_either_value() {
+        let history = register_history(&[(25, 30, json!(1)), (30, 35, json!(2))]);
+        check_register(&history, interpret).unwrap();
+        let history = register_history(&[(25, 30, json!(2)), (50, 60, json!(2))]);
+        check_register(&history, interpret).unwrap();
+    }
+
+    #[test]
+    fn stale_reads_are_not_linearizable() {
+        // Once 2 was read, a later read can't go back to 1.
+        let history = register_history(&[(25, 30, json!(2)), (31, 35, json!(1))]);
+        check_register(&history, interpret).unwrap_err();
+        // Nor can a read after the second write completed.
+        let history = register_history(&[(50, 60, json!(1))]);
+        check_register(&history, interpret).unwrap_err();
+    }
+
+    #[test]
+    fn uncompleted_writes_may_or_may_not_take_effect() {
+        let mut history = History::default();
+        record(
+            &mut history,
+            "c1",
+            json!({"type": "write", "key": 0, "value": 1}),
+            0,
+            None,
+        );
+        record(
+            &mut history,
+            "c2",
+            json!({"type": "cas", "key": 0, "from": 1, "to": 3}),
+            10,
+            Some((json!({"type": "cas_ok"}), 20)),
+        );
+        record(
+            &mut history,
+            "c2",
+            json!({"type": "read", "key": 0}),
+            30,
+            Some((json!({"type": "read_ok", "value": 3}), 40)),
+        );
+        check_register(&history, interpret).unwrap();
+
+        // Without the write, nothing could have set the register to 1.
+        let ops = history.operations()[1..].to_vec();
+        let history = History {
+            ops,
+            ..History::default()
+        };
+        check_register(&history, interpret).unwrap_err();
+    }
+
+    fn send(history: &mut History, key: &str, msg: u64, invoked: u64, offset: u64) {
+        record(
+            history,
+            "c1",
+            json!({"type": "send", "key": key, "msg": msg}),
+            invoked,
+            Some((json!({"type": "send_ok", "offset": offset}), invoked + 5)),
+        );
+    }
+
+    fn poll(history: &mut History, invoked: u64, msgs: Value) {
+        record(
+            history,
+            "c2",
+            json!({"type": "poll", "offsets": {}}),
+            invoked,
+            Some((json!({"type": "poll_ok", "msgs": msgs}), invoked + 5)),
+        );
+    }
+
+    fn list_committed(history: &mut History, invoked: u64, offsets: Value) {
+        record(
+            history,
+            "c2",
+            json!({"type": "list_committed_offsets", "keys": ["a"]}),
+            invoked,
+            Some((
+                json!({"type": "list_committed_offsets_ok", "offsets": offsets}),
+                invoked + 5,
+            )),
+        );
+    }
+
+    #[test]
+    fn accepts_a_consistent_kafka_history() {
+        let mut history = History::default();
+        send(&mut history, "a", 10, 0, 1);
+        send(&mut history, "a", 11, 10, 2);
+        send(&mut history, "b", 20, 10, 1);
+        poll(
+            &mut history,
+            20,
+            json!({"a": [[1, 10], [2, 11]], "b": [[1, 20]]}),
+        );
+        list_committed(&mut history, 30, json!({"a": 1}));
+        list_committed(&mut history, 40, json!({"a": 2}));
+        check_kafka(&history).unwrap();
+    }
+
+    #[test]
+    fn rejects_inconsistent_kafka_histories() {
+        let mut history = History::default();
+        send(&mut history, "a", 10, 0, 1);
+        send(&mut history, "a", 11, 10, 1);
+        check_kafka(&history).unwrap_err();
+
+        // Offsets must grow with real time.
+        let mut history = History::default();
+        send(&mut history, "a", 10, 0, 2);
+        send(&mut history, "a", 11, 10, 1);
+        check_kafka(&history).unwrap_err();
+
+        let mut history = History::default();
+        send(&mut history, "a", 10, 0, 1);
+        poll(&mut history, 10, json!({"a": [[1, 11]]}));
+        check_kafka(&history).unwrap_err();
+
+        let mut history = History::default();
+        poll(&mut history, 0, json!({"a": [[2, 10], [1, 11]]}));
+        check_kafka(&history).unwrap_err();
+
+        let mut history = History::default();
+        list_committed(&mut history, 0, json!({"a": 2}));
+        list_committed(&mut history, 10, json!({"a": 1}));
+        check_kafka(&history).unwrap_err();
+    }
+}
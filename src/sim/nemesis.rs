@@ -0,0 +1,195 @@
+//! Randomized fault schedules in the spirit of Maelstrom's nemesis, drawn
+//! from a seed so a failing schedule can be reproduced.
+
+use std::{ops::Range, time::Duration};
+
+use anyhow::bail;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{ClockSkew, Partition, Pause, Simulation};
+use crate::Node;
+
+/// A single fault of a [`NemesisSchedule`].
+#[derive(Debug, Clone)]
+pub enum Fault {
+    Partition(Partition),
+    Pause(Pause),
+    ClockSkew(ClockSkew),
+}
+
+impl Fault {
+    pub fn window(&self) -> &Range<Duration> {
+        match self {
+            Fault::Partition(partition) => &partition.window,
+            Fault::Pause(pause) => &pause.window,
+            Fault::ClockSkew(skew) => &skew.window,
+        }
+    }
+}
+
+/// Which faults to draw and how often.
+#[derive(Debug, Clone)]
+pub struct Nemesis {
+    partitions: bool,
+    pauses: bool,
+    clock_skew: bool,
+
+    /// Mean time between the starts of two faults.
+    interval: Duration,
+
+    /// How long each fault lasts.
+    duration: Range<Duration>,
+
+    /// Clock rates of skewed nodes, 1.0 being virtual time.
+    skew: Range<f64>,
+}
+
+impl Default for Nemesis {
+    fn default() -> Self {
+        Self {
+            partitions: true,
+            pauses: false,
+            clock_skew: false,
+            interval: Duration::from_secs(10),
+            duration: Duration::from_secs(2)..Duration::from_secs(10),
+            skew: 0.5..2.0,
+        }
+    }
+}
+
+impl Nemesis {
+    /// Partitions only, like Maelstrom's `--nemesis partition`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_partitions(mut self, partitions: bool) -> Self {
+        self.partitions = partitions;
+        self
+    }
+
+    pub fn with_pauses(mut self, pauses: bool) -> Self {
+        self.pauses = pauses;
+        self
+    }
+
+    pub fn with_clock_skew(mut self, clock_skew: bool) -> Self {
+        self.clock_skew = clock_skew;
+        self
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn with_duration(mut self, duration: Range<Duration>) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    /// Draw the rates of skewed clocks from `skew`, a range of positive
+    /// rates.
+    pub fn with_skew(mut self, skew: Range<f64>) -> anyhow::Result<Self> {
+        if !(skew.start > 0.0 && skew.end.is_finite() && skew.start < skew.end) {
+            bail!("clock skew must be a non-empty range of positive rates, got {skew:?}");
+        }
+        self.skew = skew;
+        Ok(self)
+    }
+
+    /// Draw the faults of a run of `horizon` over `node_ids`. The same seed
+    /// and settings always give the same schedule.
+    pub fn schedule(&self, seed: u64, node_ids: &[String], horizon: Duration) -> NemesisSchedule {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut kinds = Vec::new();
+        if self.partitions {
+            kinds.push(Kind::Partition);
+        }
+        if self.pauses {
+            kinds.push(Kind::Pause);
+        }
+        if self.clock_skew {
+            kinds.push(Kind::ClockSkew);
+        }
+
+        let mut faults = Vec::new();
+        let mut at = Duration::ZERO;
+        while !kinds.is_empty() && !node_ids.is_empty() {
+            at += self.interval.mul_f64(rng.gen_range(0.5..1.5));
+            if at >= horizon {
+                break;
+            }
+            let window = at..(at + rng.gen_range(self.duration.clone())).min(horizon);
+            let node = node_ids.choose(&mut rng).expect("there are nodes").clone();
+            let fault = match kinds.choose(&mut rng).expect("there are kinds") {
+                Kind::Partition => Fault::Partition(Partition {
+                    groups: partition_groups(node_ids, &mut rng),
+                    window,
+                }),
+                Kind::Pause => Fault::Pause(Pause { node, window }),
+                Kind::ClockSkew => Fault::ClockSkew(ClockSkew {
+                    node,
+                    rate: rng.gen_range(self.skew.clone()),
+                    window,
+                }),
+            };
+            faults.push(fault);
+        }
+
+        NemesisSchedule { faults }
+    }
+}
+
+enum Kind {
+    Partition,
+    Pause,
+    ClockSkew,
+}
+
+/// Split the cluster the way Maelstrom's partitioner does: one node cut off,
+/// a majority and a minority, or a third cut off from the rest.
+fn partition_groups(node_ids: &[String], rng: &mut StdRng) -> Vec<Vec<String>> {
+    let mut shuffled = node_ids.to_vec();
+    shuffled.shuffle(rng);
+    let split = match rng.gen_range(0..3) {
+        0 => 1,
+        1 => node_ids.len() / 2,
+        _ => node_ids.len() / 3,
+    }
+    .max(1);
+    let rest = shuffled.split_off(split.min(shuffled.len()));
+    vec![shuffled, rest]
+}
+
+/// Faults drawn by [`Nemesis::schedule`], by start time.
+#[derive(Debug, Clone)]
+pub struct NemesisSchedule {
+    faults: Vec<Fault>,
+}
+
+impl NemesisSchedule {
+    pub fn faults(&self) -> &[Fault] {
+        &self.faults
+    }
+
+    /// Install every fault in `sim`, partitions under the names `nemesis-0`,
+    /// `nemesis-1` and so on.
+    pub fn apply<S, P, IP, N>(&self, sim: &mut Simulation<S, P, IP, N>) -> anyhow::Result<()>
+    where
+        P: DeserializeOwned + Serialize + Send + 'static,
+        IP: Clone + Send + 'static,
+        N: Node<S, P, IP>,
+    {
+        for (i, fault) in self.faults.iter().enumerate() {
+            match fault.clone() {
+                Fault::Partition(partition) => sim.partition(&format!("nemesis-{i}"), partition),
+                Fault::Pause(pause) => sim.pause(pause),
+                Fault::ClockSkew(skew) => sim.skew_clock(skew)?,
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+//! Step-by-step replay of a recorded simulation, keeping a snapshot of node
+//! state after every event so that any point of the run can be inspected.
+//!
+//! Nodes are initialized and seeded as in the recorded run, and whatever they
+//! send or inject is discarded, since the trace already holds what they got
+//! in return. Decisions based on the wall clock may still differ.
+
+use std::collections::BTreeMap;
+
+use anyhow::Context as _;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use super::{
+    trace::{Trace, TraceEvent},
+    SimNode,
+};
+use crate::{Node, SnapshotState};
+
+type Snapshot<N> = Box<dyn Fn(&N) -> Value>;
+
+pub struct Replay<S, P, IP, N> {
+    trace: Trace<IP>,
+    nodes: BTreeMap<String, SimNode<N, IP>>,
+    snapshot: Snapshot<N>,
+
+    /// The state of every node before the first event.
+    initial: BTreeMap<String, Value>,
+
+    /// The state of the node that handled each replayed event, right after.
+    snapshots: Vec<Value>,
+    _marker: std::marker::PhantomData<fn(S, P)>,
+}
+
+impl<S, P, IP, N> Replay<S, P, IP, N>
+where
+    P: DeserializeOwned + Send + 'static,
+    IP: Clone + Send + 'static,
+    N: Node<S, P, IP>,
+{
+    /// Initialize the nodes of `trace` with the states returned by `state`,
+    /// describing each node's state with `snapshot` as the replay goes.
+    pub fn new(
+        trace: Trace<IP>,
+        mut state: impl FnMut(&str) -> S,
+        snapshot: impl Fn(&N) -> Value + 'static,
+    ) -> anyhow::Result<Self> {
+        let mut nodes = BTreeMap::new();
+        let mut initial = BTreeMap::new();
+        for node_id in &trace.node_ids {
+            let node = SimNode::init(trace.seed, node_id, &trace.node_ids, state(node_id))?;
+            node.discard_output();
+            initial.insert(node_id.clone(), snapshot(&node.node));
+            nodes.insert(node_id.clone(), node);
+        }
+
+        Ok(Self {
+            trace,
+            nodes,
+            snapshot: Box::new(snapshot),
+            initial,
+            snapshots: Vec::new(),
+            _marker: std::marker::PhantomData,
+        })
+    }
+
+    /// Replay `trace`, snapshotting nodes through their [`SnapshotState`].
+    pub fn from_trace(trace: Trace<IP>, state: impl FnMut(&str) -> S) -> anyhow::Result<Self>
+    where
+        N: SnapshotState,
+    {
+        Self::new(trace, state, |node: &N| {
+            serde_json::to_value(node.snapshot()).expect("node state snapshots serialize to JSON")
+        })
+    }
+
+    /// How many events were replayed so far.
+    pub fn position(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn trace(&self) -> &Trace<IP> {
+        &self.trace
+    }
+
+    pub fn node(&self, node_id: &str) -> Option<&N> {
+        self.nodes.get(node_id).map(|n| &n.node)
+    }
+
+    /// Replay the next event, returning it, or `None` at the end of the trace.
+    pub fn step(&mut self) -> anyhow::Result<Option<&TraceEvent<IP>>> {
+        let position = self.position();
+        let Some(event) = self.trace.events().get(position) else {
+            return Ok(None);
+        };
+        let node = self
+            .nodes
+            .get_mut(&event.node)
+            .with_context(|| format!("trace mentions unknown node {}", event.node))?;
+        node.handle(event.event.clone())?;
+        node.discard_output();
+        self.snapshots.push((self.snapshot)(&node.node));
+
+        Ok(self.trace.events().get(position))
+    }
+
+    /// Replay events until `position` of them were replayed, or the trace
+    /// ends.
+    pub fn run_to(&mut self, position: usize) -> anyhow::Result<()> {
+        while self.position() < position {
+            if self.step()?.is_none() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// The state of `node_id` once the first `position` events had been
+    /// handled, if that far was replayed already.
+    pub fn state_at(&self, node_id: &str, position: usize) -> Option<&Value> {
+        if position > self.position() {
+            return None;
+        }
+        self.trace.events()[..position]
+            .iter()
+            .zip(&self.snapshots)
+            .rev()
+            .find(|(event, _)| event.node == node_id)
+            .map(|(_, snapshot)| snapshot)
+            .or_else(|| self.initial.get(node_id))
+    }
+}
@@ -0,0 +1,202 @@
+//! A small builder DSL for nemesis timelines in simulator tests:
+//!
+//! ```ignore
+//! scenario()
+//!     .partition(nodes(["n0", "n1"]) | nodes(["n2"]))
+//!     .for_ticks(50)
+//!     .heal()
+//!     .expect_converged()
+//!     .run(&mut sim)?;
+//! ```
+
+use std::{ops::BitOr, time::Duration};
+
+use anyhow::{bail, Context as _};
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{Partition, Simulation};
+use crate::{Node, SnapshotState};
+
+/// Name of the partitions set up by scenarios, so `heal` leaves the ones
+/// added directly on the simulation alone.
+const PARTITION_PREFIX: &str = "scenario-";
+
+/// Groups of nodes cut off from each other, built with [`nodes`] and `|`.
+#[derive(Debug, Clone, Default)]
+pub struct Groups(Vec<Vec<String>>);
+
+/// A single group of nodes.
+pub fn nodes<const N: usize>(ids: [&str; N]) -> Groups {
+    Groups(vec![ids.iter().map(|id| id.to_string()).collect()])
+}
+
+impl BitOr for Groups {
+    type Output = Groups;
+
+    fn bitor(mut self, rhs: Groups) -> Groups {
+        self.0.extend(rhs.0);
+        self
+    }
+}
+
+type Check<S, P, IP, N> = Box<dyn Fn(&Simulation<S, P, IP, N>) -> anyhow::Result<()>>;
+
+enum Step<S, P, IP, N> {
+    Partition(Groups),
+    Heal,
+    Run(u32),
+    Request {
+        client: String,
+        node: String,
+        payload: P,
+    },
+    Expect(Check<S, P, IP, N>),
+    ExpectConverged,
+}
+
+/// A timeline of partitions, requests and expectations, run against a
+/// [`Simulation`] one step after the other.
+pub struct Scenario<S, P, IP, N> {
+    steps: Vec<Step<S, P, IP, N>>,
+    tick: Duration,
+
+    /// How many ticks `expect_converged` waits for the nodes to agree.
+    converge_within: u32,
+}
+
+pub fn scenario<S, P, IP, N>() -> Scenario<S, P, IP, N> {
+    Scenario {
+        steps: Vec::new(),
+        tick: Duration::from_millis(10),
+        converge_within: 500,
+    }
+}
+
+impl<S, P, IP, N> Scenario<S, P, IP, N>
+where
+    P: DeserializeOwned + Serialize + Send + 'static,
+    IP: Clone + Send + 'static,
+    N: Node<S, P, IP> + SnapshotState,
+{
+    /// Virtual time that makes up one tick.
+    pub fn with_tick(mut self, tick: Duration) -> Self {
+        self.tick = tick;
+        self
+    }
+
+    pub fn with_converge_within(mut self, ticks: u32) -> Self {
+        self.converge_within = ticks;
+        self
+    }
+
+    /// Cut the groups off from each other until the next `heal`.
+    pub fn partition(mut self, groups: Groups) -> Self {
+        self.steps.push(Step::Partition(groups));
+        self
+    }
+
+    /// Lift every partition set up by this scenario.
+    pub fn heal(mut self) -> Self {
+        self.steps.push(Step::Heal);
+        self
+    }
+
+    pub fn for_ticks(mut self, ticks: u32) -> Self {
+        self.steps.push(Step::Run(ticks));
+        self
+    }
+
+    pub fn request(mut self, client: &str, node: &str, payload: P) -> Self {
+        self.steps.push(Step::Request {
+            client: client.to_string(),
+            node: node.to_string(),
+            payload,
+        });
+        self
+    }
+
+    /// Fail the scenario unless `check` holds at this point.
+    pub fn expect(
+        mut self,
+        check: impl Fn(&Simulation<S, P, IP, N>) -> anyhow::Result<()> + 'static,
+    ) -> Self {
+        self.steps.push(Step::Expect(Box::new(check)));
+        self
+    }
+
+    /// Keep running until every node has the same [`SnapshotState`], failing
+    /// if that takes longer than the `converge_within` ticks.
+    pub fn expect_converged(mut self) -> Self {
+        self.steps.push(Step::ExpectConverged);
+        self
+    }
+
+    pub fn run(self, sim: &mut Simulation<S, P, IP, N>) -> anyhow::Result<()> {
+        let mut partitions = 0;
+        for (i, step) in self.steps.into_iter().enumerate() {
+            match step {
+                Step::Partition(Groups(groups)) => {
+                    partitions += 1;
+                    let partition = Partition {
+                        groups,
+                        window: sim.now()..Duration::MAX,
+                    };
+                    sim.partition(&format!("{PARTITION_PREFIX}{partitions}"), partition);
+                }
+                Step::Heal => {
+                    for n in 1..=partitions {
+                        sim.heal(&format!("{PARTITION_PREFIX}{n}"));
+                    }
+                }
+                Step::Run(ticks) => sim.run_for(self.tick * ticks)?,
+                Step::Request {
+                    client,
+                    node,
+                    payload,
+                } => {
+                    sim.request(&client, &node, payload)?;
+                }
+                Step::Expect(check) => {
+                    check(sim).with_context(|| format!("expectation of step {i} failed"))?
+                }
+                Step::ExpectConverged => {
+                    let mut ticks = 0;
+                    while !converged(sim)? {
+                        if ticks == self.converge_within {
+                            bail!(
+                                "step {i}: nodes did not converge within {ticks} ticks, at {:?}",
+                                sim.now()
+                            );
+                        }
+                        sim.run_for(self.tick)?;
+                        ticks += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn converged<S, P, IP, N>(sim: &Simulation<S, P, IP, N>) -> anyhow::Result<bool>
+where
+    P: DeserializeOwned + Serialize + Send + 'static,
+    IP: Clone + Send + 'static,
+    N: Node<S, P, IP> + SnapshotState,
+{
+    let mut snapshots = sim
+        .node_ids()
+        .filter_map(|id| sim.node(id))
+        .map(|node| serde_json::to_value(node.snapshot()));
+    let Some(first) = snapshots.next().transpose()? else {
+        return Ok(true);
+    };
+    for snapshot in snapshots {
+        if snapshot? != first {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
@@ -0,0 +1,77 @@
+//! Recording of every event handed to the nodes of a simulation, enough to
+//! replay the run with [`super::replay::Replay`].
+
+use std::{fs, path::Path, time::Duration};
+
+use anyhow::Context as _;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::Message;
+
+/// An event as handed to a node.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Recorded<IP> {
+    Message(Message<Value>),
+    Injected(IP),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceEvent<IP> {
+    /// Virtual time at which the node handled the event.
+    pub at: Duration,
+    pub node: String,
+    pub event: Recorded<IP>,
+}
+
+/// The events of a simulation run, in the order the nodes handled them,
+/// along with what's needed to initialize the nodes the same way again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Trace<IP> {
+    pub seed: u64,
+    pub node_ids: Vec<String>,
+    events: Vec<TraceEvent<IP>>,
+}
+
+impl<IP> Trace<IP> {
+    pub fn new(seed: u64, node_ids: Vec<String>) -> Self {
+        Self {
+            seed,
+            node_ids,
+            events: Vec::new(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, event: TraceEvent<IP>) {
+        self.events.push(event);
+    }
+
+    pub fn events(&self) -> &[TraceEvent<IP>] {
+        &self.events
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()>
+    where
+        IP: Serialize,
+    {
+        let json = serde_json::to_vec(self).context("serializing trace")?;
+        fs::write(path, json).with_context(|| format!("writing trace {}", path.display()))
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self>
+    where
+        IP: DeserializeOwned,
+    {
+        let json = fs::read(path).with_context(|| format!("reading trace {}", path.display()))?;
+        serde_json::from_slice(&json).context("parsing trace")
+    }
+}
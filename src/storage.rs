@@ -0,0 +1,472 @@
+//! Durable storage for node state that must survive a crash, like a Raft
+//! log or messages not yet delivered.
+//!
+//! A [`Wal`] is a file of checksummed records. Opening one recovers it:
+//! records are read up to the first one that is torn or corrupt, as a crash
+//! mid-append leaves behind, and the file is cut back to them. Open it in
+//! `Node::from_init`, replay what [`Wal::iter`] yields, and keep appending:
+//!
+//! ```ignore
+//! let mut wal = Wal::open_env(init, "log")?.expect("state dir is set");
+//! for record in wal.iter()? {
+//!     apply(serde_json::from_slice(&record?)?);
+//! }
+//! wal.append_json(&entry)?;
+//! wal.sync()?;
+//! ```
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context as _};
+use serde::Serialize;
+
+use crate::{log, Init, STATE_DIR_ENV};
+
+/// Records are framed by their length and checksum, both little endian.
+const HEADER_LEN: usize = 8;
+
+/// Anything longer is taken for a garbage length rather than allocated.
+const MAX_RECORD_LEN: usize = 64 << 20;
+
+/// What opening a [`Wal`] found.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Recovery {
+    /// Intact records kept.
+    pub records: usize,
+
+    /// Bytes cut off the end as torn or corrupt.
+    pub discarded_bytes: u64,
+}
+
+/// An append-only log of byte records, see the [module docs](self).
+pub struct Wal {
+    path: PathBuf,
+    file: BufWriter<File>,
+
+    /// Where each record starts, and where the next one will.
+    offsets: Vec<u64>,
+    end: u64,
+
+    recovery: Recovery,
+}
+
+impl Wal {
+    /// Open the log at `path`, creating it if needed, and recover it.
+    pub fn open(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("opening wal {}", path.display()))?;
+        let len = file.metadata()?.len();
+
+        let mut offsets = Vec::new();
+        let mut end = 0;
+        let mut reader = BufReader::new(&mut file);
+        loop {
+            match read_frame(&mut reader)
+                .with_context(|| format!("reading wal {}", path.display()))?
+            {
+                Frame::Record(record) => {
+                    offsets.push(end);
+                    end += (HEADER_LEN + record.len()) as u64;
+                }
+                Frame::End => break,
+                Frame::Corrupt(why) => {
+                    log::warn("discarding the end of a wal")
+                        .field("path", path.display().to_string())
+                        .field("offset", end)
+                        .field("reason", why)
+                        .emit();
+                    break;
+                }
+            }
+        }
+        drop(reader);
+
+        if end < len {
+            file.set_len(end)?;
+            file.sync_all()
+                .with_context(|| format!("truncating wal {}", path.display()))?;
+        }
+        file.seek(SeekFrom::Start(end))?;
+        let recovery = Recovery {
+            records: offsets.len(),
+            discarded_bytes: len - end,
+        };
+
+        Ok(Self {
+            path,
+            file: BufWriter::new(file),
+            offsets,
+            end,
+            recovery,
+        })
+    }
+
+    /// Open the log `name` of the node inside the directory named by
+    /// [`STATE_DIR_ENV`], if that variable is set.
+    pub fn open_env(init: &Init, name: &str) -> anyhow::Result<Option<Self>> {
+        match std::env::var_os(STATE_DIR_ENV) {
+            Some(dir) => Self::open(wal_file(Path::new(&dir), &init.node_id, name)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// What was found when the log was opened.
+    pub fn recovery(&self) -> Recovery {
+        self.recovery
+    }
+
+    /// How many records the log holds.
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    /// Append `record`, returning its index. It is only durable once
+    /// [`Wal::sync`] returned.
+    pub fn append(&mut self, record: &[u8]) -> anyhow::Result<usize> {
+        if record.len() > MAX_RECORD_LEN {
+            bail!(
+                "wal record of {} bytes exceeds the limit of {MAX_RECORD_LEN}",
+                record.len()
+            );
+        }
+        // Flushing first leaves only this record's bytes in the buffer, so a
+        // failed write can be undone by cutting the file back to `end`.
+        if self.file.buffer().len() + HEADER_LEN + record.len() > self.file.capacity() {
+            self.file
+                .flush()
+                .with_context(|| format!("appending to wal {}", self.path.display()))?;
+        }
+        if let Err(err) = self.write_frame(record) {
+            self.reset()
+                .with_context(|| format!("cutting back wal {}", self.path.display()))?;
+            return Err(err).with_context(|| format!("appending to wal {}", self.path.display()));
+        }
+        self.offsets.push(self.end);
+        self.end += (HEADER_LEN + record.len()) as u64;
+
+        Ok(self.offsets.len() - 1)
+    }
+
+    fn write_frame(&mut self, record: &[u8]) -> io::Result<()> {
+        self.file.write_all(&(record.len() as u32).to_le_bytes())?;
+        self.file.write_all(&crc32(record).to_le_bytes())?;
+        self.file.write_all(record)
+    }
+
+    /// Drop whatever of a failed append is buffered or was written.
+    fn reset(&mut self) -> io::Result<()> {
+        let file = BufWriter::new(self.file.get_ref().try_clone()?);
+        // Unlike dropping it, this doesn't flush the old writer.
+        let _ = std::mem::replace(&mut self.file, file).into_parts();
+        let file = self.file.get_mut();
+        file.set_len(self.end)?;
+        file.seek(SeekFrom::Start(self.end))?;
+        Ok(())
+    }
+
+    /// Append `value` serialized as JSON.
+    pub fn append_json<T: Serialize>(&mut self, value: &T) -> anyhow::Result<usize> {
+        let record = serde_json::to_vec(value).context("serializing wal record")?;
+        self.append(&record)
+    }
+
+    /// Make every appended record durable.
+    pub fn sync(&mut self) -> anyhow::Result<()> {
+        self.file.flush()?;
+        self.file
+            .get_ref()
+            .sync_data()
+            .with_context(|| format!("syncing wal {}", self.path.display()))
+    }
+
+    /// Every record, oldest first, including ones not yet synced.
+    pub fn iter(&mut self) -> anyhow::Result<WalIter> {
+        self.file.flush()?;
+        let file = File::open(&self.path)
+            .with_context(|| format!("opening wal {}", self.path.display()))?;
+        Ok(WalIter {
+            reader: BufReader::new(file.take(self.end)),
+        })
+    }
+
+    /// Keep only the first `len` records, e.g. to drop log entries that
+    /// conflict with the leader's. The cut is durable once this returns.
+    pub fn truncate(&mut self, len: usize) -> anyhow::Result<()> {
+        if len >= self.offsets.len() {
+            return Ok(());
+        }
+        self.file.flush()?;
+        self.end = self.offsets[len];
+        self.offsets.truncate(len);
+        let file = self.file.get_mut();
+        file.set_len(self.end)?;
+        file.seek(SeekFrom::Start(self.end))?;
+        file.sync_all()
+            .with_context(|| format!("truncating wal {}", self.path.display()))
+    }
+
+    /// Delete every record, e.g. once they were folded into a snapshot.
+    pub fn clear(&mut self) -> anyhow::Result<()> {
+        self.truncate(0)
+    }
+
+    /// Replace every record with `records`, e.g. to compact the log. They
+    /// are written to a new file first, which then takes the log's place,
+    /// so a crash leaves either the old records or the new ones.
+    pub fn replace<R: AsRef<[u8]>>(
+        &mut self,
+        records: impl IntoIterator<Item = R>,
+    ) -> anyhow::Result<()> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        remove(&tmp)?;
+
+        let mut new = Wal::open(&tmp)?;
+        for record in records {
+            new.append(record.as_ref())?;
+        }
+        new.sync()?;
+        fs::rename(&tmp, &self.path)
+            .with_context(|| format!("replacing wal {}", self.path.display()))?;
+        sync_dir(&self.path)?;
+        new.path = self.path.clone();
+        new.recovery = self.recovery;
+        *self = new;
+
+        Ok(())
+    }
+}
+
+/// The file holding the log `name` of `node_id` inside `dir`.
+pub fn wal_file(dir: &Path, node_id: &str, name: &str) -> PathBuf {
+    dir.join(format!("{node_id}-{name}.wal"))
+}
+
+/// Delete the log at `path`, if there is one.
+pub fn remove(path: &Path) -> anyhow::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("removing wal {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Make the directory entry of `path` durable, e.g. after a rename.
+fn sync_dir(path: &Path) -> anyhow::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("syncing directory {}", dir.display()))
+}
+
+/// The records of a [`Wal`], from [`Wal::iter`].
+pub struct WalIter {
+    reader: BufReader<io::Take<File>>,
+}
+
+impl Iterator for WalIter {
+    type Item = anyhow::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match read_frame(&mut self.reader) {
+            Ok(Frame::Record(record)) => Some(Ok(record)),
+            Ok(Frame::End) => None,
+            Ok(Frame::Corrupt(why)) => Some(Err(anyhow::anyhow!("corrupt wal record: {why}"))),
+            Err(err) => Some(Err(err.into())),
+        }
+    }
+}
+
+enum Frame {
+    Record(Vec<u8>),
+    End,
+    Corrupt(&'static str),
+}
+
+fn read_frame(reader: &mut impl Read) -> io::Result<Frame> {
+    let mut header = [0; HEADER_LEN];
+    match read_full(reader, &mut header)? {
+        0 => return Ok(Frame::End),
+        HEADER_LEN => {}
+        _ => return Ok(Frame::Corrupt("torn header")),
+    }
+    let len = u32::from_le_bytes(header[..4].try_into().expect("4 bytes")) as usize;
+    let checksum = u32::from_le_bytes(header[4..].try_into().expect("4 bytes"));
+    if len > MAX_RECORD_LEN {
+        return Ok(Frame::Corrupt("length out of range"));
+    }
+    let mut record = vec![0; len];
+    if read_full(reader, &mut record)? < len {
+        return Ok(Frame::Corrupt("torn record"));
+    }
+    if crc32(&record) != checksum {
+        return Ok(Frame::Corrupt("checksum mismatch"));
+    }
+
+    Ok(Frame::Record(record))
+}
+
+/// Fill `buf` as far as the reader goes, returning how much was read.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(read)
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// CRC-32 (IEEE), as used by zlib and Ethernet.
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, byte| {
+        CRC32_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh log file in the temp dir, unique to the test and process.
+    fn wal_path(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("vorticity-wal-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("{test}.wal"));
+        remove(&path).unwrap();
+        path
+    }
+
+    fn records(wal: &mut Wal) -> Vec<Vec<u8>> {
+        wal.iter().unwrap().collect::<anyhow::Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn recovers_from_a_torn_tail() {
+        let path = wal_path("torn-tail");
+        let mut wal = Wal::open(&path).unwrap();
+        wal.append(b"one").unwrap();
+        wal.append(b"two").unwrap();
+        wal.sync().unwrap();
+        drop(wal);
+
+        // A crash halfway through appending a third record.
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&5u32.to_le_bytes()).unwrap();
+        file.write_all(&crc32(b"three").to_le_bytes()).unwrap();
+        file.write_all(b"th").unwrap();
+        drop(file);
+
+        let mut wal = Wal::open(&path).unwrap();
+        assert_eq!(
+            wal.recovery(),
+            Recovery {
+                records: 2,
+                discarded_bytes: (HEADER_LEN + 2) as u64
+            }
+        );
+        assert_eq!(records(&mut wal), [b"one".to_vec(), b"two".to_vec()]);
+
+        // Appending carries on right after the intact records.
+        assert_eq!(wal.append(b"three").unwrap(), 2);
+        wal.sync().unwrap();
+        drop(wal);
+        let mut wal = Wal::open(&path).unwrap();
+        assert_eq!(wal.recovery().discarded_bytes, 0);
+        assert_eq!(records(&mut wal).len(), 3);
+    }
+
+    #[test]
+    fn discards_records_from_a_checksum_mismatch() {
+        let path = wal_path("checksum-mismatch");
+        let mut wal = Wal::open(&path).unwrap();
+        for record in [&b"one"[..], b"two", b"three"] {
+            wal.append(record).unwrap();
+        }
+        wal.sync().unwrap();
+        drop(wal);
+
+        // Flip a bit of the second record, leaving its length intact.
+        let mut bytes = fs::read(&path).unwrap();
+        let len = fs::metadata(&path).unwrap().len();
+        bytes[2 * HEADER_LEN + 3] ^= 1;
+        fs::write(&path, bytes).unwrap();
+
+        let mut wal = Wal::open(&path).unwrap();
+        assert_eq!(
+            wal.recovery(),
+            Recovery {
+                records: 1,
+                discarded_bytes: len - (HEADER_LEN + 3) as u64
+            }
+        );
+        assert_eq!(records(&mut wal), [b"one".to_vec()]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), (HEADER_LEN + 3) as u64);
+    }
+
+    #[test]
+    fn replace_keeps_only_the_new_records() {
+        let path = wal_path("replace");
+        let mut wal = Wal::open(&path).unwrap();
+        wal.append(b"one").unwrap();
+        wal.append(b"two").unwrap();
+        wal.replace([b"compacted"]).unwrap();
+        wal.append(b"three").unwrap();
+        wal.sync().unwrap();
+        drop(wal);
+
+        let mut wal = Wal::open(&path).unwrap();
+        assert_eq!(
+            records(&mut wal),
+            [b"compacted".to_vec(), b"three".to_vec()]
+        );
+    }
+}
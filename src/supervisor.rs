@@ -0,0 +1,204 @@
+//! Soft restarts of a failing node. Wrapped in [`Supervised`], a node whose
+//! `step` returns an error is dropped and initialized again, with the same
+//! init message and context, while the runtime, its threads and the
+//! transport keep running. The request that made it fail gets a `crash`
+//! error, and the new node picks up with the next event, as if the process
+//! had restarted without losing its connection:
+//!
+//! ```ignore
+//! Runtime::node::<Supervised<KafkaNode, ()>>()
+//!     .with_state(Supervisor::new(()))
+//!     .run()
+//! ```
+//!
+//! Whatever the node persisted, e.g. with [`crate::storage::Wal`], is
+//! restored the way it is after a real restart, in `from_init`, or from the
+//! state built by [`Supervisor::restoring`]. Threads the failed node
+//! spawned, like timers, keep running and injecting into the new one.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+
+use crate::{
+    error::{ErrorCode, MaelstromError},
+    log, Context, Event, Init, Message, Node, SnapshotState,
+};
+
+/// Restarts allowed within [`DEFAULT_RESTART_WINDOW`] before the node is
+/// taken for broken and the error stops the runtime after all.
+pub const DEFAULT_MAX_RESTARTS: usize = 5;
+pub const DEFAULT_RESTART_WINDOW: Duration = Duration::from_secs(60);
+
+/// Builds the initial state of every start of the node.
+type Restore<S> = Box<dyn FnMut(&Init) -> anyhow::Result<S>>;
+
+/// The initial state of a [`Supervised`] node: how to build the state of
+/// its inner node, and how often it may restart.
+pub struct Supervisor<S> {
+    restore: Restore<S>,
+    max_restarts: usize,
+    window: Duration,
+}
+
+impl<S> Supervisor<S> {
+    /// Start the node, and every restart of it, with a copy of `state`.
+    pub fn new(state: S) -> Self
+    where
+        S: Clone + 'static,
+    {
+        Self::restoring(move |_| Ok(state.clone()))
+    }
+
+    /// Start the node, and every restart of it, with the state `restore`
+    /// builds, e.g. from what it persisted.
+    pub fn restoring(restore: impl FnMut(&Init) -> anyhow::Result<S> + 'static) -> Self {
+        Self {
+            restore: Box::new(restore),
+            max_restarts: DEFAULT_MAX_RESTARTS,
+            window: DEFAULT_RESTART_WINDOW,
+        }
+    }
+
+    /// Give up once the node failed more than `max_restarts` times within
+    /// `window`, by the context's clock.
+    pub fn with_max_restarts(mut self, max_restarts: usize, window: Duration) -> Self {
+        self.max_restarts = max_restarts;
+        self.window = window;
+        self
+    }
+}
+
+/// The node `N`, restarted whenever it fails, see the [module docs](self).
+pub struct Supervised<N, S> {
+    node: N,
+    init: Init,
+    supervisor: Supervisor<S>,
+
+    /// When the node was restarted, within the last window.
+    recent: VecDeque<Instant>,
+    restarts: u64,
+}
+
+impl<N, S> Supervised<N, S> {
+    pub fn inner(&self) -> &N {
+        &self.node
+    }
+
+    /// How many times the node was restarted.
+    pub fn restarts(&self) -> u64 {
+        self.restarts
+    }
+
+    /// Replace the node after it failed with `err`, unless it failed too
+    /// often, answering `request` if the failure came from one.
+    fn restart<P, IP>(
+        &mut self,
+        err: anyhow::Error,
+        request: Option<Message<()>>,
+        context: &Context<IP>,
+    ) -> anyhow::Result<()>
+    where
+        N: Node<S, P, IP>,
+    {
+        let now = context.clock().now();
+        while self
+            .recent
+            .front()
+            .is_some_and(|at| now.saturating_duration_since(*at) > self.supervisor.window)
+        {
+            self.recent.pop_front();
+        }
+        if self.recent.len() >= self.supervisor.max_restarts {
+            return Err(err.context(format!(
+                "node failed more than {} times within {:?}",
+                self.supervisor.max_restarts, self.supervisor.window
+            )));
+        }
+
+        log::error("node failed, restarting it")
+            .field("error", format!("{err:#}"))
+            .field("restarts", self.restarts + 1)
+            .emit();
+        if let Some(request) = request {
+            let error = MaelstromError::new(ErrorCode::Crash, format!("node crashed: {err:#}"));
+            context.send(context.construct_reply(&request, error))?;
+        }
+
+        let state = (self.supervisor.restore)(&self.init)?;
+        self.node = N::from_init(state, &self.init, context.clone())
+            .map_err(|restart_err| restart_err.context(err))
+            .context("node re-initialization failed")?;
+        self.recent.push_back(now);
+        self.restarts += 1;
+        context.metrics().incr("supervisor.restarts", 1);
+
+        Ok(())
+    }
+}
+
+/// What it takes to answer `input` with an error, if it is a request.
+fn request_of<P, IP>(input: &Event<P, IP>) -> Option<Message<()>> {
+    let Event::Message(msg) = input else {
+        return None;
+    };
+    let (Some(id), None) = (msg.body().id, msg.body().in_reply_to) else {
+        return None;
+    };
+    Message::builder()
+        .src(msg.src())
+        .dst(msg.dst())
+        .with_id(id)
+        .payload(())
+        .build()
+        .ok()
+}
+
+impl<S, P, IP, N> Node<Supervisor<S>, P, IP> for Supervised<N, S>
+where
+    N: Node<S, P, IP>,
+{
+    fn from_init(
+        mut supervisor: Supervisor<S>,
+        init: &Init,
+        context: Context<IP>,
+    ) -> anyhow::Result<Self> {
+        let state = (supervisor.restore)(init)?;
+        Ok(Self {
+            node: N::from_init(state, init, context)?,
+            init: init.clone(),
+            supervisor,
+            recent: VecDeque::new(),
+            restarts: 0,
+        })
+    }
+
+    fn step(&mut self, input: Event<P, IP>, context: Context<IP>) -> anyhow::Result<()> {
+        let request = request_of(&input);
+        match self.node.step(input, context.clone()) {
+            Ok(()) => Ok(()),
+            Err(err) => self.restart(err, request, &context),
+        }
+    }
+
+    fn handle_reply(&mut self, input: Event<P, IP>, context: Context<IP>) -> anyhow::Result<()> {
+        match self.node.handle_reply(input, context.clone()) {
+            Ok(()) => Ok(()),
+            Err(err) => self.restart(err, None, &context),
+        }
+    }
+}
+
+impl<N, S> SnapshotState for Supervised<N, S>
+where
+    N: SnapshotState,
+{
+    type State = N::State;
+
+    fn snapshot(&self) -> N::State {
+        self.node.snapshot()
+    }
+}
@@ -0,0 +1,284 @@
+//! Helpers for unit testing nodes without going through stdin/stdout, and
+//! for checking whole binaries against recorded transcripts in [`golden`] or
+//! driving them directly with [`process`].
+
+use std::sync::{atomic::AtomicUsize, Arc};
+
+use anyhow::Context as _;
+use crossbeam_channel::Receiver;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::{
+    message::{MessageSet, ToEvent},
+    Context, Event, Handler, Init, Message, Node,
+};
+
+pub mod golden;
+pub mod kv;
+pub mod process;
+
+type Outgoing = Box<dyn erased_serde::Serialize + Send + Sync>;
+
+/// A [`Context`] whose output is captured instead of written to stdout, along
+/// with the [`Init`] of the node under test.
+pub struct TestContext<IP = ()> {
+    init: Init,
+    ctx: Context<IP>,
+    outgoing: Receiver<Outgoing>,
+    injected: Receiver<ToEvent<IP>>,
+
+    /// Everything the node sent so far, in order.
+    sent: Vec<Message<Value>>,
+
+    /// How many of `sent` were already offered to a handler by `route`.
+    routed: usize,
+
+    /// Id of the next message synthesized on behalf of a peer.
+    next_id: usize,
+}
+
+impl<IP> TestContext<IP>
+where
+    IP: Send + 'static,
+{
+    /// A context for `node_id`, in a cluster made of it and `peers`.
+    pub fn new(node_id: &str, peers: &[&str]) -> Self {
+        let (msg_in_tx, injected) = crossbeam_channel::unbounded();
+        let (msg_out_tx, outgoing) = crossbeam_channel::unbounded();
+        let mut node_ids = vec![node_id.to_string()];
+        node_ids.extend(peers.iter().map(|p| p.to_string()));
+        let init = Init {
+            node_id: node_id.to_string(),
+            node_ids,
+            extensions: Default::default(),
+        };
+        Self {
+            ctx: Context::new(msg_in_tx, msg_out_tx, Arc::new(AtomicUsize::new(0)))
+                .with_init(&init),
+            init,
+            outgoing,
+            injected,
+            sent: Vec::new(),
+            routed: 0,
+            next_id: 1_000_000,
+        }
+    }
+
+    pub fn init(&self) -> &Init {
+        &self.init
+    }
+
+    pub fn ctx(&self) -> Context<IP> {
+        self.ctx.clone()
+    }
+
+    /// Initialize a node the way the runtime would.
+    pub fn init_node<S, P, N>(&self, state: S) -> anyhow::Result<N>
+    where
+        N: Node<S, P, IP>,
+    {
+        N::from_init(state, &self.init, self.ctx()).context("node initialization failed")
+    }
+
+    /// Hand `msg` to the node, through `handle_reply` if it is a reply just
+    /// like the event loop does.
+    pub fn deliver<S, P, N>(&self, node: &mut N, msg: Message<P>) -> anyhow::Result<()>
+    where
+        N: Node<S, P, IP>,
+    {
+        if msg.body().in_reply_to.is_some() {
+            node.handle_reply(Event::Message(msg), self.ctx())
+        } else {
+            node.step(Event::Message(msg), self.ctx())
+        }
+    }
+
+    /// Offer every message sent since the last call to `handler`, e.g. a
+    /// [`kv::MockKv`] standing in for a Maelstrom service. Its replies are
+    /// picked up by [`TestContext::deliver_pending`].
+    pub fn route(&mut self, handler: &mut impl Handler<IP>) -> anyhow::Result<()> {
+        self.collect();
+        while self.routed < self.sent.len() {
+            let msg = serde_json::to_value(&self.sent[self.routed])?;
+            self.routed += 1;
+            if handler.can_handle(&msg) {
+                handler.step(msg, self.ctx())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Hand everything delivered or injected into the event loop to the node,
+    /// until nothing is left. Like in the event loop, replies to
+    /// [`Context::call`] go to their callbacks instead, messages the node
+    /// doesn't parse go to the handlers of [`Context::set_handlers`], and
+    /// calls that timed out by the context's clock get their `Timeout`
+    /// errors first.
+    pub fn deliver_pending<S, P, N>(&mut self, node: &mut N) -> anyhow::Result<()>
+    where
+        P: DeserializeOwned + Send + 'static,
+        N: Node<S, P, IP>,
+    {
+        self.ctx.expire_calls()?;
+        while let Ok(event) = self.injected.try_recv() {
+            if let ToEvent::Message(msg) = &event {
+                if let Some(on_reply) = self.ctx.calls().take(msg) {
+                    on_reply(Ok(msg.clone()), self.ctx())?;
+                    continue;
+                }
+            }
+            let event = event.into_event::<P>()?;
+            if let Event::Arbitrary(msg) = &event {
+                if self.ctx.offer_handlers(msg)? {
+                    continue;
+                }
+            }
+            if event.is_reply() {
+                node.handle_reply(event, self.ctx())?;
+            } else {
+                node.step(event, self.ctx())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// A message from `src` to the node under test.
+    pub fn message<P>(&mut self, src: &str, payload: P) -> Message<P> {
+        Message::builder()
+            .src(src)
+            .dst(self.init.node_id.clone())
+            .with_id(self.next_id())
+            .payload(payload)
+            .build()
+            .expect("all message fields are set")
+    }
+
+    /// The reply a peer would send to `msg`, which the node sent it.
+    pub fn reply_to<P, R>(&mut self, msg: &Message<P>, payload: R) -> Message<R> {
+        let builder = Message::builder()
+            .src(msg.dst())
+            .dst(msg.src())
+            .with_id(self.next_id())
+            .payload(payload);
+        match msg.body().id {
+            Some(id) => builder.in_reply_to(id),
+            None => builder,
+        }
+        .build()
+        .expect("all message fields are set")
+    }
+
+    /// The replies the recipients of `set` would send, with payloads chosen
+    /// by `payload` for each request.
+    pub fn replies_to<P, R>(
+        &mut self,
+        set: &MessageSet<P>,
+        mut payload: impl FnMut(&Message<P>) -> R,
+    ) -> Vec<Message<R>>
+    where
+        P: Clone,
+    {
+        set.pending()
+            .into_iter()
+            .map(|msg| {
+                let reply = payload(msg);
+                self.reply_to(msg, reply)
+            })
+            .collect()
+    }
+
+    /// The reply `peer` would send to its message in `set`, panicking if
+    /// there is none addressed to it.
+    pub fn reply_from<P, R>(&mut self, set: &MessageSet<P>, peer: &str, payload: R) -> Message<R>
+    where
+        P: Clone,
+    {
+        let msg = set
+            .pending()
+            .into_iter()
+            .find(|msg| msg.dst() == peer)
+            .unwrap_or_else(|| panic!("no message to {peer} is waiting for a reply"));
+        self.reply_to(msg, payload)
+    }
+
+    /// Every message sent so far whose payload parses as `P`.
+    pub fn sent_messages<P: DeserializeOwned>(&mut self) -> Vec<Message<P>> {
+        self.collect();
+        self.sent
+            .iter()
+            .filter_map(|msg| serde_json::from_value(serde_json::to_value(msg).ok()?).ok())
+            .collect()
+    }
+
+    /// Forget about everything sent so far.
+    pub fn clear_sent(&mut self) {
+        self.collect();
+        self.sent.clear();
+        self.routed = 0;
+    }
+
+    /// Drain the payloads injected into the event loop.
+    pub fn injected(&mut self) -> Vec<IP> {
+        self.injected
+            .try_iter()
+            .filter_map(|event| match event {
+                ToEvent::Injected(payload) | ToEvent::InjectedUntil(payload, _) => Some(payload),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The payload of the node's reply to `msg`, panicking if there is none.
+    pub fn expect_reply<P, R>(&mut self, msg: &Message<P>) -> R
+    where
+        R: DeserializeOwned,
+    {
+        let id = msg
+            .body()
+            .id
+            .expect("can only reply to messages with an id");
+        let reply = self
+            .sent_messages::<R>()
+            .into_iter()
+            .find(|sent| sent.dst() == msg.src() && sent.body().in_reply_to == Some(id));
+        match reply {
+            Some(reply) => reply.into_body().payload,
+            None => panic!("no reply to message {id} from {}", msg.src()),
+        }
+    }
+
+    /// The first sent message with payload `P` matching `predicate`, panicking
+    /// if there is none.
+    pub fn assert_sent<P>(&mut self, predicate: impl Fn(&Message<P>) -> bool) -> Message<P>
+    where
+        P: DeserializeOwned,
+    {
+        self.sent_messages::<P>()
+            .into_iter()
+            .find(|msg| predicate(msg))
+            .unwrap_or_else(|| panic!("no matching message among {:?}", self.sent))
+    }
+
+    /// Panic if anything was sent since the last [`TestContext::clear_sent`].
+    pub fn assert_nothing_sent(&mut self) {
+        self.collect();
+        assert!(self.sent.is_empty(), "unexpected messages: {:?}", self.sent);
+    }
+
+    fn next_id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn collect(&mut self) {
+        for msg in self.outgoing.try_iter() {
+            let msg = serde_json::to_value(&msg)
+                .and_then(serde_json::from_value)
+                .expect("nodes only send maelstrom messages");
+            self.sent.push(msg);
+        }
+    }
+}
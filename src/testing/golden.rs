@@ -0,0 +1,160 @@
+//! Golden transcript conformance checks for node binaries.
+//!
+//! A transcript is the JSONL a node reads on stdin, and its golden file the
+//! JSONL it is expected to write on stdout. Outgoing `msg_id`s depend on how
+//! many gossip messages went out in between, so they are ignored, as are the
+//! admin messages themselves.
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context as _};
+use serde_json::Value;
+
+use crate::admin::ADMIN_TYPE;
+
+/// Environment variable that, when set, makes [`GoldenTranscript::check`]
+/// overwrite golden files with the actual output instead of comparing.
+pub const BLESS_ENV: &str = "VORTICITY_BLESS";
+
+/// Runs a node binary over a transcript and compares its output to a golden
+/// file.
+#[derive(Debug, Clone)]
+pub struct GoldenTranscript {
+    binary: PathBuf,
+
+    /// How long the node gets to answer once its stdin is closed. Nodes with
+    /// background timers never exit on their own, so they are killed then.
+    timeout: Duration,
+}
+
+impl GoldenTranscript {
+    /// Usually called with `env!("CARGO_BIN_EXE_<name>")` from an integration
+    /// test.
+    pub fn new(binary: impl Into<PathBuf>) -> Self {
+        Self {
+            binary: binary.into(),
+            timeout: Duration::from_secs(2),
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Feed `transcript` to the binary and compare what it writes to
+    /// `golden`, failing with the first difference.
+    pub fn check(&self, transcript: &Path, golden: &Path) -> anyhow::Result<()> {
+        let input = fs::read_to_string(transcript)
+            .with_context(|| format!("reading transcript {}", transcript.display()))?;
+        let actual = self.run(&input)?;
+
+        if std::env::var_os(BLESS_ENV).is_some() {
+            let mut blessed = String::new();
+            for msg in &actual {
+                blessed.push_str(&serde_json::to_string(msg)?);
+                blessed.push('\n');
+            }
+            return fs::write(golden, blessed)
+                .with_context(|| format!("writing golden file {}", golden.display()));
+        }
+
+        let expected = fs::read_to_string(golden)
+            .with_context(|| format!("reading golden file {}", golden.display()))?;
+        let expected = parse_lines(&expected)
+            .with_context(|| format!("parsing golden file {}", golden.display()))?
+            .into_iter()
+            .filter_map(normalize)
+            .collect::<Vec<_>>();
+
+        for (line, (expected, actual)) in expected.iter().zip(&actual).enumerate() {
+            if expected != actual {
+                bail!(
+                    "output line {} differs from {}\n  expected: {expected}\n    actual: {actual}",
+                    line + 1,
+                    golden.display(),
+                );
+            }
+        }
+        if expected.len() != actual.len() {
+            bail!(
+                "expected {} messages from {}, got {}",
+                expected.len(),
+                golden.display(),
+                actual.len(),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Run the binary over `input`, returning its normalized output.
+    fn run(&self, input: &str) -> anyhow::Result<Vec<Value>> {
+        let mut child = Command::new(&self.binary)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .with_context(|| format!("spawning {}", self.binary.display()))?;
+
+        let stdout = child.stdout.take().context("capturing node stdout")?;
+        let reader = thread::spawn(move || {
+            BufReader::new(stdout)
+                .lines()
+                .collect::<Result<Vec<_>, _>>()
+        });
+
+        let mut stdin = child.stdin.take().context("capturing node stdin")?;
+        stdin
+            .write_all(input.as_bytes())
+            .context("writing transcript to node")?;
+        drop(stdin);
+
+        let deadline = Instant::now() + self.timeout;
+        while child.try_wait().context("waiting for node")?.is_none() {
+            if Instant::now() >= deadline {
+                child.kill().context("killing node")?;
+                child.wait().context("waiting for killed node")?;
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let lines = reader
+            .join()
+            .expect("failed to join stdout reader")
+            .context("reading node stdout")?;
+        Ok(parse_lines(&lines.join("\n"))?
+            .into_iter()
+            .filter_map(normalize)
+            .collect())
+    }
+}
+
+fn parse_lines(jsonl: &str) -> anyhow::Result<Vec<Value>> {
+    jsonl
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .enumerate()
+        .map(|(i, line)| {
+            serde_json::from_str(line).with_context(|| format!("line {} is not JSON", i + 1))
+        })
+        .collect()
+}
+
+/// Drop admin messages, and the `msg_id` of everything else.
+fn normalize(mut msg: Value) -> Option<Value> {
+    let body = msg.get_mut("body")?.as_object_mut()?;
+    if body.get("type").and_then(Value::as_str) == Some(ADMIN_TYPE) {
+        return None;
+    }
+    body.remove("msg_id");
+    Some(msg)
+}
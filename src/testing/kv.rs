@@ -0,0 +1,162 @@
+//! In-memory stand-ins for Maelstrom's key-value services, so nodes that use
+//! them can be tested hermetically.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    thread,
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use serde_json::{json, Value};
+
+use crate::{
+    error::{ErrorCode, MaelstromError},
+    message::ServiceId,
+    Context, Handler, Message,
+};
+
+/// Maelstrom error code for a request type the service doesn't know.
+pub const NOT_SUPPORTED: u64 = ErrorCode::NotSupported.code();
+
+/// Maelstrom error code for reading or CASing a key that was never written.
+pub const KEY_DOES_NOT_EXIST: u64 = ErrorCode::KeyDoesNotExist.code();
+
+/// Maelstrom error code for a CAS whose `from` didn't match.
+pub const PRECONDITION_FAILED: u64 = ErrorCode::PreconditionFailed.code();
+
+/// Maelstrom error code for a request the service couldn't serve right now.
+pub const TEMPORARILY_UNAVAILABLE: u64 = ErrorCode::TemporarilyUnavailable.code();
+
+/// A KV service keeping its state in a map and answering every request as
+/// soon as it sees it, or after a fixed latency.
+///
+/// Being a single map, it is linearizable whatever service it pretends to
+/// be; use [`MockKv::fail_next`] to exercise error handling.
+#[derive(Debug, Clone)]
+pub struct MockKv {
+    service: ServiceId,
+    data: HashMap<String, Value>,
+    latency: Option<Duration>,
+
+    /// Error codes to answer the next requests with, in order.
+    failures: VecDeque<ErrorCode>,
+}
+
+impl MockKv {
+    pub fn new(service: ServiceId) -> Self {
+        Self {
+            service,
+            data: HashMap::new(),
+            latency: None,
+            failures: VecDeque::new(),
+        }
+    }
+
+    pub fn lin_kv() -> Self {
+        Self::new(ServiceId::LinKv)
+    }
+
+    pub fn seq_kv() -> Self {
+        Self::new(ServiceId::SeqKv)
+    }
+
+    pub fn lww_kv() -> Self {
+        Self::new(ServiceId::LwwKv)
+    }
+
+    /// Answer from a background thread after `latency` instead of right away.
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = Some(latency);
+        self
+    }
+
+    /// Answer the next request with the Maelstrom error `code`, whatever it
+    /// asks for.
+    pub fn fail_next(&mut self, code: impl Into<ErrorCode>) {
+        self.failures.push_back(code.into());
+    }
+
+    /// The value stored under `key`, keyed like the service would, i.e. by
+    /// the key's JSON.
+    pub fn get(&self, key: &Value) -> Option<&Value> {
+        self.data.get(&key.to_string())
+    }
+
+    pub fn insert(&mut self, key: &Value, value: Value) {
+        self.data.insert(key.to_string(), value);
+    }
+
+    /// The reply body to a request body.
+    fn answer(&mut self, request: &Value) -> Value {
+        if let Some(code) = self.failures.pop_front() {
+            return error(code, "injected failure");
+        }
+        let key = request["key"].to_string();
+        match request["type"].as_str() {
+            Some("read") => match self.data.get(&key) {
+                Some(value) => json!({ "type": "read_ok", "value": value }),
+                None => error(ErrorCode::KeyDoesNotExist, "key does not exist"),
+            },
+            Some("write") => {
+                self.data.insert(key, request["value"].clone());
+                json!({ "type": "write_ok" })
+            }
+            Some("cas") => {
+                let create = request["create_if_not_exists"].as_bool() == Some(true);
+                match self.data.get(&key) {
+                    Some(current) if *current == request["from"] => {}
+                    Some(current) => {
+                        return error(
+                            ErrorCode::PreconditionFailed,
+                            &format!("expected {}, but had {current}", request["from"]),
+                        )
+                    }
+                    None if create => {}
+                    None => return error(ErrorCode::KeyDoesNotExist, "key does not exist"),
+                }
+                self.data.insert(key, request["to"].clone());
+                json!({ "type": "cas_ok" })
+            }
+            _ => error(ErrorCode::NotSupported, "not supported"),
+        }
+    }
+}
+
+fn error(code: ErrorCode, text: &str) -> Value {
+    serde_json::to_value(MaelstromError::new(code, text)).expect("errors serialize")
+}
+
+impl<IP> Handler<IP> for MockKv
+where
+    IP: Send + Sync + 'static,
+{
+    fn can_handle(&self, json: &Value) -> bool {
+        json["dest"].as_str() == Some(self.service.as_str())
+    }
+
+    fn step(&mut self, json: Value, ctx: Context<IP>) -> anyhow::Result<()> {
+        let request: Message<Value> =
+            serde_json::from_value(json).context("parsing KV service request")?;
+        let mut body = self.answer(&request.body().payload);
+        body["msg_id"] = ctx.next_msg_id().into();
+        body["in_reply_to"] = request.body().id.into();
+        let reply: Message<Value> = serde_json::from_value(json!({
+            "src": self.service.as_str(),
+            "dest": request.src(),
+            "body": body,
+        }))
+        .context("building KV service reply")?;
+
+        match self.latency {
+            Some(latency) => {
+                thread::spawn(move || {
+                    thread::sleep(latency);
+                    let _ = ctx.deliver(reply);
+                });
+                Ok(())
+            }
+            None => ctx.deliver(reply),
+        }
+    }
+}
@@ -0,0 +1,169 @@
+//! End-to-end tests against a built node binary, going through the real
+//! [`Runtime`](crate::Runtime) with its threads and stdio. Messages are typed
+//! on the way in and out, unlike the raw transcripts of [`super::golden`].
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context as _};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::{Init, Message};
+
+/// A node binary running as a child process, killed when dropped.
+pub struct NodeProcess {
+    binary: PathBuf,
+    child: Child,
+    stdin: ChildStdin,
+    stdout: Receiver<anyhow::Result<Message<Value>>>,
+
+    /// Messages read while waiting for something else, in order.
+    unclaimed: Vec<Message<Value>>,
+
+    /// How long to wait for a reply before giving up.
+    timeout: Duration,
+
+    node_id: String,
+    next_id: usize,
+}
+
+impl NodeProcess {
+    /// Spawn `binary`, usually `env!("CARGO_BIN_EXE_<name>")` from an
+    /// integration test, and initialize it per `init`.
+    pub fn spawn(binary: impl Into<PathBuf>, init: Init) -> anyhow::Result<Self> {
+        let binary = binary.into();
+        let mut child = Command::new(&binary)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .with_context(|| format!("spawning {}", binary.display()))?;
+        let stdin = child.stdin.take().context("capturing node stdin")?;
+        let stdout = child.stdout.take().context("capturing node stdout")?;
+
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let msg = line.context("reading node stdout").and_then(|line| {
+                    serde_json::from_str(&line).context("node wrote invalid JSON")
+                });
+                if tx.send(msg).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut node = Self {
+            binary,
+            child,
+            stdin,
+            stdout: rx,
+            unclaimed: Vec::new(),
+            timeout: Duration::from_secs(5),
+            node_id: init.node_id.clone(),
+            next_id: 0,
+        };
+        let mut init_msg = init.extensions;
+        init_msg.insert("type".to_string(), "init".into());
+        init_msg.insert("node_id".to_string(), init.node_id.into());
+        init_msg.insert("node_ids".to_string(), init.node_ids.into());
+        let init = Value::Object(init_msg);
+        let reply: Value = node.request("c0", init)?;
+        if reply["type"] != "init_ok" {
+            bail!("expected init_ok, got {reply}");
+        }
+
+        Ok(node)
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn binary(&self) -> &Path {
+        &self.binary
+    }
+
+    /// Send `payload` from `src`, returning the `msg_id` it was sent with.
+    pub fn send<P: Serialize>(&mut self, src: &str, payload: P) -> anyhow::Result<usize> {
+        self.next_id += 1;
+        let msg = Message::builder()
+            .src(src)
+            .dst(self.node_id.clone())
+            .with_id(self.next_id)
+            .payload(payload)
+            .build()?;
+        serde_json::to_writer(&mut self.stdin, &msg).context("writing to node stdin")?;
+        self.stdin
+            .write_all(b"\n")
+            .context("writing to node stdin")?;
+        self.stdin.flush().context("flushing node stdin")?;
+
+        Ok(self.next_id)
+    }
+
+    /// Send `payload` from `src` and wait for the reply, failing if there is
+    /// none within the timeout or it doesn't parse as `R`.
+    pub fn request<P, R>(&mut self, src: &str, payload: P) -> anyhow::Result<R>
+    where
+        P: Serialize,
+        R: DeserializeOwned,
+    {
+        let id = self.send(src, payload)?;
+        let reply = self
+            .recv_matching(|msg| msg.dst() == src && msg.body().in_reply_to == Some(id))?
+            .with_context(|| format!("no reply to message {id} from {src}"))?;
+        serde_json::from_value(reply.into_body().payload)
+            .with_context(|| format!("unexpected reply to message {id} from {src}"))
+    }
+
+    /// The first message the node writes that satisfies `predicate`, or
+    /// `None` if there is none within the timeout. Messages that don't match
+    /// are kept for later calls.
+    pub fn recv_matching(
+        &mut self,
+        predicate: impl Fn(&Message<Value>) -> bool,
+    ) -> anyhow::Result<Option<Message<Value>>> {
+        if let Some(i) = self.unclaimed.iter().position(&predicate) {
+            return Ok(Some(self.unclaimed.remove(i)));
+        }
+
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let msg = match self.stdout.recv_timeout(timeout) {
+                Ok(msg) => msg?,
+                Err(RecvTimeoutError::Timeout) => return Ok(None),
+                Err(RecvTimeoutError::Disconnected) => bail!("node closed its stdout"),
+            };
+            if predicate(&msg) {
+                return Ok(Some(msg));
+            }
+            self.unclaimed.push(msg);
+        }
+    }
+
+    /// Messages the node wrote so far that no call claimed, e.g. gossip to
+    /// its peers.
+    pub fn drain(&mut self) -> anyhow::Result<Vec<Message<Value>>> {
+        for msg in self.stdout.try_iter() {
+            self.unclaimed.push(msg?);
+        }
+        Ok(std::mem::take(&mut self.unclaimed))
+    }
+}
+
+impl Drop for NodeProcess {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,75 @@
+//! Timers injecting events into a node's own event loop, the usual way to
+//! gossip, retry or expire things periodically without a thread of the
+//! node's own:
+//!
+//! ```ignore
+//! fn from_init(state: S, init: &Init, ctx: Context<InjectedPayload>) -> anyhow::Result<Self> {
+//!     ctx.every(Duration::from_millis(300), InjectedPayload::Gossip);
+//!     let deadline = ctx.after(Duration::from_secs(5), InjectedPayload::GiveUp);
+//!     // ... and once it's no longer needed:
+//!     deadline.cancel();
+//! }
+//! ```
+//!
+//! The runtime stops every timer of a node at the end of its input, so none
+//! injects into a node that's done. Timers sleep on the context's
+//! [clock](crate::clock), and stopping or cancelling one wakes it up, so its
+//! thread exits right away rather than holding on to the context.
+
+use std::time::Duration;
+
+use crate::clock::{Interrupt, SharedClock};
+
+/// Whether the timers of a node were stopped, shared by the clones of its
+/// context.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Timers {
+    stop: Interrupt,
+}
+
+impl Timers {
+    /// Stop every timer of the node, e.g. at the end of its input.
+    pub(crate) fn stop(&self) {
+        self.stop.interrupt();
+    }
+
+    /// Sleep `duration` on `clock`, returning whether the timers weren't
+    /// stopped meanwhile.
+    pub(crate) fn sleep(&self, clock: &SharedClock, duration: Duration) -> bool {
+        clock.sleep_unless(duration, &self.stop)
+    }
+
+    /// A timer that stops along with the node's, or on its own.
+    pub(crate) fn timer(&self) -> Timer {
+        Timer {
+            cancel: self.stop.child(),
+        }
+    }
+}
+
+/// A timer started with [`Context::every`](crate::Context::every) or
+/// [`Context::after`](crate::Context::after). Dropping it leaves the timer
+/// running.
+#[derive(Debug, Clone)]
+pub struct Timer {
+    cancel: Interrupt,
+}
+
+impl Timer {
+    /// Inject nothing more.
+    pub fn cancel(&self) {
+        self.cancel.interrupt();
+    }
+
+    /// Whether the timer was cancelled, or stopped with the rest of the
+    /// node's.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_interrupted()
+    }
+
+    /// Sleep `duration` on `clock`, returning whether the timer is still
+    /// running.
+    pub(crate) fn sleep(&self, clock: &SharedClock, duration: Duration) -> bool {
+        clock.sleep_unless(duration, &self.cancel)
+    }
+}
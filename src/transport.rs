@@ -0,0 +1,321 @@
+//! Running a node on a single thread, over a transport of your choosing.
+//!
+//! [`Runtime`] normally reads stdin, steps the node and writes stdout on
+//! threads of their own. Where there are no threads, like on wasm32-wasi
+//! in wasmtime or in a browser, the node is stepped on the caller's thread
+//! instead: one line is read from the [`Transport`], the node stepped
+//! through it and any events it injected, and its output written back,
+//! before the next line is read. `Runtime::run` does this over
+//! [`Stdio`] on wasm targets; elsewhere it's available as
+//! [`NodeRunner::run_on`](crate::NodeRunner::run_on), e.g. for
+//! deterministic tests.
+//!
+//! Timers, `GossipSchedule` and [`Context::spawn`](crate::Context::spawn)
+//! need threads, so on wasm targets nodes must be driven by their input
+//! alone. Chaos, flush policies and serialization workers aren't applied.
+//!
+//! On byte streams like sockets, messages are delimited either by newlines,
+//! with [`Lines`], or by a length prefix, with [`Frames`], so they may hold
+//! raw newlines and aren't scanned for them. Frames are carried as bytes,
+//! with [`Transport::recv_bytes`] and [`Transport::send_bytes`], so a
+//! transport wrapping [`Frames`] may use a binary encoding on the wire, as
+//! long as it hands JSON on to the node. Only the runtime's JSON parsing
+//! requires UTF-8.
+
+use std::{
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::TcpStream,
+    str,
+};
+
+use anyhow::Context as _;
+use crossbeam_channel::Receiver;
+use erased_serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::{
+    message::{self, InitPayload, ToEvent},
+    step_input, Init, Message, Node, Runtime,
+};
+
+/// Where a node reads its input lines from and writes its output lines to.
+pub trait Transport {
+    /// The next line of input, or `None` at the end of input.
+    fn recv(&mut self) -> anyhow::Result<Option<String>>;
+
+    /// Write a line of output, without its newline.
+    fn send(&mut self, line: &str) -> anyhow::Result<()>;
+
+    /// The next message of input as bytes, or `None` at the end of input.
+    /// Line-based transports hand over their lines.
+    fn recv_bytes(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.recv()?.map(String::into_bytes))
+    }
+
+    /// Write a message of output given as bytes. Line-based transports
+    /// take UTF-8 only.
+    fn send_bytes(&mut self, msg: &[u8]) -> anyhow::Result<()> {
+        self.send(str::from_utf8(msg).context("output message is not UTF-8")?)
+    }
+
+    /// Called once the node has nothing more to write for now.
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// The process's stdin and stdout, as Maelstrom uses them.
+pub struct Stdio {
+    stdin: io::Lines<io::StdinLock<'static>>,
+    stdout: io::StdoutLock<'static>,
+}
+
+impl Stdio {
+    pub fn new() -> Self {
+        Self {
+            stdin: io::stdin().lines(),
+            stdout: io::stdout().lock(),
+        }
+    }
+}
+
+impl Default for Stdio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transport for Stdio {
+    fn recv(&mut self) -> anyhow::Result<Option<String>> {
+        self.stdin
+            .next()
+            .transpose()
+            .context("failed to read line from stdin")
+    }
+
+    fn send(&mut self, line: &str) -> anyhow::Result<()> {
+        writeln!(self.stdout, "{line}").context("write message to stdout")
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.stdout.flush().context("flush stdout")
+    }
+}
+
+/// Any reader and writer, e.g. in-memory buffers or a host's pipes.
+pub struct Lines<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R, W> Lines<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Self { reader, writer }
+    }
+
+    pub fn into_inner(self) -> (R, W) {
+        (self.reader, self.writer)
+    }
+}
+
+impl<R: BufRead, W: Write> Transport for Lines<R, W> {
+    fn recv(&mut self) -> anyhow::Result<Option<String>> {
+        let mut line = String::new();
+        if self
+            .reader
+            .read_line(&mut line)
+            .context("read input line")?
+            == 0
+        {
+            return Ok(None);
+        }
+        if line.ends_with('\n') {
+            line.pop();
+        }
+        Ok(Some(line))
+    }
+
+    fn send(&mut self, line: &str) -> anyhow::Result<()> {
+        writeln!(self.writer, "{line}").context("write output line")
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.writer.flush().context("flush output")
+    }
+}
+
+impl Lines<BufReader<TcpStream>, BufWriter<TcpStream>> {
+    /// Newline-delimited messages over a socket.
+    pub fn tcp(stream: TcpStream) -> io::Result<Self> {
+        Ok(Self::new(
+            BufReader::new(stream.try_clone()?),
+            BufWriter::new(stream),
+        ))
+    }
+}
+
+/// Frames longer than this are taken for a corrupt prefix rather than
+/// allocated.
+pub const MAX_FRAME_LEN: usize = 64 << 20;
+
+/// Read a frame: a big-endian `u32` byte count, then that many bytes.
+/// Returns `None` if the stream ended before the frame started.
+pub fn read_frame(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut prefix = [0; 4];
+    let mut read = 0;
+    while read < prefix.len() {
+        match reader.read(&mut prefix[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    let len = u32::from_be_bytes(prefix) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {len} bytes exceeds the limit of {MAX_FRAME_LEN}"),
+        ));
+    }
+    let mut frame = vec![0; len];
+    reader.read_exact(&mut frame)?;
+    Ok(Some(frame))
+}
+
+/// Write `frame` with its length prefix, see [`read_frame`].
+pub fn write_frame(writer: &mut impl Write, frame: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(frame.len())
+        .ok()
+        .filter(|len| *len as usize <= MAX_FRAME_LEN)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "frame of {} bytes exceeds the limit of {MAX_FRAME_LEN}",
+                    frame.len()
+                ),
+            )
+        })?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(frame)
+}
+
+/// Length-prefixed messages over any reader and writer, see
+/// [`read_frame`]. Frames are as the messages are, e.g. JSON that may be
+/// pretty-printed, where [`Lines`] needs it on one line.
+pub struct Frames<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R, W> Frames<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Self { reader, writer }
+    }
+
+    pub fn into_inner(self) -> (R, W) {
+        (self.reader, self.writer)
+    }
+}
+
+impl Frames<BufReader<TcpStream>, BufWriter<TcpStream>> {
+    /// Length-prefixed messages over a socket.
+    pub fn tcp(stream: TcpStream) -> io::Result<Self> {
+        Ok(Self::new(
+            BufReader::new(stream.try_clone()?),
+            BufWriter::new(stream),
+        ))
+    }
+}
+
+impl<R: Read, W: Write> Transport for Frames<R, W> {
+    fn recv(&mut self) -> anyhow::Result<Option<String>> {
+        self.recv_bytes()?
+            .map(|frame| String::from_utf8(frame).context("input frame is not UTF-8"))
+            .transpose()
+    }
+
+    fn send(&mut self, line: &str) -> anyhow::Result<()> {
+        self.send_bytes(line.as_bytes())
+    }
+
+    fn recv_bytes(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        read_frame(&mut self.reader).context("read input frame")
+    }
+
+    fn send_bytes(&mut self, msg: &[u8]) -> anyhow::Result<()> {
+        write_frame(&mut self.writer, msg).context("write output frame")
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.writer.flush().context("flush output")
+    }
+}
+
+/// Initialize a node from the first line of `transport` and step it on the
+/// current thread until the end of input.
+pub(crate) fn run<S, P, IP, N>(
+    runtime: Runtime,
+    mut transport: impl Transport,
+    init_state: impl FnOnce(&Init) -> anyhow::Result<S>,
+) -> anyhow::Result<()>
+where
+    P: DeserializeOwned + Send + 'static,
+    N: Node<S, P, IP>,
+    IP: Send + 'static,
+{
+    // Nothing drains these while the node is stepped, so they mustn't block.
+    let (msg_in_tx, msg_in_rx) = crossbeam_channel::unbounded();
+    let (msg_out_tx, msg_out_rx) = crossbeam_channel::unbounded();
+
+    let msg = transport
+        .recv_bytes()?
+        .context("no init message received")?;
+    let init_msg = message::parse_line::<Message<InitPayload>>(json(&msg)?)
+        .context("read init message from transport")?;
+    let InitPayload::Init(ref init) = init_msg.body().payload else {
+        anyhow::bail!("first message should be init")
+    };
+    let context = runtime.context_for(init, msg_in_tx, msg_out_tx)?;
+    let state = init_state(init)?;
+    let mut node: N = Runtime::init_node(state, &init_msg, context.clone())?;
+    write_output(&msg_out_rx, &mut transport)?;
+
+    loop {
+        let input = match msg_in_rx.try_recv() {
+            Ok(injected) => injected,
+            Err(_) => match transport.recv_bytes()? {
+                Some(msg) => ToEvent::from_line(json(&msg)?).context("read input message")?,
+                None => ToEvent::Eof,
+            },
+        };
+        let eof = step_input::<N, S, P, IP>(&mut node, &context, input)?;
+        write_output(&msg_out_rx, &mut transport)?;
+        if eof {
+            break;
+        }
+    }
+    context.timers().stop();
+    context.latency().report();
+
+    Ok(())
+}
+
+/// Write every message sent so far.
+fn write_output(
+    msg_out_rx: &Receiver<Box<dyn Serialize + Send + Sync>>,
+    transport: &mut impl Transport,
+) -> anyhow::Result<()> {
+    for msg in msg_out_rx.try_iter() {
+        let msg = serde_json::to_vec(&msg).context("serialize outgoing message")?;
+        transport.send_bytes(&msg)?;
+    }
+    transport.flush()
+}
+
+/// An input message as the JSON it must be by the time it gets to the node.
+fn json(msg: &[u8]) -> anyhow::Result<&str> {
+    str::from_utf8(msg).context("input message is not UTF-8 JSON")
+}
@@ -0,0 +1,230 @@
+//! Derive macros for the payload enums of vorticity nodes, re-exported by
+//! `vorticity` itself.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, parse_quote, Data, DeriveInput, FnArg, Ident, ImplItem, ItemEnum, ItemImpl,
+    Meta, Pat, Path, Type,
+};
+
+/// Pair every `<Name>Ok` variant with the `<Name>` request it answers, by
+/// implementing `vorticity::message::RequestReply`.
+#[proc_macro_derive(Payload)]
+pub fn derive_payload(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    request_reply(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Turn an enum into a Maelstrom payload: (de)serialized with its variant
+/// name in snake_case as the `type` field, and with [`Payload`] derived.
+#[proc_macro_attribute]
+pub fn payload(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        let attr = TokenStream2::from(attr);
+        return syn::Error::new_spanned(attr, "payload takes no arguments")
+            .into_compile_error()
+            .into();
+    }
+    let item = parse_macro_input!(item as ItemEnum);
+    quote! {
+        #[derive(::serde::Serialize, ::serde::Deserialize, ::vorticity::Payload)]
+        #[serde(tag = "type", rename_all = "snake_case")]
+        #item
+    }
+    .into()
+}
+
+/// Generate `fn dispatch(&mut self, input: &Message<P>, ctx: &Context<IP>)`
+/// for a node's impl block, handing each request to the method marked
+/// `#[handler]` and ignoring its `<Variant>Ok` reply. There is no catch-all
+/// arm, so a variant without a handler doesn't compile.
+///
+/// `#[handler]` on `handle_commit_offsets` handles `CommitOffsets`;
+/// `#[handler(Variant)]` names the variant explicitly, and `no_reply` skips
+/// the reply arm. The method's arguments are the variant's fields by name,
+/// plus the incoming message as `input` and the context as `ctx`.
+///
+/// ```ignore
+/// #[vorticity::handlers(payload = Payload, injected = InjectedPayload)]
+/// impl KafkaNode {
+///     #[handler]
+///     fn handle_send(&mut self, key: &str, msg: &Msg, input: &Message<Payload>) -> anyhow::Result<()> {
+///         ...
+///     }
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn handlers(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut payload: Option<Path> = None;
+    let mut injected: Option<Type> = None;
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("payload") {
+            payload = Some(meta.value()?.parse()?);
+            Ok(())
+        } else if meta.path.is_ident("injected") {
+            injected = Some(meta.value()?.parse()?);
+            Ok(())
+        } else {
+            Err(meta.error("expected `payload` or `injected`"))
+        }
+    });
+    parse_macro_input!(attr with parser);
+    let mut item = parse_macro_input!(item as ItemImpl);
+    dispatch(payload, injected, &mut item)
+        .map(|()| quote!(#item))
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn dispatch(payload: Option<Path>, injected: Option<Type>, item: &mut ItemImpl) -> syn::Result<()> {
+    let payload = payload
+        .ok_or_else(|| syn::Error::new(Span::call_site(), "handlers needs `payload = <type>`"))?;
+    let injected = injected.unwrap_or_else(|| parse_quote!(()));
+
+    let mut arms = Vec::new();
+    for impl_item in &mut item.items {
+        let ImplItem::Fn(method) = impl_item else {
+            continue;
+        };
+        let Some(i) = method
+            .attrs
+            .iter()
+            .position(|attr| attr.path().is_ident("handler"))
+        else {
+            continue;
+        };
+        let attr = method.attrs.remove(i);
+
+        let mut variant = None;
+        let mut reply = true;
+        if let Meta::List(_) = attr.meta {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("no_reply") {
+                    reply = false;
+                } else if let Some(ident) = meta.path.get_ident() {
+                    variant = Some(ident.clone());
+                } else {
+                    return Err(meta.error("expected a variant name or `no_reply`"));
+                }
+                Ok(())
+            })?;
+        }
+        let name = &method.sig.ident;
+        let variant = match variant {
+            Some(variant) => variant,
+            None => {
+                let Some(variant) = name.to_string().strip_prefix("handle_").map(camel_case) else {
+                    return Err(syn::Error::new_spanned(
+                        name,
+                        "name the variant, as in #[handler(Variant)]",
+                    ));
+                };
+                Ident::new(&variant, name.span())
+            }
+        };
+
+        let mut fields = Vec::new();
+        let mut args = Vec::new();
+        for arg in &method.sig.inputs {
+            let FnArg::Typed(arg) = arg else {
+                continue;
+            };
+            let Pat::Ident(arg) = &*arg.pat else {
+                return Err(syn::Error::new_spanned(
+                    &arg.pat,
+                    "handler arguments must be plain names",
+                ));
+            };
+            let arg = &arg.ident;
+            if arg != "input" && arg != "ctx" {
+                fields.push(arg);
+            }
+            args.push(arg);
+        }
+        arms.push(quote! {
+            #payload::#variant { #(#fields,)* .. } => self.#name(#(#args),*),
+        });
+        if reply {
+            let reply = format_ident!("{variant}Ok");
+            arms.push(quote!(#payload::#reply { .. } => Ok(()),));
+        }
+    }
+
+    item.items.push(parse_quote! {
+        /// Hand `input` to the handler of its payload's variant, ignoring
+        /// replies to handled requests.
+        fn dispatch(
+            &mut self,
+            input: &::vorticity::Message<#payload>,
+            ctx: &::vorticity::Context<#injected>,
+        ) -> ::anyhow::Result<()> {
+            match &input.body().payload {
+                #(#arms)*
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn camel_case(snake: &str) -> String {
+    snake
+        .split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|first| first.to_uppercase().chain(chars).collect::<String>())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+fn request_reply(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Enum(data) = &input.data else {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "Payload can only be derived for enums",
+        ));
+    };
+
+    let mut requests: Vec<&Ident> = Vec::new();
+    let mut replies: Vec<&Ident> = Vec::new();
+    for reply in &data.variants {
+        let name = reply.ident.to_string();
+        let Some(request) = name
+            .strip_suffix("Ok")
+            .and_then(|request| data.variants.iter().find(|v| v.ident == request))
+        else {
+            continue;
+        };
+        requests.push(&request.ident);
+        replies.push(&reply.ident);
+    }
+
+    let (is_reply, is_reply_to) = if replies.is_empty() {
+        (quote!(false), quote!(false))
+    } else {
+        (
+            quote!(matches!(self, #(Self::#replies { .. })|*)),
+            quote!(matches!((request, self), #((Self::#requests { .. }, Self::#replies { .. }))|*)),
+        )
+    };
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::vorticity::message::RequestReply for #name #ty_generics #where_clause {
+            fn is_reply(&self) -> bool {
+                #is_reply
+            }
+
+            fn is_reply_to(&self, request: &Self) -> bool {
+                #is_reply_to
+            }
+        }
+    })
+}